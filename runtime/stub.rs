@@ -3,6 +3,18 @@
 struct SnakeVal(u64);
 
 static TAG_MASK: u64 = 0x00_00_00_00_00_00_00_01;
+static HEAP_TAG_MASK: u64 = 0x00_00_00_00_00_00_00_07;
+static FLOAT_TAG: u64 = 0x00_00_00_00_00_00_00_01;
+static TUPLE_TAG: u64 = 0x00_00_00_00_00_00_00_05;
+static CLOSURE_TAG: u64 = 0x00_00_00_00_00_00_00_03;
+// shared with the booleans, which are the only values with it that
+// don't point to a variant or a string
+static VARIANT_TAG: u64 = 0x00_00_00_00_00_00_00_07;
+static STRING_TAG: u64 = 0x00_00_00_00_00_00_00_07;
+// an array is laid out like a variant whose constructor number is this
+// header, which no constructor has
+static ARRAY_TAG: u64 = 0x00_00_00_00_00_00_00_07;
+static ARRAY_HEADER: u64 = 0x40_00_00_00_00_00_00_00;
 static SNAKE_TRU: SnakeVal = SnakeVal(0xFF_FF_FF_FF_FF_FF_FF_FF);
 static SNAKE_FLS: SnakeVal = SnakeVal(0x7F_FF_FF_FF_FF_FF_FF_FF);
 
@@ -14,6 +26,32 @@ static LOGIC_ERROR: ErrorCode = 3;
 static IF_ERROR: ErrorCode = 4;
 static DIVISION_ERROR: ErrorCode = 5;
 static SQRT_ERROR: ErrorCode = 6;
+static TUPLE_ERROR: ErrorCode = 7;
+static INDEX_ERROR: ErrorCode = 8;
+static BOUNDS_ERROR: ErrorCode = 9;
+static CALL_ERROR: ErrorCode = 10;
+static ARITY_ERROR: ErrorCode = 11;
+static OUT_OF_MEMORY_ERROR: ErrorCode = 12;
+static WHILE_ERROR: ErrorCode = 13;
+static MATCH_ERROR: ErrorCode = 14;
+static STRING_ERROR: ErrorCode = 15;
+static ARRAY_ERROR: ErrorCode = 16;
+static ARRAY_BOUNDS_ERROR: ErrorCode = 17;
+static ARRAY_SIZE_ERROR: ErrorCode = 18;
+
+// default heap size in words, can be overridden by the first command line argument
+static HEAP_SIZE: usize = 1 << 20;
+
+// set in the header of a heap object once it has been copied to the to-space
+static FORWARDED: u64 = 0x80_00_00_00_00_00_00_00;
+
+// The compiled code bumps r15 until it reaches HEAP_END
+#[export_name = "\x01HEAP_END"]
+static mut HEAP_END: *const u64 = std::ptr::null();
+
+static mut FROM_SPACE: *mut u64 = std::ptr::null_mut();
+static mut TO_SPACE: *mut u64 = std::ptr::null_mut();
+static mut HEAP_WORDS: usize = 0;
 
 #[link(name = "compiled_code", kind = "static")]
 extern "sysv64" {
@@ -21,7 +59,68 @@ extern "sysv64" {
     // The \x01 here is an undocumented feature of LLVM that ensures
     // it does not add an underscore in front of the name.
     #[link_name = "\x01start_here"]
-    fn start_here() -> SnakeVal;
+    fn start_here(heap: *mut u64) -> SnakeVal;
+
+    // the tables of every module of the compiled code, ended by a null
+    // pointer; a whole program is a single module
+    #[link_name = "\x01snake_modules"]
+    static SNAKE_MODULES: [*const Module; 0];
+}
+
+// The data of a module of the compiled code, see module_data in compile.rs
+#[repr(C)]
+struct Module {
+    // the file and span of every expression the module checks
+    locations: *const Location,
+    // the label and the address of the name of every function of the
+    // module, two u64s per entry, ended by a zero label
+    functions: *const u64,
+    // the address of the name of every constructor of the module, by
+    // its number less first_constructor
+    constructors: *const u64,
+    first_constructor: u64,
+    constructors_len: u64,
+    // the words of the frames of its functions
+    space: u64,
+}
+
+// The span is on line 0, and the file null, when it is not known
+#[repr(C)]
+struct Location {
+    file: *const std::ffi::c_char,
+    start_line: u32,
+    start_col: u32,
+    end_line: u32,
+    end_col: u32,
+}
+
+fn modules() -> Vec<&'static Module> {
+    let mut modules = vec![];
+    unsafe {
+        let mut entry = std::ptr::addr_of!(SNAKE_MODULES) as *const *const Module;
+        while !(*entry).is_null() {
+            modules.push(&**entry);
+            entry = entry.add(1);
+        }
+    }
+    modules
+}
+
+// The module whose function has the label, and the name of the function
+fn find_function(label: u64) -> Option<(&'static Module, String)> {
+    for module in modules() {
+        unsafe {
+            let mut entry = module.functions;
+            while *entry != 0 {
+                if *entry == label {
+                    let name = std::ffi::CStr::from_ptr(*entry.add(1) as *const std::ffi::c_char);
+                    return Some((module, name.to_string_lossy().to_string()));
+                }
+                entry = entry.add(2);
+            }
+        }
+    }
+    None
 }
 
 // reinterprets the bytes of an unsigned number to a signed number
@@ -29,32 +128,159 @@ fn unsigned_to_signed(x: u64) -> i64 {
     i64::from_le_bytes(x.to_le_bytes())
 }
 
-fn u64_to_u8_array(value: u64) -> [u8; 8] {
-    let byte0 = ((value >> 56) & 0xFF) as u8;
-    let byte1 = ((value >> 48) & 0xFF) as u8;
-    let byte2 = ((value >> 40) & 0xFF) as u8;
-    let byte3 = ((value >> 32) & 0xFF) as u8;
-    let byte4 = ((value >> 24) & 0xFF) as u8;
-    let byte5 = ((value >> 16) & 0xFF) as u8;
-    let byte6 = ((value >> 8) & 0xFF) as u8;
-    let byte7 = (value & 0xFF) as u8;
-
-    [byte0, byte1, byte2, byte3, byte4, byte5, byte6, byte7]
+// floats are boxed: a zero header followed by the bits of the double
+fn float_value(x: SnakeVal) -> f64 {
+    let addr = (x.0 - FLOAT_TAG) as *const u64;
+    unsafe { f64::from_bits(*addr.add(1)) }
 }
 
-fn sprint_snake_val(x: SnakeVal) -> String {
+// the value of a number or a float as a double
+fn number_value(x: SnakeVal) -> Option<f64> {
     if x.0 & TAG_MASK == 0 {
-        // it's a number
-        format!("{}", unsigned_to_signed(x.0) >> 1)
-    } else if x == SNAKE_TRU {
-        String::from("true")
-    } else if x == SNAKE_FLS {
-        String::from("false")
-    } else if x.0 & 3 == 1 {
-        // it's a float
-        format!("{}", f64::from_be_bytes(u64_to_u8_array(x.0 - 1)) as f32)
+        Some((unsigned_to_signed(x.0) >> 1) as f64)
+    } else if x.0 & HEAP_TAG_MASK == FLOAT_TAG {
+        Some(float_value(x))
     } else {
-        format!("error: cannot print {}", x.0)
+        None
+    }
+}
+
+// returns the elements of a tuple value
+fn tuple_elems(x: SnakeVal) -> &'static [SnakeVal] {
+    let addr = (x.0 - TUPLE_TAG) as *const u64;
+    unsafe {
+        let len = *addr as usize;
+        std::slice::from_raw_parts(addr.add(1) as *const SnakeVal, len)
+    }
+}
+
+// a variant is the number of its constructor as a snake number, the
+// number of its fields and the fields
+fn variant(x: SnakeVal) -> (usize, &'static [SnakeVal]) {
+    let addr = (x.0 - VARIANT_TAG) as *const u64;
+    unsafe {
+        let len = *addr.add(1) as usize;
+        ((*addr >> 1) as usize, std::slice::from_raw_parts(addr.add(2) as *const SnakeVal, len))
+    }
+}
+
+// a string is its length in bytes shifted left by one with the low bit
+// set, which tells it apart from a variant, then its bytes
+fn is_string(x: SnakeVal) -> bool {
+    x.0 & HEAP_TAG_MASK == STRING_TAG
+        && x != SNAKE_TRU
+        && x != SNAKE_FLS
+        && unsafe { *((x.0 - STRING_TAG) as *const u64) } & 1 == 1
+}
+
+fn string_bytes(x: SnakeVal) -> &'static [u8] {
+    let addr = (x.0 - STRING_TAG) as *const u64;
+    unsafe { std::slice::from_raw_parts(addr.add(1) as *const u8, (*addr >> 1) as usize) }
+}
+
+fn is_array(x: SnakeVal) -> bool {
+    x.0 & HEAP_TAG_MASK == ARRAY_TAG
+        && x != SNAKE_TRU
+        && x != SNAKE_FLS
+        && unsafe { *((x.0 - ARRAY_TAG) as *const u64) } == ARRAY_HEADER
+}
+
+// the length of an array is untagged
+fn array_elems(x: SnakeVal) -> &'static [SnakeVal] {
+    let addr = (x.0 - ARRAY_TAG) as *const u64;
+    unsafe { std::slice::from_raw_parts(addr.add(2) as *const SnakeVal, *addr.add(1) as usize) }
+}
+
+fn constructor_name(n: usize) -> String {
+    let n = n as u64;
+    for module in modules() {
+        if module.first_constructor <= n && n < module.first_constructor + module.constructors_len {
+            unsafe {
+                let entry = module.constructors.add((n - module.first_constructor) as usize);
+                let name = std::ffi::CStr::from_ptr(*entry as *const std::ffi::c_char);
+                return name.to_string_lossy().into_owned();
+            }
+        }
+    }
+    String::from("?")
+}
+
+// What is left to print of a value: the values inside of it, the text
+// between them and the ends of the arrays they are in
+enum Print {
+    Value(SnakeVal),
+    Text(&'static str),
+    EndArray,
+}
+
+// Values are printed from a worklist, so that deeply nested ones don't
+// run out of stack. path holds the arrays being printed, an array
+// inside of itself is printed as [...]
+fn sprint_snake_val(x: SnakeVal) -> String {
+    let mut out = String::new();
+    let mut path = vec![];
+    let mut todo = vec![Print::Value(x)];
+    while let Some(next) = todo.pop() {
+        let x = match next {
+            Print::Value(x) => x,
+            Print::Text(text) => {
+                out.push_str(text);
+                continue;
+            }
+            Print::EndArray => {
+                path.pop();
+                out.push(']');
+                continue;
+            }
+        };
+        if x.0 & TAG_MASK == 0 {
+            // it's a number
+            out.push_str(&format!("{}", unsigned_to_signed(x.0) >> 1));
+        } else if x == SNAKE_TRU {
+            out.push_str("true");
+        } else if x == SNAKE_FLS {
+            out.push_str("false");
+        } else if x.0 & HEAP_TAG_MASK == FLOAT_TAG {
+            // it's a float
+            out.push_str(&format!("{}", float_value(x)));
+        } else if x.0 & HEAP_TAG_MASK == TUPLE_TAG {
+            let elems = tuple_elems(x);
+            out.push('(');
+            todo.push(Print::Text(if elems.len() == 1 { ",)" } else { ")" }));
+            push_elems(&mut todo, elems);
+        } else if x.0 & HEAP_TAG_MASK == CLOSURE_TAG {
+            out.push_str("<closure>");
+        } else if is_string(x) {
+            out.push_str(&String::from_utf8_lossy(string_bytes(x)));
+        } else if is_array(x) && path.contains(&x) {
+            out.push_str("[...]");
+        } else if is_array(x) {
+            path.push(x);
+            out.push('[');
+            todo.push(Print::EndArray);
+            push_elems(&mut todo, array_elems(x));
+        } else if x.0 & HEAP_TAG_MASK == VARIANT_TAG {
+            let (n, fields) = variant(x);
+            out.push_str(&constructor_name(n));
+            if !fields.is_empty() {
+                out.push('(');
+                todo.push(Print::Text(")"));
+                push_elems(&mut todo, fields);
+            }
+        } else {
+            out.push_str(&format!("error: cannot print {}", x.0));
+        }
+    }
+    out
+}
+
+// the elements go on the worklist last first, with commas between them
+fn push_elems(todo: &mut Vec<Print>, elems: &[SnakeVal]) {
+    for (i, v) in elems.iter().enumerate().rev() {
+        todo.push(Print::Value(*v));
+        if i > 0 {
+            todo.push(Print::Text(", "));
+        }
     }
 }
 
@@ -64,44 +290,431 @@ extern "sysv64" fn print_snake_val(v: SnakeVal) -> SnakeVal {
     v
 }
 
-/* Implement the following error function. You are free to change the
- * input and output types as needed for your design.
+fn is_variant(x: SnakeVal) -> bool {
+    x.0 & HEAP_TAG_MASK == VARIANT_TAG && x != SNAKE_TRU && x != SNAKE_FLS && !is_string(x) && !is_array(x)
+}
+
+// The pairs of elements still to compare are kept on a worklist, so
+// that deeply nested values don't run out of stack. seen holds the
+// pairs of arrays being compared, which are equal unless some other
+// elements of theirs differ
+fn equal_snake_val(x: SnakeVal, y: SnakeVal) -> bool {
+    let mut todo = vec![(x, y)];
+    let mut seen = vec![];
+    while let Some((x, y)) = todo.pop() {
+        let (xs, ys) = if x.0 & HEAP_TAG_MASK == TUPLE_TAG && y.0 & HEAP_TAG_MASK == TUPLE_TAG {
+            (tuple_elems(x), tuple_elems(y))
+        } else if is_array(x) && is_array(y) {
+            if x == y || seen.contains(&(x, y)) {
+                continue;
+            }
+            seen.push((x, y));
+            (array_elems(x), array_elems(y))
+        } else if is_variant(x) && is_variant(y) {
+            let ((n, xs), (m, ys)) = (variant(x), variant(y));
+            if n != m {
+                return false;
+            }
+            (xs, ys)
+        } else if equal_leaves(x, y) {
+            continue;
+        } else {
+            return false;
+        };
+        if xs.len() != ys.len() {
+            return false;
+        }
+        todo.extend(xs.iter().copied().zip(ys.iter().copied()).rev());
+    }
+    true
+}
+
+// values with nothing inside of them to compare
+fn equal_leaves(x: SnakeVal, y: SnakeVal) -> bool {
+    if x.0 & TAG_MASK == 0 && y.0 & TAG_MASK == 0 {
+        x == y
+    } else if let (Some(f), Some(g)) = (number_value(x), number_value(y)) {
+        // a number and a float are equal like they are at the top
+        f == g
+    } else if is_string(x) && is_string(y) {
+        string_bytes(x) == string_bytes(y)
+    } else {
+        x == y
+    }
+}
+
+#[export_name = "\x01snake_equal"]
+extern "sysv64" fn snake_equal(x: SnakeVal, y: SnakeVal) -> SnakeVal {
+    if equal_snake_val(x, y) {
+        SNAKE_TRU
+    } else {
+        SNAKE_FLS
+    }
+}
+
+// cos has no SSE2 instruction, the compiled code calls this instead
+#[export_name = "\x01snake_cos"]
+extern "sysv64" fn snake_cos(x: f64) -> f64 {
+    x.cos()
+}
+
+/* A new string is made where the compiled code has reserved room for
+ * it, at dest. Its value is returned in rax and the end of the heap
+ * after it in rdx.
+ */
+#[repr(C)]
+struct Made {
+    value: SnakeVal,
+    free: *mut u64,
+}
+
+unsafe fn make_string(bytes: &[u8], dest: *mut u64) -> Made {
+    *dest = (bytes.len() as u64) << 1 | 1;
+    std::ptr::copy_nonoverlapping(bytes.as_ptr(), dest.add(1) as *mut u8, bytes.len());
+    Made {
+        value: SnakeVal(dest as u64 + STRING_TAG),
+        free: dest.add(1 + (bytes.len() + 7) / 8),
+    }
+}
+
+#[export_name = "\x01snake_string_concat"]
+unsafe extern "sysv64" fn snake_string_concat(x: SnakeVal, y: SnakeVal, dest: *mut u64) -> Made {
+    let bytes = [string_bytes(x), string_bytes(y)].concat();
+    make_string(&bytes, dest)
+}
+
+// start and end are snake numbers the compiled code has checked are
+// in bounds, and one inside a character is reported with err from the
+// function whose frame is frame
+#[export_name = "\x01snake_string_substr"]
+unsafe extern "sysv64" fn snake_string_substr(
+    x: SnakeVal,
+    start: SnakeVal,
+    end: SnakeVal,
+    dest: *mut u64,
+    err: u64,
+    frame: *const u64,
+) -> Made {
+    let bytes = string_bytes(x);
+    for index in &[start, end] {
+        let i = (index.0 >> 1) as usize;
+        if i < bytes.len() && bytes[i] & 0xC0 == 0x80 {
+            snake_error(err, SnakeVal(index.0), 0, frame);
+        }
+    }
+    make_string(&bytes[(start.0 >> 1) as usize..(end.0 >> 1) as usize], dest)
+}
+
+// the number of bytes of tostring(x), to reserve room for it
+#[export_name = "\x01snake_tostring_len"]
+extern "sysv64" fn snake_tostring_len(x: SnakeVal) -> u64 {
+    sprint_snake_val(x).len() as u64
+}
+
+#[export_name = "\x01snake_tostring"]
+unsafe extern "sysv64" fn snake_tostring(x: SnakeVal, dest: *mut u64) -> Made {
+    make_string(sprint_snake_val(x).as_bytes(), dest)
+}
+
+// number of words taken by the heap object at addr
+unsafe fn object_size(addr: *const u64, tag: u64) -> usize {
+    if tag == TUPLE_TAG {
+        1 + *addr as usize
+    } else if tag == FLOAT_TAG {
+        2
+    } else if tag == STRING_TAG && *addr & 1 == 1 {
+        1 + ((*addr >> 1) as usize + 7) / 8
+    } else if tag == VARIANT_TAG {
+        2 + *addr.add(1) as usize
+    } else {
+        3 + *addr.add(2) as usize
+    }
+}
+
+// the fields of a heap object that hold snake values
+unsafe fn object_fields(addr: *mut u64, tag: u64) -> std::ops::Range<usize> {
+    if tag == TUPLE_TAG {
+        1..1 + *addr as usize
+    } else if tag == FLOAT_TAG {
+        0..0
+    } else if tag == STRING_TAG && *addr & 1 == 1 {
+        0..0
+    } else if tag == VARIANT_TAG {
+        2..2 + *addr.add(1) as usize
+    } else {
+        3..3 + *addr.add(2) as usize
+    }
+}
+
+// Copies the object v points to into the to-space (unless it already
+// has been) and returns the new value.
+unsafe fn forward(v: u64, free: &mut *mut u64, worklist: &mut Vec<(*mut u64, u64)>) -> u64 {
+    let tag = v & HEAP_TAG_MASK;
+    let addr = (v - tag) as *mut u64;
+    if *addr & FORWARDED != 0 {
+        return (*addr & !FORWARDED) + tag;
+    }
+    let size = object_size(addr, tag);
+    std::ptr::copy_nonoverlapping(addr, *free, size);
+    *addr = *free as u64 | FORWARDED;
+    worklist.push((*free, tag));
+    let new_v = *free as u64 + tag;
+    *free = free.add(size);
+    new_v
+}
+
+unsafe fn is_from_space_ptr(v: u64) -> bool {
+    let tag = v & HEAP_TAG_MASK;
+    let addr = (v - tag) as *const u64;
+    // float and string literals live outside of the heap and aren't
+    // moved, and booleans don't point anywhere
+    (tag == TUPLE_TAG || tag == CLOSURE_TAG || tag == FLOAT_TAG || tag == VARIANT_TAG)
+        && addr >= FROM_SPACE as *const u64
+        && addr < FROM_SPACE.add(HEAP_WORDS) as *const u64
+}
+
+/* Copying collector.
  *
+ * Every frame is the words of locals of its module right below the
+ * return address into its caller, at its rbp, and the frames are linked
+ * from fp, the rbp of the function that allocates, up to the frame of
+ * main, see enter_frame in compile_with_env. Frames are cleared on
+ * entry, so every local is either a valid snake value or zero. The
+ * frame of the function that allocates is `space` words, and starts at
+ * `stack_top`.
+ */
+#[export_name = "\x01snake_try_gc"]
+unsafe extern "sysv64" fn snake_try_gc(
+    words: u64,
+    stack_top: *mut u64,
+    space: u64,
+    fp: *const u64,
+) -> *mut u64 {
+    let mut free = TO_SPACE;
+    let mut worklist = Vec::new();
+
+    let mut frame = stack_top;
+    let mut space = space as usize;
+    let mut rbp = fp as *mut u64;
+    while !rbp.is_null() {
+        for i in 0..space {
+            let slot = frame.add(i);
+            if is_from_space_ptr(*slot) {
+                *slot = forward(*slot, &mut free, &mut worklist);
+            }
+        }
+        // the caller's frame ends right above the return address
+        rbp = *rbp.add(1) as *mut u64;
+        if !rbp.is_null() {
+            space = frame_space(rbp);
+            frame = rbp.sub(space);
+        }
+    }
+
+    while let Some((addr, tag)) = worklist.pop() {
+        for i in object_fields(addr, tag) {
+            let field = addr.add(i);
+            if is_from_space_ptr(*field) {
+                *field = forward(*field, &mut free, &mut worklist);
+            }
+        }
+    }
+
+    let old_space = FROM_SPACE;
+    FROM_SPACE = TO_SPACE;
+    TO_SPACE = old_space;
+    HEAP_END = FROM_SPACE.add(HEAP_WORDS);
+    // an array can ask for more words than there are addresses
+    if words as usize > HEAP_END.offset_from(free) as usize {
+        snake_error(OUT_OF_MEMORY_ERROR, SnakeVal(words), 0, fp);
+    }
+    free
+}
+
+/* Reports a runtime error and exits with 1 + err_code, so whoever ran
+ * the program can tell the errors apart without reading stderr.
+ *
+ * The low half of err holds the code of the error and the high half
+ * the number of the expression whose check failed. frame is the rbp of
+ * the function it failed in, from which the functions still running
+ * are printed, innermost first. The last line repeats the message, the
+ * file and the span of the expression as JSON for whoever ran the
+ * program, as the message may have any text in it, e.g.
+ *
+ *   division by zero at line 3, column 10 of stats.garter
+ *   backtrace:
+ *     0: average
+ *     1: main
+ *   error: {"message": "division by zero", "file": "stats.garter", "span": [3, 9, 3, 14]}
 **/
 #[export_name = "\x01snake_error"]
-extern "sysv64" fn snake_error(err_code: u64, v: SnakeVal) {
-    if err_code == ARITH_ERROR {
-        eprintln!(
+extern "sysv64" fn snake_error(err: u64, v: SnakeVal, num_provided: u64, frame: *const u64) {
+    let err_code = err & 0xFF_FF_FF_FF;
+    let message = if err_code == ARITH_ERROR {
+        format!(
             "arithmetic expected a number or float, but got {}",
             sprint_snake_val(v)
-        );
+        )
     } else if err_code == COMP_ERROR {
-        eprintln!(
+        format!(
             "comparison expected a number or float, but got {}",
             sprint_snake_val(v)
-        );
+        )
     } else if err_code == OVERFLOW_ERROR {
-        eprintln!("overflow");
+        String::from("overflow")
     } else if err_code == LOGIC_ERROR {
-        eprintln!("logic expected a boolean, but got {}", sprint_snake_val(v));
+        format!("logic expected a boolean, but got {}", sprint_snake_val(v))
     } else if err_code == IF_ERROR {
-        eprintln!("if expected a boolean, but got {}", sprint_snake_val(v));
+        format!("if expected a boolean, but got {}", sprint_snake_val(v))
+    } else if err_code == WHILE_ERROR {
+        format!("while expected a boolean, but got {}", sprint_snake_val(v))
     } else if err_code == DIVISION_ERROR {
-        eprintln!("division by zero");
+        String::from("division by zero")
     } else if err_code == SQRT_ERROR {
-        eprintln!("sqrt expected a non-negative value");
-    }
-    
-    else {
-        eprintln!(
+        String::from("sqrt expected a non-negative value")
+    } else if err_code == TUPLE_ERROR {
+        format!("indexing expected a tuple or an array, but got {}", sprint_snake_val(v))
+    } else if err_code == INDEX_ERROR {
+        format!("index expected a number, but got {}", sprint_snake_val(v))
+    } else if err_code == BOUNDS_ERROR {
+        format!("index out of bounds: {}", sprint_snake_val(v))
+    } else if err_code == CALL_ERROR {
+        format!("call expected a function, but got {}", sprint_snake_val(v))
+    } else if err_code == MATCH_ERROR {
+        format!("no pattern matches {}", sprint_snake_val(v))
+    } else if err_code == STRING_ERROR {
+        format!("string operation expected a string, but got {}", sprint_snake_val(v))
+    } else if err_code == ARRAY_ERROR {
+        format!("array operation expected an array, but got {}", sprint_snake_val(v))
+    } else if err_code == ARRAY_BOUNDS_ERROR {
+        // the length of the array is passed untagged
+        format!(
+            "index {} out of bounds for an array of length {}",
+            sprint_snake_val(v),
+            num_provided
+        )
+    } else if err_code == ARRAY_SIZE_ERROR {
+        format!("array size expected a non-negative number, but got {}", sprint_snake_val(v))
+    } else if err_code == OUT_OF_MEMORY_ERROR {
+        format!("out of memory: could not allocate {} words", v.0)
+    } else if err_code == ARITY_ERROR {
+        // the expected arity is stored untagged in the closure
+        format!(
+            "Function expecting {} arguments called with {} arguments",
+            v.0, num_provided
+        )
+    } else {
+        format!(
             "I apologize to you, dear user. I made a bug. Here's a snake value: {}",
             sprint_snake_val(v)
-        );
+        )
+    };
+    let location = location(frame, err >> 32);
+    match &location {
+        Some((file, (line, col, _, _))) => {
+            eprintln!("{} at line {}, column {} of {}", message, line, col + 1, file)
+        }
+        None => eprintln!("{}", message),
+    }
+    eprintln!("backtrace:");
+    for (i, name) in backtrace(frame).iter().enumerate() {
+        eprintln!("  {}: {}", i, name);
+    }
+    let (file, span) = match location {
+        Some((file, (start_line, start_col, end_line, end_col))) => (
+            json_string(&file),
+            format!("[{}, {}, {}, {}]", start_line, start_col, end_line, end_col),
+        ),
+        None => (String::from("null"), String::from("null")),
+    };
+    eprintln!(
+        "error: {{\"message\": {}, \"file\": {}, \"span\": {}}}",
+        json_string(&message),
+        file,
+        span
+    );
+    std::process::exit(1 + err_code as i32);
+}
+
+// s as a JSON string, on one line
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// the words of the frame at rbp, which differ between modules
+unsafe fn frame_space(rbp: *const u64) -> usize {
+    match find_function(*rbp.add(2)) {
+        Some((module, _)) => module.space as usize,
+        None => panic!("no function has the frame at {:?}", rbp),
+    }
+}
+
+// the names of the functions whose frames are linked from frame, see
+// enter_frame in compile_with_env
+fn backtrace(mut frame: *const u64) -> Vec<String> {
+    let mut names = vec![];
+    unsafe {
+        while !frame.is_null() {
+            names.push(function_name(*frame.add(2)));
+            frame = *frame.add(1) as *const u64;
+        }
+    }
+    names
+}
+
+fn function_name(label: u64) -> String {
+    match find_function(label) {
+        Some((_, name)) => name,
+        None => String::from("?"),
+    }
+}
+
+// the file and span of the n-th expression of the module of the
+// function whose frame is frame: its first line and column, and the
+// line and column it ends before, with lines counted from 1 and
+// columns from 0
+fn location(frame: *const u64, n: u64) -> Option<(String, (u32, u32, u32, u32))> {
+    if frame.is_null() {
+        return None;
+    }
+    let (module, _) = find_function(unsafe { *frame.add(2) })?;
+    let entry = unsafe { &*module.locations.add(n as usize) };
+    if entry.start_line == 0 {
+        return None;
     }
-    std::process::exit(1);
+    let file = unsafe { std::ffi::CStr::from_ptr(entry.file) };
+    Some((
+        file.to_string_lossy().into_owned(),
+        (entry.start_line, entry.start_col, entry.end_line, entry.end_col),
+    ))
 }
 
 fn main() {
-    let output = unsafe { start_here() };
+    let heap_words = match std::env::args().nth(1) {
+        Some(arg) => arg.parse().expect("heap size should be a number of words"),
+        None => HEAP_SIZE,
+    };
+    let mut from_space = vec![0u64; heap_words];
+    let mut to_space = vec![0u64; heap_words];
+    let output = unsafe {
+        FROM_SPACE = from_space.as_mut_ptr();
+        TO_SPACE = to_space.as_mut_ptr();
+        HEAP_WORDS = heap_words;
+        HEAP_END = FROM_SPACE.add(heap_words);
+        start_here(FROM_SPACE)
+    };
     println!("{}", sprint_snake_val(output));
 }
@@ -16,15 +16,9 @@ pub enum Reg {
     R13,
     R14,
     R15,
-    St0,
-    St1,
-    St2,
-    St3,
-    St4,
-    St5,
-    St6,
-    St7,
-    Ax
+    Xmm0,
+    Xmm1,
+    Xmm2,
 }
 
 
@@ -63,20 +57,6 @@ pub enum MovArgs {
     ToMem(MemRef, Reg32),
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub enum FloatMem {
-    RegMem(MemRef),
-    VarMem(String)
-}
-
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub enum FloatArg {
-    ToReg(Reg, Arg64),
-    Mem(FloatMem),
-    Reg(Reg),
-    Blank
-}
-
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum BinArgs {
     ToReg(Reg, Arg32),
@@ -90,6 +70,11 @@ pub enum Instr {
     Add(BinArgs),
     Sub(BinArgs),
     IMul(BinArgs),
+    // divides rdx:rax by the register, leaving the quotient, rounded
+    // towards zero, in rax and the remainder in rdx
+    IDiv(Reg),
+    // sign-extends rax into rdx
+    Cqo,
     And(BinArgs),
     Or(BinArgs),
     Xor(BinArgs),
@@ -103,9 +88,13 @@ pub enum Instr {
     Comment(String),
 
     Call(String),
+    CallReg(Reg),
+    // loads the address of a label
+    Lea(Reg, String),
     Ret,
 
     Jmp(String),
+    JmpReg(Reg),
     Je(String),
     Jne(String),
     Jl(String),
@@ -114,32 +103,31 @@ pub enum Instr {
     Jge(String),
 
     Js(String),  // jump if msb is 1
+    Jns(String), // jump if msb is 0
     Jz(String),  // jump if result was 0
     Jnz(String), // jump if result was not 0
 
     Jo(String),  // jump if last arith operation overflowed
     Jno(String), // jump if last arith operation didn't overflow
 
-    Fld(FloatMem),
-    Fild(FloatMem),
-    Fstp(FloatMem),
-    Fistp(FloatMem),
-    Fadd(FloatArg),
-    Faddp(FloatArg),
-    Fsub(FloatArg),
-    Fsubp(FloatArg),
-    Fmul(FloatArg),
-    Fmulp(FloatArg),
-    Fdiv(FloatArg),
-    Fdivp(FloatArg),
-    Fstsw(FloatArg),
-    Fcom(FloatArg),
-    Fcomp(FloatArg),
-    Fcompp(FloatArg),
-    Fabs,
-    Fld1,
-    Fcos,
-    Fsqrt
+    // unsigned conditions, as set by ucomisd
+    Ja(String),
+    Jae(String),
+    Jb(String),
+    Jbe(String),
+
+    // moves the bits between a general purpose and an xmm register
+    Movq(Reg, Reg),
+    Addsd(Reg, Reg),
+    Subsd(Reg, Reg),
+    Mulsd(Reg, Reg),
+    Divsd(Reg, Reg),
+    Sqrtsd(Reg, Reg),
+    Ucomisd(Reg, Reg),
+    // converts a signed integer to a double
+    Cvtsi2sd(Reg, Reg),
+    // converts a double to a signed integer, rounding towards zero
+    Cvttsd2si(Reg, Reg),
 }
 
 pub fn reg_to_string(r: Reg) -> String {
@@ -160,15 +148,9 @@ pub fn reg_to_string(r: Reg) -> String {
         Reg::R13 => String::from("r13"),
         Reg::R14 => String::from("r14"),
         Reg::R15 => String::from("r15"),
-        Reg::St0 => String::from("st0"),
-        Reg::St1 => String::from("st1"),
-        Reg::St2 => String::from("st2"),
-        Reg::St3 => String::from("st3"),
-        Reg::St4 => String::from("st4"),
-        Reg::St5 => String::from("st5"),
-        Reg::St6 => String::from("st6"),
-        Reg::St7 => String::from("st7"),
-        Reg::Ax => String::from("ax")
+        Reg::Xmm0 => String::from("xmm0"),
+        Reg::Xmm1 => String::from("xmm1"),
+        Reg::Xmm2 => String::from("xmm2"),
     }
 }
 
@@ -184,13 +166,6 @@ pub fn varmem_ref_to_string(m: String) -> String {
     format!("qword [{}]", m)
 }
 
-pub fn floatmem_to_string(m: FloatMem) -> String {
-    match m {
-        FloatMem::RegMem(m) => mem_ref_to_string(m),
-        FloatMem::VarMem(m) => varmem_ref_to_string(m)
-    }
-}
-
 pub fn reg32_to_string(r_or_i: Reg32) -> String {
     match r_or_i {
         Reg32::Reg(r) => reg_to_string(r),
@@ -239,15 +214,8 @@ pub fn bin_args_to_string(args: BinArgs) -> String {
     }
 }
 
-pub fn farg_to_string(arg: FloatArg) -> String {
-    match arg {
-        FloatArg::ToReg(r, arg) => {
-            format!("{}, {}", reg_to_string(r), arg64_to_string(arg))
-        }
-        FloatArg::Mem(m) => floatmem_to_string(m),
-        FloatArg::Reg(r) => reg_to_string(r),
-        FloatArg::Blank => " ".to_string()
-    }
+pub fn regs_to_string(dst: Reg, src: Reg) -> String {
+    format!("{}, {}", reg_to_string(dst), reg_to_string(src))
 }
 
 pub fn instr_to_string(i: &Instr) -> String {
@@ -264,6 +232,10 @@ pub fn instr_to_string(i: &Instr) -> String {
         Instr::IMul(args) => {
             format!("        imul {}", bin_args_to_string(*args))
         }
+        Instr::IDiv(r) => {
+            format!("        idiv {}", reg_to_string(*r))
+        }
+        Instr::Cqo => String::from("        cqo"),
         Instr::And(args) => {
             format!("        and {}", bin_args_to_string(*args))
         }
@@ -299,6 +271,12 @@ pub fn instr_to_string(i: &Instr) -> String {
         Instr::Call(s) => {
             format!("        call {}", s)
         }
+        Instr::CallReg(r) => {
+            format!("        call {}", reg_to_string(*r))
+        }
+        Instr::Lea(r, l) => {
+            format!("        lea {}, [rel {}]", reg_to_string(*r), l)
+        }
         Instr::Ret => {
             format!("        ret")
         }
@@ -306,6 +284,9 @@ pub fn instr_to_string(i: &Instr) -> String {
         Instr::Jmp(s) => {
             format!("        jmp {}", s)
         }
+        Instr::JmpReg(r) => {
+            format!("        jmp {}", reg_to_string(*r))
+        }
         Instr::Je(s) => {
             format!("        je {}", s)
         }
@@ -327,6 +308,9 @@ pub fn instr_to_string(i: &Instr) -> String {
         Instr::Js(s) => {
             format!("        js {}", s)
         }
+        Instr::Jns(s) => {
+            format!("        jns {}", s)
+        }
         Instr::Jz(s) => {
             format!("        jz {}", s)
         }
@@ -339,65 +323,44 @@ pub fn instr_to_string(i: &Instr) -> String {
         Instr::Jno(s) => {
             format!("        jno {}", s)
         }
-        Instr::Fld(arg) => {
-            format!("        fld {}", floatmem_to_string(arg.clone()))
-        }
-        Instr::Fabs => {
-            format!("        fabs")
-        }
-        Instr::Fld1 => {
-            format!("        fld1")
-        }
-        Instr::Fcos => {
-            format!("        fcos")
-        }
-        Instr::Fsqrt => {
-            format!("        fsqrt")
-        }
-        Instr::Fild(arg) => {
-            format!("        fild {}", floatmem_to_string(arg.clone()))
-        }
-        Instr::Fstp(arg) => {
-            format!("        fstp {}", floatmem_to_string(arg.clone()))
-        }
-        Instr::Fistp(arg) => {
-            format!("        fistp {}", floatmem_to_string(arg.clone()))
+        Instr::Ja(s) => {
+            format!("        ja {}", s)
         }
-        Instr::Fcom(arg) => {
-            format!("        fcom {}", farg_to_string(arg.clone()))
+        Instr::Jae(s) => {
+            format!("        jae {}", s)
         }
-        Instr::Fcomp(arg) => {
-            format!("        fcomp {}", farg_to_string(arg.clone()))
+        Instr::Jb(s) => {
+            format!("        jb {}", s)
         }
-        Instr::Fcompp(arg) => {
-            format!("        fcompp {}", farg_to_string(arg.clone()))
+        Instr::Jbe(s) => {
+            format!("        jbe {}", s)
         }
-        Instr::Fadd(arg) => {
-            format!("        fadd {}", farg_to_string(arg.clone()))
+        Instr::Movq(dst, src) => {
+            format!("        movq {}", regs_to_string(*dst, *src))
         }
-        Instr::Faddp(arg) => {
-            format!("        faddp {}", farg_to_string(arg.clone()))
+        Instr::Addsd(dst, src) => {
+            format!("        addsd {}", regs_to_string(*dst, *src))
         }
-        Instr::Fsub(arg) => {
-            format!("        fsub {}", farg_to_string(arg.clone()))
+        Instr::Subsd(dst, src) => {
+            format!("        subsd {}", regs_to_string(*dst, *src))
         }
-        Instr::Fsubp(arg) => {
-            format!("        fsubp {}", farg_to_string(arg.clone()))
+        Instr::Mulsd(dst, src) => {
+            format!("        mulsd {}", regs_to_string(*dst, *src))
         }
-        Instr::Fmul(arg) => {
-            format!("        fmul {}", farg_to_string(arg.clone()))
+        Instr::Divsd(dst, src) => {
+            format!("        divsd {}", regs_to_string(*dst, *src))
         }
-        Instr::Fmulp(arg) => {
-            format!("        fmul {}", farg_to_string(arg.clone()))
+        Instr::Sqrtsd(dst, src) => {
+            format!("        sqrtsd {}", regs_to_string(*dst, *src))
         }
-        Instr::Fdiv(arg) => {
-            format!("        fdiv {}", farg_to_string(arg.clone()))
+        Instr::Ucomisd(dst, src) => {
+            format!("        ucomisd {}", regs_to_string(*dst, *src))
         }
-        Instr::Fdivp(arg) => {
-            format!("        fdivp {}", farg_to_string(arg.clone()))
+        Instr::Cvtsi2sd(dst, src) => {
+            format!("        cvtsi2sd {}", regs_to_string(*dst, *src))
         }
-        Instr::Fstsw(arg) => {
-            format!("        fstsw {}", farg_to_string(arg.clone()))
+        Instr::Cvttsd2si(dst, src) => {
+            format!("        cvttsd2si {}", regs_to_string(*dst, *src))
         }
     }
 }
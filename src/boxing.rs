@@ -0,0 +1,244 @@
+use crate::syntax::{Exp, FunDecl, Prim};
+use std::collections::{HashMap, HashSet};
+
+/* Lambda lifting gives a function copies of the variables it uses from
+ * around it, so x := e in one place would go unseen in the others.
+ * The variables that are both assigned and used in a function nested
+ * in their scope are boxed instead: they hold a tuple of one element
+ * that every copy points to, which reads and assignments go through.
+ *
+ * The names of e must be unique, as tag_exp leaves them.
+ */
+pub fn box_assigned<Ann: Clone>(e: &Exp<Ann>) -> Exp<Ann> {
+    let mut assigned = HashSet::new();
+    let mut captured = HashSet::new();
+    uses(e, 0, &mut HashMap::new(), &mut assigned, &mut captured);
+    let boxed: HashSet<String> = assigned.intersection(&captured).cloned().collect();
+    if boxed.is_empty() {
+        return e.clone();
+    }
+    box_exp(e, &boxed)
+}
+
+// Finds the assigned variables and the ones used at a deeper level of
+// functions than they are bound at
+fn uses<Ann>(
+    e: &Exp<Ann>,
+    level: usize,
+    bound_at: &mut HashMap<String, usize>,
+    assigned: &mut HashSet<String>,
+    captured: &mut HashSet<String>,
+) {
+    let used = |x: &String, captured: &mut HashSet<String>| {
+        if matches!(bound_at.get(x), Some(l) if *l < level) {
+            captured.insert(x.clone());
+        }
+    };
+    match e {
+        Exp::Var(x, _) => used(x, captured),
+        Exp::Assign { var, value, .. } => {
+            used(var, captured);
+            assigned.insert(var.clone());
+            uses(value, level, bound_at, assigned, captured);
+        }
+        Exp::Call(f, args, _) => {
+            // f may be a variable holding a closure
+            used(f, captured);
+            for arg in args {
+                uses(arg, level, bound_at, assigned, captured);
+            }
+        }
+        Exp::Let { bindings, body, .. } => {
+            for (x, e) in bindings {
+                uses(e, level, bound_at, assigned, captured);
+                bound_at.insert(x.clone(), level);
+            }
+            uses(body, level, bound_at, assigned, captured);
+        }
+        Exp::FunDefs { decls, body, .. } => {
+            for decl in decls {
+                for x in &decl.parameters {
+                    bound_at.insert(x.clone(), level + 1);
+                }
+                uses(&decl.body, level + 1, bound_at, assigned, captured);
+            }
+            uses(body, level, bound_at, assigned, captured);
+        }
+        Exp::Lambda {
+            parameters, body, ..
+        } => {
+            for x in parameters {
+                bound_at.insert(x.clone(), level + 1);
+            }
+            uses(body, level + 1, bound_at, assigned, captured);
+        }
+        Exp::Prim(_, es, _) => {
+            for e in es {
+                uses(e, level, bound_at, assigned, captured);
+            }
+        }
+        Exp::If { cond, thn, els, .. } => {
+            uses(cond, level, bound_at, assigned, captured);
+            uses(thn, level, bound_at, assigned, captured);
+            uses(els, level, bound_at, assigned, captured);
+        }
+        Exp::Semicolon { e1, e2, .. } => {
+            uses(e1, level, bound_at, assigned, captured);
+            uses(e2, level, bound_at, assigned, captured);
+        }
+        Exp::While { cond, body, .. } => {
+            uses(cond, level, bound_at, assigned, captured);
+            uses(body, level, bound_at, assigned, captured);
+        }
+        Exp::ClosureCall { fun, args, .. } => {
+            uses(fun, level, bound_at, assigned, captured);
+            for arg in args {
+                uses(arg, level, bound_at, assigned, captured);
+            }
+        }
+        Exp::Num(..) | Exp::Bool(..) | Exp::Float(..) | Exp::Str(..) => {}
+        Exp::InternalTailCall(..) | Exp::ExternalCall { .. } | Exp::MakeClosure { .. } => {
+            panic!("Should never happen: boxing called with internal compiler forms")
+        }
+        Exp::TypeDecl { .. } | Exp::Construct { .. } | Exp::Match { .. } => {
+            panic!("Should never happen: boxing called before lower_matches")
+        }
+    }
+}
+
+fn make_box<Ann: Clone>(e: Exp<Ann>, ann: &Ann) -> Exp<Ann> {
+    Exp::Prim(Prim::MakeTuple, vec![Box::new(e)], ann.clone())
+}
+
+// A boxed parameter is passed as it is and boxed at the start of the
+// body, under its own name
+fn box_params<Ann: Clone>(parameters: &[String], body: &Exp<Ann>, boxed: &HashSet<String>) -> (Vec<String>, Exp<Ann>) {
+    let ann = body.ann();
+    let mut new_parameters = vec![];
+    let mut bindings = vec![];
+    for x in parameters {
+        if boxed.contains(x) {
+            let passed = format!("{}#unboxed", x);
+            bindings.push((x.clone(), make_box(Exp::Var(passed.clone(), ann.clone()), &ann)));
+            new_parameters.push(passed);
+        } else {
+            new_parameters.push(x.clone());
+        }
+    }
+    let body = box_exp(body, boxed);
+    if bindings.is_empty() {
+        return (new_parameters, body);
+    }
+    (
+        new_parameters,
+        Exp::Let {
+            bindings,
+            body: Box::new(body),
+            ann,
+        },
+    )
+}
+
+fn box_exp<Ann: Clone>(e: &Exp<Ann>, boxed: &HashSet<String>) -> Exp<Ann> {
+    let rec = |e: &Exp<Ann>| Box::new(box_exp(e, boxed));
+    match e {
+        Exp::Var(x, ann) if boxed.contains(x) => {
+            Exp::Prim(Prim::Unbox, vec![Box::new(e.clone())], ann.clone())
+        }
+        Exp::Assign { var, value, ann } if boxed.contains(var) => Exp::Prim(
+            Prim::SetBox,
+            vec![Box::new(Exp::Var(var.clone(), ann.clone())), rec(value)],
+            ann.clone(),
+        ),
+        Exp::Assign { var, value, ann } => Exp::Assign {
+            var: var.clone(),
+            value: rec(value),
+            ann: ann.clone(),
+        },
+        Exp::Call(f, args, ann) if boxed.contains(f) => Exp::ClosureCall {
+            fun: rec(&Exp::Var(f.clone(), ann.clone())),
+            args: args.iter().map(|e| box_exp(e, boxed)).collect(),
+            is_tail: false,
+            ann: ann.clone(),
+        },
+        Exp::Call(f, args, ann) => Exp::Call(f.clone(), args.iter().map(|e| box_exp(e, boxed)).collect(), ann.clone()),
+        Exp::Let { bindings, body, ann } => Exp::Let {
+            bindings: bindings
+                .iter()
+                .map(|(x, e)| {
+                    let e = box_exp(e, boxed);
+                    if boxed.contains(x) {
+                        (x.clone(), make_box(e, ann))
+                    } else {
+                        (x.clone(), e)
+                    }
+                })
+                .collect(),
+            body: rec(body),
+            ann: ann.clone(),
+        },
+        Exp::FunDefs { decls, body, ann } => Exp::FunDefs {
+            decls: decls
+                .iter()
+                .map(|decl| {
+                    let (parameters, body) = box_params(&decl.parameters, &decl.body, boxed);
+                    FunDecl {
+                        name: decl.name.clone(),
+                        parameters,
+                        signature: decl.signature.clone(),
+                        body,
+                        ann: decl.ann.clone(),
+                    }
+                })
+                .collect(),
+            body: rec(body),
+            ann: ann.clone(),
+        },
+        Exp::Lambda {
+            parameters,
+            body,
+            ann,
+        } => {
+            let (parameters, body) = box_params(parameters, body, boxed);
+            Exp::Lambda {
+                parameters,
+                body: Box::new(body),
+                ann: ann.clone(),
+            }
+        }
+        Exp::Prim(p, es, ann) => Exp::Prim(*p, es.iter().map(|e| rec(e)).collect(), ann.clone()),
+        Exp::If {
+            cond,
+            thn,
+            els,
+            ann,
+        } => Exp::If {
+            cond: rec(cond),
+            thn: rec(thn),
+            els: rec(els),
+            ann: ann.clone(),
+        },
+        Exp::Semicolon { e1, e2, ann } => Exp::Semicolon {
+            e1: rec(e1),
+            e2: rec(e2),
+            ann: ann.clone(),
+        },
+        Exp::While { cond, body, ann } => Exp::While {
+            cond: rec(cond),
+            body: rec(body),
+            ann: ann.clone(),
+        },
+        Exp::ClosureCall {
+            fun,
+            args,
+            is_tail,
+            ann,
+        } => Exp::ClosureCall {
+            fun: rec(fun),
+            args: args.iter().map(|e| box_exp(e, boxed)).collect(),
+            is_tail: *is_tail,
+            ann: ann.clone(),
+        },
+        _ => e.clone(),
+    }
+}
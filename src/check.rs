@@ -1,138 +1,145 @@
 use crate::compile::CompileErr;
-use crate::syntax::{Exp, FloatWrapper, SurfProg};
+use crate::syntax::{Exp, FloatWrapper, Pattern, SurfProg};
 use std::collections::{HashMap, HashSet};
 
 static MAX_SNAKE_INT: i64 = i64::MAX >> 1;
 static MIN_SNAKE_INT: i64 = i64::MIN >> 1;
 
+// The constructors in scope, each with every constructor of its type
+// and their arities, its own included
+pub type Constructors = HashMap<String, Vec<(String, usize)>>;
+
+/* Pushes every error of p to errs. After an error the check goes on
+ * as if the program were fine there: the arguments of a call to an
+ * undefined function or with the wrong number of arguments are still
+ * checked, and a name bound twice is bound all the same.
+ *
+ * A match that some values of its type get through without matching
+ * an arm gets a warning, which goes to errs with the errors.
+ */
 pub fn check_exp<Span>(
     p: &SurfProg<Span>,
     env: &mut HashSet<String>,
     fun_to_paralen: &mut HashMap<String, usize>,
-) -> Result<(), CompileErr<Span>>
-where
+    constructors: &mut Constructors,
+    errs: &mut Vec<CompileErr<Span>>,
+) where
     Span: Clone,
 {
     match p {
         Exp::Num(n, ann) => {
-            if *n <= MAX_SNAKE_INT && *n >= MIN_SNAKE_INT {
-                Ok(())
-            } else {
-                Err(CompileErr::NumOverflow {
+            if *n > MAX_SNAKE_INT || *n < MIN_SNAKE_INT {
+                errs.push(CompileErr::NumOverflow {
                     num: *n,
                     location: ann.clone(),
                 })
             }
         }
-        Exp::Bool(_, _) => {
-            Ok(())
-        }
+        Exp::Bool(_, _) | Exp::Str(_, _) => {}
         Exp::Float(FloatWrapper(f), ann) => {
-            if *f <= f32::MAX as f64 && *f >= f32::MIN as f64 {
-                Ok(())
-            } else {
-                Err(CompileErr::FloatOverflow {
+            if !f.is_finite() {
+                errs.push(CompileErr::FloatOverflow {
                     num: FloatWrapper(*f),
                     location: ann.clone(),
                 })
             }
         }
         Exp::Var(s, ann) => {
-            if !env.contains(s) {
-                if fun_to_paralen.contains_key(s) {
-                    Err(CompileErr::FunctionUsedAsValue {
-                        function_name: s.clone(),
-                        location: ann.clone(),
-                    })
-                } else {
-                    Err(CompileErr::UnboundVariable {
-                        unbound: s.clone(),
-                        location: ann.clone(),
-                    })
-                }
-            } else {
-                Ok(())
+            // functions are values too
+            if !env.contains(s) && !fun_to_paralen.contains_key(s) {
+                errs.push(CompileErr::UnboundVariable {
+                    unbound: s.clone(),
+                    location: ann.clone(),
+                    suggestion: suggest(s, env.iter().chain(fun_to_paralen.keys())),
+                })
             }
         }
         Exp::Prim(_, exps, _) => {
             for e in exps {
-                match check_exp(e, &mut env.clone(), &mut fun_to_paralen.clone()) {
-                    Ok(()) => {}
-                    Err(e) => return Err(e),
-                }
+                check_exp(e, &mut env.clone(), &mut fun_to_paralen.clone(), &mut constructors.clone(), errs);
             }
-            Ok(())
         }
-        Exp::Let {
-            bindings,
-            body,
-            ann,
-        } => {
-            let mut uniq_names = HashSet::new();
+        Exp::Let { bindings, body, .. } => {
+            let mut uniq_names: HashMap<String, Span> = HashMap::new();
             for (a, b) in bindings {
-                if !uniq_names.insert(a.clone()) {
-                    return Err(CompileErr::DuplicateBinding {
+                match uniq_names.get(a) {
+                    Some(first) => errs.push(CompileErr::DuplicateBinding {
                         duplicated_name: a.clone(),
-                        location: ann.clone(),
-                    });
-                }
-
-                match check_exp(b, &mut env.clone(), &mut fun_to_paralen.clone()) {
-                    Ok(()) => {}
-                    Err(err) => return Err(err),
+                        location: b.ann(),
+                        first: first.clone(),
+                    }),
+                    None => {
+                        uniq_names.insert(a.clone(), b.ann());
+                    }
                 }
+                check_exp(b, &mut env.clone(), &mut fun_to_paralen.clone(), &mut constructors.clone(), errs);
                 env.insert(a.clone());
+                fun_to_paralen.remove(a);
             }
-            check_exp(body, &mut env.clone(), &mut fun_to_paralen.clone())
+            check_exp(body, &mut env.clone(), &mut fun_to_paralen.clone(), &mut constructors.clone(), errs)
         }
         Exp::If { cond, thn, els, .. } => {
-            match check_exp(cond, &mut env.clone(), &mut fun_to_paralen.clone()) {
-                Ok(()) => {}
-                Err(err) => return Err(err),
-            }
-            match check_exp(thn, &mut env.clone(), &mut fun_to_paralen.clone()) {
-                Ok(()) => {}
-                Err(err) => return Err(err),
-            }
-            match check_exp(els, &mut env.clone(), &mut fun_to_paralen.clone()) {
-                Ok(()) => {}
-                Err(err) => return Err(err),
+            check_exp(cond, &mut env.clone(), &mut fun_to_paralen.clone(), &mut constructors.clone(), errs);
+            check_exp(thn, &mut env.clone(), &mut fun_to_paralen.clone(), &mut constructors.clone(), errs);
+            check_exp(els, &mut env.clone(), &mut fun_to_paralen.clone(), &mut constructors.clone(), errs);
+        }
+        Exp::Semicolon { e1, e2, .. } => {
+            check_exp(e1, &mut env.clone(), &mut fun_to_paralen.clone(), &mut constructors.clone(), errs);
+            check_exp(e2, &mut env.clone(), &mut fun_to_paralen.clone(), &mut constructors.clone(), errs);
+        }
+        Exp::While { cond, body, .. } => {
+            check_exp(cond, &mut env.clone(), &mut fun_to_paralen.clone(), &mut constructors.clone(), errs);
+            check_exp(body, &mut env.clone(), &mut fun_to_paralen.clone(), &mut constructors.clone(), errs);
+        }
+        // any variable in scope can be assigned, parameters too
+        Exp::Assign { var, value, ann } => {
+            if fun_to_paralen.contains_key(var) {
+                errs.push(CompileErr::AssignToFunction {
+                    function_name: var.clone(),
+                    location: ann.clone(),
+                });
+            } else if !env.contains(var) {
+                errs.push(CompileErr::UnboundVariable {
+                    unbound: var.clone(),
+                    location: ann.clone(),
+                    suggestion: suggest(var, env.iter()),
+                });
             }
-            Ok(())
+            check_exp(value, &mut env.clone(), &mut fun_to_paralen.clone(), &mut constructors.clone(), errs);
         }
-        Exp::FunDefs { decls, body, ann } => {
-            let mut uniq_fun_names = HashSet::new();
+        Exp::FunDefs { decls, body, .. } => {
+            let mut uniq_fun_names = HashMap::new();
             for decl in decls {
-                if fun_to_paralen.contains_key(&decl.name)
-                    || !uniq_fun_names.insert(decl.name.clone())
-                {
-                    return Err(CompileErr::DuplicateFunName {
+                if fun_to_paralen.contains_key(&decl.name) {
+                    // a function of the same group, or an enclosing one
+                    // whose definition isn't known here
+                    errs.push(CompileErr::DuplicateFunName {
                         duplicated_name: decl.name.clone(),
-                        location: ann.clone(),
+                        location: decl.ann.clone(),
+                        first: uniq_fun_names.get(&decl.name).cloned(),
                     });
                 }
+                uniq_fun_names.insert(decl.name.clone(), decl.ann.clone());
                 fun_to_paralen.insert(decl.name.clone(), decl.parameters.len());
+                env.remove(&decl.name);
             }
             for decl in decls {
-                // if !new_env.contains(&decl.name) {
-                //     new_func.insert(decl.name.clone(), decl.parameters.len());
-                // }
+                let mut decl_env = env.clone();
+                let mut decl_funs = fun_to_paralen.clone();
                 let mut uniq_para_names = HashSet::new();
                 for para_name in &decl.parameters {
                     if !uniq_para_names.insert(para_name.clone()) {
-                        return Err(CompileErr::DuplicateArgName {
+                        errs.push(CompileErr::DuplicateArgName {
                             duplicated_name: para_name.clone(),
-                            location: ann.clone(),
+                            location: decl.ann.clone(),
                         });
                     }
-                    env.insert(para_name.clone());
-                }
-                match check_exp(&decl.body, &mut env.clone(), &mut fun_to_paralen.clone()) {
-                    Ok(()) => {}
-                    Err(err) => return Err(err),
+                    decl_env.insert(para_name.clone());
+                    decl_funs.remove(para_name);
                 }
+                check_exp(&decl.body, &mut decl_env, &mut decl_funs, &mut constructors.clone(), errs);
             }
-            check_exp(body, &mut env.clone(), &mut fun_to_paralen.clone())
+            check_exp(body, &mut env.clone(), &mut fun_to_paralen.clone(), &mut constructors.clone(), errs)
         }
         Exp::Call(fun_name, args, ann)
         | Exp::InternalTailCall(fun_name, args, ann)
@@ -145,36 +152,264 @@ where
             match fun_to_paralen.get(fun_name) {
                 Some(value) => {
                     if args.len() != *value {
-                        return Err(CompileErr::FunctionCalledWrongArity {
+                        errs.push(CompileErr::FunctionCalledWrongArity {
                             function_name: fun_name.clone(),
                             correct_arity: *value,
                             arity_used: args.len(),
                             location: ann.clone(),
                         });
-                    } else {
-                        for arg in args {
-                            match check_exp(arg, &mut env.clone(), &mut fun_to_paralen.clone()) {
-                                Ok(()) => {}
-                                Err(err) => return Err(err),
-                            }
-                        }
-                        Ok(())
                     }
                 }
+                // calling a function value, the arity is checked at runtime
+                None if env.contains(fun_name) => {}
+                None => errs.push(CompileErr::UndefinedFunction {
+                    undefined: fun_name.clone(),
+                    location: ann.clone(),
+                    suggestion: suggest(fun_name, fun_to_paralen.keys().chain(env.iter())),
+                }),
+            }
+            for arg in args {
+                check_exp(arg, &mut env.clone(), &mut fun_to_paralen.clone(), &mut constructors.clone(), errs);
+            }
+        }
+        Exp::Lambda {
+            parameters,
+            body,
+            ann,
+        } => {
+            let mut uniq_para_names = HashSet::new();
+            for para_name in parameters {
+                if !uniq_para_names.insert(para_name.clone()) {
+                    errs.push(CompileErr::DuplicateArgName {
+                        duplicated_name: para_name.clone(),
+                        location: ann.clone(),
+                    });
+                }
+                env.insert(para_name.clone());
+                fun_to_paralen.remove(para_name);
+            }
+            check_exp(body, &mut env.clone(), &mut fun_to_paralen.clone(), &mut constructors.clone(), errs)
+        }
+        Exp::ClosureCall { fun, args, .. } => {
+            check_exp(fun, &mut env.clone(), &mut fun_to_paralen.clone(), &mut constructors.clone(), errs);
+            for arg in args {
+                check_exp(arg, &mut env.clone(), &mut fun_to_paralen.clone(), &mut constructors.clone(), errs);
+            }
+        }
+        Exp::TypeDecl {
+            constructors: decl_constructors,
+            body,
+            ..
+        } => {
+            let siblings: Vec<(String, usize)> =
+                decl_constructors.iter().map(|c| (c.name.clone(), c.fields.len())).collect();
+            let mut uniq_names = HashMap::new();
+            for c in decl_constructors {
+                if constructors.contains_key(&c.name) {
+                    errs.push(CompileErr::DuplicateConstructor {
+                        duplicated_name: c.name.clone(),
+                        location: c.ann.clone(),
+                        first: uniq_names.get(&c.name).cloned(),
+                    });
+                }
+                uniq_names.insert(c.name.clone(), c.ann.clone());
+                constructors.insert(c.name.clone(), siblings.clone());
+            }
+            check_exp(body, &mut env.clone(), &mut fun_to_paralen.clone(), &mut constructors.clone(), errs)
+        }
+        Exp::Construct { name, args, ann } => {
+            check_constructor(name, args.len(), ann, constructors, errs);
+            for arg in args {
+                check_exp(arg, &mut env.clone(), &mut fun_to_paralen.clone(), &mut constructors.clone(), errs);
+            }
+        }
+        Exp::Match { scrutinee, arms, ann } => {
+            check_exp(scrutinee, &mut env.clone(), &mut fun_to_paralen.clone(), &mut constructors.clone(), errs);
+            let mut patterns_ok = true;
+            for (pattern, body) in arms {
+                patterns_ok &= check_pattern(pattern, constructors, &mut HashMap::new(), errs);
+                let mut arm_env = env.clone();
+                let mut arm_funs = fun_to_paralen.clone();
+                for x in pattern.vars() {
+                    arm_env.insert(x.clone());
+                    arm_funs.remove(x);
+                }
+                check_exp(body, &mut arm_env, &mut arm_funs, &mut constructors.clone(), errs);
+            }
+            // the patterns with errors would only make the warning wrong
+            if patterns_ok {
+                let rows = arms.iter().map(|(p, _)| vec![p]).collect();
+                if let Some(missing) = uncovered(rows, 1, constructors) {
+                    errs.push(CompileErr::NonExhaustiveMatch {
+                        missing: missing[0].clone(),
+                        location: ann.clone(),
+                    });
+                }
+            }
+        }
+        Exp::MakeClosure { .. } => {}
+    }
+}
+
+// Whether name is a constructor in scope with the given number of fields
+fn check_constructor<Span>(
+    name: &str,
+    arity_used: usize,
+    ann: &Span,
+    constructors: &Constructors,
+    errs: &mut Vec<CompileErr<Span>>,
+) -> bool
+where
+    Span: Clone,
+{
+    match constructors.get(name).and_then(|cs| cs.iter().find(|(c, _)| c == name)) {
+        Some((_, arity)) if *arity == arity_used => true,
+        Some((_, arity)) => {
+            errs.push(CompileErr::ConstructorWrongArity {
+                constructor: name.to_string(),
+                correct_arity: *arity,
+                arity_used,
+                location: ann.clone(),
+            });
+            false
+        }
+        None => {
+            errs.push(CompileErr::UnknownConstructor {
+                unknown: name.to_string(),
+                location: ann.clone(),
+                suggestion: suggest(name, constructors.keys()),
+            });
+            false
+        }
+    }
+}
+
+// Pushes the errors of a pattern, and tells whether its constructors
+// are all fine. seen holds the variables bound before in the pattern.
+fn check_pattern<Span>(
+    p: &Pattern<Span>,
+    constructors: &Constructors,
+    seen: &mut HashMap<String, Span>,
+    errs: &mut Vec<CompileErr<Span>>,
+) -> bool
+where
+    Span: Clone,
+{
+    match p {
+        Pattern::Wildcard(_) => true,
+        Pattern::Var(x, ann) => {
+            match seen.get(x) {
+                Some(first) => errs.push(CompileErr::DuplicatePatternVar {
+                    duplicated_name: x.clone(),
+                    location: ann.clone(),
+                    first: first.clone(),
+                }),
                 None => {
-                    if env.contains(fun_name) {
-                        return Err(CompileErr::ValueUsedAsFunction {
-                            variable_name: fun_name.clone(),
-                            location: ann.clone(),
-                        });
-                    } else {
-                        return Err(CompileErr::UndefinedFunction {
-                            undefined: fun_name.clone(),
-                            location: ann.clone(),
-                        });
-                    }
+                    seen.insert(x.clone(), ann.clone());
                 }
             }
+            true
+        }
+        Pattern::Constructor(c, ps, ann) => {
+            let mut ok = check_constructor(c, ps.len(), ann, constructors, errs);
+            for p in ps {
+                ok &= check_pattern(p, constructors, seen, errs);
+            }
+            ok
+        }
+    }
+}
+
+/* A row of values, one for each of the n columns of rows, that no row
+ * of patterns matches, written as patterns, or None when the rows match
+ * every value. The first column is split by the constructors of its
+ * type: when the rows mention all of them, each is tried in turn with
+ * its fields as new columns; when some are missing, one of those is
+ * the witness, and the rows that match anything in the column must
+ * cover the rest.
+ */
+fn uncovered<Span>(rows: Vec<Vec<&Pattern<Span>>>, n: usize, constructors: &Constructors) -> Option<Vec<String>> {
+    if n == 0 {
+        return if rows.is_empty() { Some(vec![]) } else { None };
+    }
+    let heads: Vec<&String> = rows
+        .iter()
+        .filter_map(|row| match row[0] {
+            Pattern::Constructor(c, _, _) => Some(c),
+            _ => None,
+        })
+        .collect();
+    let siblings = match heads.first() {
+        Some(c) => constructors[*c].clone(),
+        None => vec![],
+    };
+    let missing: Vec<&(String, usize)> = siblings.iter().filter(|(c, _)| !heads.contains(&c)).collect();
+    if !siblings.is_empty() && missing.is_empty() {
+        for (c, arity) in &siblings {
+            let specialized = rows
+                .iter()
+                .filter_map(|row| {
+                    let mut fields = match row[0] {
+                        Pattern::Constructor(d, ps, _) if d == c => ps.iter().collect(),
+                        Pattern::Constructor(..) => return None,
+                        wildcard => vec![wildcard; *arity],
+                    };
+                    fields.extend(row[1..].iter().copied());
+                    Some(fields)
+                })
+                .collect();
+            if let Some(mut witness) = uncovered(specialized, arity + n - 1, constructors) {
+                let rest = witness.split_off(*arity);
+                let head = if *arity == 0 {
+                    c.clone()
+                } else {
+                    format!("{}({})", c, witness.join(", "))
+                };
+                return Some(std::iter::once(head).chain(rest).collect());
+            }
+        }
+        return None;
+    }
+    let default = rows
+        .iter()
+        .filter(|row| !matches!(row[0], Pattern::Constructor(..)))
+        .map(|row| row[1..].to_vec())
+        .collect();
+    let rest = uncovered(default, n - 1, constructors)?;
+    let head = match missing.first() {
+        None => "_".to_string(),
+        Some((c, 0)) => c.clone(),
+        Some((c, arity)) => format!("{}({})", c, vec!["_"; *arity].join(", ")),
+    };
+    Some(std::iter::once(head).chain(rest).collect())
+}
+
+// The name in scope closest to a misspelled one, if any is close enough
+fn suggest<'a, I>(name: &str, in_scope: I) -> Option<String>
+where
+    I: Iterator<Item = &'a String>,
+{
+    // a third of the name may be wrong, but not all of a short one
+    let len = name.chars().count();
+    let max = std::cmp::max(1, len / 3);
+    in_scope
+        .map(|x| (edit_distance(name, x), x))
+        .filter(|(d, _)| *d <= max && *d < len)
+        .min()
+        .map(|(_, x)| x.clone())
+}
+
+// Levenshtein distance
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let replace = prev[j] + if ca == *cb { 0 } else { 1 };
+            cur.push(replace.min(prev[j + 1] + 1).min(cur[j] + 1));
         }
+        prev = cur;
     }
+    prev[b.len()]
 }
@@ -1,32 +1,44 @@
 use crate::asm::instrs_to_string;
 use crate::asm::Instr;
-use crate::compile_with_env::compile_with_env;
-use crate::lambda_lift::lambda_lift_helper;
+use crate::boxing::box_assigned;
+use crate::compile_with_env::{
+    clear_frame, compile_with_env, enter_frame, float_label, string_header, string_label, Ctx,
+};
+use crate::lambda_lift::{lambda_lift_helper, LiftState};
+use crate::matching::lower_matches;
+use crate::regalloc::{allocate, LINK_SLOTS, VAR_REGS};
 use crate::seq_exp::{seq_decl, seq_exp};
+use crate::span::Span2;
 use crate::should_lift::should_lift_helper;
 use crate::syntax::FloatWrapper;
-use crate::syntax::{Exp, SeqExp, SeqProg, SurfFunDecl, SurfProg};
+use crate::syntax::{unqualified, Exp, ImmExp, SeqExp, SeqProg, SurfFunDecl, SurfProg};
 use crate::tag::tag_funs;
 use crate::tag::{tag_exp, tag_seq};
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet};
 
 use crate::check::check_exp;
+use crate::infer::{infer_prog, proven_types};
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum CompileErr<Span> {
     UnboundVariable {
         unbound: String,
         location: Span,
+        // the closest name in scope, if there is a close one
+        suggestion: Option<String>,
     },
     UndefinedFunction {
         undefined: String,
         location: Span,
+        suggestion: Option<String>,
     },
-    // The Span here is the Span of the let-expression that has the two duplicated bindings
+    // The Spans here are the Spans of the expressions bound the second
+    // and the first time
     DuplicateBinding {
         duplicated_name: String,
         location: Span,
+        first: Span,
     },
 
     NumOverflow {
@@ -42,154 +54,723 @@ pub enum CompileErr<Span> {
     DuplicateFunName {
         duplicated_name: String,
         location: Span, // the location of the 2nd function
+        // the location of the 1st function, when it is in the same group
+        first: Option<Span>,
     },
 
     DuplicateArgName {
         duplicated_name: String,
-        location: Span,
+        location: Span, // the function with the parameters
     },
 
-    FunctionUsedAsValue {
+    FunctionCalledWrongArity {
         function_name: String,
+        correct_arity: usize,
+        arity_used: usize,
+        location: Span, // location of the function *call*
+    },
+
+    // e.g. "arithmetic expected a number or float, but got Bool"
+    TypeMismatch {
+        who: String,
+        expected: String,
+        got: String,
         location: Span,
     },
 
-    ValueUsedAsFunction {
-        variable_name: String,
+    // a function value called with the wrong number of arguments
+    ArityMismatch {
+        expected: usize,
+        got: usize,
         location: Span,
     },
 
-    FunctionCalledWrongArity {
+    // f := e, where f names a function rather than a variable
+    AssignToFunction {
         function_name: String,
+        location: Span, // the location of the assignment
+    },
+
+    UnknownConstructor {
+        unknown: String,
+        location: Span,
+        suggestion: Option<String>,
+    },
+
+    // a constructor applied to, or matched with, the wrong number of fields
+    ConstructorWrongArity {
+        constructor: String,
         correct_arity: usize,
         arity_used: usize,
-        location: Span, // location of the function *call*
+        location: Span,
+    },
+
+    DuplicateConstructor {
+        duplicated_name: String,
+        location: Span, // the location of the 2nd constructor
+        // the location of the 1st constructor, when it is in the same type
+        first: Option<Span>,
+    },
+
+    // a variable bound twice by the same pattern, e.g. Rect(x, x)
+    DuplicatePatternVar {
+        duplicated_name: String,
+        location: Span,
+        first: Span,
+    },
+
+    // a warning: no arm of a match matches the values that look like
+    // missing, e.g. `Rect(_, _)`
+    NonExhaustiveMatch {
+        missing: String,
+        location: Span, // the location of the match
+    },
+
+    // an `import` of a file that can't be read
+    ImportFailed {
+        path: String,
+        reason: String,
+        location: Span, // the location of the import
+    },
+
+    // files that import each other, from the one with the import at
+    // location back to itself
+    ImportCycle {
+        cycle: Vec<String>,
+        location: Span,
+    },
+
+    // `export` on a function that isn't one of the top-level ones
+    ExportNotTopLevel {
+        function_name: String,
+        location: Span,
+    },
+
+    // two imports of a file export functions of the same name
+    ConflictingImport {
+        function_name: String,
+        location: Span, // the location of the 2nd import
+        first: Span,
     },
 }
 
-pub fn check_prog<Span>(p: &SurfProg<Span>) -> Result<(), CompileErr<Span>>
+// The warnings of the program, or all its errors, in the order the
+// checker finds them
+pub fn check_prog<Span>(p: &SurfProg<Span>) -> Result<Vec<CompileErr<Span>>, Vec<CompileErr<Span>>>
 where
     Span: Clone,
 {
     let mut env = HashSet::new();
     let mut func = HashMap::new();
-    check_exp(p, &mut env, &mut func)
+    let mut errs = vec![];
+    check_exp(p, &mut env, &mut func, &mut HashMap::new(), &mut errs);
+    let (warnings, errs): (Vec<_>, Vec<_>) = errs.into_iter().partition(|e| e.is_warning());
+    if errs.is_empty() {
+        Ok(warnings)
+    } else {
+        Err(errs)
+    }
 }
 
-// Identify which functions should be lifted to the top level
-fn should_lift<Ann>(p: &Exp<Ann>) -> HashSet<String> {
+// Identify which functions should be lifted to the top level, and the
+// ones in always, which other objects call
+fn should_lift<Ann>(p: &Exp<Ann>, always: &[&str]) -> HashSet<String> {
     let mut hashset: HashSet<String> = HashSet::new();
-    should_lift_helper(p, &mut hashset, true);
+    should_lift_helper(p, &mut hashset, true, &[], &[]);
+    hashset.extend(always.iter().map(|f| f.to_string()));
     hashset
 }
 
+// The name in p of each top-level function of its renamed copy tagged,
+// by the label it got. Types can come between the functions.
+fn spine_names<Ann1, Ann2>(p: &Exp<Ann1>, tagged: &Exp<Ann2>) -> HashMap<String, String> {
+    let mut names = HashMap::new();
+    let (mut e, mut t) = (p, tagged);
+    loop {
+        match (e, t) {
+            (Exp::FunDefs { decls, body, .. }, Exp::FunDefs { decls: labels, body: rest, .. }) => {
+                for (decl, label) in decls.iter().zip(labels) {
+                    names.insert(label.name.clone(), decl.name.clone());
+                }
+                e = body;
+                t = rest;
+            }
+            (Exp::TypeDecl { body, .. }, Exp::TypeDecl { body: rest, .. }) => {
+                e = body;
+                t = rest;
+            }
+            _ => return names,
+        }
+    }
+}
+
 
 // Lift some functions to global definitions
-fn lambda_lift<Ann>(p: &Exp<Ann>) -> (Vec<SurfFunDecl<()>>, Exp<()>, HashMap<String, Vec<String>>) {
+fn lambda_lift<Ann>(
+    p: &Exp<Ann>,
+    always: &[&str],
+) -> (Vec<SurfFunDecl<Ann>>, Exp<Ann>, HashMap<String, Vec<String>>)
+where
+    Ann: Clone,
+{
     let mut env = Vec::new();
-    let mut lifted = vec![];
-    let should_lift = should_lift(p);
-    let mut fun_to_decl = HashMap::new();
-    let mut fun_to_env = HashMap::new();
-    let body = lambda_lift_helper(
-        p,
-        &mut env,
-        &mut lifted,
-        &should_lift,
-        &mut fun_to_decl,
-        true,
-        &mut fun_to_env,
-    );
-    (lifted, body, fun_to_env)
+    let should_lift = should_lift(p, always);
+    let mut state = LiftState {
+        lifted: vec![],
+        should_lift: &should_lift,
+        fun_to_decl: HashMap::new(),
+        fun_to_env: HashMap::new(),
+        fun_to_arity: HashMap::new(),
+    };
+    let body = lambda_lift_helper(p, &mut env, true, &mut state);
+    (state.lifted, body, state.fun_to_env)
 }
 
-fn seq_prog(decls: &[SurfFunDecl<()>], p: &Exp<()>) -> SeqProg<()> {
+fn seq_prog<Ann>(decls: &[SurfFunDecl<Ann>], p: &Exp<Ann>) -> SeqProg<Ann>
+where
+    Ann: Clone,
+{
     SeqProg {
         funs: seq_decl(decls),
         main: seq_exp(&tag_exp(p, &mut 0, &mut HashMap::new(),  false)),
-        ann: (),
+        ann: p.ann(),
     }
 }
 
-fn space_needed<Ann>(e: &SeqExp<Ann>) -> i32 {
-    match e {
-        SeqExp::Let {
-            bound_exp, body, ..
-        } => std::cmp::max(1 + space_needed(body), space_needed(bound_exp)),
-        SeqExp::If { thn, els, .. } => std::cmp::max(space_needed(thn), space_needed(els)),
-        SeqExp::FunDefs { decls, body, .. } => {
-            let mut max = 0;
-            for decl in decls {
-                max = std::cmp::max(max, space_needed(&decl.body) + decl.parameters.len() as i32);
-            }
-            std::cmp::max(space_needed(body), max)
-        }
-        _ => 0,
-    }
-}
+// The instructions of the functions and of main, the annotation of the
+// expression each number in them stands for and the size of the frames
+type Compiled<Ann> = (Vec<Instr>, Vec<Instr>, HashMap<u32, Ann>, i32);
 
-fn compile_to_instrs(
-    seq_prog: &SeqProg<()>,
+// linked holds the names of the functions of a module that is linked
+// with others which it exports or which other objects define, by their
+// labels
+fn compile_to_instrs<Ann>(
+    seq_prog: &SeqProg<Ann>,
     fun_to_env: &HashMap<String, Vec<String>>,
-         
-) -> (Vec<Instr>, Vec<Instr>) {
+    linkage: Option<&Linkage>,
+    linked: &HashMap<String, String>,
+) -> Compiled<Ann>
+where
+    Ann: Clone,
+{
     let mut counter: u32 = 1;
-    let funs = tag_funs(&seq_prog.funs, &mut counter);
-    let main = tag_seq(&seq_prog.main, &mut counter);
+    let mut anns = HashMap::new();
+    let funs = tag_funs(&seq_prog.funs, &mut counter, &mut anns);
+    let main = tag_seq(&seq_prog.main, &mut counter, &mut anns);
+    let types = proven_types(seq_prog);
+    let fun_allocs: Vec<_> = funs
+        .iter()
+        .map(|fun| allocate(&fun.body, &fun.parameters, fun_to_env))
+        .collect();
+    let main_alloc = allocate(&main, &[], fun_to_env);
 
-    // handle funs
-    let mut space = 0;
-    for fun in &funs {
-        space = std::cmp::max(space, space_needed(&fun.body) + fun.parameters.len() as i32);
+    // every frame of the module has the same size: the stack slots of
+    // the variables, a scratch slot, a slot to save each register in
+    // around calls and the links to the frame of a callee. Frames of
+    // other modules can have other sizes.
+    let mut slots = main_alloc.slots;
+    for alloc in &fun_allocs {
+        slots = std::cmp::max(slots, alloc.slots);
     }
-    let space = std::cmp::max(space, space_needed(&main));
+    let space = slots + 1 + VAR_REGS.len() as i32 + LINK_SLOTS;
     let space = if space % 2 == 0 { space + 1 } else { space };
+
+    // handle funs
     let mut funs_instrs = vec![];
-    for decl in &funs {
+    let mut exported = HashSet::new();
+    for (decl, alloc) in funs.iter().zip(fun_allocs.iter()) {
+        if let (Some(linkage), Some(name)) = (linkage, linked.get(&decl.name)) {
+            // the functions of other objects are called through a jump
+            // to their symbol, which takes the frame as it is
+            if linkage.externs.contains(name) {
+                funs_instrs.push(Instr::Label(decl.name.clone()));
+                funs_instrs.push(Instr::Jmp(name.to_string()));
+                continue;
+            }
+            if linkage.exports.contains(name) && exported.insert(name) {
+                funs_instrs.push(Instr::Label(linkage.symbol(name)));
+            }
+        }
         funs_instrs.push(Instr::Label(decl.name.clone()));
-        funs_instrs.append(&mut compile_with_env(
-            &decl.body,
-            decl.parameters.clone(),
+        funs_instrs.append(&mut enter_frame(&decl.name));
+        funs_instrs.append(&mut clear_frame(decl.parameters.len(), space));
+        let ctx = Ctx {
             space,
-            &fun_to_env,
-        ));
+            fun_to_env: &fun_to_env,
+            types: &types,
+            alloc,
+        };
+        funs_instrs.append(&mut compile_with_env(&decl.body, decl.parameters.clone(), &ctx));
         funs_instrs.push(Instr::Ret);
     }
     funs_instrs.push(Instr::Label(format!("funend_{}", 0)));
 
     // handle main
-    let mut main_instrs = compile_with_env(&main, vec![], space, &fun_to_env);
+    let mut main_instrs = enter_frame("main");
+    main_instrs.append(&mut clear_frame(0, space));
+    let ctx = Ctx {
+        space,
+        fun_to_env: &fun_to_env,
+        types: &types,
+        alloc: &main_alloc,
+    };
+    main_instrs.append(&mut compile_with_env(&main, vec![], &ctx));
 
     main_instrs.push(Instr::Ret);
-    (funs_instrs, main_instrs)
+    (funs_instrs, main_instrs, anns, space)
+}
+
+fn imm_consts(imms: &[ImmExp], consts: &mut Consts) {
+    for imm in imms {
+        match imm {
+            ImmExp::Float(FloatWrapper(f)) => {
+                consts.floats.insert(f.to_bits());
+            }
+            ImmExp::Str(s) => {
+                consts.strings.insert(s.clone());
+            }
+            _ => {}
+        }
+    }
+}
+
+// The float and string literals of a program
+#[derive(Default)]
+struct Consts {
+    floats: BTreeSet<u64>,
+    strings: BTreeSet<String>,
+}
+
+// Collects the bits of every float literal and the text of every
+// string literal in e
+fn literal_consts<Ann>(e: &SeqExp<Ann>, consts: &mut Consts) {
+    match e {
+        SeqExp::Imm(imm, _) => imm_consts(std::slice::from_ref(imm), consts),
+        SeqExp::Prim(_, args, _)
+        | SeqExp::InternalTailCall(_, args, _)
+        | SeqExp::ExternalCall { args, .. } => imm_consts(args, consts),
+        SeqExp::ClosureCall { fun, args, .. } => {
+            imm_consts(std::slice::from_ref(fun), consts);
+            imm_consts(args, consts);
+        }
+        SeqExp::MakeClosure { env, .. } => imm_consts(env, consts),
+        SeqExp::Let {
+            bound_exp, body, ..
+        } => {
+            literal_consts(bound_exp, consts);
+            literal_consts(body, consts);
+        }
+        SeqExp::If { cond, thn, els, .. } => {
+            imm_consts(std::slice::from_ref(cond), consts);
+            literal_consts(thn, consts);
+            literal_consts(els, consts);
+        }
+        SeqExp::While { cond, body, .. } => {
+            literal_consts(cond, consts);
+            literal_consts(body, consts);
+        }
+        SeqExp::Assign { value, .. } => imm_consts(std::slice::from_ref(value), consts),
+        SeqExp::FunDefs { decls, body, .. } => {
+            for decl in decls {
+                literal_consts(&decl.body, consts);
+            }
+            literal_consts(body, consts);
+        }
+    }
+}
+
+/* The float and string literals are boxed in the data section, so they
+ * never need to be allocated and the garbage collector leaves them
+ * alone. A string is its header, then its bytes padded to a word.
+ */
+fn literal_data<Ann>(p: &SeqProg<Ann>) -> String {
+    let mut consts = Consts::default();
+    for decl in &p.funs {
+        literal_consts(&decl.body, &mut consts);
+    }
+    literal_consts(&p.main, &mut consts);
+    let mut data = String::new();
+    for bits in consts.floats {
+        data.push_str(&format!(
+            "{}: dq 0, 0x{:016x}\n",
+            float_label(f64::from_bits(bits)),
+            bits
+        ));
+    }
+    let mut labels = HashMap::new();
+    for s in consts.strings {
+        let label = string_label(&s);
+        if let Some(other) = labels.insert(label.clone(), s.clone()) {
+            panic!("string literals {:?} and {:?} have the same label {}", other, s, label);
+        }
+        let mut bytes: Vec<String> = s.bytes().map(|b| b.to_string()).collect();
+        bytes.resize(s.len().div_ceil(8) * 8, "0".to_string());
+        data.push_str(&format!("{}: dq {}\n", label, string_header(s.len())));
+        for word in bytes.chunks(8) {
+            data.push_str(&format!("        db {}\n", word.join(", ")));
+        }
+    }
+    data
+}
+
+/* The file and span of the expression behind each number the checks
+ * of the program pass to snake_error, which prints them with the
+ * error. Entry 0 stands for no location, as does a span on line 0,
+ * whose file is a null pointer.
+ */
+// The span of an expression that isn't in any file
+pub const NO_SPAN: Span2 = Span2 {
+    start_line: 0,
+    start_col: 0,
+    end_line: 0,
+    end_col: 0,
+};
+
+fn location_data<Span, L>(anns: &HashMap<u32, Span>, locate: L) -> String
+where
+    L: Fn(&Span) -> (String, Span2),
+{
+    let len = anns.keys().max().map_or(1, |n| n + 1);
+    let mut data = String::from("snake_locations:\n");
+    let mut files: Vec<String> = vec![];
+    for n in 0..len {
+        let (file, span) = anns.get(&n).map_or((String::new(), NO_SPAN), &locate);
+        let file = if span.start_line == 0 {
+            String::from("0")
+        } else {
+            let k = files.iter().position(|f| *f == file).unwrap_or_else(|| {
+                files.push(file);
+                files.len() - 1
+            });
+            format!("snake_file_{}", k)
+        };
+        data.push_str(&format!(
+            "        dq {}\n        dd {}, {}, {}, {}\n",
+            file, span.start_line, span.start_col, span.end_line, span.end_col
+        ));
+    }
+    for (k, file) in files.iter().enumerate() {
+        let bytes: Vec<String> = file.bytes().chain(Some(0)).map(|b| b.to_string()).collect();
+        data.push_str(&format!("snake_file_{}: db {}\n", k, bytes.join(", ")));
+    }
+    // the functions come next
+    data.push_str("        align 8\n");
+    data
+}
+
+/* The name in the source of every function the compiled code has a
+ * label for, ended by a zero label, so snake_error can print the
+ * functions in a backtrace. Functions that weren't lifted are only
+ * ever tail called, so they take over the frame of the function they
+ * are defined in.
+ */
+// The functions of a module that is linked with others are named after
+// it, as the ones it imports are in whole programs
+fn function_data<Ann>(p: &SeqProg<Ann>, module: Option<&str>) -> String {
+    let mut labels = vec![];
+    for decl in &p.funs {
+        labels.push(decl.name.as_str());
+        local_funs(&decl.body, &mut labels);
+    }
+    labels.push("main");
+    local_funs(&p.main, &mut labels);
+    let mut data = String::from("snake_functions:\n");
+    for (i, label) in labels.iter().enumerate() {
+        data.push_str(&format!("        dq {}, snake_function_{}\n", label, i));
+    }
+    data.push_str("        dq 0, 0\n");
+    for (i, label) in labels.iter().enumerate() {
+        let name = match module {
+            Some(m) if *label != "main" => format!("{}.{}", m, source_name(label)),
+            _ => source_name(label).to_string(),
+        };
+        data.push_str(&format!("snake_function_{}: db \"{}\", 0\n", i, name));
+    }
+    data
+}
+
+// The name of every constructor, by its number, for printing variants
+fn constructor_data(names: &[String]) -> String {
+    // after the names of the functions, which don't keep it aligned
+    let mut data = String::from("        align 8\nsnake_constructors:\n");
+    for i in 0..names.len() {
+        data.push_str(&format!("        dq snake_constructor_{}\n", i));
+    }
+    for (i, name) in names.iter().enumerate() {
+        data.push_str(&format!("snake_constructor_{}: db \"{}\", 0\n", i, unqualified(name)));
+    }
+    data
+}
+
+// The functions defined in e that weren't lifted
+fn local_funs<'a, Ann>(e: &'a SeqExp<Ann>, labels: &mut Vec<&'a str>) {
+    match e {
+        SeqExp::Let {
+            bound_exp, body, ..
+        } => {
+            local_funs(bound_exp, labels);
+            local_funs(body, labels);
+        }
+        SeqExp::If { thn, els, .. } => {
+            local_funs(thn, labels);
+            local_funs(els, labels);
+        }
+        SeqExp::While { cond, body, .. } => {
+            local_funs(cond, labels);
+            local_funs(body, labels);
+        }
+        SeqExp::FunDefs { decls, body, .. } => {
+            for decl in decls {
+                labels.push(&decl.name);
+                local_funs(&decl.body, labels);
+            }
+            local_funs(body, labels);
+        }
+        _ => {}
+    }
+}
+
+// Undoes the renaming of tag_exp, fun_<name>_<n>, and the .copy.<...>
+// suffix of the copies lambda_lift makes of local functions, which no
+// identifier can contain
+fn source_name(label: &str) -> &str {
+    let label = label.split(".copy.").next().unwrap();
+    match label.strip_prefix("fun_").and_then(|s| s.rsplit_once('_')) {
+        Some((name, _)) => name,
+        None if label.starts_with("lambda_") => "lambda",
+        None => label,
+    }
+}
+
+/* How a module compiled to an object of its own is linked with the
+ * objects of the others, see runner.rs. Its constructors are numbered
+ * from first_constructor on so their numbers differ from theirs.
+ */
+pub struct Linkage {
+    // the name of the module, which its symbols start with
+    pub module: String,
+    // the names of the functions it exports
+    pub exports: Vec<String>,
+    // the functions it calls that other objects define, whose names
+    // are their symbols
+    pub externs: Vec<String>,
+    pub first_constructor: usize,
+}
+
+impl Linkage {
+    // The symbol of one of the functions the module exports
+    pub fn symbol(&self, f: &str) -> String {
+        format!("{}.{}", self.module, f)
+    }
+}
+
+// The symbols of the body of a module and of the tables of its data,
+// which no function's can be the same as
+pub fn main_symbol(module: &str) -> String {
+    format!("{}..main", module)
+}
+
+pub fn module_symbol(module: &str) -> String {
+    format!("{}..module", module)
+}
+
+/* The code that the runtime calls, which runs main on an empty stack
+ * and returns its value. main is the label of the body of the program,
+ * or the symbol of the body of the module that is run, see main_symbol.
+ */
+fn entry(main: &str) -> String {
+    format!(
+        "\
+        start_here:
+        push rbx
+        push r12
+        push r13
+        push r14
+        push r15
+        push rbp
+        sub rsp, 16
+        push 0
+        mov r15, rdi
+        call {}
+        add rsp, 24
+        pop rbp
+        pop r15
+        pop r14
+        pop r13
+        pop r12
+        pop rbx
+        ret
+",
+        main
+    )
+}
+
+// The runtime looks up the tables of data of each module, see
+// snake_modules in runtime/stub.rs
+fn module_data(label: &str, first_constructor: usize, constructors: usize, space: i32) -> String {
+    format!(
+        "{}: dq snake_locations, snake_functions, snake_constructors, {}, {}, {}\n",
+        label, first_constructor, constructors, space
+    )
 }
 
-pub fn compile_to_string<Span>(p: &SurfProg<Span>) -> Result<String, CompileErr<Span>>
+// The externs every module needs
+static RUNTIME_EXTERNS: &str = "\
+        extern print_snake_val
+        extern snake_error
+        extern snake_equal
+        extern snake_cos
+        extern snake_try_gc
+        extern snake_string_concat
+        extern snake_string_substr
+        extern snake_tostring_len
+        extern snake_tostring
+        extern HEAP_END
+";
+
+// The code of the functions and of main, followed by the data, of a
+// program that is compiled on its own or linked as one module of many
+fn compile_parts<Span, L>(
+    p: &SurfProg<Span>,
+    locate: L,
+    linkage: Option<&Linkage>,
+) -> Result<(String, String), Vec<CompileErr<Span>>>
 where
     Span: Clone,
+    L: Fn(&Span) -> (String, Span2),
 {
     check_prog(p)?;
-    let uniquified = tag_exp(p, &mut 0, &mut HashMap::new(), true);
-    let (lifted, exp, fun_to_env) = lambda_lift(&uniquified);
+    // types are only inferred for programs that pass the checks
+    infer_prog(p).map_err(|e| vec![e])?;
+    let uniquified = tag_exp(p, &mut 0, &mut HashMap::new(), true).map_ann(&|(_, span)| span.clone());
+    let first_constructor = linkage.map_or(0, |l| l.first_constructor);
+    let (lowered, constructors) = lower_matches(&uniquified, first_constructor);
+    // the top-level functions that other objects call or that are
+    // called from them, by their labels
+    let linked: HashMap<String, String> = match linkage {
+        Some(l) => spine_names(p, &uniquified)
+            .into_iter()
+            .filter(|(_, name)| l.exports.contains(name) || l.externs.contains(name))
+            .collect(),
+        None => HashMap::new(),
+    };
+    let always: Vec<&str> = linked.keys().map(|f| f.as_str()).collect();
+    let (lifted, exp, fun_to_env) = lambda_lift(&box_assigned(&lowered), &always);
     let seq_prog = seq_prog(&lifted, &exp);
-    let (fun_instrs, main_instrs) = compile_to_instrs(&seq_prog, &fun_to_env);
+    let (fun_instrs, main_instrs, anns, space) = compile_to_instrs(&seq_prog, &fun_to_env, linkage, &linked);
+    let data = literal_data(&seq_prog);
+    let locations = location_data(&anns, locate);
+    let functions = function_data(&seq_prog, linkage.map(|l| l.module.as_str()));
+    let constructors_data = constructor_data(&constructors);
+    // a module's body and tables are also under its symbols
+    let (main, module) = match linkage {
+        Some(l) => (format!("{}:\nmain", main_symbol(&l.module)), module_symbol(&l.module)),
+        None => ("main".to_string(), "snake_module".to_string()),
+    };
+    let module = module_data(&module, first_constructor, constructors.len(), space);
+
+    let text = format!(
+        "\
+{}snake_fail:
+        mov rcx, rbp
+        jmp snake_error
+{}:
+{}
+",
+        instrs_to_string(&fun_instrs),
+        main,
+        instrs_to_string(&main_instrs)
+    );
+    let data = format!("{}{}{}{}        align 8\n{}", data, locations, functions, constructors_data, module);
+    Ok((text, data))
+}
 
+// locate gives the file a Span is in and where in it
+pub fn compile_to_string<Span, L>(p: &SurfProg<Span>, locate: L) -> Result<String, Vec<CompileErr<Span>>>
+where
+    Span: Clone,
+    L: Fn(&Span) -> (String, Span2),
+{
+    let (text, data) = compile_parts(p, locate, None)?;
     Ok(format!(
         "\
         section .text
         global start_here
-        extern print_snake_val
-        extern snake_error
-{}        start_here:
-        push r15
-        mov r15, rdi
-        call main
-        pop r15
-        ret
-main:
+{}        global snake_modules
+{}{}
+        section .data
+        align 8
+snake_modules: dq snake_module, 0
 {}
         ",
-        instrs_to_string(&fun_instrs),
-        instrs_to_string(&main_instrs)
+        RUNTIME_EXTERNS,
+        entry("main"),
+        text,
+        data
     ))
 }
+
+/* The assembly of a module that is linked with others: the functions
+ * it exports are global under their symbols, and so are its body and
+ * the tables of its data. The functions of linkage.externs, which p
+ * declares, are left to the objects that define them.
+ */
+pub fn compile_module<Span, L>(p: &SurfProg<Span>, locate: L, linkage: &Linkage) -> Result<String, Vec<CompileErr<Span>>>
+where
+    Span: Clone,
+    L: Fn(&Span) -> (String, Span2),
+{
+    let (text, data) = compile_parts(p, locate, Some(linkage))?;
+    let mut globals = String::new();
+    for f in &linkage.exports {
+        globals.push_str(&format!("        global {}\n", linkage.symbol(f)));
+    }
+    globals.push_str(&format!("        global {}\n", main_symbol(&linkage.module)));
+    globals.push_str(&format!("        global {}\n", module_symbol(&linkage.module)));
+    for f in &linkage.externs {
+        globals.push_str(&format!("        extern {}\n", f));
+    }
+    Ok(format!(
+        "\
+        section .text
+{}{}{}
+        section .data
+        align 8
+{}
+        ",
+        RUNTIME_EXTERNS,
+        globals,
+        text,
+        data
+    ))
+}
+
+/* The assembly that links the objects of modules together: the entry
+ * of the runtime, which runs the body of the first module, and the
+ * list of the tables of their data.
+ */
+pub fn link_glue(modules: &[String]) -> String {
+    let mut externs = String::new();
+    let mut tables = String::new();
+    for m in modules {
+        externs.push_str(&format!("        extern {}\n", module_symbol(m)));
+        tables.push_str(&format!("{}, ", module_symbol(m)));
+    }
+    let main = main_symbol(&modules[0]);
+    format!(
+        "\
+        section .text
+        global start_here
+        global snake_modules
+        extern {}
+{}{}
+        section .data
+        align 8
+snake_modules: dq {}0
+        ",
+        main,
+        externs,
+        entry(&main),
+        tables
+    )
+}
@@ -1,13 +1,39 @@
 use core::panic;
 use std::collections::HashMap;
 
-use crate::asm::{Arg32, Arg64, BinArgs, FloatArg, FloatMem, Instr, MemRef, MovArgs, Reg, Reg32};
+use crate::asm::{Arg32, Arg64, BinArgs, Instr, MemRef, MovArgs, Reg, Reg32};
+use crate::infer::{proven_imm, proven_seq_type, Proven, ProvenTypes};
+use crate::regalloc::{save_slot, Allocation, CALLER_SAVED};
 use crate::syntax::{FloatWrapper, ImmExp, Prim, SeqExp, SeqFunDecl};
 
 pub type Space = i32;
 
 pub static BOOL_MASK: u64 = 0x80_00_00_00_00_00_00_00;
-pub static FLOAT_MASK: u64 = 0xFF_FF_FF_FF_E0_00_00_00;
+
+// the low 3 bits of every non-number value
+pub static TAG_MASK: u64 = 0x07;
+pub static BOOL_TAG: u64 = 0x07;
+pub static FLOAT_TAG: u64 = 0x01;
+pub static TUPLE_TAG: u64 = 0x05;
+pub static CLOSURE_TAG: u64 = 0x03;
+// a variant is a pointer to the number of its constructor, then the
+// number of its fields, then the fields; booleans are the only values
+// with this tag that aren't pointers
+pub static VARIANT_TAG: u64 = 0x07;
+// a string is a pointer to its length in bytes shifted left by one
+// with the low bit set, then its bytes padded to a word. There are no
+// tags left for it, so it shares the one of variants, whose number of
+// constructor is even.
+pub static STRING_TAG: u64 = 0x07;
+// an array is a pointer to a header no constructor or string has, then
+// its length, untagged, then its elements
+pub static ARRAY_TAG: u64 = 0x07;
+pub static ARRAY_HEADER: u64 = 0x40_00_00_00_00_00_00_00;
+// true and false both shifted left by one
+static BOOL_SHIFTED: i32 = -2;
+
+// a float is a pointer to a zero header followed by the bits of an f64
+pub static FLOAT_WORDS: i32 = 2;
 
 pub static SNAKE_TRUE: u64 = 0xFF_FF_FF_FF_FF_FF_FF_FF;
 pub static SNAKE_FALSE: u64 = 0x7F_FF_FF_FF_FF_FF_FF_FF;
@@ -20,55 +46,142 @@ static LOGIC_ERROR: ErrorCode = 3;
 static IF_ERROR: ErrorCode = 4;
 static DIVISION_ERROR: ErrorCode = 5;
 static SQRT_ERROR: ErrorCode = 6;
+static TUPLE_ERROR: ErrorCode = 7;
+static INDEX_ERROR: ErrorCode = 8;
+static BOUNDS_ERROR: ErrorCode = 9;
+static CALL_ERROR: ErrorCode = 10;
+static ARITY_ERROR: ErrorCode = 11;
+// 12 is out of memory, which the runtime reports itself
+static WHILE_ERROR: ErrorCode = 13;
+static MATCH_ERROR: ErrorCode = 14;
+static STRING_ERROR: ErrorCode = 15;
+static ARRAY_ERROR: ErrorCode = 16;
+static ARRAY_BOUNDS_ERROR: ErrorCode = 17;
+static ARRAY_SIZE_ERROR: ErrorCode = 18;
+
+/* The argument in rdi of a jump to snake_error: the code of the error
+ * in the low half, and in the high half the number of the expression
+ * that failed the check, which the runtime looks up in the
+ * snake_locations table. The checks set rdi whether they fail or not,
+ * so passing the location takes no other register.
+ */
+fn error_arg(code: ErrorCode, ann: &u32) -> Arg64 {
+    Arg64::Unsigned(code | (*ann as u64) << 32)
+}
+
+/* What every expression of a function is compiled with: the words of
+ * its frame, the environments of the functions in scope, the types
+ * inference proved and where the variables of the function live.
+ */
+#[derive(Clone, Copy)]
+pub struct Ctx<'a> {
+    pub space: Space,
+    pub fun_to_env: &'a HashMap<String, Vec<String>>,
+    pub types: &'a ProvenTypes,
+    pub alloc: &'a Allocation,
+}
 
 pub enum RuntimeType {
     Num,
     Bool,
 }
 
-pub fn compile_with_env(
-    e: &SeqExp<u32>,
-    env: Vec<String>,
-    space: i32,
-    fun_to_env: &HashMap<String, Vec<String>>,
-) -> Vec<Instr> {
+pub fn compile_with_env(e: &SeqExp<u32>, env: Vec<String>, ctx: &Ctx) -> Vec<Instr> {
+    let Ctx { alloc, .. } = *ctx;
     let mut instr = vec![];
     // ...
     match e {
         SeqExp::Imm(ie, _) => {
-            immexp_instrs(ie, &env, &mut instr);
+            immexp_instrs(ie, &env, alloc, &mut instr);
         }
         SeqExp::Let {
             var,
             bound_exp,
             body,
             ..
-        } => let_instrs(var, bound_exp, body, &env, &mut instr, space, fun_to_env),
+        } => let_instrs(var, bound_exp, body, &env, &mut instr, ctx),
         SeqExp::If {
             cond,
             thn,
             els,
             ann,
-        } => if_instrs(cond, thn, els, ann, &env, &mut instr, space, fun_to_env),
-        SeqExp::Prim(op, exps, ann) => prim_instrs(op, exps, ann, &env, &mut instr, space),
+        } => if_instrs(cond, thn, els, ann, &env, &mut instr, ctx),
+        SeqExp::While { cond, body, ann } => {
+            while_instrs(cond, body, ann, &env, &mut instr, ctx)
+        }
+        SeqExp::Assign { var, value, .. } => {
+            immexp_instrs(value, &env, alloc, &mut instr);
+            instr.push(match var_arg(var, &env, alloc) {
+                Arg64::Reg(reg) => Instr::Mov(MovArgs::ToReg(reg, Arg64::Reg(Reg::Rax))),
+                Arg64::Mem(mem) => Instr::Mov(MovArgs::ToMem(mem, Reg32::Reg(Reg::Rax))),
+                _ => unreachable!(),
+            });
+        }
+        SeqExp::Prim(op, exps, ann) => {
+            prim_instrs(op, exps, ann, &env, &mut instr, ctx)
+        }
         SeqExp::InternalTailCall(fun_name, args, _) => {
-            incall_instr(fun_name, args, &env, &mut instr, fun_to_env)
+            incall_instr(fun_name, args, &env, &mut instr, ctx)
         }
         SeqExp::ExternalCall {
             fun_name,
             args,
             is_tail,
-            ..
-        } => excall_instr(fun_name, args, is_tail, &env, &mut instr, space, fun_to_env),
+            ann,
+        } => excall_instr(fun_name, args, is_tail, ann, &env, &mut instr, ctx),
         SeqExp::FunDefs { decls, body, ann } => {
-            fundefs_instr(&decls, &body, &env, &mut instr, ann, space, fun_to_env);
+            fundefs_instr(&decls, &body, &env, &mut instr, ann, ctx);
         }
+        SeqExp::ClosureCall {
+            fun,
+            args,
+            is_tail,
+            ann,
+        } => closure_call_instr(fun, args, is_tail, ann, &env, &mut instr, ctx),
+        SeqExp::MakeClosure {
+            fun_name,
+            arity,
+            env: captured,
+            ann,
+        } => make_closure_instr(fun_name, *arity, captured, ann, &env, &mut instr, ctx),
     }
     // ...
     instr
 }
 
-fn immexp_instrs(imme: &ImmExp, env: &Vec<String>, instr: &mut Vec<Instr>) {
+// Where a variable lives: its register, or its slot in the frame
+fn var_arg(x: &String, env: &Vec<String>, alloc: &Allocation) -> Arg64 {
+    match alloc.regs.get(x) {
+        Some(reg) => Arg64::Reg(*reg),
+        None => Arg64::Mem(MemRef {
+            reg: Reg::Rsp,
+            offset: -8 * (env.iter().position(|y| y == x).unwrap() as i32 + 1),
+        }),
+    }
+}
+
+// Float literals are boxed like computed floats, but live in the data
+// section under a name derived from their bits
+pub fn float_label(f: f64) -> String {
+    format!("float_{:016x}", f.to_bits())
+}
+
+// String literals live in the data section too, under a name derived
+// from a hash of their text; compile_to_string makes sure two literals
+// never share one
+pub fn string_label(s: &str) -> String {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    s.hash(&mut hasher);
+    format!("string_{:016x}", hasher.finish())
+}
+
+// The first word of a string of len bytes
+pub fn string_header(len: usize) -> u64 {
+    (len as u64) << 1 | 1
+}
+
+fn immexp_instrs(imme: &ImmExp, env: &Vec<String>, alloc: &Allocation, instr: &mut Vec<Instr>) {
     match imme {
         ImmExp::Num(n) => instr.push(Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Signed(n << 1)))),
         ImmExp::Bool(b) => {
@@ -84,15 +197,14 @@ fn immexp_instrs(imme: &ImmExp, env: &Vec<String>, instr: &mut Vec<Instr>) {
                 )))
             }
         }
-        ImmExp::Var(id) => instr.push(Instr::Mov(MovArgs::ToReg(
-            Reg::Rax,
-            Arg64::Mem(MemRef {
-                reg: Reg::Rsp,
-                offset: -8 * (env.iter().position(|x| x == id).unwrap() as i32 + 1),
-            }),
-        ))),
+        ImmExp::Var(id) => instr.push(Instr::Mov(MovArgs::ToReg(Reg::Rax, var_arg(id, env, alloc)))),
         ImmExp::Float(FloatWrapper(f)) => {
-            instr.append(&mut st_constfloat(Reg::Rax, *f as f32));
+            instr.push(Instr::Lea(Reg::Rax, float_label(*f)));
+            instr.push(Instr::Add(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(FLOAT_TAG as u32))));
+        }
+        ImmExp::Str(s) => {
+            instr.push(Instr::Lea(Reg::Rax, string_label(s)));
+            instr.push(Instr::Add(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(STRING_TAG as u32))));
         }
     }
 }
@@ -103,15 +215,15 @@ fn let_instrs(
     body: &Box<SeqExp<u32>>,
     env: &Vec<String>,
     instr: &mut Vec<Instr>,
-    space: i32,
-    fun_to_env: &HashMap<String, Vec<String>>,
+    ctx: &Ctx,
 ) {
-    instr.append(&mut compile_with_env(
-        bound_exp,
-        env.clone(),
-        space,
-        fun_to_env,
-    ));
+    let Ctx { alloc, .. } = *ctx;
+    instr.append(&mut compile_with_env(bound_exp, env.clone(), ctx));
+    if let Some(reg) = alloc.regs.get(var) {
+        instr.push(Instr::Mov(MovArgs::ToReg(*reg, Arg64::Reg(Reg::Rax))));
+        instr.append(&mut compile_with_env(body, env.clone(), ctx));
+        return;
+    }
     instr.push(Instr::Mov(MovArgs::ToMem(
         MemRef {
             reg: Reg::Rsp,
@@ -128,7 +240,7 @@ fn let_instrs(
     if !new_env.contains(var) {
         new_env.push(var.clone());
     }
-    instr.append(&mut compile_with_env(body, new_env, space, fun_to_env));
+    instr.append(&mut compile_with_env(body, new_env, ctx));
 }
 
 fn if_instrs(
@@ -138,11 +250,13 @@ fn if_instrs(
     ann: &u32,
     env: &Vec<String>,
     instr: &mut Vec<Instr>,
-    space: i32,
-    fun_to_env: &HashMap<String, Vec<String>>,
+    ctx: &Ctx,
 ) {
-    immexp_instrs(cond, &env, instr);
-    instr.append(&mut check_bool(Reg::Rax, IF_ERROR, true));
+    let Ctx { types, alloc, .. } = *ctx;
+    immexp_instrs(cond, &env, alloc, instr);
+    if proven_imm(cond, types) != Some(Proven::Bool) {
+        instr.append(&mut check_bool(Reg::Rax, IF_ERROR, true, ann));
+    }
     instr.push(Instr::Mov(MovArgs::ToReg(
         Reg::R8,
         Arg64::Unsigned(SNAKE_FALSE),
@@ -150,23 +264,69 @@ fn if_instrs(
 
     instr.push(Instr::Cmp(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::R8))));
     instr.push(Instr::Je(format!("else_{:?}", ann)));
-    instr.append(&mut compile_with_env(thn, env.clone(), space, fun_to_env));
+    instr.append(&mut compile_with_env(thn, env.clone(), ctx));
     instr.push(Instr::Jmp(format!("end_{:?}", ann)));
     instr.push(Instr::Label(format!("else_{:?}", ann)));
-    instr.append(&mut compile_with_env(els, env.clone(), space, fun_to_env));
+    instr.append(&mut compile_with_env(els, env.clone(), ctx));
     instr.push(Instr::Label(format!("end_{:?}", ann)));
 }
 
-fn logic_prim(op: &Prim, exps: &Vec<ImmExp>, env: &Vec<String>, instr: &mut Vec<Instr>) {
+// The body runs with the same env each time, so the variables it binds
+// keep their slots from one iteration to the next
+fn while_instrs(
+    cond: &SeqExp<u32>,
+    body: &SeqExp<u32>,
+    ann: &u32,
+    env: &[String],
+    instr: &mut Vec<Instr>,
+    ctx: &Ctx,
+) {
+    let Ctx { types, .. } = *ctx;
+    instr.push(Instr::Label(format!("while_start_{}", ann)));
+    instr.append(&mut compile_with_env(cond, env.to_vec(), ctx));
+    if proven_seq_type(cond, types) != Some(Proven::Bool) {
+        instr.append(&mut check_bool(Reg::Rax, WHILE_ERROR, true, ann));
+    }
+    instr.push(Instr::Mov(MovArgs::ToReg(
+        Reg::R8,
+        Arg64::Unsigned(SNAKE_FALSE),
+    )));
+    instr.push(Instr::Cmp(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::R8))));
+    instr.push(Instr::Je(format!("while_end_{}", ann)));
+    instr.append(&mut compile_with_env(body, env.to_vec(), ctx));
+    instr.push(Instr::Jmp(format!("while_start_{}", ann)));
+    instr.push(Instr::Label(format!("while_end_{}", ann)));
+    instr.push(Instr::Mov(MovArgs::ToReg(
+        Reg::Rax,
+        Arg64::Unsigned(SNAKE_FALSE),
+    )));
+}
+
+fn logic_prim(
+    op: &Prim,
+    exps: &Vec<ImmExp>,
+    ann: &u32,
+    env: &Vec<String>,
+    instr: &mut Vec<Instr>,
+    ctx: &Ctx,
+) {
+    let Ctx { types, alloc, .. } = *ctx;
+    let check = |e: &ImmExp| {
+        if proven_imm(e, types) == Some(Proven::Bool) {
+            vec![]
+        } else {
+            check_bool(Reg::Rax, LOGIC_ERROR, true, ann)
+        }
+    };
     if exps.len() == 1 {
-        immexp_instrs(&exps[0], &env, instr);
-        instr.append(&mut check_bool(Reg::Rax, LOGIC_ERROR, true));
+        immexp_instrs(&exps[0], &env, alloc, instr);
+        instr.append(&mut check(&exps[0]));
     } else if exps.len() == 2 {
-        immexp_instrs(&exps[1], &env, instr);
-        instr.append(&mut check_bool(Reg::Rax, LOGIC_ERROR, true));
+        immexp_instrs(&exps[1], &env, alloc, instr);
+        instr.append(&mut check(&exps[1]));
         instr.push(Instr::Mov(MovArgs::ToReg(Reg::R8, Arg64::Reg(Reg::Rax))));
-        immexp_instrs(&exps[0], &env, instr);
-        instr.append(&mut check_bool(Reg::Rax, LOGIC_ERROR, true));
+        immexp_instrs(&exps[0], &env, alloc, instr);
+        instr.append(&mut check(&exps[0]));
     }
     match *op {
         Prim::Not => {
@@ -194,17 +354,21 @@ fn other_prim(
     env: &Vec<String>,
     instr: &mut Vec<Instr>,
     ann: &u32,
-    space: i32,
+    ctx: &Ctx,
 ) {
+    let Ctx { space, alloc, .. } = *ctx;
     match *op {
         Prim::Print => {
-            immexp_instrs(&exps[0], &env, instr);
+            immexp_instrs(&exps[0], &env, alloc, instr);
+            let saved = runtime_saved(alloc.saved_at(ann));
+            instr.append(&mut save_regs(&saved, space));
             instr.extend(vec![
                 Instr::Mov(MovArgs::ToReg(Reg::Rdi, Arg64::Reg(Reg::Rax))),
                 Instr::Sub(BinArgs::ToReg(Reg::Rsp, Arg32::Signed(8 * space))),
                 Instr::Call("print_snake_val".to_string()),
                 Instr::Add(BinArgs::ToReg(Reg::Rsp, Arg32::Signed(8 * space))),
             ]);
+            instr.append(&mut restore_regs(&saved, space));
         }
         Prim::IsNum => {
             instr.push(Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Unsigned(1))));
@@ -222,9 +386,9 @@ fn other_prim(
             instr.push(Instr::Label(format!("isnum_done_{}", ann)));
         }
         Prim::IsBool => {
-            instr.push(Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Unsigned(3))));
-            instr.push(Instr::And(BinArgs::ToReg(Reg::R9, Arg32::Reg(Reg::Rax))));
-            instr.push(Instr::Cmp(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(3))));
+            instr.push(Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Reg(Reg::Rax))));
+            instr.push(Instr::Shl(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(1))));
+            instr.push(Instr::Cmp(BinArgs::ToReg(Reg::R9, Arg32::Signed(BOOL_SHIFTED))));
             instr.push(Instr::Mov(MovArgs::ToReg(
                 Reg::Rax,
                 Arg64::Unsigned(SNAKE_TRUE),
@@ -237,9 +401,9 @@ fn other_prim(
             instr.push(Instr::Label(format!("isbool_done_{}", ann)));
         }
         Prim::IsFloat => {
-            instr.push(Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Unsigned(3))));
+            instr.push(Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Unsigned(TAG_MASK))));
             instr.push(Instr::And(BinArgs::ToReg(Reg::R9, Arg32::Reg(Reg::Rax))));
-            instr.push(Instr::Cmp(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(1))));
+            instr.push(Instr::Cmp(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(FLOAT_TAG as u32))));
             instr.push(Instr::Mov(MovArgs::ToReg(
                 Reg::Rax,
                 Arg64::Unsigned(SNAKE_TRUE),
@@ -251,360 +415,949 @@ fn other_prim(
             )));
             instr.push(Instr::Label(format!("isfloat_done_{}", ann)));
         }
+        Prim::IsTuple => {
+            immexp_instrs(&exps[0], &env, alloc, instr);
+            instr.push(Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Unsigned(TAG_MASK))));
+            instr.push(Instr::And(BinArgs::ToReg(Reg::R9, Arg32::Reg(Reg::Rax))));
+            instr.push(Instr::Cmp(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(TUPLE_TAG as u32))));
+            instr.push(Instr::Mov(MovArgs::ToReg(
+                Reg::Rax,
+                Arg64::Unsigned(SNAKE_TRUE),
+            )));
+            instr.push(Instr::Jz(format!("istuple_done_{}", ann)));
+            instr.push(Instr::Mov(MovArgs::ToReg(
+                Reg::Rax,
+                Arg64::Unsigned(SNAKE_FALSE),
+            )));
+            instr.push(Instr::Label(format!("istuple_done_{}", ann)));
+        }
         _ => panic!("other prim here"),
     }
 }
 
-fn arith_prim(op: &Prim, exps: &Vec<ImmExp>, ann: &u32, env: &Vec<String>, instr: &mut Vec<Instr>) {
-    let offset = -8 * (env.len() + 1) as i32;
+fn arith_prim(
+    op: &Prim,
+    exps: &Vec<ImmExp>,
+    ann: &u32,
+    env: &Vec<String>,
+    instr: &mut Vec<Instr>,
+    ctx: &Ctx,
+) {
+    let Ctx { space, types, alloc, .. } = *ctx;
+    // the result may be a float, which needs room on the heap. This has
+    // to happen before the operands are loaded since they may be moved.
+    let allocates = match *op {
+        Prim::Div | Prim::Sqrt | Prim::Cos => true,
+        Prim::FloorDiv => false,
+        _ => !exps.iter().all(|e| proven_imm(e, types) == Some(Proven::Int)),
+    };
+    if allocates {
+        instr.append(&mut reserve(FLOAT_WORDS, ann, space, alloc.saved_at(ann)));
+    }
     if exps.len() == 1 {
-        immexp_instrs(&exps[0], &env, instr);
-        instr.append(&mut check_bool(Reg::Rax, ARITH_ERROR, false));
+        immexp_instrs(&exps[0], &env, alloc, instr);
+        instr.append(&mut check_operand(&exps[0], types, ARITH_ERROR, format!("arith_ok1_{}", ann), ann));
     } else if exps.len() == 2 {
-        immexp_instrs(&exps[1], &env, instr);
-        instr.append(&mut check_bool(Reg::Rax, ARITH_ERROR, false));
+        immexp_instrs(&exps[1], &env, alloc, instr);
+        instr.append(&mut check_operand(&exps[1], types, ARITH_ERROR, format!("arith_ok2_{}", ann), ann));
         instr.push(Instr::Mov(MovArgs::ToReg(Reg::R8, Arg64::Reg(Reg::Rax))));
-        immexp_instrs(&exps[0], &env, instr);
-        instr.append(&mut check_bool(Reg::Rax, ARITH_ERROR, false));
+        immexp_instrs(&exps[0], &env, alloc, instr);
+        instr.append(&mut check_operand(&exps[0], types, ARITH_ERROR, format!("arith_ok1_{}", ann), ann));
     }
     match *op {
         Prim::Add1 => {
             let mut instr1 = vec![Instr::Add(BinArgs::ToReg(Reg::Rax, Arg32::Signed(1 << 1)))];
-            instr1.append(&mut check_overflow());
-            let instr2 = vec![Instr::Fld1, Instr::Faddp(FloatArg::Blank)];
+            instr1.append(&mut check_overflow(ann));
+            let mut instr2 = ld_constfloat(1.0, Reg::Xmm1);
+            instr2.push(Instr::Addsd(Reg::Xmm0, Reg::Xmm1));
 
-            unaryop(&instr1, &instr2, ann, offset, instr);
+            unaryop(&instr1, &instr2, ann, instr);
         }
         Prim::Sub1 => {
             let mut instr1 = vec![Instr::Sub(BinArgs::ToReg(Reg::Rax, Arg32::Signed(1 << 1)))];
-            instr1.append(&mut check_overflow());
-            let instr2 = vec![Instr::Fld1, Instr::Fsubp(FloatArg::Blank)];
+            instr1.append(&mut check_overflow(ann));
+            let mut instr2 = ld_constfloat(1.0, Reg::Xmm1);
+            instr2.push(Instr::Subsd(Reg::Xmm0, Reg::Xmm1));
 
-            unaryop(&instr1, &instr2, ann, offset, instr);
+            unaryop(&instr1, &instr2, ann, instr);
         }
         Prim::Add => {
             let mut instr1 = vec![Instr::Add(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::R8)))];
-            instr1.append(&mut check_overflow());
-            let mut instr2 = vec![Instr::Faddp(FloatArg::Blank)];
-            instr2.append(&mut check_float_overflow(ann, offset));
-            instr2.append(&mut st_float_to_reg(Reg::Rax, offset));
+            instr1.append(&mut check_overflow(ann));
+            let mut instr2 = vec![Instr::Addsd(Reg::Xmm0, Reg::Xmm1)];
+            instr2.append(&mut check_float_overflow(ann));
+            instr2.append(&mut st_float_to_reg(Reg::Rax));
 
-            binop(&instr1, &instr2, ann, offset, instr);
+            binop(&instr1, &instr2, ann, instr);
         }
         Prim::Sub => {
             let mut instr1 = vec![Instr::Sub(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::R8)))];
-            instr1.append(&mut check_overflow());
-            let mut instr2 = vec![Instr::Fsubp(FloatArg::Blank)];
-            instr2.append(&mut check_float_overflow(ann, offset));
-            instr2.append(&mut st_float_to_reg(Reg::Rax, offset));
+            instr1.append(&mut check_overflow(ann));
+            let mut instr2 = vec![Instr::Subsd(Reg::Xmm0, Reg::Xmm1)];
+            instr2.append(&mut check_float_overflow(ann));
+            instr2.append(&mut st_float_to_reg(Reg::Rax));
 
-            binop(&instr1, &instr2, ann, offset, instr);
+            binop(&instr1, &instr2, ann, instr);
         }
         Prim::Mul => {
+            // a tagged number times an untagged one is tagged, and
+            // overflows exactly when the result doesn't fit into 63 bits
             let mut instr1 = vec![
                 Instr::Sar(BinArgs::ToReg(Reg::R8, Arg32::Unsigned(1))),
-                Instr::Sar(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(1))),
                 Instr::IMul(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::R8))),
             ];
-            instr1.append(&mut check_overflow());
-            instr1.push(Instr::Shl(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(1))));
-            let mut instr2 = vec![Instr::Fmulp(FloatArg::Blank)];
-            instr2.append(&mut check_float_overflow(ann, offset));
-            instr2.append(&mut st_float_to_reg(Reg::Rax, offset));
+            instr1.append(&mut check_overflow(ann));
+            let mut instr2 = vec![Instr::Mulsd(Reg::Xmm0, Reg::Xmm1)];
+            instr2.append(&mut check_float_overflow(ann));
+            instr2.append(&mut st_float_to_reg(Reg::Rax));
 
-            binop(&instr1, &instr2, ann, offset, instr);
+            binop(&instr1, &instr2, ann, instr);
         }
         Prim::Div => {
-            let mut instr1 = vec![];
-            instr1.append(&mut ld_num_from_reg(Reg::Rax, offset));
-            instr1.append(&mut ld_num_from_reg(Reg::R8, offset));
-            instr1.append(&mut check_division_zero_num(Reg::R8, ann));
-            instr1.push(Instr::Fdivp(FloatArg::Blank));
-            instr1.append(&mut st_float_to_reg(Reg::Rax, offset));
-
-            let mut instr2 = check_division_zero_float(ann, offset);
-            instr2.push(Instr::Fdivp(FloatArg::Blank));
-            instr2.append(&mut check_float_overflow(ann, offset));
-            instr2.append(&mut st_float_to_reg(Reg::Rax, offset));
-
-            binop(&instr1, &instr2, ann, offset, instr);
+            let mut instr1 = check_division_zero_num(Reg::R8, ann);
+            instr1.append(&mut ld_num_from_reg(Reg::Rax, Reg::Xmm0));
+            instr1.append(&mut ld_num_from_reg(Reg::R8, Reg::Xmm1));
+            instr1.push(Instr::Divsd(Reg::Xmm0, Reg::Xmm1));
+            instr1.append(&mut st_float_to_reg(Reg::Rax));
+
+            let mut instr2 = check_division_zero_float(ann);
+            instr2.push(Instr::Divsd(Reg::Xmm0, Reg::Xmm1));
+            instr2.append(&mut check_float_overflow(ann));
+            instr2.append(&mut st_float_to_reg(Reg::Rax));
+
+            binop(&instr1, &instr2, ann, instr);
         }
         Prim::FloorDiv => {
-            let mut instr1 = ld_num_from_reg(Reg::Rax, offset);
-            instr1.append(&mut ld_num_from_reg(Reg::R8, offset));
-            instr1.append(&mut check_division_zero_num(Reg::R8, ann));
-            instr1.push(Instr::Fdivp(FloatArg::Blank));
-            instr1.append(&mut st_floornum_to_reg(Reg::Rax, offset));
+            let mut instr1 = check_division_zero_num(Reg::R8, ann);
+            instr1.append(&mut floor_div_nums(format!("floor_num_done_{}", ann), ann));
 
-            let mut instr2 = check_division_zero_float(ann, offset);
-            instr2.push(Instr::Fdivp(FloatArg::Blank));
-            instr2.append(&mut check_float_overflow(ann, offset));
-            instr2.append(&mut st_floornum_to_reg(Reg::Rax, offset));
+            let mut instr2 = check_division_zero_float(ann);
+            instr2.push(Instr::Divsd(Reg::Xmm0, Reg::Xmm1));
+            instr2.append(&mut check_float_overflow(ann));
+            instr2.append(&mut st_floornum_to_reg(Reg::Rax, format!("floor_float_done_{}", ann), ann));
 
-            binop(&instr1, &instr2, ann, offset, instr);
+            binop(&instr1, &instr2, ann, instr);
         }
 
         Prim::Cos => {
-            let mut instr1 = vec![];
-            instr1.append(&mut ld_num_from_reg(Reg::Rax, offset));
-            instr1.push(Instr::Fcos);
-            instr1.append(&mut st_float_to_reg(Reg::Rax, offset));
-            let instr2 = vec![Instr::Fcos];
+            let saved = runtime_saved(alloc.saved_at(ann));
+            let mut instr1 = ld_num_from_reg(Reg::Rax, Reg::Xmm0);
+            instr1.append(&mut call_cos(&saved, space));
+            instr1.append(&mut st_float_to_reg(Reg::Rax));
+            let instr2 = call_cos(&saved, space);
 
-            unaryop(&instr1, &instr2, ann, offset, instr);
+            unaryop(&instr1, &instr2, ann, instr);
         }
         Prim::Sqrt => {
-            let mut instr1 = vec![];
-            instr1.append(&mut check_sqrt_num(Reg::Rax, ann));
-            instr1.append(&mut ld_num_from_reg(Reg::Rax, offset));
-            instr1.push(Instr::Fsqrt);
-            instr1.append(&mut st_float_to_reg(Reg::Rax, offset));
-            let mut instr2 = check_sqrt_float(ann, offset);
-            instr2.push(Instr::Fsqrt);
-
-            unaryop(&instr1, &instr2, ann, offset, instr);
+            let mut instr1 = check_sqrt_num(Reg::Rax, ann);
+            instr1.append(&mut ld_num_from_reg(Reg::Rax, Reg::Xmm0));
+            instr1.push(Instr::Sqrtsd(Reg::Xmm0, Reg::Xmm0));
+            instr1.append(&mut st_float_to_reg(Reg::Rax));
+            let mut instr2 = check_sqrt_float(ann);
+            instr2.push(Instr::Sqrtsd(Reg::Xmm0, Reg::Xmm0));
+
+            unaryop(&instr1, &instr2, ann, instr);
         }
         _ => panic!("arith prim here"),
     }
 }
 
-fn comp_prim(op: &Prim, exps: &Vec<ImmExp>, ann: &u32, env: &Vec<String>, instr: &mut Vec<Instr>) {
-    let offset = -8 * (env.len() + 1) as i32;
+// There is no SSE2 instruction for cosine, so it's left to the runtime.
+// The argument and the result are passed in xmm0.
+fn call_cos(saved: &[Reg], space: i32) -> Vec<Instr> {
+    let mut instr = save_regs(saved, space);
+    instr.extend(vec![
+        Instr::Sub(BinArgs::ToReg(Reg::Rsp, Arg32::Signed(8 * space))),
+        Instr::Call("snake_cos".to_string()),
+        Instr::Add(BinArgs::ToReg(Reg::Rsp, Arg32::Signed(8 * space))),
+    ]);
+    instr.append(&mut restore_regs(saved, space));
+    instr
+}
+
+fn comp_prim(
+    op: &Prim,
+    exps: &Vec<ImmExp>,
+    ann: &u32,
+    env: &Vec<String>,
+    instr: &mut Vec<Instr>,
+    ctx: &Ctx,
+) {
+    let Ctx { types, alloc, .. } = *ctx;
     if exps.len() == 1 {
-        immexp_instrs(&exps[0], &env, instr);
-        instr.append(&mut check_bool(Reg::Rax, COMP_ERROR, false));
+        immexp_instrs(&exps[0], &env, alloc, instr);
+        instr.append(&mut check_operand(&exps[0], types, COMP_ERROR, format!("comp_ok1_{}", ann), ann));
     } else if exps.len() == 2 {
-        immexp_instrs(&exps[1], &env, instr);
-        instr.append(&mut check_bool(Reg::Rax, COMP_ERROR, false));
+        immexp_instrs(&exps[1], &env, alloc, instr);
+        instr.append(&mut check_operand(&exps[1], types, COMP_ERROR, format!("comp_ok2_{}", ann), ann));
         instr.push(Instr::Mov(MovArgs::ToReg(Reg::R8, Arg64::Reg(Reg::Rax))));
-        immexp_instrs(&exps[0], &env, instr);
-        instr.append(&mut check_bool(Reg::Rax, COMP_ERROR, false));
+        immexp_instrs(&exps[0], &env, alloc, instr);
+        instr.append(&mut check_operand(&exps[0], types, COMP_ERROR, format!("comp_ok1_{}", ann), ann));
     }
     match *op {
         Prim::Lt | Prim::Gt | Prim::Le | Prim::Ge | Prim::Eq | Prim::Neq => {
-            let mut instr1 = vec![];
-            instr1.push(Instr::Cmp(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::R8))));
-            instr1.push(Instr::Mov(MovArgs::ToReg(
-                Reg::Rax,
-                Arg64::Unsigned(SNAKE_TRUE),
-            )));
-            instr1.push(match op {
-                Prim::Ge => Instr::Jge(format!("greater_equal_{}", ann)),
-                Prim::Eq => Instr::Je(format!("equal_{}", ann)),
-                Prim::Neq => Instr::Jne(format!("not_equal_{}", ann)),
-                Prim::Lt => Instr::Jl(format!("less_than_{}", ann)),
-                Prim::Gt => Instr::Jg(format!("greater_than_{}", ann)),
-                Prim::Le => Instr::Jle(format!("less_equal_{}", ann)),
+            let name = match op {
+                Prim::Lt => "less_than",
+                Prim::Gt => "greater_than",
+                Prim::Le => "less_equal",
+                Prim::Ge => "greater_equal",
+                Prim::Eq => "equal",
+                Prim::Neq => "not_equal",
                 _ => panic!("invalid logic comparison"),
-            });
-            instr1.push(Instr::Mov(MovArgs::ToReg(
-                Reg::Rax,
-                Arg64::Unsigned(SNAKE_FALSE),
-            )));
-            instr1.push(Instr::Label(format!(
-                "{}_{}",
+            };
+
+            // numbers are compared as signed integers
+            let label = format!("{}_{}", name, ann);
+            let instr1 = set_bool(
+                Instr::Cmp(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::R8))),
                 match op {
-                    Prim::Lt => "less_than",
-                    Prim::Gt => "greater_than",
-                    Prim::Le => "less_equal",
-                    Prim::Ge => "greater_equal",
-                    Prim::Eq => "equal",
-                    Prim::Neq => "not_equal",
+                    Prim::Ge => Instr::Jge(label.clone()),
+                    Prim::Eq => Instr::Je(label.clone()),
+                    Prim::Neq => Instr::Jne(label.clone()),
+                    Prim::Lt => Instr::Jl(label.clone()),
+                    Prim::Gt => Instr::Jg(label.clone()),
+                    Prim::Le => Instr::Jle(label.clone()),
                     _ => panic!("invalid logic comparison"),
                 },
-                ann,
-            )));
-
-            // r8 > rax, so rax < r8
-            let larger = vec![Instr::Mov(MovArgs::ToReg(
-                Reg::Rax,
-                Arg64::Unsigned(match *op {
-                    Prim::Ge => SNAKE_FALSE,
-                    Prim::Eq => SNAKE_FALSE,
-                    Prim::Neq => SNAKE_TRUE,
-                    Prim::Lt => SNAKE_TRUE,
-                    Prim::Gt => SNAKE_FALSE,
-                    Prim::Le => SNAKE_TRUE,
-                    _ => panic!("invalid logic comparison"),
-                }),
-            ))];
-
-            // r8 < rax, so rax > r8
-            let smaller = vec![Instr::Mov(MovArgs::ToReg(
-                Reg::Rax,
-                Arg64::Unsigned(match *op {
-                    Prim::Ge => SNAKE_TRUE,
-                    Prim::Eq => SNAKE_FALSE,
-                    Prim::Neq => SNAKE_TRUE,
-                    Prim::Lt => SNAKE_FALSE,
-                    Prim::Gt => SNAKE_TRUE,
-                    Prim::Le => SNAKE_FALSE,
-                    _ => panic!("invalid logic comparison"),
-                }),
-            ))];
+                label,
+            );
 
-            // r8 == rax, so rax == r8
-            let equal = vec![Instr::Mov(MovArgs::ToReg(
-                Reg::Rax,
-                Arg64::Unsigned(match *op {
-                    Prim::Ge => SNAKE_FALSE,
-                    Prim::Eq => SNAKE_TRUE,
-                    Prim::Neq => SNAKE_FALSE,
-                    Prim::Lt => SNAKE_FALSE,
-                    Prim::Gt => SNAKE_FALSE,
-                    Prim::Le => SNAKE_FALSE,
+            // ucomisd sets the flags like an unsigned comparison
+            let label = format!("float_{}_{}", name, ann);
+            let instr2 = set_bool(
+                Instr::Ucomisd(Reg::Xmm0, Reg::Xmm1),
+                match op {
+                    Prim::Ge => Instr::Jae(label.clone()),
+                    Prim::Eq => Instr::Je(label.clone()),
+                    Prim::Neq => Instr::Jne(label.clone()),
+                    Prim::Lt => Instr::Jb(label.clone()),
+                    Prim::Gt => Instr::Ja(label.clone()),
+                    Prim::Le => Instr::Jbe(label.clone()),
                     _ => panic!("invalid logic comparison"),
-                }),
-            ))];
-
-            let instr2 = cmp_floats(
-                (&larger, format!("logic_larger_{}", ann)),
-                (&smaller, format!("logic_smaller_{}", ann)),
-                (&equal, format!("logic_equal_{}", ann)),
-                format!("cmp_done_{}", ann),
-                2,
+                },
+                label,
             );
 
-            binop(&instr1, &instr2, ann, offset, instr);
+            binop(&instr1, &instr2, ann, instr);
         }
         _ => panic!("comp prim here"),
     }
 }
 
-fn prim_instrs(
+// Sets rax to true if the jump after the comparison is taken
+fn set_bool(cmp: Instr, jump: Instr, label: String) -> Vec<Instr> {
+    vec![
+        cmp,
+        Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Unsigned(SNAKE_TRUE))),
+        jump,
+        Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Unsigned(SNAKE_FALSE))),
+        Instr::Label(label),
+    ]
+}
+
+// Booleans, tuples and closures are compared by the runtime,
+// everything else goes through the usual number and float comparison.
+fn eq_prim(
     op: &Prim,
     exps: &Vec<ImmExp>,
     ann: &u32,
     env: &Vec<String>,
     instr: &mut Vec<Instr>,
-    space: i32,
+    ctx: &Ctx,
 ) {
-    match *op {
-        Prim::Add1
-        | Prim::Sub1
-        | Prim::Add
-        | Prim::Sub
-        | Prim::Mul
-        | Prim::Div
-        | Prim::FloorDiv
-        | Prim::Cos
-        | Prim::Sqrt => arith_prim(op, exps, ann, env, instr),
-        Prim::Lt | Prim::Gt | Prim::Le | Prim::Ge | Prim::Eq | Prim::Neq => {
-            comp_prim(op, exps, ann, env, instr)
-        }
-        Prim::And | Prim::Or | Prim::Not => logic_prim(op, exps, env, instr),
-        Prim::Print | Prim::IsBool | Prim::IsNum | Prim::IsFloat => {
-            other_prim(op, exps, env, instr, ann, space)
+    let Ctx { space, alloc, .. } = *ctx;
+    let tuples = format!("eq_tuples_{}", ann);
+    let done = format!("eq_done_{}", ann);
+    immexp_instrs(&exps[1], &env, alloc, instr);
+    instr.push(Instr::Mov(MovArgs::ToReg(Reg::R8, Arg64::Reg(Reg::Rax))));
+    immexp_instrs(&exps[0], &env, alloc, instr);
+    for reg in [Reg::Rax, Reg::R8] {
+        for tag in [BOOL_TAG, TUPLE_TAG, CLOSURE_TAG] {
+            instr.push(Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Unsigned(TAG_MASK))));
+            instr.push(Instr::And(BinArgs::ToReg(Reg::R9, Arg32::Reg(reg))));
+            instr.push(Instr::Cmp(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(tag as u32))));
+            instr.push(Instr::Je(tuples.clone()));
         }
     }
-}
-
-pub fn check_division_zero_num(reg: Reg, ann: &u32) -> Vec<Instr> {
-    let mut instr = vec![];
-    instr.push(Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Unsigned(0))));
-    instr.push(Instr::Cmp(BinArgs::ToReg(Reg::R9, Arg32::Reg(reg))));
-    instr.push(Instr::Jne(format!("not_zero_{}", ann)));
-    instr.push(Instr::Mov(MovArgs::ToReg(
-        Reg::Rdi,
-        Arg64::Unsigned(DIVISION_ERROR),
-    )));
-    instr.push(Instr::Jmp("snake_error".to_string()));
-    instr.push(Instr::Label(format!("not_zero_{}", ann)));
-    instr
-}
+    comp_prim(op, exps, ann, env, instr, ctx);
+    instr.push(Instr::Jmp(done.clone()));
 
-pub fn check_sqrt_num(reg: Reg, ann: &u32) -> Vec<Instr> {
-    let mut instr = vec![];
-    instr.push(Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Unsigned(0))));
-    instr.push(Instr::Cmp(BinArgs::ToReg(Reg::R9, Arg32::Reg(reg))));
-    instr.push(Instr::Jle(format!("not_lt_zero_{}", ann)));
-    instr.push(Instr::Mov(MovArgs::ToReg(
-        Reg::Rdi,
-        Arg64::Unsigned(SQRT_ERROR),
-    )));
-    instr.push(Instr::Jmp("snake_error".to_string()));
-    instr.push(Instr::Label(format!("not_lt_zero_{}", ann)));
-    instr
+    instr.push(Instr::Label(tuples));
+    let saved = runtime_saved(alloc.saved_at(ann));
+    instr.append(&mut save_regs(&saved, space));
+    instr.extend(vec![
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, Arg64::Reg(Reg::Rax))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::R8))),
+        Instr::Sub(BinArgs::ToReg(Reg::Rsp, Arg32::Signed(8 * space))),
+        Instr::Call("snake_equal".to_string()),
+        Instr::Add(BinArgs::ToReg(Reg::Rsp, Arg32::Signed(8 * space))),
+    ]);
+    instr.append(&mut restore_regs(&saved, space));
+    if *op == Prim::Neq {
+        instr.push(Instr::Mov(MovArgs::ToReg(Reg::R8, Arg64::Unsigned(BOOL_MASK))));
+        instr.push(Instr::Xor(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::R8))));
+    }
+    instr.push(Instr::Label(done));
 }
 
-pub fn check_division_zero_float(ann: &u32, offset: i32) -> Vec<Instr> {
-    let mut instr = vec![];
-    instr.append(&mut ld_constfloat(0.0, offset));
-    let equal = vec![
-        Instr::Mov(MovArgs::ToReg(Reg::Rdi, Arg64::Unsigned(DIVISION_ERROR))),
-        Instr::Jmp("snake_error".to_string()),
-    ];
-    let larger = vec![];
-    let smaller = vec![];
-    instr.append(&mut cmp_floats(
-        (&larger, format!("check_div_larger_{}", ann)),
-        (&smaller, format!("check_div_smaller_{}", ann)),
-        (&equal, format!("check_div_equal_{}", ann)),
-        format!("cmp_done_{}", ann),
-        1,
-    ));
-    instr
+fn tuple_prim(
+    op: &Prim,
+    exps: &Vec<ImmExp>,
+    ann: &u32,
+    env: &Vec<String>,
+    instr: &mut Vec<Instr>,
+    ctx: &Ctx,
+) {
+    let Ctx { space, types, alloc, .. } = *ctx;
+    match *op {
+        Prim::MakeTuple => {
+            instr.append(&mut reserve(exps.len() as i32 + 1, ann, space, alloc.saved_at(ann)));
+            // header word holds the number of elements
+            instr.push(Instr::Mov(MovArgs::ToMem(
+                MemRef {
+                    reg: Reg::R15,
+                    offset: 0,
+                },
+                Reg32::Imm(exps.len() as i32),
+            )));
+            for (i, e) in exps.iter().enumerate() {
+                immexp_instrs(e, &env, alloc, instr);
+                instr.push(Instr::Mov(MovArgs::ToMem(
+                    MemRef {
+                        reg: Reg::R15,
+                        offset: 8 * (i as i32 + 1),
+                    },
+                    Reg32::Reg(Reg::Rax),
+                )));
+            }
+            instr.push(Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Reg(Reg::R15))));
+            instr.push(Instr::Add(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(TUPLE_TAG as u32))));
+            instr.push(Instr::Add(BinArgs::ToReg(
+                Reg::R15,
+                Arg32::Signed(8 * (exps.len() as i32 + 1)),
+            )));
+        }
+        // tuples and arrays are indexed alike, which one it is may
+        // only be known when the program runs
+        Prim::GetIndex => {
+            let proven = proven_imm(&exps[0], types);
+            let tuple = format!("index_tuple_{}", ann);
+            let array = format!("index_array_{}", ann);
+            let done = format!("index_done_{}", ann);
+            immexp_instrs(&exps[0], env, alloc, instr);
+            if proven != Some(Proven::Tuple) && proven != Some(Proven::Array) {
+                instr.extend(vec![
+                    Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Unsigned(TAG_MASK))),
+                    Instr::And(BinArgs::ToReg(Reg::R9, Arg32::Reg(Reg::Rax))),
+                    Instr::Cmp(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(TUPLE_TAG as u32))),
+                    Instr::Je(tuple.clone()),
+                    Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_arg(TUPLE_ERROR, ann))),
+                    Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rax))),
+                ]);
+                instr.append(&mut if_not_array(Reg::Rax, "snake_fail".to_string()));
+                instr.push(Instr::Jmp(array.clone()));
+            }
+            if proven != Some(Proven::Array) {
+                instr.push(Instr::Label(tuple));
+                instr.push(Instr::Mov(MovArgs::ToReg(Reg::R8, Arg64::Reg(Reg::Rax))));
+                instr.push(Instr::Sub(BinArgs::ToReg(Reg::R8, Arg32::Unsigned(TUPLE_TAG as u32))));
+                index_slot(&exps[1], false, ann, env, instr, ctx);
+                instr.push(Instr::Mov(MovArgs::ToReg(
+                    Reg::Rax,
+                    Arg64::Mem(MemRef {
+                        reg: Reg::Rax,
+                        offset: 0,
+                    }),
+                )));
+                instr.push(Instr::Jmp(done.clone()));
+            }
+            if proven != Some(Proven::Tuple) {
+                instr.push(Instr::Label(array));
+                instr.push(Instr::Mov(MovArgs::ToReg(Reg::R8, Arg64::Reg(Reg::Rax))));
+                instr.push(Instr::Sub(BinArgs::ToReg(Reg::R8, Arg32::Unsigned(ARRAY_TAG as u32))));
+                index_slot(&exps[1], true, ann, env, instr, ctx);
+                instr.push(Instr::Mov(MovArgs::ToReg(
+                    Reg::Rax,
+                    Arg64::Mem(MemRef {
+                        reg: Reg::Rax,
+                        offset: 0,
+                    }),
+                )));
+            }
+            instr.push(Instr::Label(done));
+        }
+        _ => panic!("tuple prim here"),
+    }
 }
 
-pub fn check_sqrt_float(ann: &u32, offset: i32) -> Vec<Instr> {
-    let mut instr = vec![];
-    instr.append(&mut ld_constfloat(0.0, offset));
-    let larger = vec![
-        Instr::Mov(MovArgs::ToReg(Reg::Rdi, Arg64::Unsigned(DIVISION_ERROR))),
-        Instr::Jmp("snake_error".to_string()),
-    ];
-    let equal = vec![];
-    let smaller = vec![];
-    instr.append(&mut cmp_floats(
-        (&larger, format!("check_sqrt_larger_{}", ann)),
-        (&smaller, format!("check_sqrt_smaller_{}", ann)),
-        (&equal, format!("check_sqrt_equal_{}", ann)),
-        format!("cmp_done_{}", ann),
-        1,
-    ));
-    instr
-}
+/* Checks that the index e is a number within the bounds of the tuple or
+ * array r8 points to, and leaves the address of the element in rax.
+ * The length of an array is reported along with the index, in rdx.
+ */
+fn index_slot(
+    e: &ImmExp,
+    is_array: bool,
+    ann: &u32,
+    env: &Vec<String>,
+    instr: &mut Vec<Instr>,
+    ctx: &Ctx,
+) {
+    let Ctx { types, alloc, .. } = *ctx;
+    let (kind, len, first, error) = if is_array {
+        ("array", 8, 16, ARRAY_BOUNDS_ERROR)
+    } else {
+        ("tuple", 0, 8, BOUNDS_ERROR)
+    };
+    immexp_instrs(e, env, alloc, instr);
+    if proven_imm(e, types) != Some(Proven::Int) {
+        instr.extend(vec![
+            Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Unsigned(1))),
+            Instr::And(BinArgs::ToReg(Reg::R9, Arg32::Reg(Reg::Rax))),
+            Instr::Cmp(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(0))),
+            Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_arg(INDEX_ERROR, ann))),
+            Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rax))),
+            Instr::Jne("snake_fail".to_string()),
+        ]);
+    }
 
-pub fn check_overflow() -> Vec<Instr> {
-    vec![
-        Instr::Mov(MovArgs::ToReg(Reg::Rdi, Arg64::Unsigned(OVERFLOW_ERROR))),
-        Instr::Jo("snake_error".to_string()),
-    ]
+    // 0 <= index < length
+    let out_of_bounds = format!("{}_out_of_bounds_{}", kind, ann);
+    let in_bounds = format!("{}_in_bounds_{}", kind, ann);
+    let length = MemRef {
+        reg: Reg::R8,
+        offset: len,
+    };
+    instr.extend(vec![
+        Instr::Sar(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(1))),
+        Instr::Cmp(BinArgs::ToReg(Reg::Rax, Arg32::Signed(0))),
+        Instr::Jl(out_of_bounds.clone()),
+        Instr::Cmp(BinArgs::ToReg(Reg::Rax, Arg32::Mem(length))),
+        Instr::Jl(in_bounds.clone()),
+        Instr::Label(out_of_bounds),
+        // report the offending index
+        Instr::Shl(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(1))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rax))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Mem(length))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_arg(error, ann))),
+        Instr::Jmp("snake_fail".to_string()),
+        Instr::Label(in_bounds),
+        Instr::Shl(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(3))),
+        Instr::Add(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::R8))),
+        Instr::Add(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(first))),
+    ]);
 }
 
-pub fn unaryop(
-    instr1: &Vec<Instr>,
-    instr2: &Vec<Instr>,
+fn array_prim(
+    op: &Prim,
+    exps: &Vec<ImmExp>,
     ann: &u32,
-    offset: i32,
+    env: &Vec<String>,
     instr: &mut Vec<Instr>,
+    ctx: &Ctx,
 ) {
-    // check number of float
-    let label = format!("when_float_{}", ann);
-    let done = format!("done_{}", ann);
-    instr.append(&mut if_float(Reg::Rax, &label));
-
-    // when number
-    instr.append(&mut instr1.clone());
-
-    instr.push(Instr::Jmp(done.clone()));
-
-    //when float
-    instr.push(Instr::Label(label));
-    instr.append(&mut ld_float_from_reg(Reg::Rax, offset));
-    instr.append(&mut instr2.clone());
-    instr.append(&mut check_float_overflow(ann, offset));
-    instr.append(&mut st_float_to_reg(Reg::Rax, offset));
-
-    // done
-    instr.push(Instr::Label(done));
+    let Ctx { space, types, alloc, .. } = *ctx;
+    match *op {
+        // the words are reserved before anything is written, so the
+        // length is read again after the garbage collector may have run
+        Prim::MakeArray => {
+            let fill = format!("array_fill_{}", ann);
+            let filled = format!("array_filled_{}", ann);
+            immexp_instrs(&exps[0], env, alloc, instr);
+            instr.extend(vec![
+                Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_arg(ARRAY_SIZE_ERROR, ann))),
+                Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rax))),
+                Instr::Test(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(1))),
+                Instr::Jnz("snake_fail".to_string()),
+                Instr::Cmp(BinArgs::ToReg(Reg::Rax, Arg32::Signed(0))),
+                Instr::Jl("snake_fail".to_string()),
+                Instr::Sar(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(1))),
+                Instr::Add(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(2))),
+                Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Reg(Reg::Rax))),
+            ]);
+            instr.append(&mut reserve_reg(Reg::Rdx, ann, space, alloc.saved_at(ann)));
+            instr.extend(vec![
+                Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Unsigned(ARRAY_HEADER))),
+                Instr::Mov(MovArgs::ToMem(
+                    MemRef {
+                        reg: Reg::R15,
+                        offset: 0,
+                    },
+                    Reg32::Reg(Reg::Rax),
+                )),
+            ]);
+            immexp_instrs(&exps[0], env, alloc, instr);
+            instr.extend(vec![
+                Instr::Sar(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(1))),
+                Instr::Mov(MovArgs::ToMem(
+                    MemRef {
+                        reg: Reg::R15,
+                        offset: 8,
+                    },
+                    Reg32::Reg(Reg::Rax),
+                )),
+                Instr::Mov(MovArgs::ToReg(Reg::R8, Arg64::Reg(Reg::Rax))),
+                Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Reg(Reg::R15))),
+                Instr::Add(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(16))),
+            ]);
+            // r8 counts down the elements left to write at r9
+            immexp_instrs(&exps[1], env, alloc, instr);
+            instr.extend(vec![
+                Instr::Label(fill.clone()),
+                Instr::Cmp(BinArgs::ToReg(Reg::R8, Arg32::Signed(0))),
+                Instr::Je(filled.clone()),
+                Instr::Mov(MovArgs::ToMem(
+                    MemRef {
+                        reg: Reg::R9,
+                        offset: 0,
+                    },
+                    Reg32::Reg(Reg::Rax),
+                )),
+                Instr::Add(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(8))),
+                Instr::Sub(BinArgs::ToReg(Reg::R8, Arg32::Unsigned(1))),
+                Instr::Jmp(fill),
+                Instr::Label(filled),
+                Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Reg(Reg::R15))),
+                Instr::Add(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(ARRAY_TAG as u32))),
+                Instr::Mov(MovArgs::ToReg(Reg::R15, Arg64::Reg(Reg::R9))),
+            ]);
+        }
+        Prim::SetIndex => {
+            immexp_instrs(&exps[0], env, alloc, instr);
+            instr.append(&mut check_array(&exps[0], types, ann));
+            instr.push(Instr::Mov(MovArgs::ToReg(Reg::R8, Arg64::Reg(Reg::Rax))));
+            instr.push(Instr::Sub(BinArgs::ToReg(Reg::R8, Arg32::Unsigned(ARRAY_TAG as u32))));
+            index_slot(&exps[1], true, ann, env, instr, ctx);
+            instr.push(Instr::Mov(MovArgs::ToReg(Reg::R8, Arg64::Reg(Reg::Rax))));
+            immexp_instrs(&exps[2], env, alloc, instr);
+            instr.push(Instr::Mov(MovArgs::ToMem(
+                MemRef {
+                    reg: Reg::R8,
+                    offset: 0,
+                },
+                Reg32::Reg(Reg::Rax),
+            )));
+        }
+        Prim::Length => {
+            immexp_instrs(&exps[0], env, alloc, instr);
+            instr.append(&mut check_array(&exps[0], types, ann));
+            instr.extend(vec![
+                Instr::Mov(MovArgs::ToReg(
+                    Reg::Rax,
+                    Arg64::Mem(MemRef {
+                        reg: Reg::Rax,
+                        offset: 8 - ARRAY_TAG as i32,
+                    }),
+                )),
+                Instr::Shl(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(1))),
+            ]);
+        }
+        _ => panic!("array prim here"),
+    }
 }
 
-pub fn binop(
-    instr1: &Vec<Instr>,
-    instr2: &Vec<Instr>,
+/* The bytes of a new string are copied by the runtime, after the words
+ * it takes are reserved. Reserving may move the operands, so they are
+ * read again for the copy. The runtime returns the string and the new
+ * end of the heap.
+ */
+fn string_prim(
+    op: &Prim,
+    exps: &Vec<ImmExp>,
+    ann: &u32,
+    env: &Vec<String>,
+    instr: &mut Vec<Instr>,
+    ctx: &Ctx,
+) {
+    let Ctx { space, types, alloc, .. } = *ctx;
+    let saved = runtime_saved(alloc.saved_at(ann));
+    let make = match *op {
+        Prim::Len => {
+            immexp_instrs(&exps[0], env, alloc, instr);
+            instr.append(&mut check_string(&exps[0], types, ann));
+            instr.extend(vec![
+                Instr::Mov(MovArgs::ToReg(
+                    Reg::Rax,
+                    Arg64::Mem(MemRef {
+                        reg: Reg::Rax,
+                        offset: -(STRING_TAG as i32),
+                    }),
+                )),
+                Instr::Sub(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(1))),
+            ]);
+            return;
+        }
+        Prim::Concat => {
+            immexp_instrs(&exps[0], env, alloc, instr);
+            instr.append(&mut check_string(&exps[0], types, ann));
+            instr.push(Instr::Mov(MovArgs::ToReg(
+                Reg::R8,
+                Arg64::Mem(MemRef {
+                    reg: Reg::Rax,
+                    offset: -(STRING_TAG as i32),
+                }),
+            )));
+            immexp_instrs(&exps[1], env, alloc, instr);
+            instr.append(&mut check_string(&exps[1], types, ann));
+            instr.extend(vec![
+                Instr::Mov(MovArgs::ToReg(
+                    Reg::Rax,
+                    Arg64::Mem(MemRef {
+                        reg: Reg::Rax,
+                        offset: -(STRING_TAG as i32),
+                    }),
+                )),
+                Instr::Shr(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(1))),
+                Instr::Shr(BinArgs::ToReg(Reg::R8, Arg32::Unsigned(1))),
+                Instr::Add(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::R8))),
+            ]);
+            "snake_string_concat"
+        }
+        Prim::Substr => {
+            immexp_instrs(&exps[0], env, alloc, instr);
+            instr.append(&mut check_string(&exps[0], types, ann));
+            instr.push(Instr::Mov(MovArgs::ToReg(
+                Reg::R8,
+                Arg64::Mem(MemRef {
+                    reg: Reg::Rax,
+                    offset: -(STRING_TAG as i32),
+                }),
+            )));
+            instr.push(Instr::Shr(BinArgs::ToReg(Reg::R8, Arg32::Unsigned(1))));
+            // 0 <= start <= end <= length, reporting start if it's out
+            // of bounds and end otherwise. start is kept in rdx.
+            for (i, e) in exps.iter().enumerate().skip(1) {
+                let out_of_bounds = format!("substr_out_of_bounds_{}_{}", i, ann);
+                let in_bounds = format!("substr_in_bounds_{}_{}", i, ann);
+                immexp_instrs(e, env, alloc, instr);
+                if proven_imm(e, types) != Some(Proven::Int) {
+                    instr.extend(vec![
+                        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_arg(INDEX_ERROR, ann))),
+                        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rax))),
+                        Instr::Test(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(1))),
+                        Instr::Jnz("snake_fail".to_string()),
+                    ]);
+                }
+                let lower = if i == 1 { Arg32::Signed(0) } else { Arg32::Reg(Reg::Rdx) };
+                instr.extend(vec![
+                    Instr::Sar(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(1))),
+                    Instr::Cmp(BinArgs::ToReg(Reg::Rax, lower)),
+                    Instr::Jl(out_of_bounds.clone()),
+                    Instr::Cmp(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::R8))),
+                    Instr::Jle(in_bounds.clone()),
+                    Instr::Label(out_of_bounds),
+                    Instr::Shl(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(1))),
+                    Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rax))),
+                    Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_arg(BOUNDS_ERROR, ann))),
+                    Instr::Jmp("snake_fail".to_string()),
+                    Instr::Label(in_bounds),
+                ]);
+                if i == 1 {
+                    instr.push(Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Reg(Reg::Rax))));
+                }
+            }
+            // the length is end - start
+            instr.push(Instr::Sub(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::Rdx))));
+            "snake_string_substr"
+        }
+        Prim::ToString => {
+            // the length of the text of a value is up to the runtime
+            immexp_instrs(&exps[0], env, alloc, instr);
+            instr.append(&mut save_regs(&saved, space));
+            instr.extend(vec![
+                Instr::Mov(MovArgs::ToReg(Reg::Rdi, Arg64::Reg(Reg::Rax))),
+                Instr::Sub(BinArgs::ToReg(Reg::Rsp, Arg32::Signed(8 * space))),
+                Instr::Call("snake_tostring_len".to_string()),
+                Instr::Add(BinArgs::ToReg(Reg::Rsp, Arg32::Signed(8 * space))),
+            ]);
+            instr.append(&mut restore_regs(&saved, space));
+            "snake_tostring"
+        }
+        _ => panic!("string prim here"),
+    };
+
+    // rax holds the length of the new string in bytes
+    instr.extend(vec![
+        Instr::Add(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(7))),
+        Instr::Shr(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(3))),
+        Instr::Add(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(1))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Reg(Reg::Rax))),
+    ]);
+    instr.append(&mut reserve_reg(Reg::Rdx, ann, space, alloc.saved_at(ann)));
+
+    instr.append(&mut save_regs(&saved, space));
+    let arg_regs = [Reg::Rdi, Reg::Rsi, Reg::Rdx, Reg::Rcx];
+    for (e, reg) in exps.iter().zip(arg_regs.iter()) {
+        immexp_instrs(e, env, alloc, instr);
+        instr.push(Instr::Mov(MovArgs::ToReg(*reg, Arg64::Reg(Reg::Rax))));
+    }
+    instr.push(Instr::Mov(MovArgs::ToReg(arg_regs[exps.len()], Arg64::Reg(Reg::R15))));
+    if *op == Prim::Substr {
+        // the runtime reports an index inside a character itself
+        instr.extend(vec![
+            Instr::Mov(MovArgs::ToReg(Reg::R8, error_arg(BOUNDS_ERROR, ann))),
+            Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Reg(Reg::Rbp))),
+        ]);
+    }
+    instr.extend(vec![
+        Instr::Sub(BinArgs::ToReg(Reg::Rsp, Arg32::Signed(8 * space))),
+        Instr::Call(make.to_string()),
+        Instr::Add(BinArgs::ToReg(Reg::Rsp, Arg32::Signed(8 * space))),
+        Instr::Mov(MovArgs::ToReg(Reg::R15, Arg64::Reg(Reg::Rdx))),
+    ]);
+    instr.append(&mut restore_regs(&saved, space));
+}
+
+fn prim_instrs(
+    op: &Prim,
+    exps: &Vec<ImmExp>,
+    ann: &u32,
+    env: &Vec<String>,
+    instr: &mut Vec<Instr>,
+    ctx: &Ctx,
+) {
+    let Ctx { space, alloc, .. } = *ctx;
+    match *op {
+        Prim::Add1
+        | Prim::Sub1
+        | Prim::Add
+        | Prim::Sub
+        | Prim::Mul
+        | Prim::Div
+        | Prim::FloorDiv
+        | Prim::Cos
+        | Prim::Sqrt => arith_prim(op, exps, ann, env, instr, ctx),
+        Prim::Lt | Prim::Gt | Prim::Le | Prim::Ge => {
+            comp_prim(op, exps, ann, env, instr, ctx)
+        }
+        Prim::Eq | Prim::Neq => eq_prim(op, exps, ann, env, instr, ctx),
+        Prim::And | Prim::Or | Prim::Not => logic_prim(op, exps, ann, env, instr, ctx),
+        Prim::Print | Prim::IsBool | Prim::IsNum | Prim::IsFloat | Prim::IsTuple => {
+            other_prim(op, exps, env, instr, ann, ctx)
+        }
+        Prim::MakeTuple | Prim::GetIndex => {
+            tuple_prim(op, exps, ann, env, instr, ctx)
+        }
+        Prim::Len | Prim::Concat | Prim::Substr | Prim::ToString => {
+            string_prim(op, exps, ann, env, instr, ctx)
+        }
+        Prim::MakeArray | Prim::SetIndex | Prim::Length => {
+            array_prim(op, exps, ann, env, instr, ctx)
+        }
+        Prim::ClosureEnv => {
+            immexp_instrs(&exps[0], env, alloc, instr);
+            instr.push(Instr::Sub(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(CLOSURE_TAG as u32))));
+            instr.push(Instr::Mov(MovArgs::ToReg(Reg::R8, Arg64::Reg(Reg::Rax))));
+            immexp_instrs(&exps[1], env, alloc, instr);
+            instr.extend(vec![
+                Instr::Shl(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(2))),
+                Instr::Add(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::R8))),
+                Instr::Mov(MovArgs::ToReg(
+                    Reg::Rax,
+                    Arg64::Mem(MemRef {
+                        reg: Reg::Rax,
+                        offset: 24,
+                    }),
+                )),
+            ]);
+        }
+        Prim::MakeVariant => {
+            instr.append(&mut reserve(exps.len() as i32 + 1, ann, space, alloc.saved_at(ann)));
+            // the constructor, then the number of fields
+            for (i, e) in exps.iter().enumerate().skip(1) {
+                immexp_instrs(e, env, alloc, instr);
+                instr.push(Instr::Mov(MovArgs::ToMem(
+                    MemRef {
+                        reg: Reg::R15,
+                        offset: 8 * (i as i32 + 1),
+                    },
+                    Reg32::Reg(Reg::Rax),
+                )));
+            }
+            instr.push(Instr::Mov(MovArgs::ToMem(
+                MemRef {
+                    reg: Reg::R15,
+                    offset: 8,
+                },
+                Reg32::Imm(exps.len() as i32 - 1),
+            )));
+            immexp_instrs(&exps[0], env, alloc, instr);
+            instr.push(Instr::Mov(MovArgs::ToMem(
+                MemRef {
+                    reg: Reg::R15,
+                    offset: 0,
+                },
+                Reg32::Reg(Reg::Rax),
+            )));
+            instr.push(Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Reg(Reg::R15))));
+            instr.push(Instr::Add(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(VARIANT_TAG as u32))));
+            instr.push(Instr::Add(BinArgs::ToReg(
+                Reg::R15,
+                Arg32::Signed(8 * (exps.len() as i32 + 1)),
+            )));
+        }
+        Prim::IsVariant => {
+            let done = format!("isvariant_done_{}", ann);
+            immexp_instrs(&exps[0], env, alloc, instr);
+            instr.push(Instr::Mov(MovArgs::ToReg(Reg::R8, Arg64::Reg(Reg::Rax))));
+            immexp_instrs(&exps[1], env, alloc, instr);
+            instr.push(Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Reg(Reg::Rax))));
+            instr.extend(vec![
+                Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Unsigned(SNAKE_FALSE))),
+                Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Reg(Reg::R8))),
+                Instr::Shl(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(1))),
+                Instr::Cmp(BinArgs::ToReg(Reg::R9, Arg32::Signed(BOOL_SHIFTED))),
+                Instr::Je(done.clone()),
+                Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Unsigned(TAG_MASK))),
+                Instr::And(BinArgs::ToReg(Reg::R9, Arg32::Reg(Reg::R8))),
+                Instr::Cmp(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(VARIANT_TAG as u32))),
+                Instr::Jne(done.clone()),
+                Instr::Cmp(BinArgs::ToReg(
+                    Reg::Rdx,
+                    Arg32::Mem(MemRef {
+                        reg: Reg::R8,
+                        offset: -(VARIANT_TAG as i32),
+                    }),
+                )),
+                Instr::Jne(done.clone()),
+                Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Unsigned(SNAKE_TRUE))),
+                Instr::Label(done),
+            ]);
+        }
+        // only reached once IsVariant has found the constructor, so
+        // the field is there
+        Prim::VariantField => {
+            immexp_instrs(&exps[0], env, alloc, instr);
+            instr.push(Instr::Mov(MovArgs::ToReg(Reg::R8, Arg64::Reg(Reg::Rax))));
+            immexp_instrs(&exps[1], env, alloc, instr);
+            instr.extend(vec![
+                Instr::Shl(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(2))),
+                Instr::Add(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::R8))),
+                Instr::Mov(MovArgs::ToReg(
+                    Reg::Rax,
+                    Arg64::Mem(MemRef {
+                        reg: Reg::Rax,
+                        offset: 16 - VARIANT_TAG as i32,
+                    }),
+                )),
+            ]);
+        }
+        Prim::MatchFail => {
+            immexp_instrs(&exps[0], env, alloc, instr);
+            instr.extend(vec![
+                Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rax))),
+                Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_arg(MATCH_ERROR, ann))),
+                Instr::Jmp("snake_fail".to_string()),
+            ]);
+        }
+        // boxes are tuples of one element that only the compiler makes,
+        // so they need no checks
+        Prim::Unbox => {
+            immexp_instrs(&exps[0], env, alloc, instr);
+            instr.push(Instr::Mov(MovArgs::ToReg(
+                Reg::Rax,
+                Arg64::Mem(MemRef {
+                    reg: Reg::Rax,
+                    offset: 8 - TUPLE_TAG as i32,
+                }),
+            )));
+        }
+        Prim::SetBox => {
+            immexp_instrs(&exps[0], env, alloc, instr);
+            instr.push(Instr::Mov(MovArgs::ToReg(Reg::R8, Arg64::Reg(Reg::Rax))));
+            immexp_instrs(&exps[1], env, alloc, instr);
+            instr.push(Instr::Mov(MovArgs::ToMem(
+                MemRef {
+                    reg: Reg::R8,
+                    offset: 8 - TUPLE_TAG as i32,
+                },
+                Reg32::Reg(Reg::Rax),
+            )));
+        }
+    }
+}
+
+pub fn check_division_zero_num(reg: Reg, ann: &u32) -> Vec<Instr> {
+    let mut instr = vec![];
+    instr.push(Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Unsigned(0))));
+    instr.push(Instr::Cmp(BinArgs::ToReg(Reg::R9, Arg32::Reg(reg))));
+    instr.push(Instr::Jne(format!("not_zero_{}", ann)));
+    instr.push(Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_arg(DIVISION_ERROR, ann))));
+    instr.push(Instr::Jmp("snake_fail".to_string()));
+    instr.push(Instr::Label(format!("not_zero_{}", ann)));
+    instr
+}
+
+pub fn check_sqrt_num(reg: Reg, ann: &u32) -> Vec<Instr> {
+    let mut instr = vec![];
+    instr.push(Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Unsigned(0))));
+    instr.push(Instr::Cmp(BinArgs::ToReg(Reg::R9, Arg32::Reg(reg))));
+    instr.push(Instr::Jle(format!("not_lt_zero_{}", ann)));
+    instr.push(Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_arg(SQRT_ERROR, ann))));
+    instr.push(Instr::Jmp("snake_fail".to_string()));
+    instr.push(Instr::Label(format!("not_lt_zero_{}", ann)));
+    instr
+}
+
+// Checks the divisor in xmm1
+pub fn check_division_zero_float(ann: &u32) -> Vec<Instr> {
+    let mut instr = ld_constfloat(0.0, Reg::Xmm2);
+    instr.extend(vec![
+        Instr::Ucomisd(Reg::Xmm1, Reg::Xmm2),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_arg(DIVISION_ERROR, ann))),
+        Instr::Je("snake_fail".to_string()),
+    ]);
+    instr
+}
+
+pub fn check_sqrt_float(ann: &u32) -> Vec<Instr> {
+    let mut instr = ld_constfloat(0.0, Reg::Xmm1);
+    instr.extend(vec![
+        Instr::Ucomisd(Reg::Xmm0, Reg::Xmm1),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_arg(SQRT_ERROR, ann))),
+        Instr::Jb("snake_fail".to_string()),
+    ]);
+    instr
+}
+
+pub fn check_overflow(ann: &u32) -> Vec<Instr> {
+    vec![
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_arg(OVERFLOW_ERROR, ann))),
+        Instr::Jo("snake_fail".to_string()),
+    ]
+}
+
+pub fn unaryop(
+    instr1: &Vec<Instr>,
+    instr2: &Vec<Instr>,
+    ann: &u32,
+    instr: &mut Vec<Instr>,
+) {
+    // check number of float
+    let label = format!("when_float_{}", ann);
+    let done = format!("done_{}", ann);
+    instr.append(&mut if_float(Reg::Rax, &label));
+
+    // when number
+    instr.append(&mut instr1.clone());
+
+    instr.push(Instr::Jmp(done.clone()));
+
+    //when float
+    instr.push(Instr::Label(label));
+    instr.append(&mut ld_float_from_reg(Reg::Rax, Reg::Xmm0));
+    instr.append(&mut instr2.clone());
+    instr.append(&mut check_float_overflow(ann));
+    instr.append(&mut st_float_to_reg(Reg::Rax));
+
+    // done
+    instr.push(Instr::Label(done));
+}
+
+// The float cases of instr2 find the operands in xmm0 and xmm1
+pub fn binop(
+    instr1: &Vec<Instr>,
+    instr2: &Vec<Instr>,
     ann: &u32,
-    offset: i32,
     instr: &mut Vec<Instr>,
 ) {
     // check number or float
@@ -625,20 +1378,20 @@ pub fn binop(
     instr.append(&mut if_float(Reg::R8, &both_floats_label));
 
     // when first is float and second is not
-    instr.append(&mut ld_float_from_reg(Reg::Rax, offset));
-    instr.append(&mut ld_num_from_reg(Reg::R8, offset));
+    instr.append(&mut ld_float_from_reg(Reg::Rax, Reg::Xmm0));
+    instr.append(&mut ld_num_from_reg(Reg::R8, Reg::Xmm1));
     instr.push(Instr::Jmp(handle_floats.clone()));
 
     // when both are floats
     instr.push(Instr::Label(both_floats_label));
-    instr.append(&mut ld_float_from_reg(Reg::Rax, offset));
-    instr.append(&mut ld_float_from_reg(Reg::R8, offset));
+    instr.append(&mut ld_float_from_reg(Reg::Rax, Reg::Xmm0));
+    instr.append(&mut ld_float_from_reg(Reg::R8, Reg::Xmm1));
     instr.push(Instr::Jmp(handle_floats.clone()));
 
     // when first is not but second is float
     instr.push(Instr::Label(second_float_label));
-    instr.append(&mut ld_num_from_reg(Reg::Rax, offset));
-    instr.append(&mut ld_float_from_reg(Reg::R8, offset));
+    instr.append(&mut ld_num_from_reg(Reg::Rax, Reg::Xmm0));
+    instr.append(&mut ld_float_from_reg(Reg::R8, Reg::Xmm1));
 
     // handle_floats
     instr.push(Instr::Label(handle_floats.clone()));
@@ -648,202 +1401,389 @@ pub fn binop(
     instr.push(Instr::Label(done));
 }
 
-fn check_bool(reg: Reg, error_code: ErrorCode, is_or_not: bool) -> Vec<Instr> {
+fn check_bool(reg: Reg, error_code: ErrorCode, is_or_not: bool, ann: &u32) -> Vec<Instr> {
     vec![
-        // only check 2 bits, 11 then bool
-        Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Unsigned(3))),
-        Instr::And(BinArgs::ToReg(Reg::R9, Arg32::Reg(reg))),
-        Instr::Cmp(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(3))),
-        Instr::Mov(MovArgs::ToReg(Reg::Rdi, Arg64::Unsigned(error_code))),
+        // variants share the tag of booleans, so look at the value
+        Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Reg(reg))),
+        Instr::Shl(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(1))),
+        Instr::Cmp(BinArgs::ToReg(Reg::R9, Arg32::Signed(BOOL_SHIFTED))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_arg(error_code, ann))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(reg))),
         if is_or_not {
-            Instr::Jne("snake_error".to_string())
+            Instr::Jne("snake_fail".to_string())
         } else {
-            Instr::Je("snake_error".to_string())
+            Instr::Je("snake_fail".to_string())
         },
     ]
 }
 
-fn if_float(reg: Reg, label: &String) -> Vec<Instr> {
+fn check_num_or_float(reg: Reg, error_code: ErrorCode, ok: String, ann: &u32) -> Vec<Instr> {
     vec![
-        // check 1 bit, 0 then num and 1 float
+        // 0 in the last bit then num
         Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Unsigned(1))),
         Instr::And(BinArgs::ToReg(Reg::R9, Arg32::Reg(reg))),
-        Instr::Cmp(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(1))),
-        Instr::Je(label.clone()),
+        Instr::Cmp(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(0))),
+        Instr::Je(ok.clone()),
+        // 001 in the last 3 bits then float
+        Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Unsigned(TAG_MASK))),
+        Instr::And(BinArgs::ToReg(Reg::R9, Arg32::Reg(reg))),
+        Instr::Cmp(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(FLOAT_TAG as u32))),
+        Instr::Je(ok.clone()),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_arg(error_code, ann))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(reg))),
+        Instr::Jmp("snake_fail".to_string()),
+        Instr::Label(ok),
     ]
 }
 
-fn ld_float_from_reg(reg: Reg, offset: i32) -> Vec<Instr> {
-    vec![
-        Instr::Sub(BinArgs::ToReg(reg, Arg32::Unsigned(1))),
-        Instr::Mov(MovArgs::ToMem(
-            MemRef {
-                reg: Reg::Rsp,
-                offset: offset,
-            },
-            Reg32::Reg(reg),
+// Makes sure there is room for `words` more words on the heap, running
+// the garbage collector if there isn't. The stack is scanned starting
+// from the bottom of the current frame, so the live registers are saved
+// in it first.
+fn reserve(words: i32, ann: &u32, space: i32, saved: &[Reg]) -> Vec<Instr> {
+    let mut instr = vec![Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Signed(words as i64)))];
+    instr.append(&mut reserve_reg(Reg::Rdx, ann, space, saved));
+    instr
+}
+
+// Like reserve, for a number of words only known when the program runs.
+// Too many words to add to r15 go straight to the collector, which
+// reports that they don't fit.
+fn reserve_reg(words: Reg, ann: &u32, space: i32, saved: &[Reg]) -> Vec<Instr> {
+    let ok = format!("alloc_ok_{}", ann);
+    let collect = format!("alloc_gc_{}", ann);
+    let mut instr = vec![
+        Instr::Cmp(BinArgs::ToReg(words, Arg32::Signed(i32::MAX))),
+        Instr::Ja(collect.clone()),
+        Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Reg(words))),
+        Instr::Shl(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(3))),
+        Instr::Add(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::R15))),
+        Instr::Lea(Reg::R9, "HEAP_END".to_string()),
+        Instr::Cmp(BinArgs::ToReg(
+            Reg::Rax,
+            Arg32::Mem(MemRef {
+                reg: Reg::R9,
+                offset: 0,
+            }),
         )),
-        Instr::Fld(FloatMem::RegMem(MemRef {
-            reg: Reg::Rsp,
-            offset: offset,
-        })),
-    ]
+        Instr::Jle(ok.clone()),
+        Instr::Label(collect),
+    ];
+    instr.append(&mut save_regs(saved, space));
+    instr.extend(vec![
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, Arg64::Reg(words))),
+        Instr::Sub(BinArgs::ToReg(Reg::Rsp, Arg32::Signed(8 * space))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rsp))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Signed(space as i64))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rcx, Arg64::Reg(Reg::Rbp))),
+        Instr::Call("snake_try_gc".to_string()),
+        Instr::Add(BinArgs::ToReg(Reg::Rsp, Arg32::Signed(8 * space))),
+        Instr::Mov(MovArgs::ToReg(Reg::R15, Arg64::Reg(Reg::Rax))),
+    ]);
+    instr.append(&mut restore_regs(saved, space));
+    instr.push(Instr::Label(ok));
+    instr
+}
+
+// Saves the registers holding live variables in their slots at the end
+// of the frame before a call
+fn save_regs(regs: &[Reg], space: i32) -> Vec<Instr> {
+    regs.iter()
+        .map(|reg| {
+            Instr::Mov(MovArgs::ToMem(
+                MemRef {
+                    reg: Reg::Rsp,
+                    offset: -8 * (save_slot(*reg, space) + 1),
+                },
+                Reg32::Reg(*reg),
+            ))
+        })
+        .collect()
+}
+
+fn restore_regs(regs: &[Reg], space: i32) -> Vec<Instr> {
+    regs.iter()
+        .map(|reg| {
+            Instr::Mov(MovArgs::ToReg(
+                *reg,
+                Arg64::Mem(MemRef {
+                    reg: Reg::Rsp,
+                    offset: -8 * (save_slot(*reg, space) + 1),
+                }),
+            ))
+        })
+        .collect()
+}
+
+// The runtime functions keep the callee-saved registers intact
+fn runtime_saved(regs: &[Reg]) -> Vec<Reg> {
+    regs.iter().filter(|r| CALLER_SAVED.contains(r)).copied().collect()
 }
 
-fn ld_num_from_reg(reg: Reg, offset: i32) -> Vec<Instr> {
+// Clears the stack slots of a frame that are not parameters, so that
+// the garbage collector never sees stale values from earlier frames.
+/* rbp points at the return address of the running function, and the
+ * two slots above it, the last ones of its caller's frame, link its
+ * frame into a chain that ends at main:
+ *
+ *   [rbp]       return address
+ *   [rbp + 8]   the caller's rbp, 0 for main
+ *   [rbp + 16]  the label of the function
+ *
+ * The caller stores its rbp in link_frame and the function its label
+ * on entry, so a tail call relabels the frame it reuses. snake_error
+ * walks the chain to print a backtrace.
+ */
+pub fn enter_frame(label: &str) -> Vec<Instr> {
     vec![
-        Instr::Shr(BinArgs::ToReg(reg, Arg32::Unsigned(1))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rbp, Arg64::Reg(Reg::Rsp))),
+        Instr::Lea(Reg::Rax, label.to_string()),
         Instr::Mov(MovArgs::ToMem(
             MemRef {
-                reg: Reg::Rsp,
-                offset: offset,
+                reg: Reg::Rbp,
+                offset: 16,
             },
-            Reg32::Reg(reg),
+            Reg32::Reg(Reg::Rax),
         )),
-        Instr::Fild(FloatMem::RegMem(MemRef {
-            reg: Reg::Rsp,
-            offset: offset,
-        })),
     ]
 }
 
-fn st_constfloat(reg: Reg, f: f32) -> Vec<Instr> {
-    vec![
-        Instr::Mov(MovArgs::ToReg(reg, Arg64::Unsigned((f as f64).to_bits()))),
-        Instr::Add(BinArgs::ToReg(reg, Arg32::Unsigned(1))),
-    ]
+// Saves rbp in the last slot of the frame before a call
+fn link_frame(space: i32) -> Instr {
+    Instr::Mov(MovArgs::ToMem(
+        MemRef {
+            reg: Reg::Rsp,
+            offset: -8 * space,
+        },
+        Reg32::Reg(Reg::Rbp),
+    ))
+}
+
+// Points rbp back at the frame after a call
+fn unlink_frame(space: i32) -> Instr {
+    Instr::Mov(MovArgs::ToReg(
+        Reg::Rbp,
+        Arg64::Mem(MemRef {
+            reg: Reg::Rsp,
+            offset: -8 * space,
+        }),
+    ))
+}
+
+pub fn clear_frame(from: usize, space: i32) -> Vec<Instr> {
+    (from as i32..space)
+        .map(|i| {
+            Instr::Mov(MovArgs::ToMem(
+                MemRef {
+                    reg: Reg::Rsp,
+                    offset: -8 * (i + 1),
+                },
+                Reg32::Imm(0),
+            ))
+        })
+        .collect()
+}
+
+// Operands of arithmetic and comparisons that are proven to be numbers
+// or floats need no check
+fn check_operand(e: &ImmExp, types: &ProvenTypes, error_code: ErrorCode, ok: String, ann: &u32) -> Vec<Instr> {
+    match proven_imm(e, types) {
+        Some(t) if t.is_number() => vec![],
+        _ => check_num_or_float(Reg::Rax, error_code, ok, ann),
+    }
 }
 
-fn ld_constfloat(f: f32, offset: i32) -> Vec<Instr> {
+// Checks that the value of e, in rax, is a string: it has the tag of
+// variants, isn't a boolean and its first word is odd
+fn check_string(e: &ImmExp, types: &ProvenTypes, ann: &u32) -> Vec<Instr> {
+    if proven_imm(e, types) == Some(Proven::String) {
+        return vec![];
+    }
     vec![
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_arg(STRING_ERROR, ann))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rax))),
+        Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Reg(Reg::Rax))),
+        Instr::Shl(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(1))),
+        Instr::Cmp(BinArgs::ToReg(Reg::R9, Arg32::Signed(BOOL_SHIFTED))),
+        Instr::Je("snake_fail".to_string()),
+        Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Unsigned(TAG_MASK))),
+        Instr::And(BinArgs::ToReg(Reg::R9, Arg32::Reg(Reg::Rax))),
+        Instr::Cmp(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(STRING_TAG as u32))),
+        Instr::Jne("snake_fail".to_string()),
         Instr::Mov(MovArgs::ToReg(
             Reg::R9,
-            Arg64::Unsigned((f as f64).to_bits()),
-        )),
-        Instr::Mov(MovArgs::ToMem(
-            MemRef {
-                reg: Reg::Rsp,
-                offset: offset,
-            },
-            Reg32::Reg(Reg::R9),
+            Arg64::Mem(MemRef {
+                reg: Reg::Rax,
+                offset: -(STRING_TAG as i32),
+            }),
         )),
-        Instr::Fld(FloatMem::RegMem(MemRef {
-            reg: Reg::Rsp,
-            offset: offset,
-        })),
+        Instr::Test(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(1))),
+        Instr::Jz("snake_fail".to_string()),
     ]
 }
 
-fn st_float_to_reg(reg: Reg, offset: i32) -> Vec<Instr> {
+// Jumps to label unless reg holds an array: it has the tag of variants,
+// isn't a boolean and its first word is the header of arrays
+fn if_not_array(reg: Reg, label: String) -> Vec<Instr> {
     vec![
-        Instr::Fstp(FloatMem::RegMem(MemRef {
-            reg: Reg::Rsp,
-            offset: offset,
-        })),
-        Instr::Mov(MovArgs::ToReg(
-            reg,
-            Arg64::Mem(MemRef {
-                reg: Reg::Rsp,
-                offset: offset,
+        Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Reg(reg))),
+        Instr::Shl(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(1))),
+        Instr::Cmp(BinArgs::ToReg(Reg::R9, Arg32::Signed(BOOL_SHIFTED))),
+        Instr::Je(label.clone()),
+        Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Unsigned(TAG_MASK))),
+        Instr::And(BinArgs::ToReg(Reg::R9, Arg32::Reg(reg))),
+        Instr::Cmp(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(ARRAY_TAG as u32))),
+        Instr::Jne(label.clone()),
+        Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Unsigned(ARRAY_HEADER))),
+        Instr::Cmp(BinArgs::ToReg(
+            Reg::R9,
+            Arg32::Mem(MemRef {
+                reg,
+                offset: -(ARRAY_TAG as i32),
             }),
         )),
-        // make sure the last two bits are 01
-        Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Unsigned(FLOAT_MASK))),
-        Instr::Add(BinArgs::ToReg(reg, Arg32::Unsigned(0x10000000))),
-        Instr::And(BinArgs::ToReg(reg, Arg32::Reg(Reg::R9))),
-        Instr::Add(BinArgs::ToReg(reg, Arg32::Unsigned(1))),
+        Instr::Jne(label),
     ]
 }
 
-fn st_floornum_to_reg(reg: Reg, offset: i32) -> Vec<Instr> {
-    let mut instr = ld_constfloat(0.5, offset);
-    instr.append(&mut vec![
-        Instr::Fsubp(FloatArg::Blank),
-        Instr::Fistp(FloatMem::RegMem(MemRef {
-            reg: Reg::Rsp,
-            offset: offset,
-        })),
+// Checks that the value of e, in rax, is an array
+fn check_array(e: &ImmExp, types: &ProvenTypes, ann: &u32) -> Vec<Instr> {
+    if proven_imm(e, types) == Some(Proven::Array) {
+        return vec![];
+    }
+    let mut instr = vec![
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_arg(ARRAY_ERROR, ann))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rax))),
+    ];
+    instr.append(&mut if_not_array(Reg::Rax, "snake_fail".to_string()));
+    instr
+}
+
+fn if_float(reg: Reg, label: &String) -> Vec<Instr> {
+    vec![
+        // check 1 bit, 0 then num and 1 float
+        Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Unsigned(1))),
+        Instr::And(BinArgs::ToReg(Reg::R9, Arg32::Reg(reg))),
+        Instr::Cmp(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(1))),
+        Instr::Je(label.clone()),
+    ]
+}
+
+fn ld_float_from_reg(reg: Reg, xmm: Reg) -> Vec<Instr> {
+    vec![
         Instr::Mov(MovArgs::ToReg(
             reg,
             Arg64::Mem(MemRef {
-                reg: Reg::Rsp,
-                offset: offset,
+                reg,
+                offset: 8 - FLOAT_TAG as i32,
             }),
         )),
-        // make sure the last bit is 0
-        Instr::Shl(BinArgs::ToReg(reg, Arg32::Unsigned(1))),
-    ]);
-    instr
+        Instr::Movq(xmm, reg),
+    ]
+}
+
+fn ld_num_from_reg(reg: Reg, xmm: Reg) -> Vec<Instr> {
+    vec![
+        Instr::Sar(BinArgs::ToReg(reg, Arg32::Unsigned(1))),
+        Instr::Cvtsi2sd(xmm, reg),
+    ]
+}
+
+fn ld_constfloat(f: f64, xmm: Reg) -> Vec<Instr> {
+    vec![
+        Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Unsigned(f.to_bits()))),
+        Instr::Movq(xmm, Reg::R9),
+    ]
+}
+
+// Boxes the result in xmm0 as a float, in the room made by reserve
+fn st_float_to_reg(reg: Reg) -> Vec<Instr> {
+    vec![
+        Instr::Mov(MovArgs::ToMem(
+            MemRef {
+                reg: Reg::R15,
+                offset: 0,
+            },
+            Reg32::Imm(0),
+        )),
+        Instr::Movq(Reg::R9, Reg::Xmm0),
+        Instr::Mov(MovArgs::ToMem(
+            MemRef {
+                reg: Reg::R15,
+                offset: 8,
+            },
+            Reg32::Reg(Reg::R9),
+        )),
+        Instr::Mov(MovArgs::ToReg(reg, Arg64::Reg(Reg::R15))),
+        Instr::Add(BinArgs::ToReg(reg, Arg32::Unsigned(FLOAT_TAG as u32))),
+        Instr::Add(BinArgs::ToReg(Reg::R15, Arg32::Signed(8 * FLOAT_WORDS))),
+    ]
 }
 
-fn cmp_floats(
-    larger: (&Vec<Instr>, String),
-    smaller: (&Vec<Instr>, String),
-    equal: (&Vec<Instr>, String),
-    done: String,
-    pop: u32,
-) -> Vec<Instr> {
+// Divides the number in rax by the one in r8, rounding down. idiv
+// rounds towards zero, which is one too high when there is a remainder
+// and the operands have different signs. The quotient only overflows
+// for the smallest number divided by -1.
+fn floor_div_nums(done: String, ann: &u32) -> Vec<Instr> {
     let mut instr = vec![
-        if pop == 2 {
-            Instr::Fcompp(FloatArg::Blank)
-        } else if pop == 1 {
-            Instr::Fcomp(FloatArg::Blank)
-        } else if pop == 0 {
-            Instr::Fcom(FloatArg::Blank)
-        } else {
-            panic!("pop wrong")
-        },
-        Instr::Fstsw(FloatArg::Reg(Reg::Ax)),
-        Instr::And(BinArgs::ToReg(Reg::Ax, Arg32::Unsigned(0x4100))),
-        Instr::Cmp(BinArgs::ToReg(Reg::Ax, Arg32::Unsigned(0x4000))),
-        Instr::Je(equal.1.clone()),
-        Instr::Cmp(BinArgs::ToReg(Reg::Ax, Arg32::Unsigned(0x0100))),
-        Instr::Je(smaller.1.clone()),
+        Instr::Sar(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(1))),
+        Instr::Sar(BinArgs::ToReg(Reg::R8, Arg32::Unsigned(1))),
+        Instr::Cqo,
+        Instr::IDiv(Reg::R8),
+        Instr::Test(BinArgs::ToReg(Reg::Rdx, Arg32::Reg(Reg::Rdx))),
+        Instr::Jz(done.clone()),
+        // the remainder has the sign of rax
+        Instr::Xor(BinArgs::ToReg(Reg::Rdx, Arg32::Reg(Reg::R8))),
+        Instr::Jns(done.clone()),
+        Instr::Sub(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(1))),
+        Instr::Label(done),
+        Instr::Add(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::Rax))),
     ];
-    instr.append(&mut larger.0.clone());
-    instr.push(Instr::Jmp(done.clone()));
-    instr.push(Instr::Label(equal.1.clone()));
-    instr.append(&mut equal.0.clone());
-    instr.push(Instr::Jmp(done.clone()));
-    instr.push(Instr::Label(smaller.1.clone()));
-    instr.append(&mut smaller.0.clone());
-    instr.push(Instr::Label(done.clone()));
+    instr.append(&mut check_overflow(ann));
     instr
 }
 
-fn check_float_overflow(ann: &u32, offset: i32) -> Vec<Instr> {
-    let mut instr = vec![];
-
-    instr.append(&mut ld_constfloat(f32::MAX, offset));
-    let smaller = vec![
-        Instr::Mov(MovArgs::ToReg(Reg::Rdi, Arg64::Unsigned(OVERFLOW_ERROR))),
-        Instr::Jmp("snake_error".to_string()),
-    ];
-    let larger = vec![];
-    let equal = vec![];
-    instr.append(&mut cmp_floats(
-        (&larger, format!("checkfloatover1_larger_{}", ann)),
-        (&smaller, format!("checkfloatover1_smaller_{}", ann)),
-        (&equal, format!("checkfloatover1_equal_{}", ann)),
-        format!("overflow1_done_{}", ann),
-        1,
-    ));
-
-    instr.append(&mut ld_constfloat(f32::MIN, offset));
-    let larger = vec![
-        Instr::Mov(MovArgs::ToReg(Reg::Rdi, Arg64::Unsigned(OVERFLOW_ERROR))),
-        Instr::Jo("snake_error".to_string()),
-    ];
-    let smaller = vec![];
-    let equal = vec![];
-    instr.append(&mut cmp_floats(
-        (&larger, format!("checkfloatover2_larger_{}", ann)),
-        (&smaller, format!("checkfloatover2_smaller_{}", ann)),
-        (&equal, format!("checkfloatover2_equal_{}", ann)),
-        format!("overflow2_done_{}", ann),
-        1,
-    ));
+// Stores the result in xmm0 rounded down as a number, which it has to
+// fit into
+fn st_floornum_to_reg(reg: Reg, done: String, ann: &u32) -> Vec<Instr> {
+    let mut instr = ld_constfloat(-(2f64.powi(62)), Reg::Xmm1);
+    instr.extend(vec![
+        Instr::Ucomisd(Reg::Xmm0, Reg::Xmm1),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_arg(OVERFLOW_ERROR, ann))),
+        Instr::Jb("snake_fail".to_string()),
+    ]);
+    instr.append(&mut ld_constfloat(2f64.powi(62), Reg::Xmm1));
+    instr.extend(vec![
+        Instr::Ucomisd(Reg::Xmm0, Reg::Xmm1),
+        Instr::Jae("snake_fail".to_string()),
+        // cvttsd2si rounds towards zero, which is one too high for
+        // negative results with a fractional part
+        Instr::Cvttsd2si(reg, Reg::Xmm0),
+        Instr::Cvtsi2sd(Reg::Xmm1, reg),
+        Instr::Ucomisd(Reg::Xmm1, Reg::Xmm0),
+        Instr::Jbe(done.clone()),
+        Instr::Sub(BinArgs::ToReg(reg, Arg32::Unsigned(1))),
+        Instr::Label(done),
+        // make sure the last bit is 0
+        Instr::Shl(BinArgs::ToReg(reg, Arg32::Unsigned(1))),
+    ]);
+    instr
+}
 
+// Results that don't fit into a double end up infinite
+fn check_float_overflow(ann: &u32) -> Vec<Instr> {
+    let mut instr = ld_constfloat(f64::MAX, Reg::Xmm1);
+    instr.extend(vec![
+        Instr::Ucomisd(Reg::Xmm0, Reg::Xmm1),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_arg(OVERFLOW_ERROR, ann))),
+        Instr::Ja("snake_fail".to_string()),
+    ]);
+    instr.append(&mut ld_constfloat(f64::MIN, Reg::Xmm1));
+    instr.extend(vec![
+        Instr::Ucomisd(Reg::Xmm0, Reg::Xmm1),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_arg(OVERFLOW_ERROR, ann))),
+        Instr::Jb("snake_fail".to_string()),
+    ]);
     instr
 }
 
@@ -851,52 +1791,21 @@ fn excall_instr(
     fun_name: &String,
     args: &Vec<ImmExp>,
     is_tail: &bool,
+    ann: &u32,
     env: &Vec<String>,
     instr: &mut Vec<Instr>,
-    space: i32,
-    fun_to_env: &HashMap<String, Vec<String>>,
+    ctx: &Ctx,
 ) {
+    let Ctx { space, fun_to_env, alloc, .. } = *ctx;
     if *is_tail {
-        incall_instr(fun_name, args, env, instr, fun_to_env);
+        incall_instr(fun_name, args, env, instr, ctx);
     } else {
         match fun_to_env.get(fun_name) {
             Some(e) => {
-                for i in 0..e.len() {
-                    instr.push(Instr::Mov(MovArgs::ToReg(
-                        Reg::Rax,
-                        Arg64::Mem(MemRef {
-                            reg: Reg::Rsp,
-                            offset: -8 * (env.iter().position(|x| *x == *e[i]).unwrap() as i32 + 1),
-                        }),
-                    )));
-                    instr.push(Instr::Mov(MovArgs::ToMem(
-                        MemRef {
-                            reg: Reg::Rsp,
-                            offset: -8 * (space + 2 + i as i32),
-                        },
-                        Reg32::Reg(Reg::Rax),
-                    )))
-                }
-                for i in 0..args.len() {
-                    let s = match &args[i] {
-                        ImmExp::Var(value) => value,
-                        _ => panic!("wrong in args"),
-                    };
-                    instr.push(Instr::Mov(MovArgs::ToReg(
-                        Reg::Rax,
-                        Arg64::Mem(MemRef {
-                            reg: Reg::Rsp,
-                            offset: -8 * (env.iter().position(|x| *x == *s).unwrap() as i32 + 1),
-                        }),
-                    )));
-                    instr.push(Instr::Mov(MovArgs::ToMem(
-                        MemRef {
-                            reg: Reg::Rsp,
-                            offset: -8 * (space + 2 + i as i32 + e.len() as i32),
-                        },
-                        Reg32::Reg(Reg::Rax),
-                    )))
-                }
+                push_args(e, args, env, instr, ctx);
+                let saved = alloc.saved_at(ann);
+                instr.append(&mut save_regs(saved, space));
+                instr.push(link_frame(space));
                 instr.push(Instr::Sub(BinArgs::ToReg(
                     Reg::Rsp,
                     Arg32::Signed(8 * space),
@@ -906,6 +1815,8 @@ fn excall_instr(
                     Reg::Rsp,
                     Arg32::Signed(8 * space),
                 )));
+                instr.push(unlink_frame(space));
+                instr.append(&mut restore_regs(saved, space));
             }
             None => {
                 panic!("compile_with_env, line 371");
@@ -914,47 +1825,70 @@ fn excall_instr(
     }
 }
 
+// Puts the captured variables followed by the arguments right below the
+// current frame, where they become the first slots of the callee's frame
+fn push_args(
+    captured: &Vec<String>,
+    args: &Vec<ImmExp>,
+    env: &Vec<String>,
+    instr: &mut Vec<Instr>,
+    ctx: &Ctx,
+) {
+    let Ctx { space, alloc, .. } = *ctx;
+    for i in 0..captured.len() {
+        instr.push(Instr::Mov(MovArgs::ToReg(
+            Reg::Rax,
+            var_arg(&captured[i], env, alloc),
+        )));
+        instr.push(Instr::Mov(MovArgs::ToMem(
+            MemRef {
+                reg: Reg::Rsp,
+                offset: -8 * (space + 2 + i as i32),
+            },
+            Reg32::Reg(Reg::Rax),
+        )))
+    }
+    for i in 0..args.len() {
+        let s = match &args[i] {
+            ImmExp::Var(value) => value,
+            _ => panic!("wrong in args"),
+        };
+        instr.push(Instr::Mov(MovArgs::ToReg(Reg::Rax, var_arg(s, env, alloc))));
+        instr.push(Instr::Mov(MovArgs::ToMem(
+            MemRef {
+                reg: Reg::Rsp,
+                offset: -8 * (space + 2 + i as i32 + captured.len() as i32),
+            },
+            Reg32::Reg(Reg::Rax),
+        )))
+    }
+}
+
+// The arguments are staged below the frame first, since some of them
+// may live in the slots they are moved to
 fn incall_instr(
     fun_name: &String,
     args: &Vec<ImmExp>,
     env: &Vec<String>,
     instr: &mut Vec<Instr>,
-    fun_to_env: &HashMap<String, Vec<String>>,
+    ctx: &Ctx,
 ) {
+    let Ctx { space, fun_to_env, .. } = *ctx;
     match fun_to_env.get(fun_name) {
         Some(e) => {
-            for i in 0..e.len() {
-                instr.push(Instr::Mov(MovArgs::ToReg(
-                    Reg::Rax,
-                    Arg64::Mem(MemRef {
-                        reg: Reg::Rsp,
-                        offset: -8 * (env.iter().position(|x| *x == *e[i]).unwrap() as i32 + 1),
-                    }),
-                )));
-                instr.push(Instr::Mov(MovArgs::ToMem(
-                    MemRef {
-                        reg: Reg::Rsp,
-                        offset: -8 * (1 + i as i32),
-                    },
-                    Reg32::Reg(Reg::Rax),
-                )))
-            }
-            for i in 0..args.len() {
-                let s = match &args[i] {
-                    ImmExp::Var(value) => value,
-                    _ => panic!("wrong in args"),
-                };
+            push_args(e, args, env, instr, ctx);
+            for i in 0..(e.len() + args.len()) as i32 {
                 instr.push(Instr::Mov(MovArgs::ToReg(
                     Reg::Rax,
                     Arg64::Mem(MemRef {
                         reg: Reg::Rsp,
-                        offset: -8 * (env.iter().position(|x| *x == *s).unwrap() as i32 + 1),
+                        offset: -8 * (space + 2 + i),
                     }),
                 )));
                 instr.push(Instr::Mov(MovArgs::ToMem(
                     MemRef {
                         reg: Reg::Rsp,
-                        offset: -8 * (e.len() + i + 1) as i32,
+                        offset: -8 * (i + 1),
                     },
                     Reg32::Reg(Reg::Rax),
                 )))
@@ -967,18 +1901,176 @@ fn incall_instr(
     }
 }
 
+// A closure is [arity, code pointer, number of captured values, captured values...]
+fn make_closure_instr(
+    fun_name: &String,
+    arity: usize,
+    captured: &Vec<ImmExp>,
+    ann: &u32,
+    env: &Vec<String>,
+    instr: &mut Vec<Instr>,
+    ctx: &Ctx,
+) {
+    let Ctx { space, alloc, .. } = *ctx;
+    instr.append(&mut reserve(captured.len() as i32 + 3, ann, space, alloc.saved_at(ann)));
+    instr.push(Instr::Mov(MovArgs::ToMem(
+        MemRef {
+            reg: Reg::R15,
+            offset: 0,
+        },
+        Reg32::Imm(arity as i32),
+    )));
+    instr.push(Instr::Lea(Reg::Rax, fun_name.clone()));
+    instr.push(Instr::Mov(MovArgs::ToMem(
+        MemRef {
+            reg: Reg::R15,
+            offset: 8,
+        },
+        Reg32::Reg(Reg::Rax),
+    )));
+    instr.push(Instr::Mov(MovArgs::ToMem(
+        MemRef {
+            reg: Reg::R15,
+            offset: 16,
+        },
+        Reg32::Imm(captured.len() as i32),
+    )));
+    for (i, e) in captured.iter().enumerate() {
+        immexp_instrs(e, &env, alloc, instr);
+        instr.push(Instr::Mov(MovArgs::ToMem(
+            MemRef {
+                reg: Reg::R15,
+                offset: 8 * (i as i32 + 3),
+            },
+            Reg32::Reg(Reg::Rax),
+        )));
+    }
+    instr.push(Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Reg(Reg::R15))));
+    instr.push(Instr::Add(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(CLOSURE_TAG as u32))));
+    instr.push(Instr::Add(BinArgs::ToReg(
+        Reg::R15,
+        Arg32::Signed(8 * (captured.len() as i32 + 3)),
+    )));
+}
+
+// The closure is passed as the first argument, followed by the actual arguments
+fn closure_call_instr(
+    fun: &ImmExp,
+    args: &Vec<ImmExp>,
+    is_tail: &bool,
+    ann: &u32,
+    env: &Vec<String>,
+    instr: &mut Vec<Instr>,
+    ctx: &Ctx,
+) {
+    let Ctx { space, alloc, .. } = *ctx;
+    immexp_instrs(fun, &env, alloc, instr);
+    instr.extend(vec![
+        Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Unsigned(TAG_MASK))),
+        Instr::And(BinArgs::ToReg(Reg::R9, Arg32::Reg(Reg::Rax))),
+        Instr::Cmp(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(CLOSURE_TAG as u32))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_arg(CALL_ERROR, ann))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rax))),
+        Instr::Jne("snake_fail".to_string()),
+        Instr::Mov(MovArgs::ToReg(Reg::R8, Arg64::Reg(Reg::Rax))),
+        Instr::Sub(BinArgs::ToReg(Reg::R8, Arg32::Unsigned(CLOSURE_TAG as u32))),
+        Instr::Cmp(BinArgs::ToMem(
+            MemRef {
+                reg: Reg::R8,
+                offset: 0,
+            },
+            Reg32::Imm(args.len() as i32),
+        )),
+        Instr::Je(format!("arity_ok_{}", ann)),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_arg(ARITY_ERROR, ann))),
+        Instr::Mov(MovArgs::ToReg(
+            Reg::Rsi,
+            Arg64::Mem(MemRef {
+                reg: Reg::R8,
+                offset: 0,
+            }),
+        )),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Unsigned(args.len() as u64))),
+        Instr::Jmp("snake_fail".to_string()),
+        Instr::Label(format!("arity_ok_{}", ann)),
+    ]);
+
+    // put the closure and the arguments right below the current frame
+    instr.push(Instr::Mov(MovArgs::ToMem(
+        MemRef {
+            reg: Reg::Rsp,
+            offset: -8 * (space + 2),
+        },
+        Reg32::Reg(Reg::Rax),
+    )));
+    for (i, arg) in args.iter().enumerate() {
+        immexp_instrs(arg, &env, alloc, instr);
+        instr.push(Instr::Mov(MovArgs::ToMem(
+            MemRef {
+                reg: Reg::Rsp,
+                offset: -8 * (space + 3 + i as i32),
+            },
+            Reg32::Reg(Reg::Rax),
+        )));
+    }
+    instr.push(Instr::Mov(MovArgs::ToReg(
+        Reg::R8,
+        Arg64::Mem(MemRef {
+            reg: Reg::R8,
+            offset: 8,
+        }),
+    )));
+
+    if *is_tail {
+        // reuse the current frame: move them to the top of it and jump
+        for i in 0..(args.len() as i32 + 1) {
+            instr.push(Instr::Mov(MovArgs::ToReg(
+                Reg::Rax,
+                Arg64::Mem(MemRef {
+                    reg: Reg::Rsp,
+                    offset: -8 * (space + 2 + i),
+                }),
+            )));
+            instr.push(Instr::Mov(MovArgs::ToMem(
+                MemRef {
+                    reg: Reg::Rsp,
+                    offset: -8 * (i + 1),
+                },
+                Reg32::Reg(Reg::Rax),
+            )));
+        }
+        instr.push(Instr::JmpReg(Reg::R8));
+    } else {
+        let saved = alloc.saved_at(ann);
+        instr.append(&mut save_regs(saved, space));
+        instr.push(link_frame(space));
+        instr.push(Instr::Sub(BinArgs::ToReg(
+            Reg::Rsp,
+            Arg32::Signed(8 * space),
+        )));
+        instr.push(Instr::CallReg(Reg::R8));
+        instr.push(Instr::Add(BinArgs::ToReg(
+            Reg::Rsp,
+            Arg32::Signed(8 * space),
+        )));
+        instr.push(unlink_frame(space));
+        instr.append(&mut restore_regs(saved, space));
+    }
+}
+
 fn fundefs_instr(
     decls: &Vec<SeqFunDecl<u32>>,
     body: &Box<SeqExp<u32>>,
     env: &Vec<String>,
     instr: &mut Vec<Instr>,
     ann: &u32,
-    space: i32,
-    fun_to_env: &HashMap<String, Vec<String>>,
+    ctx: &Ctx,
 ) {
+    let Ctx { fun_to_env, alloc, .. } = *ctx;
     instr.push(Instr::Jmp(format!("funend_{}", ann)));
     for decl in decls {
         instr.push(Instr::Label(decl.name.clone()));
+        instr.append(&mut enter_frame(&decl.name));
         match fun_to_env.get(&decl.name) {
             Some(value) => {
                 let mut new_env = value.clone();
@@ -987,9 +2079,11 @@ fn fundefs_instr(
                         new_env.push(parameter.clone());
                     }
                 }
-                instr.append(&mut compile_with_env(
-                    &decl.body, new_env, space, fun_to_env,
-                ));
+                let local = Ctx {
+                    alloc: alloc.local(&decl.name),
+                    ..*ctx
+                };
+                instr.append(&mut compile_with_env(&decl.body, new_env, &local));
                 instr.push(Instr::Ret);
             }
             None => panic!("wrong"),
@@ -997,5 +2091,5 @@ fn fundefs_instr(
     }
     instr.push(Instr::Label(format!("funend_{}", ann)));
 
-    instr.append(&mut compile_with_env(&body, env.clone(), space, fun_to_env));
+    instr.append(&mut compile_with_env(&body, env.clone(), ctx));
 }
@@ -0,0 +1,452 @@
+/* Compile errors shown the way rustc shows them: the offending source
+ * lines with the spans underlined, a stable code for each kind of
+ * error, labels on the other places involved and a suggestion when
+ * there is one.
+ *
+ *   error[E0001]: Unbound variable xs
+ *    --> examples/foo.garter:2:9
+ *     |
+ *   2 | let y = xs + 1 in
+ *     |         ^^ not found in this scope
+ *     |
+ *     = help: did you mean `x`?
+ *
+ * Syntax errors look the same, but have no code. Warnings too, with a
+ * code that starts with W, and they don't stop the program from being
+ * compiled or run.
+ */
+use crate::compile::CompileErr;
+use crate::runner::{ParseErr, RunnerErr};
+use crate::span::Span2;
+
+// The text of a file that errors point into
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Source {
+    pub name: String,
+    pub text: String,
+}
+
+impl<Span> CompileErr<Span> {
+    pub fn location(&self) -> &Span {
+        match self {
+            CompileErr::UnboundVariable { location, .. }
+            | CompileErr::UndefinedFunction { location, .. }
+            | CompileErr::DuplicateBinding { location, .. }
+            | CompileErr::NumOverflow { location, .. }
+            | CompileErr::FloatOverflow { location, .. }
+            | CompileErr::DuplicateFunName { location, .. }
+            | CompileErr::DuplicateArgName { location, .. }
+            | CompileErr::FunctionCalledWrongArity { location, .. }
+            | CompileErr::TypeMismatch { location, .. }
+            | CompileErr::ArityMismatch { location, .. }
+            | CompileErr::AssignToFunction { location, .. }
+            | CompileErr::UnknownConstructor { location, .. }
+            | CompileErr::ConstructorWrongArity { location, .. }
+            | CompileErr::DuplicateConstructor { location, .. }
+            | CompileErr::DuplicatePatternVar { location, .. }
+            | CompileErr::NonExhaustiveMatch { location, .. }
+            | CompileErr::ImportFailed { location, .. }
+            | CompileErr::ImportCycle { location, .. }
+            | CompileErr::ExportNotTopLevel { location, .. }
+            | CompileErr::ConflictingImport { location, .. } => location,
+        }
+    }
+
+    pub fn is_warning(&self) -> bool {
+        matches!(self, CompileErr::NonExhaustiveMatch { .. })
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            CompileErr::UnboundVariable { .. } => "E0001",
+            CompileErr::UndefinedFunction { .. } => "E0002",
+            CompileErr::DuplicateBinding { .. } => "E0003",
+            CompileErr::NumOverflow { .. } => "E0004",
+            CompileErr::FloatOverflow { .. } => "E0005",
+            CompileErr::DuplicateFunName { .. } => "E0006",
+            CompileErr::DuplicateArgName { .. } => "E0007",
+            CompileErr::FunctionCalledWrongArity { .. } => "E0008",
+            CompileErr::TypeMismatch { .. } => "E0009",
+            CompileErr::ArityMismatch { .. } => "E0010",
+            CompileErr::AssignToFunction { .. } => "E0011",
+            CompileErr::UnknownConstructor { .. } => "E0012",
+            CompileErr::ConstructorWrongArity { .. } => "E0013",
+            CompileErr::DuplicateConstructor { .. } => "E0014",
+            CompileErr::DuplicatePatternVar { .. } => "E0015",
+            CompileErr::ImportFailed { .. } => "E0016",
+            CompileErr::ImportCycle { .. } => "E0017",
+            CompileErr::ExportNotTopLevel { .. } => "E0018",
+            CompileErr::ConflictingImport { .. } => "E0019",
+            CompileErr::NonExhaustiveMatch { .. } => "W0001",
+        }
+    }
+
+    // What went wrong, without where
+    pub fn message(&self) -> String {
+        match self {
+            CompileErr::UnboundVariable { unbound, .. } => format!("Unbound variable {}", unbound),
+            CompileErr::UndefinedFunction { undefined, .. } => {
+                format!("Undefined function {} called", undefined)
+            }
+            CompileErr::DuplicateBinding { duplicated_name, .. } => {
+                format!("Variable {} defined twice in let-expression", duplicated_name)
+            }
+            CompileErr::NumOverflow { num, .. } => {
+                format!("Number literal {} doesn't fit into 63-bit integer, overflow", num)
+            }
+            CompileErr::FloatOverflow { num, .. } => {
+                format!("Float literal {} doesn't fit into 64-bit float, overflow", num.0)
+            }
+            CompileErr::DuplicateArgName { duplicated_name, .. } => {
+                format!("multiple arguments named \"{}\"", duplicated_name)
+            }
+            CompileErr::DuplicateFunName { duplicated_name, .. } => {
+                format!("multiple defined functions named \"{}\"", duplicated_name)
+            }
+            CompileErr::FunctionCalledWrongArity {
+                function_name,
+                correct_arity,
+                arity_used,
+                ..
+            } => format!(
+                "function {} of arity {} called with {} arguments",
+                function_name, correct_arity, arity_used
+            ),
+            CompileErr::TypeMismatch {
+                who, expected, got, ..
+            } => format!("{} expected {}, but got {}", who, expected, got),
+            CompileErr::ArityMismatch { expected, got, .. } => format!(
+                "Function expecting {} arguments called with {} arguments",
+                expected, got
+            ),
+            CompileErr::AssignToFunction { function_name, .. } => {
+                format!("cannot assign to function {}", function_name)
+            }
+            CompileErr::UnknownConstructor { unknown, .. } => format!("Unknown constructor {}", unknown),
+            CompileErr::ConstructorWrongArity {
+                constructor,
+                correct_arity,
+                arity_used,
+                ..
+            } => format!(
+                "constructor {} of arity {} used with {} fields",
+                constructor, correct_arity, arity_used
+            ),
+            CompileErr::DuplicateConstructor { duplicated_name, .. } => {
+                format!("multiple constructors named \"{}\"", duplicated_name)
+            }
+            CompileErr::DuplicatePatternVar { duplicated_name, .. } => {
+                format!("Variable {} bound twice in a pattern", duplicated_name)
+            }
+            CompileErr::NonExhaustiveMatch { missing, .. } => {
+                format!("non-exhaustive match: `{}` not covered", missing)
+            }
+            CompileErr::ImportFailed { path, reason, .. } => format!("cannot import {}: {}", path, reason),
+            CompileErr::ImportCycle { cycle, .. } => format!("import cycle: {}", cycle.join(" -> ")),
+            CompileErr::ExportNotTopLevel { function_name, .. } => {
+                format!("cannot export {}, which isn't a top-level function", function_name)
+            }
+            CompileErr::ConflictingImport { function_name, .. } => {
+                format!("function {} imported from two files", function_name)
+            }
+        }
+    }
+
+    // The label under the location of the error
+    pub fn label(&self) -> String {
+        match self {
+            CompileErr::UnboundVariable { .. } => "not found in this scope".to_string(),
+            CompileErr::UndefinedFunction { .. } => "not a function in this scope".to_string(),
+            CompileErr::DuplicateBinding { duplicated_name, .. } => {
+                format!("`{}` bound again here", duplicated_name)
+            }
+            CompileErr::NumOverflow { .. } => "doesn't fit into 63 bits".to_string(),
+            CompileErr::FloatOverflow { .. } => "doesn't fit into 64 bits".to_string(),
+            CompileErr::DuplicateArgName { duplicated_name, .. } => {
+                format!("`{}` is a parameter more than once", duplicated_name)
+            }
+            CompileErr::DuplicateFunName { duplicated_name, .. } => {
+                format!("`{}` defined again here", duplicated_name)
+            }
+            CompileErr::FunctionCalledWrongArity { correct_arity, .. } => {
+                format!("expected {} arguments", correct_arity)
+            }
+            CompileErr::TypeMismatch { got, .. } => format!("found {}", got),
+            CompileErr::ArityMismatch { expected, .. } => {
+                format!("expected {} arguments", expected)
+            }
+            CompileErr::AssignToFunction { .. } => "only variables can be assigned".to_string(),
+            CompileErr::UnknownConstructor { .. } => "not a constructor in this scope".to_string(),
+            CompileErr::ConstructorWrongArity { correct_arity, .. } => {
+                format!("expected {} fields", correct_arity)
+            }
+            CompileErr::DuplicateConstructor { duplicated_name, .. } => {
+                format!("`{}` defined again here", duplicated_name)
+            }
+            CompileErr::DuplicatePatternVar { duplicated_name, .. } => {
+                format!("`{}` bound again here", duplicated_name)
+            }
+            CompileErr::NonExhaustiveMatch { missing, .. } => format!("pattern `{}` not covered", missing),
+            CompileErr::ImportFailed { .. } => "imported here".to_string(),
+            CompileErr::ImportCycle { .. } => "this import closes the cycle".to_string(),
+            CompileErr::ExportNotTopLevel { .. } => "defined inside of an expression".to_string(),
+            CompileErr::ConflictingImport { function_name, .. } => {
+                format!("`{}` imported again here", function_name)
+            }
+        }
+    }
+
+    // Labels on the other places involved in the error
+    pub fn secondary(&self) -> Vec<(&Span, String)> {
+        match self {
+            CompileErr::DuplicateBinding {
+                duplicated_name,
+                first,
+                ..
+            } => vec![(first, format!("first binding of `{}`", duplicated_name))],
+            CompileErr::DuplicateFunName {
+                duplicated_name,
+                first: Some(first),
+                ..
+            } => vec![(first, format!("first definition of `{}`", duplicated_name))],
+            CompileErr::DuplicateConstructor {
+                duplicated_name,
+                first: Some(first),
+                ..
+            } => vec![(first, format!("first definition of `{}`", duplicated_name))],
+            CompileErr::DuplicatePatternVar {
+                duplicated_name,
+                first,
+                ..
+            } => vec![(first, format!("first binding of `{}`", duplicated_name))],
+            CompileErr::ConflictingImport {
+                function_name,
+                first,
+                ..
+            } => vec![(first, format!("first import of `{}`", function_name))],
+            _ => vec![],
+        }
+    }
+
+    pub fn help(&self) -> Option<String> {
+        match self {
+            CompileErr::UnboundVariable {
+                suggestion: Some(x),
+                ..
+            }
+            | CompileErr::UndefinedFunction {
+                suggestion: Some(x),
+                ..
+            }
+            | CompileErr::UnknownConstructor {
+                suggestion: Some(x),
+                ..
+            } => Some(format!("did you mean `{}`?", x)),
+            CompileErr::DuplicateFunName { first: None, .. } => {
+                Some("functions can't shadow an enclosing function".to_string())
+            }
+            CompileErr::DuplicateConstructor { first: None, .. } => {
+                Some("constructors can't shadow an enclosing constructor".to_string())
+            }
+            CompileErr::NonExhaustiveMatch { .. } => Some("add an arm for it, or a `_` arm".to_string()),
+            CompileErr::ImportCycle { .. } => {
+                Some("move the functions they share to a file that imports neither".to_string())
+            }
+            CompileErr::ExportNotTopLevel { .. } => {
+                Some("only the `def`s at the start of a file can be exported".to_string())
+            }
+            _ => None,
+        }
+    }
+}
+
+pub fn render(err: &CompileErr<Span2>, source: &Source) -> String {
+    let mut labels = vec![(err.location(), '^', err.label())];
+    labels.extend(err.secondary().into_iter().map(|(span, text)| (span, '-', text)));
+    let severity = if err.is_warning() { "warning" } else { "error" };
+    snippet(
+        &format!("{}[{}]: {}", severity, err.code(), err.message()),
+        err.location(),
+        labels,
+        err.help(),
+        source,
+    )
+}
+
+// Syntax errors have no code, and no snippet when the parser couldn't
+// tell where it stopped
+pub fn render_parse(err: &ParseErr<Span2>, source: &Source) -> String {
+    let head = format!("error: {}", err.message);
+    match &err.location {
+        Some(location) => snippet(&head, location, vec![(location, '^', err.label.clone())], None, source),
+        None => format!("{}\n", head),
+    }
+}
+
+// The head line of an error followed by the lines of source its labels
+// point at, and the help if there is one
+fn snippet(
+    head: &str,
+    primary: &Span2,
+    mut labels: Vec<(&Span2, char, String)>,
+    help: Option<String>,
+    source: &Source,
+) -> String {
+    let lines: Vec<&str> = source.text.lines().collect();
+    // labels are shown in the order of the source
+    labels.sort_by_key(|(span, _, _)| (span.start_line, span.start_col));
+
+    let width = labels
+        .iter()
+        .map(|(span, _, _)| span.start_line.to_string().len())
+        .max()
+        .unwrap_or(1);
+    let pad = " ".repeat(width);
+    let mut out = format!("{}\n", head);
+    out.push_str(&format!(
+        "{}--> {}:{}:{}\n",
+        pad,
+        source.name,
+        primary.start_line,
+        primary.start_col + 1
+    ));
+    out.push_str(&format!("{} |\n", pad));
+    let mut shown = None;
+    for (span, mark, text) in labels {
+        let line = lines.get(span.start_line - 1).copied().unwrap_or("");
+        if shown != Some(span.start_line) {
+            out.push_str(&format!("{:>w$} | {}\n", span.start_line, line, w = width));
+            shown = Some(span.start_line);
+        }
+        // a span over several lines is underlined to the end of its first
+        let end = if span.end_line == span.start_line {
+            span.end_col
+        } else {
+            line.len()
+        };
+        let start = chars_before(line, span.start_col);
+        let len = std::cmp::max(1, chars_before(line, end).saturating_sub(start));
+        out.push_str(&format!(
+            "{} | {}{} {}\n",
+            pad,
+            " ".repeat(start),
+            mark.to_string().repeat(len),
+            text
+        ));
+    }
+    if let Some(help) = help {
+        out.push_str(&format!("{} |\n{} = help: {}\n", pad, pad, help));
+    }
+    out
+}
+
+// The characters in the first col bytes of line, where the carets of
+// a column go. Columns past the end of the line, like the end of the
+// input, are a character each.
+fn chars_before(line: &str, col: usize) -> usize {
+    match line.get(..col) {
+        Some(before) => before.chars().count(),
+        None => line.chars().count() + col.saturating_sub(line.len()),
+    }
+}
+
+/* The same errors as JSON, one object per line, e.g.
+ *
+ *   {"kind": "compile", "code": "E0001", "message": "Unbound variable xs",
+ *    "file": "examples/foo.garter", "spans": [{"start_line": 2,
+ *    "start_col": 8, "end_line": 2, "end_col": 10, "primary": true,
+ *    "label": "not found in this scope"}], "help": "did you mean `x`?"}
+ *
+ * Lines are counted from 1 and columns from 0, as in Span2. Errors of
+ * the compiled program have the code R followed by its exit status, and
+ * leave the backtrace out.
+ */
+pub fn json(e: &RunnerErr<Span2>, file: &str) -> Vec<String> {
+    let simple = |kind: &str, message: &str| json_object(kind, None, message, file, &[], None);
+    match e {
+        RunnerErr::FileOpen(s) => vec![simple("file", s)],
+        RunnerErr::Lex(s) => vec![simple("lex", s)],
+        RunnerErr::Parse(errs, source) => errs
+            .iter()
+            .map(|err| {
+                let spans: Vec<(&Span2, bool, String)> =
+                    err.location.iter().map(|l| (l, true, err.label.clone())).collect();
+                json_object("parse", None, &err.message, &source.name, &spans, None)
+            })
+            .collect(),
+        RunnerErr::CodeGen(errs, source) => errs.iter().map(|err| json_compile(err, &source.name)).collect(),
+        RunnerErr::Link(s) => vec![simple("link", s)],
+        RunnerErr::Interp(err) => vec![simple("runtime", &err.to_string())],
+        RunnerErr::Run(s) => vec![simple("run", s)],
+        RunnerErr::RunFailed { status, message, location, .. } => {
+            let spans: Vec<(&Span2, bool, String)> =
+                location.iter().map(|(_, s)| (s, true, String::from("the error happened here"))).collect();
+            vec![json_object(
+                "runtime",
+                status.code().map(|c| format!("R{:04}", c)),
+                message,
+                location.as_ref().map_or(file, |(file, _)| file),
+                &spans,
+                None,
+            )]
+        }
+    }
+}
+
+// A compile error or warning, whose kind is "warning"
+pub fn json_compile(err: &CompileErr<Span2>, file: &str) -> String {
+    let mut spans = vec![(err.location(), true, err.label())];
+    spans.extend(err.secondary().into_iter().map(|(span, text)| (span, false, text)));
+    let kind = if err.is_warning() { "warning" } else { "compile" };
+    json_object(kind, Some(err.code().to_string()), &err.message(), file, &spans, err.help())
+}
+
+fn json_object(
+    kind: &str,
+    code: Option<String>,
+    message: &str,
+    file: &str,
+    spans: &[(&Span2, bool, String)],
+    help: Option<String>,
+) -> String {
+    let spans: Vec<String> = spans
+        .iter()
+        .map(|(span, primary, label)| {
+            format!(
+                "{{\"start_line\": {}, \"start_col\": {}, \"end_line\": {}, \"end_col\": {}, \"primary\": {}, \"label\": {}}}",
+                span.start_line,
+                span.start_col,
+                span.end_line,
+                span.end_col,
+                primary,
+                json_string(label)
+            )
+        })
+        .collect();
+    format!(
+        "{{\"kind\": {}, \"code\": {}, \"message\": {}, \"file\": {}, \"spans\": [{}], \"help\": {}}}",
+        json_string(kind),
+        code.map_or("null".to_string(), |c| json_string(&c)),
+        json_string(message),
+        json_string(file),
+        spans.join(", "),
+        help.map_or("null".to_string(), |h| json_string(&h)),
+    )
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
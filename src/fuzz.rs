@@ -0,0 +1,1124 @@
+/* Random programs for fuzzing the compiler against the interpreter.
+ *
+ * Programs are generated by type, so both backends almost always get
+ * far enough to run them, and they respect the rules of check_exp:
+ * every variable is bound, function names are unique and calls match
+ * the arity of the function. Every function takes a fuel parameter
+ * first and only recurses with less of it, and every loop counts down
+ * a fuel variable of its own, so every program terminates. Fuel is
+ * never assigned to otherwise.
+ */
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+use crate::compile::check_prog;
+use crate::infer::infer_prog;
+use crate::runner::{diff_file, RunnerErr};
+use crate::span::Span2;
+use crate::syntax::{Exp, FloatWrapper, FunDecl, Pattern, Prim, Signature, SurfProg, Type};
+
+// SplitMix64, so a seed always gives the same program
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+
+    // inclusive on both ends
+    fn range(&mut self, lo: i64, hi: i64) -> i64 {
+        lo + self.below((hi - lo + 1) as usize) as i64
+    }
+
+    fn pick<T: Clone>(&mut self, xs: &[T]) -> T {
+        xs[self.below(xs.len())].clone()
+    }
+}
+
+// The type of a generated expression. Unlike syntax::Type it knows the
+// shape of tuples, so indexing them can be generated at a type.
+#[derive(Clone, Debug, PartialEq)]
+enum Ty {
+    Int,
+    Bool,
+    Float,
+    Tuple(Vec<Ty>),
+    Fun(Vec<Ty>, Box<Ty>),
+}
+
+// How a call passes the fuel of a function. Outside its own definition
+// group a function gets a small constant, inside it gets the fuel of
+// the caller minus one.
+#[derive(Clone)]
+enum Fuel {
+    Const,
+    Caller(String),
+}
+
+#[derive(Clone)]
+struct Fun {
+    name: String,
+    params: Vec<Ty>,
+    ret: Ty,
+    fuel: Fuel,
+}
+
+// Variables are drawn from a small pool so they shadow each other often
+const NAMES: [&str; 6] = ["x", "y", "z", "u", "v", "w"];
+
+struct Gen {
+    rng: Rng,
+    vars: Vec<(String, Ty)>,
+    funs: Vec<Fun>,
+    fresh: usize,
+}
+
+fn prim(p: Prim, es: Vec<Exp<()>>) -> Exp<()> {
+    Exp::Prim(p, es.into_iter().map(Box::new).collect(), ())
+}
+
+fn is_fuel(x: &str) -> bool {
+    x.starts_with("fuel")
+}
+
+impl Gen {
+    fn scalar_ty(&mut self) -> Ty {
+        match self.rng.below(5) {
+            0 | 1 => Ty::Int,
+            2 => Ty::Bool,
+            _ => Ty::Float,
+        }
+    }
+
+    // a type values of which can be printed and compared
+    fn data_ty(&mut self) -> Ty {
+        if self.rng.chance(15) {
+            let n = self.rng.below(4);
+            Ty::Tuple((0..n).map(|_| self.scalar_ty()).collect())
+        } else {
+            self.scalar_ty()
+        }
+    }
+
+    fn any_ty(&mut self) -> Ty {
+        if self.rng.chance(20) {
+            let n = self.rng.below(3);
+            let params = (0..n).map(|_| self.data_ty()).collect();
+            Ty::Fun(params, Box::new(self.data_ty()))
+        } else {
+            self.data_ty()
+        }
+    }
+
+    fn int(&mut self) -> i64 {
+        if self.rng.chance(3) {
+            let big = (i64::MAX >> 1) - self.rng.range(0, 2);
+            if self.rng.chance(50) {
+                -big
+            } else {
+                big
+            }
+        } else {
+            self.rng.range(-5, 20)
+        }
+    }
+
+    fn float(&mut self) -> f64 {
+        if self.rng.chance(3) {
+            self.rng.range(1, 17) as f64 * 1.0e300
+        } else {
+            self.rng.range(-40, 40) as f64 / 4.0
+        }
+    }
+
+    // n distinct variable names
+    fn names(&mut self, n: usize) -> Vec<String> {
+        let mut names: Vec<String> = NAMES.iter().map(|x| x.to_string()).collect();
+        for i in 0..n {
+            let j = i + self.rng.below(names.len() - i);
+            names.swap(i, j);
+        }
+        names.truncate(n);
+        names
+    }
+
+    // the variables in scope, without the shadowed ones
+    fn visible(&self) -> Vec<(String, Ty)> {
+        self.vars
+            .iter()
+            .enumerate()
+            .filter(|(i, (x, _))| !self.vars[i + 1..].iter().any(|(y, _)| x == y))
+            .map(|(_, b)| b.clone())
+            .collect()
+    }
+
+    fn vars_of(&self, ty: &Ty) -> Vec<String> {
+        self.visible()
+            .into_iter()
+            .filter(|(_, t)| t == ty)
+            .map(|(x, _)| x)
+            .collect()
+    }
+
+    fn exp(&mut self, ty: &Ty, depth: usize) -> Exp<()> {
+        if depth == 0 || self.rng.chance(20) {
+            return self.leaf(ty);
+        }
+        let depth = depth - 1;
+        match self.rng.below(12) {
+            0 | 1 => self.let_exp(ty, depth),
+            2 => Exp::If {
+                cond: Box::new(self.exp(&Ty::Bool, depth)),
+                thn: Box::new(self.exp(ty, depth)),
+                els: Box::new(self.exp(ty, depth)),
+                ann: (),
+            },
+            3 => self.fun_defs(ty, depth),
+            4 | 5 => match self.call(ty, depth) {
+                Some(e) => e,
+                None => self.op(ty, depth),
+            },
+            6 if !matches!(ty, Ty::Fun(..)) => prim(Prim::Print, vec![self.exp(ty, depth)]),
+            7 => {
+                let t = self.data_ty();
+                Exp::Semicolon {
+                    e1: Box::new(prim(Prim::Print, vec![self.exp(&t, depth)])),
+                    e2: Box::new(self.exp(ty, depth)),
+                    ann: (),
+                }
+            }
+            8 => match self.assign(ty, depth) {
+                Some(e) => e,
+                None => self.op(ty, depth),
+            },
+            9 => self.while_loop(ty, depth),
+            _ => self.op(ty, depth),
+        }
+    }
+
+    // x := e for a variable x of type ty
+    fn assign(&mut self, ty: &Ty, depth: usize) -> Option<Exp<()>> {
+        let vars: Vec<String> = self.vars_of(ty).into_iter().filter(|x| !is_fuel(x)).collect();
+        if vars.is_empty() {
+            return None;
+        }
+        let var = self.rng.pick(&vars);
+        Some(Exp::Assign {
+            var,
+            value: Box::new(self.exp(ty, depth)),
+            ann: (),
+        })
+    }
+
+    // let fuel = n in (while fuel > 0: (fuel := fuel - 1; e)); e'
+    fn while_loop(&mut self, ty: &Ty, depth: usize) -> Exp<()> {
+        self.fresh += 1;
+        let fuel = format!("fuel_while{}", self.fresh);
+        let n = self.rng.range(0, 3);
+        self.vars.push((fuel.clone(), Ty::Int));
+        let t = self.data_ty();
+        let body = Exp::Semicolon {
+            e1: Box::new(Exp::Assign {
+                var: fuel.clone(),
+                value: Box::new(prim(Prim::Sub, vec![Exp::Var(fuel.clone(), ()), Exp::Num(1, ())])),
+                ann: (),
+            }),
+            e2: Box::new(prim(Prim::Print, vec![self.exp(&t, depth)])),
+            ann: (),
+        };
+        let rest = self.exp(ty, depth);
+        self.vars.pop();
+        Exp::Let {
+            bindings: vec![(fuel.clone(), Exp::Num(n, ()))],
+            body: Box::new(Exp::Semicolon {
+                e1: Box::new(Exp::While {
+                    cond: Box::new(prim(Prim::Gt, vec![Exp::Var(fuel, ()), Exp::Num(0, ())])),
+                    body: Box::new(body),
+                    ann: (),
+                }),
+                e2: Box::new(rest),
+                ann: (),
+            }),
+            ann: (),
+        }
+    }
+
+    fn leaf(&mut self, ty: &Ty) -> Exp<()> {
+        let vars = self.vars_of(ty);
+        if !vars.is_empty() && self.rng.chance(60) {
+            return Exp::Var(self.rng.pick(&vars), ());
+        }
+        match ty {
+            Ty::Int => Exp::Num(self.int(), ()),
+            Ty::Bool => Exp::Bool(self.rng.chance(50), ()),
+            Ty::Float => Exp::Float(FloatWrapper(self.float()), ()),
+            Ty::Tuple(ts) => prim(Prim::MakeTuple, ts.iter().map(|t| self.leaf(t)).collect()),
+            Ty::Fun(params, ret) => {
+                // functions are values too, when their fuel is a constant
+                let funs: Vec<String> = self
+                    .funs
+                    .iter()
+                    .filter(|f| {
+                        matches!(f.fuel, Fuel::Const)
+                            && params.first() == Some(&Ty::Int)
+                            && f.params[..] == params[1..]
+                            && f.ret == **ret
+                    })
+                    .map(|f| f.name.clone())
+                    .collect();
+                if !funs.is_empty() && self.rng.chance(50) {
+                    Exp::Var(self.rng.pick(&funs), ())
+                } else {
+                    self.lambda(params, ret, 0)
+                }
+            }
+        }
+    }
+
+    fn lambda(&mut self, params: &[Ty], ret: &Ty, depth: usize) -> Exp<()> {
+        let names = self.names(params.len());
+        let len = self.vars.len();
+        self.vars.extend(names.iter().cloned().zip(params.iter().cloned()));
+        let body = self.exp(ret, depth);
+        self.vars.truncate(len);
+        Exp::Lambda {
+            parameters: names,
+            body: Box::new(body),
+            ann: (),
+        }
+    }
+
+    fn let_exp(&mut self, ty: &Ty, depth: usize) -> Exp<()> {
+        let n = 1 + self.rng.below(3);
+        let len = self.vars.len();
+        let mut bindings = vec![];
+        for x in self.names(n) {
+            let consts: Vec<Fun> = self
+                .funs
+                .iter()
+                .filter(|f| matches!(f.fuel, Fuel::Const))
+                .cloned()
+                .collect();
+            // sometimes at the type of a function, which makes it a value
+            let t = if !consts.is_empty() && self.rng.chance(15) {
+                let f = self.rng.pick(&consts);
+                let mut params = vec![Ty::Int];
+                params.extend(f.params);
+                Ty::Fun(params, Box::new(f.ret))
+            } else {
+                self.any_ty()
+            };
+            let e = self.exp(&t, depth);
+            self.vars.push((x.clone(), t));
+            bindings.push((x, e));
+        }
+        let body = self.exp(ty, depth);
+        self.vars.truncate(len);
+        Exp::Let {
+            bindings,
+            body: Box::new(body),
+            ann: (),
+        }
+    }
+
+    fn fun_defs(&mut self, ty: &Ty, depth: usize) -> Exp<()> {
+        let n = 1 + self.rng.below(2);
+        let mut group = vec![];
+        for _ in 0..n {
+            self.fresh += 1;
+            let arity = self.rng.below(3);
+            group.push(Fun {
+                name: format!("f{}", self.fresh),
+                params: (0..arity).map(|_| self.data_ty()).collect(),
+                ret: self.data_ty(),
+                fuel: Fuel::Const,
+            });
+        }
+        let (vars, funs) = (self.vars.len(), self.funs.len());
+        let mut decls = vec![];
+        for f in &group {
+            let fuel = format!("fuel{}", &f.name[1..]);
+            let names = self.names(f.params.len());
+            self.vars.push((fuel.clone(), Ty::Int));
+            self.vars.extend(names.iter().cloned().zip(f.params.iter().cloned()));
+            let base = self.exp(&f.ret, depth);
+            self.funs.extend(group.iter().map(|g| Fun {
+                fuel: Fuel::Caller(fuel.clone()),
+                ..g.clone()
+            }));
+            let recur = self.exp(&f.ret, depth);
+            self.vars.truncate(vars);
+            self.funs.truncate(funs);
+
+            let mut parameters = vec![fuel.clone()];
+            parameters.extend(names);
+            decls.push(FunDecl {
+                name: f.name.clone(),
+                signature: Signature {
+                    parameters: vec![None; parameters.len()],
+                    ret: None,
+                },
+                parameters,
+                body: Exp::If {
+                    cond: Box::new(prim(Prim::Le, vec![Exp::Var(fuel, ()), Exp::Num(0, ())])),
+                    thn: Box::new(base),
+                    els: Box::new(recur),
+                    ann: (),
+                },
+                ann: (),
+            });
+        }
+        self.funs.extend(group);
+        let body = self.exp(ty, depth);
+        self.funs.truncate(funs);
+        Exp::FunDefs {
+            decls,
+            body: Box::new(body),
+            ann: (),
+        }
+    }
+
+    // A call of a function or closure in scope returning ty
+    fn call(&mut self, ty: &Ty, depth: usize) -> Option<Exp<()>> {
+        let funs: Vec<Fun> = self.funs.iter().filter(|f| f.ret == *ty).cloned().collect();
+        let closures: Vec<(String, Vec<Ty>)> = self
+            .visible()
+            .into_iter()
+            .filter_map(|(x, t)| match t {
+                Ty::Fun(params, ret) if *ret == *ty => Some((x, params)),
+                _ => None,
+            })
+            .collect();
+        if !funs.is_empty() && (closures.is_empty() || self.rng.chance(60)) {
+            let f = self.rng.pick(&funs);
+            let fuel = match &f.fuel {
+                Fuel::Const => Exp::Num(self.rng.range(0, 2), ()),
+                Fuel::Caller(fuel) => prim(Prim::Sub, vec![Exp::Var(fuel.clone(), ()), Exp::Num(1, ())]),
+            };
+            let mut args = vec![fuel];
+            args.extend(f.params.iter().map(|t| self.exp(t, depth)));
+            Some(Exp::Call(f.name, args, ()))
+        } else if !closures.is_empty() {
+            // the closure may be a function, which takes its fuel first
+            let (x, params) = self.rng.pick(&closures);
+            let args = params
+                .iter()
+                .enumerate()
+                .map(|(i, t)| match t {
+                    Ty::Int if i == 0 => Exp::Num(self.rng.range(0, 2), ()),
+                    t => self.exp(t, depth),
+                })
+                .collect();
+            Some(Exp::Call(x, args, ()))
+        } else if self.rng.chance(30) {
+            let n = self.rng.below(3);
+            let params: Vec<Ty> = (0..n).map(|_| self.data_ty()).collect();
+            let fun = self.lambda(&params, ty, depth);
+            Some(Exp::ClosureCall {
+                fun: Box::new(fun),
+                args: params.iter().map(|t| self.exp(t, depth)).collect(),
+                is_tail: false,
+                ann: (),
+            })
+        } else {
+            None
+        }
+    }
+
+    fn num(&mut self, depth: usize) -> Exp<()> {
+        let ty = if self.rng.chance(50) { Ty::Int } else { Ty::Float };
+        self.exp(&ty, depth)
+    }
+
+    fn divisor(&mut self, depth: usize) -> Exp<()> {
+        match self.rng.below(4) {
+            0 => self.num(depth),
+            1 => Exp::Float(FloatWrapper(self.rng.range(1, 12) as f64 / 4.0), ()),
+            _ => Exp::Num(self.rng.pick(&[-3, -2, -1, 1, 2, 3, 5]), ()),
+        }
+    }
+
+    // ty at a random position of a tuple
+    fn index(&mut self, ty: &Ty, depth: usize) -> Exp<()> {
+        let n = 1 + self.rng.below(3);
+        let i = self.rng.below(n);
+        let elems = (0..n).map(|j| if j == i { ty.clone() } else { self.scalar_ty() }).collect();
+        let tuple = self.exp(&Ty::Tuple(elems), depth);
+        let index = if self.rng.chance(10) {
+            self.exp(&Ty::Int, depth)
+        } else {
+            Exp::Num(i as i64, ())
+        };
+        prim(Prim::GetIndex, vec![tuple, index])
+    }
+
+    fn op(&mut self, ty: &Ty, depth: usize) -> Exp<()> {
+        match ty {
+            Ty::Int => match self.rng.below(6) {
+                0 => {
+                    let p = self.rng.pick(&[Prim::Add1, Prim::Sub1]);
+                    prim(p, vec![self.exp(ty, depth)])
+                }
+                1 => prim(Prim::FloorDiv, vec![self.num(depth), self.divisor(depth)]),
+                2 => self.index(ty, depth),
+                _ => {
+                    let p = self.rng.pick(&[Prim::Add, Prim::Sub, Prim::Mul]);
+                    prim(p, vec![self.exp(ty, depth), self.exp(ty, depth)])
+                }
+            },
+            Ty::Float => match self.rng.below(7) {
+                0 => prim(Prim::Div, vec![self.num(depth), self.divisor(depth)]),
+                1 => {
+                    let e = if self.rng.chance(70) {
+                        Exp::Float(FloatWrapper(self.float().abs()), ())
+                    } else {
+                        self.num(depth)
+                    };
+                    prim(Prim::Sqrt, vec![e])
+                }
+                2 => prim(Prim::Cos, vec![self.num(depth)]),
+                3 => self.index(ty, depth),
+                4 => {
+                    let p = self.rng.pick(&[Prim::Add1, Prim::Sub1]);
+                    prim(p, vec![self.exp(ty, depth)])
+                }
+                _ => {
+                    // at least one of the operands is a float
+                    let p = self.rng.pick(&[Prim::Add, Prim::Sub, Prim::Mul]);
+                    let mut es = vec![self.exp(ty, depth), self.num(depth)];
+                    if self.rng.chance(50) {
+                        es.reverse();
+                    }
+                    prim(p, es)
+                }
+            },
+            Ty::Bool => match self.rng.below(7) {
+                0 | 1 => {
+                    let p = self.rng.pick(&[Prim::Lt, Prim::Gt, Prim::Le, Prim::Ge]);
+                    prim(p, vec![self.num(depth), self.num(depth)])
+                }
+                2 => {
+                    let p = self.rng.pick(&[Prim::And, Prim::Or]);
+                    prim(p, vec![self.exp(ty, depth), self.exp(ty, depth)])
+                }
+                3 => prim(Prim::Not, vec![self.exp(ty, depth)]),
+                4 => {
+                    let p = self.rng.pick(&[Prim::Eq, Prim::Neq]);
+                    let t1 = self.data_ty();
+                    let t2 = if self.rng.chance(70) { t1.clone() } else { self.data_ty() };
+                    prim(p, vec![self.exp(&t1, depth), self.exp(&t2, depth)])
+                }
+                5 => {
+                    let p = self.rng.pick(&[Prim::IsNum, Prim::IsBool, Prim::IsFloat, Prim::IsTuple]);
+                    let t = self.data_ty();
+                    prim(p, vec![self.exp(&t, depth)])
+                }
+                _ => self.index(ty, depth),
+            },
+            Ty::Tuple(ts) => prim(Prim::MakeTuple, ts.iter().map(|t| self.exp(t, depth)).collect()),
+            Ty::Fun(params, ret) => self.lambda(params, ret, depth),
+        }
+    }
+}
+
+/* The random program for seed */
+pub fn gen_prog(seed: u64) -> SurfProg<()> {
+    let mut gen = Gen {
+        rng: Rng(seed),
+        vars: vec![],
+        funs: vec![],
+        fresh: 0,
+    };
+    let ty = gen.data_ty();
+    gen.exp(&ty, 6)
+}
+
+/* Pretty printing */
+
+/* Prints a surface program as source the parser reads back. Compound
+ * expressions are parenthesized so precedence never matters.
+ */
+pub fn pretty<Ann>(e: &Exp<Ann>) -> String {
+    let mut s = String::new();
+    pp(e, 0, &mut s);
+    s
+}
+
+fn newline(indent: usize, s: &mut String) {
+    s.push('\n');
+    s.push_str(&"    ".repeat(indent));
+}
+
+fn pp_float(f: f64) -> String {
+    // the lexer needs a decimal point, which {:?} leaves out of 1e300
+    let s = format!("{:?}", f);
+    if s.contains('.') {
+        s
+    } else {
+        s.replacen('e', ".0e", 1)
+    }
+}
+
+fn pp_type(t: &Type) -> &'static str {
+    match t {
+        Type::Int => "Int",
+        Type::Float => "Float",
+        Type::Bool => "Bool",
+        Type::Tuple => "Tuple",
+        Type::String => "String",
+        Type::Array => "Array",
+        _ => panic!("type {:?} can't be written in a program", t),
+    }
+}
+
+// The literal the lexer reads back as s
+fn pp_str(text: &str) -> String {
+    let mut s = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '\n' => s.push_str("\\n"),
+            '\t' => s.push_str("\\t"),
+            '\\' => s.push_str("\\\\"),
+            '"' => s.push_str("\\\""),
+            c => s.push(c),
+        }
+    }
+    s.push('"');
+    s
+}
+
+fn pp_args<'a, Ann: 'a>(es: impl Iterator<Item = &'a Exp<Ann>>, indent: usize, s: &mut String) {
+    s.push('(');
+    for (i, e) in es.enumerate() {
+        if i > 0 {
+            s.push_str(", ");
+        }
+        pp(e, indent, s);
+    }
+    s.push(')');
+}
+
+// e followed by [ or (, which binds tighter than anything but variables
+fn pp_callee<Ann>(e: &Exp<Ann>, indent: usize, s: &mut String) {
+    match e {
+        Exp::Var(x, _) => s.push_str(x),
+        e => {
+            s.push('(');
+            pp(e, indent, s);
+            s.push(')');
+        }
+    }
+}
+
+fn pp<Ann>(e: &Exp<Ann>, indent: usize, s: &mut String) {
+    match e {
+        Exp::Num(n, _) => s.push_str(&n.to_string()),
+        Exp::Bool(b, _) => s.push_str(&b.to_string()),
+        Exp::Float(f, _) => s.push_str(&pp_float(f.0)),
+        Exp::Str(text, _) => s.push_str(&pp_str(text)),
+        Exp::Var(x, _) => s.push_str(x),
+        Exp::Prim(p, es, _) => {
+            let es: Vec<&Exp<Ann>> = es.iter().map(|e| &**e).collect();
+            let name = match p {
+                Prim::Add1 => "add1",
+                Prim::Sub1 => "sub1",
+                Prim::Print => "print",
+                Prim::IsBool => "isbool",
+                Prim::IsNum => "isnum",
+                Prim::IsFloat => "isfloat",
+                Prim::IsTuple => "istuple",
+                Prim::Sqrt => "sqrt",
+                Prim::Cos => "cos",
+                Prim::Add => "+",
+                Prim::Sub => "-",
+                Prim::Mul => "*",
+                Prim::Div => "/",
+                Prim::FloorDiv => "//",
+                Prim::And => "&&",
+                Prim::Or => "||",
+                Prim::Lt => "<",
+                Prim::Gt => ">",
+                Prim::Le => "<=",
+                Prim::Ge => ">=",
+                Prim::Eq => "==",
+                Prim::Neq => "!=",
+                Prim::Not => "!",
+                Prim::Len => "len",
+                Prim::ToString => "tostring",
+                Prim::Concat => "++",
+                Prim::Substr => "substr",
+                Prim::MakeArray => "array",
+                Prim::Length => "length",
+                Prim::GetIndex | Prim::SetIndex | Prim::MakeTuple => "",
+                Prim::ClosureEnv => panic!("ClosureEnv only appears after lambda lifting"),
+                Prim::Unbox | Prim::SetBox => panic!("boxes only appear after boxing"),
+                Prim::MatchFail | Prim::IsVariant | Prim::VariantField | Prim::MakeVariant => {
+                    panic!("variants only appear after lower_matches")
+                }
+            };
+            match (p, &es[..]) {
+                (Prim::Not, [e]) => {
+                    s.push_str("!(");
+                    pp(e, indent, s);
+                    s.push(')');
+                }
+                (Prim::GetIndex, [t, i]) => {
+                    pp_callee(t, indent, s);
+                    s.push('[');
+                    pp(i, indent, s);
+                    s.push(']');
+                }
+                (Prim::SetIndex, [a, i, v]) => {
+                    s.push('(');
+                    pp_callee(a, indent, s);
+                    s.push('[');
+                    pp(i, indent, s);
+                    s.push_str("] := ");
+                    pp(v, indent, s);
+                    s.push(')');
+                }
+                (Prim::MakeTuple, [e]) => {
+                    s.push('(');
+                    pp(e, indent, s);
+                    s.push_str(",)");
+                }
+                (Prim::MakeTuple, es) => pp_args(es.iter().copied(), indent, s),
+                (Prim::Substr | Prim::MakeArray, es) => {
+                    s.push_str(name);
+                    pp_args(es.iter().copied(), indent, s);
+                }
+                (_, [e]) => {
+                    s.push_str(name);
+                    s.push('(');
+                    pp(e, indent, s);
+                    s.push(')');
+                }
+                (_, [e1, e2]) => {
+                    s.push('(');
+                    pp(e1, indent, s);
+                    s.push_str(&format!(" {} ", name));
+                    pp(e2, indent, s);
+                    s.push(')');
+                }
+                _ => panic!("{} arguments to {:?}", es.len(), p),
+            }
+        }
+        Exp::Let { bindings, body, .. } => {
+            s.push_str("(let ");
+            for (i, (x, e)) in bindings.iter().enumerate() {
+                if i > 0 {
+                    s.push(',');
+                    newline(indent + 1, s);
+                }
+                s.push_str(&format!("{} = ", x));
+                pp(e, indent + 1, s);
+            }
+            s.push_str(" in");
+            newline(indent + 1, s);
+            pp(body, indent + 1, s);
+            s.push(')');
+        }
+        Exp::If { cond, thn, els, .. } => {
+            s.push_str("(if ");
+            pp(cond, indent, s);
+            s.push(':');
+            newline(indent + 1, s);
+            pp(thn, indent + 1, s);
+            newline(indent, s);
+            s.push_str("else:");
+            newline(indent + 1, s);
+            pp(els, indent + 1, s);
+            s.push(')');
+        }
+        Exp::FunDefs { decls, body, .. } => {
+            s.push('(');
+            for (i, decl) in decls.iter().enumerate() {
+                if i > 0 {
+                    newline(indent, s);
+                    s.push_str("and ");
+                }
+                s.push_str(&format!("def {}(", decl.name));
+                for (j, x) in decl.parameters.iter().enumerate() {
+                    if j > 0 {
+                        s.push_str(", ");
+                    }
+                    s.push_str(x);
+                    if let Some(Some(t)) = decl.signature.parameters.get(j) {
+                        s.push_str(&format!(": {}", pp_type(t)));
+                    }
+                }
+                s.push(')');
+                if let Some(t) = &decl.signature.ret {
+                    s.push_str(&format!(" -> {}", pp_type(t)));
+                }
+                s.push(':');
+                newline(indent + 1, s);
+                pp(&decl.body, indent + 1, s);
+            }
+            newline(indent, s);
+            s.push_str("in");
+            newline(indent + 1, s);
+            pp(body, indent + 1, s);
+            s.push(')');
+        }
+        Exp::Semicolon { e1, e2, .. } => {
+            s.push('(');
+            pp(e1, indent, s);
+            s.push(';');
+            newline(indent, s);
+            pp(e2, indent, s);
+            s.push(')');
+        }
+        Exp::While { cond, body, .. } => {
+            s.push_str("(while ");
+            pp(cond, indent, s);
+            s.push(':');
+            newline(indent + 1, s);
+            pp(body, indent + 1, s);
+            s.push(')');
+        }
+        Exp::Assign { var, value, .. } => {
+            s.push_str(&format!("({} := ", var));
+            pp(value, indent, s);
+            s.push(')');
+        }
+        Exp::Lambda { parameters, body, .. } => {
+            s.push_str(&format!("(lambda({}): ", parameters.join(", ")));
+            pp(body, indent + 1, s);
+            s.push_str(" end)");
+        }
+        Exp::Call(f, args, _) => {
+            s.push_str(f);
+            pp_args(args.iter(), indent, s);
+        }
+        Exp::ClosureCall { fun, args, .. } => {
+            pp_callee(fun, indent, s);
+            pp_args(args.iter(), indent, s);
+        }
+        Exp::TypeDecl {
+            name, constructors, body, ..
+        } => {
+            s.push_str(&format!("(type {} =", name));
+            for (i, c) in constructors.iter().enumerate() {
+                if i > 0 {
+                    s.push_str(" |");
+                }
+                s.push_str(&format!(" {}", c.name));
+                if !c.fields.is_empty() {
+                    s.push_str(&format!("({})", c.fields.join(", ")));
+                }
+            }
+            s.push_str(" in");
+            newline(indent + 1, s);
+            pp(body, indent + 1, s);
+            s.push(')');
+        }
+        Exp::Construct { name, args, .. } => {
+            s.push_str(name);
+            if !args.is_empty() {
+                pp_args(args.iter(), indent, s);
+            }
+        }
+        Exp::Match { scrutinee, arms, .. } => {
+            s.push_str("match ");
+            pp(scrutinee, indent, s);
+            s.push(':');
+            for (p, e) in arms {
+                newline(indent + 1, s);
+                s.push_str("| ");
+                pp_pattern(p, s);
+                s.push_str(" => ");
+                pp(e, indent + 2, s);
+            }
+            newline(indent, s);
+            s.push_str("end");
+        }
+        Exp::InternalTailCall(..) | Exp::ExternalCall { .. } | Exp::MakeClosure { .. } => {
+            panic!("only surface programs can be printed")
+        }
+    }
+}
+
+fn pp_pattern<Ann>(p: &Pattern<Ann>, s: &mut String) {
+    match p {
+        Pattern::Wildcard(_) => s.push('_'),
+        Pattern::Var(x, _) => s.push_str(x),
+        Pattern::Constructor(c, ps, _) => {
+            s.push_str(c);
+            if !ps.is_empty() {
+                s.push('(');
+                for (i, p) in ps.iter().enumerate() {
+                    if i > 0 {
+                        s.push_str(", ");
+                    }
+                    pp_pattern(p, s);
+                }
+                s.push(')');
+            }
+        }
+    }
+}
+
+/* Shrinking */
+
+// The recursion guard of a generated function, which stays in place so
+// shrinking never makes a program diverge
+fn is_guard(e: &Exp<()>) -> bool {
+    matches!(e, Exp::Prim(Prim::Le, es, _)
+        if matches!(&*es[0], Exp::Var(x, _) if is_fuel(x)) && *es[1] == Exp::Num(0, ()))
+}
+
+// The fuel passed on by a recursive call, which may only become 0
+fn is_fuel_step(e: &Exp<()>) -> bool {
+    matches!(e, Exp::Prim(Prim::Sub, es, _)
+        if matches!(&*es[0], Exp::Var(x, _) if is_fuel(x)) && *es[1] == Exp::Num(1, ()))
+}
+
+// The countdown at the start of the body of a generated loop, which
+// only its own fuel step may shrink
+fn is_loop_step(e: &Exp<()>) -> bool {
+    matches!(e, Exp::Assign { var, .. } if is_fuel(var))
+}
+
+fn children(e: &Exp<()>) -> Vec<&Exp<()>> {
+    match e {
+        Exp::Prim(_, es, _) => es.iter().map(|e| &**e).collect(),
+        Exp::Let { bindings, body, .. } => {
+            let mut cs: Vec<&Exp<()>> = bindings.iter().map(|(_, e)| e).collect();
+            cs.push(body);
+            cs
+        }
+        Exp::If { cond, thn, els, .. } => vec![cond, thn, els],
+        Exp::Semicolon { e1, e2, .. } => vec![e1, e2],
+        Exp::While { cond, body, .. } => vec![cond, body],
+        Exp::Assign { value, .. } => vec![value],
+        Exp::FunDefs { decls, body, .. } => {
+            let mut cs: Vec<&Exp<()>> = decls.iter().map(|d| &d.body).collect();
+            cs.push(body);
+            cs
+        }
+        Exp::Lambda { body, .. } => vec![body],
+        Exp::Call(_, args, _) => args.iter().collect(),
+        Exp::ClosureCall { fun, args, .. } => {
+            let mut cs: Vec<&Exp<()>> = vec![fun];
+            cs.extend(args.iter());
+            cs
+        }
+        _ => vec![],
+    }
+}
+
+fn children_mut(e: &mut Exp<()>) -> Vec<&mut Exp<()>> {
+    match e {
+        Exp::Prim(_, es, _) => es.iter_mut().map(|e| &mut **e).collect(),
+        Exp::Let { bindings, body, .. } => {
+            let mut cs: Vec<&mut Exp<()>> = bindings.iter_mut().map(|(_, e)| e).collect();
+            cs.push(body);
+            cs
+        }
+        Exp::If { cond, thn, els, .. } => vec![cond, thn, els],
+        Exp::Semicolon { e1, e2, .. } => vec![e1, e2],
+        Exp::While { cond, body, .. } => vec![cond, body],
+        Exp::Assign { value, .. } => vec![value],
+        Exp::FunDefs { decls, body, .. } => {
+            let mut cs: Vec<&mut Exp<()>> = decls.iter_mut().map(|d| &mut d.body).collect();
+            cs.push(body);
+            cs
+        }
+        Exp::Lambda { body, .. } => vec![body],
+        Exp::Call(_, args, _) => args.iter_mut().collect(),
+        Exp::ClosureCall { fun, args, .. } => {
+            let mut cs: Vec<&mut Exp<()>> = vec![fun];
+            cs.extend(args.iter_mut());
+            cs
+        }
+        _ => vec![],
+    }
+}
+
+// Smaller variants of e, the ones removing the most coming first. They
+// aren't necessarily well-formed, shrink filters them.
+fn shrinks(e: &Exp<()>) -> Vec<Exp<()>> {
+    if is_fuel_step(e) {
+        return vec![Exp::Num(0, ())];
+    }
+    if let Exp::Semicolon { e1, e2, .. } = e {
+        if is_loop_step(e1) {
+            let mut smaller = vec![];
+            for e1 in shrinks(e1) {
+                smaller.push(Exp::Semicolon { e1: Box::new(e1), e2: e2.clone(), ann: () });
+            }
+            for e2 in shrinks(e2) {
+                smaller.push(Exp::Semicolon { e1: e1.clone(), e2: Box::new(e2), ann: () });
+            }
+            return smaller;
+        }
+    }
+    if is_loop_step(e) {
+        return match e {
+            Exp::Assign { var, value, .. } => shrinks(value)
+                .into_iter()
+                .map(|value| Exp::Assign { var: var.clone(), value: Box::new(value), ann: () })
+                .collect(),
+            _ => unreachable!(),
+        };
+    }
+    let mut smaller = vec![];
+    match e {
+        Exp::Num(0, _) | Exp::Bool(false, _) | Exp::Var(..) => {}
+        Exp::Num(n, _) => {
+            smaller.push(Exp::Num(0, ()));
+            if n / 2 != 0 {
+                smaller.push(Exp::Num(n / 2, ()));
+            }
+        }
+        Exp::Bool(true, _) => smaller.push(Exp::Bool(false, ())),
+        Exp::Float(f, _) => {
+            if f.0 != 0.0 {
+                smaller.push(Exp::Float(FloatWrapper(0.0), ()));
+            }
+            if f.0.trunc() != f.0 {
+                smaller.push(Exp::Float(FloatWrapper(f.0.trunc()), ()));
+            }
+        }
+        _ => {
+            smaller.push(Exp::Num(0, ()));
+            smaller.push(Exp::Bool(false, ()));
+            smaller.push(Exp::Float(FloatWrapper(0.0), ()));
+        }
+    }
+    match e {
+        Exp::If { cond, thn, els, .. } => {
+            smaller.push((**thn).clone());
+            if !is_guard(cond) {
+                smaller.push((**els).clone());
+            }
+        }
+        _ => smaller.extend(children(e).into_iter().cloned()),
+    }
+    match e {
+        Exp::Let { bindings, body, .. } if bindings.len() > 1 => {
+            for i in 0..bindings.len() {
+                let mut bindings = bindings.clone();
+                bindings.remove(i);
+                smaller.push(Exp::Let {
+                    bindings,
+                    body: body.clone(),
+                    ann: (),
+                });
+            }
+        }
+        Exp::FunDefs { decls, body, .. } if decls.len() > 1 => {
+            for i in 0..decls.len() {
+                let mut decls = decls.clone();
+                decls.remove(i);
+                smaller.push(Exp::FunDefs {
+                    decls,
+                    body: body.clone(),
+                    ann: (),
+                });
+            }
+        }
+        Exp::Prim(Prim::MakeTuple, es, _) => {
+            for i in 0..es.len() {
+                let mut es = es.clone();
+                es.remove(i);
+                smaller.push(Exp::Prim(Prim::MakeTuple, es, ()));
+            }
+        }
+        _ => {}
+    }
+    let guarded = matches!(e, Exp::If { cond, .. } if is_guard(cond));
+    for (i, child) in children(e).into_iter().enumerate() {
+        if guarded && i == 0 {
+            continue;
+        }
+        for c in shrinks(child) {
+            let mut e = e.clone();
+            *children_mut(&mut e)[i] = c;
+            smaller.push(e);
+        }
+    }
+    smaller
+}
+
+/* Greedily replaces p by smaller programs that still fail, until no
+ * smaller variant of it does. Only variants that pass the checks of the
+ * compiler are tried.
+ */
+pub fn shrink<F>(p: &SurfProg<()>, mut fails: F) -> SurfProg<()>
+where
+    F: FnMut(&SurfProg<()>) -> bool,
+{
+    let mut p = p.clone();
+    'shrinking: loop {
+        for q in shrinks(&p) {
+            if check_prog(&q).is_ok() && infer_prog(&q).is_ok() && fails(&q) {
+                p = q;
+                continue 'shrinking;
+            }
+        }
+        return p;
+    }
+}
+
+/* Writes p to file and runs it through both the interpreter and the
+ * compiler, building in dir. Returns how they differ, where a panic of
+ * either and assembly that fails to link count as differences.
+ */
+pub fn differ(p: &SurfProg<()>, file: &Path, dir: &Path) -> Result<Option<String>, RunnerErr<Span2>> {
+    std::fs::write(file, pretty(p) + "\n").map_err(|e| RunnerErr::FileOpen(e.to_string()))?;
+    match panic::catch_unwind(AssertUnwindSafe(|| diff_file(file, dir))) {
+        // the compiler emitted assembly that doesn't assemble
+        Ok(Err(e @ RunnerErr::Link(_))) => Ok(Some(format!("{}: {}\n", file.display(), e))),
+        Ok(r) => r,
+        Err(payload) => {
+            let msg = match payload.downcast_ref::<&str>() {
+                Some(s) => s.to_string(),
+                None => payload.downcast_ref::<String>().cloned().unwrap_or_default(),
+            };
+            Ok(Some(format!("{}: panicked: {}\n", file.display(), msg)))
+        }
+    }
+}
+
+/* Runs count random programs, starting with the one for seed, through
+ * both backends, building in dir. The first program they disagree on is
+ * shrunk and saved in examples as fuzz_<seed>.garter. Returns the path
+ * of the reproducer and how the backends differ on it.
+ */
+pub fn fuzz(seed: u64, count: u64, dir: &Path, examples: &Path) -> Result<Option<(PathBuf, String)>, RunnerErr<Span2>> {
+    // panics of the compiler are expected, differ reports them
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let r = find_and_shrink(seed, count, dir, examples);
+    panic::set_hook(hook);
+    r
+}
+
+fn find_and_shrink(seed: u64, count: u64, dir: &Path, examples: &Path) -> Result<Option<(PathBuf, String)>, RunnerErr<Span2>> {
+    let file = dir.join("fuzz.garter");
+    for seed in (0..count).map(|i| seed.wrapping_add(i)) {
+        let p = gen_prog(seed);
+        if differ(&p, &file, dir)?.is_none() {
+            continue;
+        }
+        let p = shrink(&p, |q| matches!(differ(q, &file, dir), Ok(Some(_))));
+        let path = examples.join(format!("fuzz_{}.garter", seed));
+        let report = differ(&p, &path, dir)?.unwrap_or_default();
+        return Ok(Some((path, report)));
+    }
+    Ok(None)
+}
@@ -0,0 +1,1027 @@
+use crate::compile::CompileErr;
+use crate::syntax::{Exp, ImmExp, Pattern, Prim, SeqExp, SeqProg, SurfFunDecl, SurfProg, Type};
+use std::collections::{HashMap, HashSet};
+
+// A type with some of its variables generalized, e.g. the type of
+// `def id(x): x` is forall a. (a) -> a
+#[derive(Clone, Debug)]
+struct Scheme {
+    vars: Vec<u32>,
+    typ: Type,
+    // the annotations on the parameters of a function, which its
+    // arguments are checked against even where code isn't annotated
+    declared: Vec<Option<Type>>,
+}
+
+impl Scheme {
+    fn mono(typ: Type) -> Self {
+        Scheme {
+            vars: vec![],
+            typ,
+            declared: vec![],
+        }
+    }
+}
+
+type TypeEnv = HashMap<String, Scheme>;
+
+// What binding a variable or constraining it added, to take back
+#[derive(Clone)]
+enum Undo {
+    Subst(u32),
+    Numeric(u32),
+    Indexable(u32),
+}
+
+#[derive(Clone)]
+struct Infer {
+    // solutions of the unification variables found so far
+    subst: HashMap<u32, Type>,
+    // variables that can only stand for Int or Float
+    numeric: HashSet<u32>,
+    // variables that can only stand for Tuple or Array
+    indexable: HashSet<u32>,
+    next: u32,
+    // what was added to the above, in order
+    trail: Vec<Undo>,
+    // whether the code being checked is in an annotated function, where
+    // a mismatch is an error; elsewhere its value is left to the tag
+    // checks of the runtime
+    strict: bool,
+}
+
+impl Infer {
+    fn new() -> Self {
+        Infer {
+            subst: HashMap::new(),
+            numeric: HashSet::new(),
+            indexable: HashSet::new(),
+            next: 0,
+            trail: vec![],
+            strict: false,
+        }
+    }
+
+    fn constrain_numeric(&mut self, v: u32) {
+        if self.numeric.insert(v) {
+            self.trail.push(Undo::Numeric(v));
+        }
+    }
+
+    fn constrain_indexable(&mut self, v: u32) {
+        if self.indexable.insert(v) {
+            self.trail.push(Undo::Indexable(v));
+        }
+    }
+
+    fn solve(&mut self, v: u32, t: Type) {
+        self.subst.insert(v, t);
+        self.trail.push(Undo::Subst(v));
+    }
+
+    // Takes back everything added since the trail was mark long
+    fn undo(&mut self, mark: usize) {
+        while self.trail.len() > mark {
+            match self.trail.pop() {
+                Some(Undo::Subst(v)) => {
+                    self.subst.remove(&v);
+                }
+                Some(Undo::Numeric(v)) => {
+                    self.numeric.remove(&v);
+                }
+                Some(Undo::Indexable(v)) => {
+                    self.indexable.remove(&v);
+                }
+                None => {}
+            }
+        }
+    }
+
+    // Unifies the types, or leaves everything as it was if they don't
+    fn try_unify(&mut self, t1: &Type, t2: &Type) -> Result<(), ()> {
+        let mark = self.trail.len();
+        let r = self.unify(t1, t2);
+        if r.is_err() {
+            self.undo(mark);
+        }
+        r
+    }
+
+    // A mismatch is only an error in annotated code
+    fn mismatch<Span>(&self, err: CompileErr<Span>) -> Result<(), CompileErr<Span>> {
+        if self.strict {
+            Err(err)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn fresh(&mut self) -> Type {
+        self.next += 1;
+        Type::Var(self.next)
+    }
+
+    fn fresh_numeric(&mut self) -> Type {
+        self.next += 1;
+        self.constrain_numeric(self.next);
+        Type::Var(self.next)
+    }
+
+    fn fresh_indexable(&mut self) -> Type {
+        self.next += 1;
+        self.constrain_indexable(self.next);
+        Type::Var(self.next)
+    }
+
+    // follows the solutions of variables until something else is found
+    fn shallow(&self, t: &Type) -> Type {
+        match t {
+            Type::Var(v) => match self.subst.get(v) {
+                Some(t) => self.shallow(t),
+                None => t.clone(),
+            },
+            _ => t.clone(),
+        }
+    }
+
+    fn resolve(&self, t: &Type) -> Type {
+        match self.shallow(t) {
+            Type::Fun(params, ret) => Type::Fun(
+                params.iter().map(|p| self.resolve(p)).collect(),
+                Box::new(self.resolve(&ret)),
+            ),
+            t => t,
+        }
+    }
+
+    // how a type shows up in error messages
+    fn describe(&self, t: &Type) -> String {
+        match self.resolve(t) {
+            Type::Var(v) if self.numeric.contains(&v) => "a number or float".to_string(),
+            Type::Var(v) if self.indexable.contains(&v) => "a tuple or an array".to_string(),
+            t => t.to_string(),
+        }
+    }
+
+    fn free_vars(&self, t: &Type, acc: &mut HashSet<u32>) {
+        match self.shallow(t) {
+            Type::Var(v) => {
+                acc.insert(v);
+            }
+            Type::Fun(params, ret) => {
+                for p in params.iter() {
+                    self.free_vars(p, acc);
+                }
+                self.free_vars(&ret, acc);
+            }
+            _ => {}
+        }
+    }
+
+    fn bind(&mut self, v: u32, t: Type) -> Result<(), ()> {
+        let mut vars = HashSet::new();
+        self.free_vars(&t, &mut vars);
+        if vars.contains(&v) {
+            // a function applied to itself, nothing useful to say about it
+            self.solve(v, Type::Dyn);
+            return Ok(());
+        }
+        if let Type::Var(w) = t {
+            // no type is both a number and indexable
+            if self.numeric.contains(&v) && self.indexable.contains(&w)
+                || self.indexable.contains(&v) && self.numeric.contains(&w)
+            {
+                return Err(());
+            }
+        }
+        if self.numeric.contains(&v) {
+            match t {
+                Type::Int | Type::Float | Type::Dyn => {}
+                Type::Var(w) => self.constrain_numeric(w),
+                _ => return Err(()),
+            }
+        }
+        if self.indexable.contains(&v) {
+            match t {
+                Type::Tuple | Type::Array | Type::Dyn => {}
+                Type::Var(w) => self.constrain_indexable(w),
+                _ => return Err(()),
+            }
+        }
+        self.solve(v, t);
+        Ok(())
+    }
+
+    fn unify(&mut self, t1: &Type, t2: &Type) -> Result<(), ()> {
+        match (self.shallow(t1), self.shallow(t2)) {
+            (Type::Var(v1), Type::Var(v2)) if v1 == v2 => Ok(()),
+            (Type::Var(v), t) | (t, Type::Var(v)) => self.bind(v, t),
+            (Type::Dyn, _) | (_, Type::Dyn) => Ok(()),
+            (Type::Int, Type::Int)
+            | (Type::Float, Type::Float)
+            | (Type::Bool, Type::Bool)
+            | (Type::Tuple, Type::Tuple)
+            | (Type::String, Type::String)
+            | (Type::Array, Type::Array) => Ok(()),
+            (Type::Data(name1), Type::Data(name2)) if name1 == name2 => Ok(()),
+            (Type::Fun(params1, ret1), Type::Fun(params2, ret2)) => {
+                if params1.len() != params2.len() {
+                    return Err(());
+                }
+                for (p1, p2) in params1.iter().zip(params2.iter()) {
+                    self.unify(p1, p2)?;
+                }
+                self.unify(&ret1, &ret2)
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn generalize(&self, env: &TypeEnv, t: &Type) -> Scheme {
+        let mut in_env = HashSet::new();
+        for scheme in env.values() {
+            let mut vars = HashSet::new();
+            self.free_vars(&scheme.typ, &mut vars);
+            for v in vars {
+                if !scheme.vars.contains(&v) {
+                    in_env.insert(v);
+                }
+            }
+        }
+        let mut vars = HashSet::new();
+        self.free_vars(t, &mut vars);
+        Scheme {
+            vars: vars.into_iter().filter(|v| !in_env.contains(v)).collect(),
+            typ: self.resolve(t),
+            declared: vec![],
+        }
+    }
+
+    fn instantiate(&mut self, s: &Scheme) -> Type {
+        let mut renaming = HashMap::new();
+        for v in s.vars.iter() {
+            let fresh = if self.numeric.contains(v) {
+                self.fresh_numeric()
+            } else if self.indexable.contains(v) {
+                self.fresh_indexable()
+            } else {
+                self.fresh()
+            };
+            renaming.insert(*v, fresh);
+        }
+        self.rename(&s.typ, &renaming)
+    }
+
+    fn rename(&self, t: &Type, renaming: &HashMap<u32, Type>) -> Type {
+        match self.shallow(t) {
+            Type::Var(v) => match renaming.get(&v) {
+                Some(t) => t.clone(),
+                None => Type::Var(v),
+            },
+            Type::Fun(params, ret) => Type::Fun(
+                params.iter().map(|p| self.rename(p, renaming)).collect(),
+                Box::new(self.rename(&ret, renaming)),
+            ),
+            t => t,
+        }
+    }
+
+    // operands of arithmetic and comparisons can be numbers or floats
+    fn numeric<Span>(&mut self, t: &Type, who: &str, ann: &Span) -> Result<(), CompileErr<Span>>
+    where
+        Span: Clone,
+    {
+        match self.shallow(t) {
+            Type::Int | Type::Float | Type::Dyn => Ok(()),
+            Type::Var(v) if !self.indexable.contains(&v) => {
+                self.constrain_numeric(v);
+                Ok(())
+            }
+            t => self.mismatch(CompileErr::TypeMismatch {
+                who: who.to_string(),
+                expected: "a number or float".to_string(),
+                got: self.describe(&t),
+                location: ann.clone(),
+            }),
+        }
+    }
+
+    // indexing works on tuples and arrays alike
+    fn indexable<Span>(&mut self, t: &Type, ann: &Span) -> Result<(), CompileErr<Span>>
+    where
+        Span: Clone,
+    {
+        match self.shallow(t) {
+            Type::Tuple | Type::Array | Type::Dyn => Ok(()),
+            Type::Var(v) if !self.numeric.contains(&v) => {
+                self.constrain_indexable(v);
+                Ok(())
+            }
+            t => self.mismatch(CompileErr::TypeMismatch {
+                who: "indexing".to_string(),
+                expected: "a tuple or an array".to_string(),
+                got: self.describe(&t),
+                location: ann.clone(),
+            }),
+        }
+    }
+
+    fn expect<Span>(
+        &mut self,
+        t: &Type,
+        expected: Type,
+        who: &str,
+        what: &str,
+        ann: &Span,
+    ) -> Result<(), CompileErr<Span>>
+    where
+        Span: Clone,
+    {
+        if self.try_unify(t, &expected).is_err() {
+            return self.mismatch(CompileErr::TypeMismatch {
+                who: who.to_string(),
+                expected: what.to_string(),
+                got: self.describe(t),
+                location: ann.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    fn arith_result(&mut self, t1: &Type, t2: &Type) -> Type {
+        match (self.shallow(t1), self.shallow(t2)) {
+            (Type::Int, Type::Int) => Type::Int,
+            (Type::Float, _) | (_, Type::Float) => Type::Float,
+            _ => self.fresh_numeric(),
+        }
+    }
+
+    fn call<Span>(
+        &mut self,
+        fun_type: &Type,
+        arg_types: Vec<Type>,
+        name: &str,
+        ann: &Span,
+    ) -> Result<Type, CompileErr<Span>>
+    where
+        Span: Clone,
+    {
+        match self.shallow(fun_type) {
+            Type::Fun(params, ret) => {
+                if params.len() != arg_types.len() {
+                    self.mismatch(CompileErr::ArityMismatch {
+                        expected: params.len(),
+                        got: arg_types.len(),
+                        location: ann.clone(),
+                    })?;
+                    return Ok(Type::Dyn);
+                }
+                for (i, (param, arg)) in params.iter().zip(arg_types.iter()).enumerate() {
+                    if self.try_unify(param, arg).is_err() {
+                        self.mismatch(CompileErr::TypeMismatch {
+                            who: format!("argument {} of {}", i + 1, name),
+                            expected: self.describe(param),
+                            got: self.describe(arg),
+                            location: ann.clone(),
+                        })?;
+                        return Ok(Type::Dyn);
+                    }
+                }
+                Ok(*ret)
+            }
+            Type::Dyn => Ok(Type::Dyn),
+            t => {
+                let ret = self.fresh();
+                if self.try_unify(&t, &Type::Fun(arg_types, Box::new(ret.clone()))).is_err() {
+                    self.mismatch(CompileErr::TypeMismatch {
+                        who: "call".to_string(),
+                        expected: "a function".to_string(),
+                        got: self.describe(&t),
+                        location: ann.clone(),
+                    })?;
+                    return Ok(Type::Dyn);
+                }
+                Ok(ret)
+            }
+        }
+    }
+}
+
+// Hindley-Milner style type inference. Tuples may hold values of any
+// type, so their elements are Dyn, which is compatible with every type.
+// Only annotated functions, and the arguments of their annotated
+// parameters, are checked strictly; a mismatch in other code makes the
+// value Dyn and leaves it to the tag checks of the runtime.
+pub fn infer_prog<Span>(p: &SurfProg<Span>) -> Result<(), CompileErr<Span>>
+where
+    Span: Clone,
+{
+    infer_exp(p, &HashMap::new(), &mut Infer::new())?;
+    Ok(())
+}
+
+/* The types of the names bound by earlier inputs of the REPL. A
+ * failing input leaves the session as it was before it.
+ */
+#[derive(Clone)]
+pub struct TypeSession {
+    env: TypeEnv,
+    st: Infer,
+}
+
+impl TypeSession {
+    pub fn new() -> Self {
+        TypeSession {
+            env: HashMap::new(),
+            st: Infer::new(),
+        }
+    }
+
+    pub fn define_let<Span>(&mut self, bindings: &[(String, Exp<Span>)]) -> Result<(), CompileErr<Span>>
+    where
+        Span: Clone,
+    {
+        let mut st = self.st.clone();
+        self.env = infer_bindings(bindings, &self.env, &mut st)?;
+        self.st = st;
+        Ok(())
+    }
+
+    pub fn define_funs<Span>(&mut self, decls: &[SurfFunDecl<Span>]) -> Result<(), CompileErr<Span>>
+    where
+        Span: Clone,
+    {
+        let mut st = self.st.clone();
+        self.env = infer_decls(decls, &self.env, &mut st)?;
+        self.st = st;
+        Ok(())
+    }
+
+    // The type of e, with its variables numbered from 1 in the order
+    // they show up
+    pub fn type_of<Span>(&self, e: &Exp<Span>) -> Result<Type, CompileErr<Span>>
+    where
+        Span: Clone,
+    {
+        let mut st = self.st.clone();
+        let t = infer_exp(e, &self.env, &mut st)?;
+        let t = st.resolve(&t);
+        let mut free = vec![];
+        vars_in_order(&t, &mut free);
+        let renaming = free
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (*v, Type::Var(i as u32 + 1)))
+            .collect();
+        Ok(st.rename(&t, &renaming))
+    }
+}
+
+impl Default for TypeSession {
+    fn default() -> Self {
+        TypeSession::new()
+    }
+}
+
+fn vars_in_order(t: &Type, acc: &mut Vec<u32>) {
+    match t {
+        Type::Var(v) if !acc.contains(v) => acc.push(*v),
+        Type::Fun(params, ret) => {
+            for p in params {
+                vars_in_order(p, acc);
+            }
+            vars_in_order(ret, acc);
+        }
+        _ => {}
+    }
+}
+
+fn infer_exp<Span>(e: &Exp<Span>, env: &TypeEnv, st: &mut Infer) -> Result<Type, CompileErr<Span>>
+where
+    Span: Clone,
+{
+    match e {
+        Exp::Num(_, _) => Ok(Type::Int),
+        Exp::Bool(_, _) => Ok(Type::Bool),
+        Exp::Float(_, _) => Ok(Type::Float),
+        Exp::Str(_, _) => Ok(Type::String),
+        Exp::Var(x, _) => match env.get(x) {
+            Some(scheme) => Ok(st.instantiate(scheme)),
+            None => Ok(Type::Dyn),
+        },
+        Exp::Prim(op, es, ann) => {
+            let mut ts = vec![];
+            for e in es {
+                ts.push(infer_exp(e, env, st)?);
+            }
+            match op {
+                Prim::Add1 | Prim::Sub1 => {
+                    st.numeric(&ts[0], "arithmetic", ann)?;
+                    Ok(st.arith_result(&ts[0], &Type::Int))
+                }
+                Prim::Add | Prim::Sub | Prim::Mul => {
+                    st.numeric(&ts[0], "arithmetic", ann)?;
+                    st.numeric(&ts[1], "arithmetic", ann)?;
+                    Ok(st.arith_result(&ts[0], &ts[1]))
+                }
+                Prim::Div | Prim::FloorDiv => {
+                    st.numeric(&ts[0], "arithmetic", ann)?;
+                    st.numeric(&ts[1], "arithmetic", ann)?;
+                    Ok(if *op == Prim::Div { Type::Float } else { Type::Int })
+                }
+                Prim::Sqrt | Prim::Cos => {
+                    st.numeric(&ts[0], "arithmetic", ann)?;
+                    Ok(Type::Float)
+                }
+                Prim::Lt | Prim::Gt | Prim::Le | Prim::Ge => {
+                    st.numeric(&ts[0], "comparison", ann)?;
+                    st.numeric(&ts[1], "comparison", ann)?;
+                    Ok(Type::Bool)
+                }
+                Prim::And | Prim::Or | Prim::Not => {
+                    for t in ts.iter() {
+                        st.expect(t, Type::Bool, "logic", "a boolean", ann)?;
+                    }
+                    Ok(Type::Bool)
+                }
+                Prim::Eq | Prim::Neq => Ok(Type::Bool),
+                Prim::IsBool | Prim::IsNum | Prim::IsFloat | Prim::IsTuple => Ok(Type::Bool),
+                Prim::Print => Ok(ts[0].clone()),
+                Prim::MakeTuple => Ok(Type::Tuple),
+                Prim::GetIndex => {
+                    st.indexable(&ts[0], ann)?;
+                    st.expect(&ts[1], Type::Int, "index", "a number", ann)?;
+                    Ok(Type::Dyn)
+                }
+                Prim::MakeArray => {
+                    st.expect(&ts[0], Type::Int, "array size", "a number", ann)?;
+                    Ok(Type::Array)
+                }
+                Prim::SetIndex => {
+                    st.expect(&ts[0], Type::Array, "array operation", "an array", ann)?;
+                    st.expect(&ts[1], Type::Int, "index", "a number", ann)?;
+                    Ok(ts[2].clone())
+                }
+                Prim::Length => {
+                    st.expect(&ts[0], Type::Array, "array operation", "an array", ann)?;
+                    Ok(Type::Int)
+                }
+                Prim::Concat => {
+                    st.expect(&ts[0], Type::String, "string operation", "a string", ann)?;
+                    st.expect(&ts[1], Type::String, "string operation", "a string", ann)?;
+                    Ok(Type::String)
+                }
+                Prim::Len => {
+                    st.expect(&ts[0], Type::String, "string operation", "a string", ann)?;
+                    Ok(Type::Int)
+                }
+                Prim::Substr => {
+                    st.expect(&ts[0], Type::String, "string operation", "a string", ann)?;
+                    st.expect(&ts[1], Type::Int, "index", "a number", ann)?;
+                    st.expect(&ts[2], Type::Int, "index", "a number", ann)?;
+                    Ok(Type::String)
+                }
+                Prim::ToString => Ok(Type::String),
+                Prim::IsVariant => Ok(Type::Bool),
+                Prim::ClosureEnv
+                | Prim::Unbox
+                | Prim::SetBox
+                | Prim::MakeVariant
+                | Prim::VariantField
+                | Prim::MatchFail => Ok(Type::Dyn),
+            }
+        }
+        Exp::Let { bindings, body, .. } => {
+            let env = infer_bindings(bindings, env, st)?;
+            infer_exp(body, &env, st)
+        }
+        Exp::If {
+            cond,
+            thn,
+            els,
+            ann,
+        } => {
+            let t = infer_exp(cond, env, st)?;
+            st.expect(&t, Type::Bool, "if", "a boolean", ann)?;
+            let t1 = infer_exp(thn, env, st)?;
+            let t2 = infer_exp(els, env, st)?;
+            if st.try_unify(&t1, &t2).is_err() {
+                st.mismatch(CompileErr::TypeMismatch {
+                    who: "the else branch".to_string(),
+                    expected: st.describe(&t1),
+                    got: st.describe(&t2),
+                    location: els.ann(),
+                })?;
+                return Ok(Type::Dyn);
+            }
+            Ok(t1)
+        }
+        Exp::FunDefs { decls, body, .. } => {
+            let env = infer_decls(decls, env, st)?;
+            infer_exp(body, &env, st)
+        }
+        Exp::Semicolon { e1, e2, .. } => {
+            infer_exp(e1, env, st)?;
+            infer_exp(e2, env, st)
+        }
+        Exp::While { cond, body, ann } => {
+            let t = infer_exp(cond, env, st)?;
+            st.expect(&t, Type::Bool, "while", "a boolean", ann)?;
+            infer_exp(body, env, st)?;
+            Ok(Type::Bool)
+        }
+        Exp::Assign { var, value, ann } => {
+            let t = infer_exp(value, env, st)?;
+            if let Some(scheme) = env.get(var) {
+                let var_type = st.instantiate(scheme);
+                if st.try_unify(&var_type, &t).is_err() {
+                    st.mismatch(CompileErr::TypeMismatch {
+                        who: format!("the assignment to {}", var),
+                        expected: st.describe(&var_type),
+                        got: st.describe(&t),
+                        location: ann.clone(),
+                    })?;
+                }
+            }
+            Ok(t)
+        }
+        Exp::Lambda {
+            parameters, body, ..
+        } => {
+            let mut env = env.clone();
+            let mut params = vec![];
+            for x in parameters {
+                let t = st.fresh();
+                env.insert(x.clone(), Scheme::mono(t.clone()));
+                params.push(t);
+            }
+            let ret = infer_exp(body, &env, st)?;
+            Ok(Type::Fun(params, Box::new(ret)))
+        }
+        Exp::Call(fun, args, ann) => {
+            let (fun_type, declared) = match env.get(fun) {
+                Some(scheme) => (st.instantiate(scheme), scheme.declared.clone()),
+                None => (Type::Dyn, vec![]),
+            };
+            let mut arg_types = vec![];
+            for arg in args {
+                arg_types.push(infer_exp(arg, env, st)?);
+            }
+            for (i, (param, arg)) in declared.iter().zip(arg_types.iter()).enumerate() {
+                if let Some(param) = param {
+                    if st.try_unify(param, arg).is_err() {
+                        return Err(CompileErr::TypeMismatch {
+                            who: format!("argument {} of {}", i + 1, fun),
+                            expected: st.describe(param),
+                            got: st.describe(arg),
+                            location: ann.clone(),
+                        });
+                    }
+                }
+            }
+            st.call(&fun_type, arg_types, fun, ann)
+        }
+        Exp::ClosureCall { fun, args, ann, .. } => {
+            let fun_type = infer_exp(fun, env, st)?;
+            let mut arg_types = vec![];
+            for arg in args {
+                arg_types.push(infer_exp(arg, env, st)?);
+            }
+            st.call(&fun_type, arg_types, "the function", ann)
+        }
+        // a constructor has the type of a function from its fields,
+        // which may hold anything like the elements of a tuple
+        Exp::TypeDecl {
+            name,
+            constructors,
+            body,
+            ..
+        } => {
+            let mut env = env.clone();
+            for c in constructors {
+                let t = Type::Fun(vec![Type::Dyn; c.fields.len()], Box::new(Type::Data(name.clone())));
+                env.insert(c.name.clone(), Scheme::mono(t));
+            }
+            infer_exp(body, &env, st)
+        }
+        Exp::Construct { name, args, ann } => {
+            let fun_type = match env.get(name) {
+                Some(scheme) => st.instantiate(scheme),
+                None => Type::Dyn,
+            };
+            let mut arg_types = vec![];
+            for arg in args {
+                arg_types.push(infer_exp(arg, env, st)?);
+            }
+            st.call(&fun_type, arg_types, name, ann)
+        }
+        Exp::Match { scrutinee, arms, ann } => {
+            let t = infer_exp(scrutinee, env, st)?;
+            let mut arm_types: Vec<Type> = vec![];
+            let mut dynamic = false;
+            for (pattern, body) in arms {
+                let mut env = env.clone();
+                match pattern {
+                    Pattern::Wildcard(_) => {}
+                    Pattern::Var(x, _) => {
+                        env.insert(x.clone(), Scheme::mono(t.clone()));
+                    }
+                    Pattern::Constructor(c, ..) => {
+                        if let Some(Type::Fun(_, ret)) = env.get(c).map(|scheme| scheme.typ.clone()) {
+                            st.expect(&t, (*ret).clone(), "match", &ret.to_string(), ann)?;
+                        }
+                        for x in pattern.vars() {
+                            env.insert(x.clone(), Scheme::mono(Type::Dyn));
+                        }
+                    }
+                }
+                let arm_type = infer_exp(body, &env, st)?;
+                if let Some(first) = arm_types.first().cloned() {
+                    if st.try_unify(&first, &arm_type).is_err() {
+                        st.mismatch(CompileErr::TypeMismatch {
+                            who: "this arm of the match".to_string(),
+                            expected: st.describe(&first),
+                            got: st.describe(&arm_type),
+                            location: body.ann(),
+                        })?;
+                        dynamic = true;
+                    }
+                }
+                arm_types.push(arm_type);
+            }
+            if dynamic {
+                return Ok(Type::Dyn);
+            }
+            Ok(arm_types.swap_remove(0))
+        }
+        Exp::InternalTailCall(..) | Exp::ExternalCall { .. } | Exp::MakeClosure { .. } => {
+            panic!("Should never happen: type inference called with internal compiler forms")
+        }
+    }
+}
+
+// The environment of the body of a let
+fn infer_bindings<Span>(
+    bindings: &[(String, Exp<Span>)],
+    env: &TypeEnv,
+    st: &mut Infer,
+) -> Result<TypeEnv, CompileErr<Span>>
+where
+    Span: Clone,
+{
+    let mut env = env.clone();
+    for (x, e) in bindings {
+        let t = infer_exp(e, &env, st)?;
+        let scheme = st.generalize(&env, &t);
+        env.insert(x.clone(), scheme);
+    }
+    Ok(env)
+}
+
+// The environment of the body of a group of function definitions
+fn infer_decls<Span>(
+    decls: &[SurfFunDecl<Span>],
+    env: &TypeEnv,
+    st: &mut Infer,
+) -> Result<TypeEnv, CompileErr<Span>>
+where
+    Span: Clone,
+{
+    // the declarations are mutually recursive, so they are
+    // only generalized once all of them have been checked
+    let mut rec_env = env.clone();
+    let mut fun_types = vec![];
+    for decl in decls {
+        let params: Vec<Type> = (0..decl.parameters.len())
+            .map(|i| match decl.signature.parameters.get(i) {
+                Some(Some(t)) => t.clone(),
+                _ => st.fresh(),
+            })
+            .collect();
+        let ret = match &decl.signature.ret {
+            Some(t) => t.clone(),
+            None => st.fresh(),
+        };
+        let t = Type::Fun(params, Box::new(ret));
+        let scheme = Scheme {
+            declared: decl.signature.parameters.clone(),
+            ..Scheme::mono(t.clone())
+        };
+        rec_env.insert(decl.name.clone(), scheme);
+        fun_types.push(t);
+    }
+    for (decl, t) in decls.iter().zip(fun_types.iter()) {
+        let (params, ret) = match t {
+            Type::Fun(params, ret) => (params, ret),
+            _ => unreachable!(),
+        };
+        let mut body_env = rec_env.clone();
+        for (x, t) in decl.parameters.iter().zip(params.iter()) {
+            body_env.insert(x.clone(), Scheme::mono(t.clone()));
+        }
+        let outer = std::mem::replace(&mut st.strict, annotated(decl));
+        let checked = infer_exp(&decl.body, &body_env, st).and_then(|body_type| {
+            if st.try_unify(&body_type, ret).is_err() {
+                st.mismatch(CompileErr::TypeMismatch {
+                    who: format!("the body of {}", decl.name),
+                    expected: st.describe(ret),
+                    got: st.describe(&body_type),
+                    location: decl.ann.clone(),
+                })?;
+            }
+            Ok(())
+        });
+        st.strict = outer;
+        checked?;
+    }
+    let schemes: Vec<Scheme> = fun_types.iter().map(|t| st.generalize(env, t)).collect();
+    let mut env = env.clone();
+    for (decl, mut scheme) in decls.iter().zip(schemes) {
+        scheme.declared = decl.signature.parameters.clone();
+        env.insert(decl.name.clone(), scheme);
+    }
+    Ok(env)
+}
+
+fn annotated<Span>(decl: &SurfFunDecl<Span>) -> bool {
+    decl.signature.ret.is_some() || decl.signature.parameters.iter().any(|t| t.is_some())
+}
+
+/* Types that hold for every value an expression can produce, no
+ * matter which arguments the enclosing function is called with.
+ * Unlike the inferred types, these don't trust the parameters, since
+ * a function can be called through a closure with anything. The
+ * codegen leaves out the tag checks of operands with a proven type.
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Proven {
+    Int,
+    Float,
+    // either Int or Float
+    Number,
+    Bool,
+    Tuple,
+    String,
+    Array,
+}
+
+impl Proven {
+    pub fn is_number(&self) -> bool {
+        matches!(self, Proven::Int | Proven::Float | Proven::Number)
+    }
+}
+
+pub type ProvenTypes = HashMap<String, Option<Proven>>;
+
+pub fn proven_types<Ann>(p: &SeqProg<Ann>) -> ProvenTypes {
+    // nothing is proven about a variable that is assigned, since its
+    // uses can come before the assignments or after them
+    let mut assigned = HashSet::new();
+    for decl in p.funs.iter() {
+        assigned_vars(&decl.body, &mut assigned);
+    }
+    assigned_vars(&p.main, &mut assigned);
+    let mut types: ProvenTypes = assigned.into_iter().map(|x| (x, None)).collect();
+    for decl in p.funs.iter() {
+        proven_seq(&decl.body, &mut types);
+    }
+    proven_seq(&p.main, &mut types);
+    types
+}
+
+// The proven type of the value of e, e.g. the condition of a loop
+pub fn proven_seq_type<Ann>(e: &SeqExp<Ann>, types: &ProvenTypes) -> Option<Proven> {
+    proven_seq(e, &mut types.clone())
+}
+
+pub fn proven_imm(e: &ImmExp, types: &ProvenTypes) -> Option<Proven> {
+    match e {
+        ImmExp::Num(_) => Some(Proven::Int),
+        ImmExp::Float(_) => Some(Proven::Float),
+        ImmExp::Bool(_) => Some(Proven::Bool),
+        ImmExp::Str(_) => Some(Proven::String),
+        ImmExp::Var(x) => types.get(x).cloned().flatten(),
+    }
+}
+
+fn assigned_vars<Ann>(e: &SeqExp<Ann>, acc: &mut HashSet<String>) {
+    match e {
+        SeqExp::Assign { var, .. } => {
+            acc.insert(var.clone());
+        }
+        SeqExp::Let {
+            bound_exp, body, ..
+        } => {
+            assigned_vars(bound_exp, acc);
+            assigned_vars(body, acc);
+        }
+        SeqExp::If { thn, els, .. } => {
+            assigned_vars(thn, acc);
+            assigned_vars(els, acc);
+        }
+        SeqExp::While { cond, body, .. } => {
+            assigned_vars(cond, acc);
+            assigned_vars(body, acc);
+        }
+        SeqExp::FunDefs { decls, body, .. } => {
+            for decl in decls.iter() {
+                assigned_vars(&decl.body, acc);
+            }
+            assigned_vars(body, acc);
+        }
+        _ => {}
+    }
+}
+
+fn join(t1: Option<Proven>, t2: Option<Proven>) -> Option<Proven> {
+    match (t1, t2) {
+        (Some(t1), Some(t2)) if t1 == t2 => Some(t1),
+        (Some(t1), Some(t2)) if t1.is_number() && t2.is_number() => Some(Proven::Number),
+        _ => None,
+    }
+}
+
+fn proven_seq<Ann>(e: &SeqExp<Ann>, types: &mut ProvenTypes) -> Option<Proven> {
+    match e {
+        SeqExp::Imm(imm, _) => proven_imm(imm, types),
+        SeqExp::Prim(op, args, _) => {
+            let arg = |i: usize| proven_imm(&args[i], types);
+            match op {
+                Prim::Add1 | Prim::Sub1 => match arg(0) {
+                    Some(Proven::Int) => Some(Proven::Int),
+                    Some(Proven::Float) => Some(Proven::Float),
+                    _ => Some(Proven::Number),
+                },
+                Prim::Add | Prim::Sub | Prim::Mul => match (arg(0), arg(1)) {
+                    (Some(Proven::Int), Some(Proven::Int)) => Some(Proven::Int),
+                    (Some(Proven::Float), _) | (_, Some(Proven::Float)) => Some(Proven::Float),
+                    _ => Some(Proven::Number),
+                },
+                Prim::Div | Prim::Sqrt | Prim::Cos => Some(Proven::Float),
+                Prim::FloorDiv | Prim::Len | Prim::Length => Some(Proven::Int),
+                Prim::Concat | Prim::Substr | Prim::ToString => Some(Proven::String),
+                Prim::Lt
+                | Prim::Gt
+                | Prim::Le
+                | Prim::Ge
+                | Prim::Eq
+                | Prim::Neq
+                | Prim::And
+                | Prim::Or
+                | Prim::Not
+                | Prim::IsBool
+                | Prim::IsNum
+                | Prim::IsFloat
+                | Prim::IsTuple
+                | Prim::IsVariant => Some(Proven::Bool),
+                Prim::Print => arg(0),
+                Prim::MakeTuple => Some(Proven::Tuple),
+                Prim::MakeArray => Some(Proven::Array),
+                Prim::SetIndex => arg(2),
+                Prim::GetIndex
+                | Prim::ClosureEnv
+                | Prim::Unbox
+                | Prim::MakeVariant
+                | Prim::VariantField
+                | Prim::MatchFail => None,
+                Prim::SetBox => arg(1),
+            }
+        }
+        SeqExp::Let {
+            var,
+            bound_exp,
+            body,
+            ..
+        } => {
+            let t = proven_seq(bound_exp, types);
+            // a name bound in several places only keeps what they agree on
+            let t = match types.get(var) {
+                Some(old) => join(*old, t),
+                None => t,
+            };
+            types.insert(var.clone(), t);
+            proven_seq(body, types)
+        }
+        SeqExp::If { thn, els, .. } => {
+            let t1 = proven_seq(thn, types);
+            let t2 = proven_seq(els, types);
+            join(t1, t2)
+        }
+        SeqExp::While { cond, body, .. } => {
+            proven_seq(cond, types);
+            proven_seq(body, types);
+            Some(Proven::Bool)
+        }
+        SeqExp::Assign { value, .. } => proven_imm(value, types),
+        SeqExp::FunDefs { decls, body, .. } => {
+            for decl in decls.iter() {
+                proven_seq(&decl.body, types);
+            }
+            proven_seq(body, types)
+        }
+        SeqExp::InternalTailCall(..)
+        | SeqExp::ExternalCall { .. }
+        | SeqExp::ClosureCall { .. }
+        | SeqExp::MakeClosure { .. } => None,
+    }
+}
@@ -1,5 +1,6 @@
-use crate::syntax::{Exp, Prim, SurfProg, SurfFunDecl, FloatWrapper};
+use crate::syntax::{unqualified, Constructor, Exp, Pattern, Prim, SurfProg, SurfFunDecl, FloatWrapper};
 
+use std::cell::RefCell;
 use std::rc::Rc;
 
 #[derive(Debug, Clone)]
@@ -7,7 +8,16 @@ enum SnakeVal {
     Num(i64), // should fit into 63 bits though
     Bool(bool),
     Float(f64),
+    Tuple(usize),   // index into the heap
     Closure(usize), // index into the closure arena
+    // the name and number of the constructor, and the index of the
+    // fields in the heap
+    Variant(Rc<str>, usize, usize),
+    // strings are immutable, so they are shared rather than copied
+    Str(Rc<[u8]>),
+    // arrays are mutated in place, so unlike tuples they can hold
+    // themselves
+    Array(usize), // index into the heap
 }
 
 // A reference-counted linked list/the functional programmer's List
@@ -17,10 +27,13 @@ enum List<T> {
     Cons(T, Rc<List<T>>),
 }
 
-type Env = Rc<List<(String, SnakeVal)>>;
+// Every name is bound to a cell, so x := e updates x for the closures
+// that captured it too. Constructors are bound to their number, under
+// their name, which no variable can have.
+type Env = Rc<List<(String, Rc<RefCell<SnakeVal>>)>>;
 
 fn push_local(env: &Env, name: String, v: SnakeVal) -> Env {
-    Rc::new(List::Cons((name, v), env.clone()))
+    Rc::new(List::Cons((name, Rc::new(RefCell::new(v))), env.clone()))
 }
 
 #[derive(Debug, Clone)]
@@ -29,26 +42,53 @@ struct Closure<'exp, Ann> {
     env: Env,
 }
 
+// The errors print the same messages as the runtime of the compiled code
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum InterpErr {
     ExpectedNum {
         who: String,
         got: String,
-        msg: String,
+    },
+    ExpectedNumOrFloat {
+        who: String,
+        got: String,
     },
     ExpectedBool {
         who: String,
         got: String,
-        msg: String,
     },
     ExpectedFun {
         who: String,
         got: String,
-        msg: String,
     },
-    Overflow {
-        msg: String,
+    ExpectedTuple {
+        who: String,
+        got: String,
+    },
+    ExpectedString {
+        who: String,
+        got: String,
+    },
+    ExpectedArray {
+        who: String,
+        got: String,
+    },
+    IndexOutOfBounds {
+        index: i64,
+    },
+    ArrayIndexOutOfBounds {
+        index: i64,
+        length: usize,
     },
+    ArraySize {
+        got: String,
+    },
+    OutOfMemory {
+        words: u64,
+    },
+    Overflow,
+    DivisionByZero,
+    SqrtNegative,
     Write {
         msg: String,
     },
@@ -56,6 +96,9 @@ pub enum InterpErr {
         expected_arity: usize,
         num_provided: usize,
     },
+    NoMatch {
+        got: String,
+    },
 }
 
 type Interp<T> = Result<T, InterpErr>;
@@ -63,30 +106,45 @@ type Interp<T> = Result<T, InterpErr>;
 use std::fmt;
 use std::fmt::Display;
 
-impl Display for SnakeVal {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            SnakeVal::Num(n) => write!(f, "{}", n),
-            SnakeVal::Bool(b) => write!(f, "{}", b),
-            SnakeVal::Float(n) => write!(f, "{}", n),
-            SnakeVal::Closure { .. } => write!(f, "closure"),
-        }
-    }
-}
-
 impl Display for InterpErr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            InterpErr::ExpectedNum { who, got: v, msg } => {
-                write!(f, "{} expected a number, but got {} in {}", who, v, msg)
+            InterpErr::ExpectedNum { who, got: v } => {
+                write!(f, "{} expected a number, but got {}", who, v)
+            }
+            InterpErr::ExpectedNumOrFloat { who, got: v } => {
+                write!(f, "{} expected a number or float, but got {}", who, v)
+            }
+            InterpErr::ExpectedBool { who, got: v } => {
+                write!(f, "{} expected a boolean, but got {}", who, v)
+            }
+            InterpErr::ExpectedFun { who, got: v } => {
+                write!(f, "{} expected a function, but got {}", who, v)
+            }
+            InterpErr::ExpectedTuple { who, got: v } => {
+                write!(f, "{} expected a tuple or an array, but got {}", who, v)
+            }
+            InterpErr::ExpectedString { who, got: v } => {
+                write!(f, "{} expected a string, but got {}", who, v)
             }
-            InterpErr::ExpectedBool { who, got: v, msg } => {
-                write!(f, "{} expected a boolean, but got {} in {}", who, v, msg)
+            InterpErr::ExpectedArray { who, got: v } => {
+                write!(f, "{} expected an array, but got {}", who, v)
             }
-            InterpErr::ExpectedFun { who, got: v, msg } => {
-                write!(f, "{} expected a function, but got {} in {}", who, v, msg)
+            InterpErr::IndexOutOfBounds { index } => {
+                write!(f, "index out of bounds: {}", index)
             }
-            InterpErr::Overflow { msg } => write!(f, "Operation {} overflowed", msg),
+            InterpErr::ArrayIndexOutOfBounds { index, length } => {
+                write!(f, "index {} out of bounds for an array of length {}", index, length)
+            }
+            InterpErr::ArraySize { got } => {
+                write!(f, "array size expected a non-negative number, but got {}", got)
+            }
+            InterpErr::OutOfMemory { words } => {
+                write!(f, "out of memory: could not allocate {} words", words)
+            }
+            InterpErr::Overflow => write!(f, "overflow"),
+            InterpErr::DivisionByZero => write!(f, "division by zero"),
+            InterpErr::SqrtNegative => write!(f, "sqrt expected a non-negative value"),
             InterpErr::Write { msg } => write!(f, "I/O Error when printing: {}", msg),
             InterpErr::ArityErr {
                 expected_arity,
@@ -98,6 +156,7 @@ impl Display for InterpErr {
                     expected_arity, num_provided
                 )
             }
+            InterpErr::NoMatch { got } => write!(f, "no pattern matches {}", got),
         }
     }
 }
@@ -115,70 +174,192 @@ fn get<'l, T>(stk: &'l List<(String, T)>, x: &str) -> Option<&'l T> {
     }
 }
 
-fn bool(v: SnakeVal, who: &str, msg: &str) -> Interp<bool> {
+fn bool(v: SnakeVal, who: &str, h: &Heap) -> Interp<bool> {
     match v {
         SnakeVal::Bool(b) => Ok(b),
         _ => Err(InterpErr::ExpectedBool {
             who: String::from(who),
-            got: v.to_string(),
-            msg: String::from(msg),
+            got: sprint_snake_val(&v, h),
         }),
     }
 }
 
-fn num(v: SnakeVal, who: &str, msg: &str) -> Interp<i64> {
+fn num(v: SnakeVal, who: &str, h: &Heap) -> Interp<i64> {
     match v {
         SnakeVal::Num(n) => Ok(n),
         _ => Err(InterpErr::ExpectedNum {
             who: String::from(who),
-            got: v.to_string(),
-            msg: String::from(msg),
+            got: sprint_snake_val(&v, h),
         }),
     }
 }
 
-fn print_snake_val<'e, W>(w: &mut W, v: SnakeVal, _h: &Heap) -> Interp<SnakeVal>
-where
-    W: std::io::Write,
-{
-    fn fixup_err(e: std::io::Error) -> InterpErr {
-        InterpErr::Write { msg: e.to_string() }
+// Numbers and floats are both accepted by arithmetic and comparisons
+fn num_or_float(v: SnakeVal, who: &str, h: &Heap) -> Interp<SnakeVal> {
+    match v {
+        SnakeVal::Num(_) | SnakeVal::Float(_) => Ok(v),
+        _ => Err(InterpErr::ExpectedNumOrFloat {
+            who: String::from(who),
+            got: sprint_snake_val(&v, h),
+        }),
     }
-    fn print_loop<'e, W>(
-        w: &mut W,
-        v: &SnakeVal,
-    ) -> Result<(), std::io::Error>
-    where
-        W: std::io::Write,
-    {
-        match v {
-            SnakeVal::Num(n) => write!(w, "{}", n)?,
-            SnakeVal::Bool(b) => write!(w, "{}", b)?,
-            SnakeVal::Float(n) => write!(w, "{}", n)?,
-            SnakeVal::Closure { .. } => {
-                write!(w, "<closure>")?;
+}
+
+fn tuple(v: SnakeVal, who: &str, h: &Heap) -> Interp<usize> {
+    match v {
+        SnakeVal::Tuple(addr) => Ok(addr),
+        _ => Err(InterpErr::ExpectedTuple {
+            who: String::from(who),
+            got: sprint_snake_val(&v, h),
+        }),
+    }
+}
+
+fn string(v: SnakeVal, who: &str, h: &Heap) -> Interp<Rc<[u8]>> {
+    match v {
+        SnakeVal::Str(s) => Ok(s),
+        _ => Err(InterpErr::ExpectedString {
+            who: String::from(who),
+            got: sprint_snake_val(&v, h),
+        }),
+    }
+}
+
+fn array(v: SnakeVal, who: &str, h: &Heap) -> Interp<usize> {
+    match v {
+        SnakeVal::Array(addr) => Ok(addr),
+        _ => Err(InterpErr::ExpectedArray {
+            who: String::from(who),
+            got: sprint_snake_val(&v, h),
+        }),
+    }
+}
+
+// Numbers are converted to doubles when they meet a float
+fn to_float(v: &SnakeVal) -> f64 {
+    match v {
+        SnakeVal::Num(n) => *n as f64,
+        SnakeVal::Float(f) => *f,
+        _ => unreachable!(),
+    }
+}
+
+// Results that don't fit into a double overflow
+fn float(f: f64) -> Interp<SnakeVal> {
+    if f.is_finite() {
+        Ok(SnakeVal::Float(f))
+    } else {
+        Err(InterpErr::Overflow)
+    }
+}
+
+// What is left to print of a value: the values inside of it, the text
+// between them and the ends of the arrays they are in
+enum Print<'h> {
+    Value(&'h SnakeVal),
+    Text(&'static str),
+    EndArray,
+}
+
+// Like the runtime, values are printed from a worklist, so that deeply
+// nested ones don't run out of stack
+fn sprint_snake_val(v: &SnakeVal, h: &Heap) -> String {
+    // the elements go on the worklist last first, with commas between
+    // them
+    fn push_elems<'h>(todo: &mut Vec<Print<'h>>, elems: &'h [SnakeVal]) {
+        for (i, e) in elems.iter().enumerate().rev() {
+            todo.push(Print::Value(e));
+            if i > 0 {
+                todo.push(Print::Text(", "));
             }
         }
-        Ok(())
     }
+    let mut out = String::new();
+    // the arrays being printed, an array inside of itself is printed
+    // as [...]
+    let mut path = vec![];
+    let mut todo = vec![Print::Value(v)];
+    while let Some(next) = todo.pop() {
+        match next {
+            Print::Text(text) => out.push_str(text),
+            Print::EndArray => {
+                path.pop();
+                out.push(']');
+            }
+            Print::Value(SnakeVal::Num(n)) => out.push_str(&n.to_string()),
+            Print::Value(SnakeVal::Bool(b)) => out.push_str(&b.to_string()),
+            Print::Value(SnakeVal::Float(f)) => out.push_str(&f.to_string()),
+            Print::Value(SnakeVal::Tuple(addr)) => {
+                out.push('(');
+                todo.push(Print::Text(if h[*addr].len() == 1 { ",)" } else { ")" }));
+                push_elems(&mut todo, &h[*addr]);
+            }
+            Print::Value(SnakeVal::Closure { .. }) => out.push_str("<closure>"),
+            Print::Value(SnakeVal::Variant(name, _, addr)) => {
+                out.push_str(unqualified(name));
+                if !h[*addr].is_empty() {
+                    out.push('(');
+                    todo.push(Print::Text(")"));
+                    push_elems(&mut todo, &h[*addr]);
+                }
+            }
+            Print::Value(SnakeVal::Str(s)) => out.push_str(&String::from_utf8_lossy(s)),
+            Print::Value(SnakeVal::Array(addr)) if path.contains(addr) => out.push_str("[...]"),
+            Print::Value(SnakeVal::Array(addr)) => {
+                path.push(*addr);
+                out.push('[');
+                todo.push(Print::EndArray);
+                push_elems(&mut todo, &h[*addr]);
+            }
+        }
+    }
+    out
+}
 
-    print_loop(w, &v).map_err(fixup_err)?;
-    writeln!(w).map_err(fixup_err)?;
+fn print_snake_val<W>(w: &mut W, v: SnakeVal, h: &Heap) -> Interp<SnakeVal>
+where
+    W: std::io::Write,
+{
+    writeln!(w, "{}", sprint_snake_val(&v, h)).map_err(|e| InterpErr::Write { msg: e.to_string() })?;
     Ok(v)
 }
 
-fn equal_snake_val(v1: &SnakeVal, v2: &SnakeVal, _h: &Heap) -> bool {
-    fn eq_loop(
-        v1: &SnakeVal,
-        v2: &SnakeVal,
-    ) -> bool {
-        match (v1, v2) {
-            (SnakeVal::Bool(b1), SnakeVal::Bool(b2)) => b1 == b2,
-            (SnakeVal::Num(n1), SnakeVal::Num(n2)) => n1 == n2,
-            _ => false,
+// The pairs of elements still to compare are kept on a worklist, like
+// the runtime does
+fn equal_snake_val(v1: &SnakeVal, v2: &SnakeVal, h: &Heap) -> bool {
+    let mut todo = vec![(v1, v2)];
+    // the pairs of arrays being compared, which are equal unless some
+    // other elements of theirs differ
+    let mut seen = vec![];
+    while let Some((v1, v2)) = todo.pop() {
+        let (a1, a2) = match (v1, v2) {
+            (SnakeVal::Bool(b1), SnakeVal::Bool(b2)) if b1 == b2 => continue,
+            (SnakeVal::Num(n1), SnakeVal::Num(n2)) if n1 == n2 => continue,
+            (SnakeVal::Num(_), SnakeVal::Num(_)) => return false,
+            // a number and a float are equal when their values are
+            (SnakeVal::Num(_) | SnakeVal::Float(_), SnakeVal::Float(_))
+            | (SnakeVal::Float(_), SnakeVal::Num(_))
+                if to_float(v1) == to_float(v2) =>
+            {
+                continue
+            }
+            (SnakeVal::Closure(c1), SnakeVal::Closure(c2)) if c1 == c2 => continue,
+            (SnakeVal::Str(s1), SnakeVal::Str(s2)) if s1 == s2 => continue,
+            (SnakeVal::Tuple(a1), SnakeVal::Tuple(a2)) => (*a1, *a2),
+            (SnakeVal::Variant(_, c1, a1), SnakeVal::Variant(_, c2, a2)) if c1 == c2 => (*a1, *a2),
+            (SnakeVal::Array(a1), SnakeVal::Array(a2)) if a1 == a2 || seen.contains(&(*a1, *a2)) => continue,
+            (SnakeVal::Array(a1), SnakeVal::Array(a2)) => {
+                seen.push((*a1, *a2));
+                (*a1, *a2)
+            }
+            _ => return false,
+        };
+        if h[a1].len() != h[a2].len() {
+            return false;
         }
+        todo.extend(h[a1].iter().zip(h[a2].iter()).rev());
     }
-    eq_loop(v1, v2)
+    true
 }
 
 fn interpret_prim1<W>(p: &Prim, w: &mut W, v: SnakeVal, h: &Heap) -> Interp<SnakeVal>
@@ -186,9 +367,9 @@ where
     W: std::io::Write,
 {
     match p {
-        Prim::Add1 => snake_arith(v, SnakeVal::Num(1), |n1, n2| n1.overflowing_add(n2), "add1"),
-        Prim::Sub1 => snake_arith(v, SnakeVal::Num(1), |n1, n2| n1.overflowing_sub(n2), "sub1"),
-        Prim::Not => Ok(SnakeVal::Bool(!bool(v, "logic", "!")?)),
+        Prim::Add1 => snake_arith(v, SnakeVal::Num(1), |n1, n2| n1.overflowing_add(n2), |f1, f2| f1 + f2, h),
+        Prim::Sub1 => snake_arith(v, SnakeVal::Num(1), |n1, n2| n1.overflowing_sub(n2), |f1, f2| f1 - f2, h),
+        Prim::Not => Ok(SnakeVal::Bool(!bool(v, "logic", h)?)),
         Prim::Print => print_snake_val(w, v, h),
         Prim::IsBool => match v {
             SnakeVal::Bool(_) => Ok(SnakeVal::Bool(true)),
@@ -198,6 +379,26 @@ where
             SnakeVal::Num(_) => Ok(SnakeVal::Bool(true)),
             _ => Ok(SnakeVal::Bool(false)),
         },
+        Prim::IsFloat => match v {
+            SnakeVal::Float(_) => Ok(SnakeVal::Bool(true)),
+            _ => Ok(SnakeVal::Bool(false)),
+        },
+        Prim::IsTuple => match v {
+            SnakeVal::Tuple(_) => Ok(SnakeVal::Bool(true)),
+            _ => Ok(SnakeVal::Bool(false)),
+        },
+        Prim::Sqrt => {
+            let x = to_float(&num_or_float(v, "arithmetic", h)?);
+            if x < 0.0 {
+                Err(InterpErr::SqrtNegative)
+            } else {
+                float(x.sqrt())
+            }
+        }
+        Prim::Cos => float(to_float(&num_or_float(v, "arithmetic", h)?).cos()),
+        Prim::Len => Ok(SnakeVal::Num(string(v, "string operation", h)?.len() as i64)),
+        Prim::ToString => Ok(SnakeVal::Str(Rc::from(sprint_snake_val(&v, h).as_bytes()))),
+        Prim::Length => Ok(SnakeVal::Num(h[array(v, "array operation", h)?].len() as i64)),
         _ => unreachable!(),
     }
 }
@@ -208,64 +409,197 @@ fn out_of_bounds(n: i64) -> bool {
     n > MAX_INT || n < MIN_INT
 }
 
-fn snake_arith<F>(v1: SnakeVal, v2: SnakeVal, arith: F, op: &str) -> Interp<SnakeVal>
+// Like the compiled code, the second operand is checked first
+fn operands(v1: SnakeVal, v2: SnakeVal, who: &str, h: &Heap) -> Interp<(SnakeVal, SnakeVal)> {
+    let v2 = num_or_float(v2, who, h)?;
+    let v1 = num_or_float(v1, who, h)?;
+    Ok((v1, v2))
+}
+
+fn snake_arith<F, G>(v1: SnakeVal, v2: SnakeVal, arith: F, farith: G, h: &Heap) -> Interp<SnakeVal>
 where
     F: Fn(i64, i64) -> (i64, bool),
+    G: Fn(f64, f64) -> f64,
 {
-    let n1 = num(v1, "arithmetic", op)?;
-    let n2 = num(v2, "arithmetic", op)?;
-    let (n3, overflow) = arith(n1, n2);
-    if overflow || out_of_bounds(n3) {
-        Err(InterpErr::Overflow {
-            msg: format!("{} {} {} = {}", n1, op, n2, n3),
-        })
+    match operands(v1, v2, "arithmetic", h)? {
+        (SnakeVal::Num(n1), SnakeVal::Num(n2)) => {
+            let (n3, overflow) = arith(n1, n2);
+            if overflow || out_of_bounds(n3) {
+                Err(InterpErr::Overflow)
+            } else {
+                Ok(SnakeVal::Num(n3))
+            }
+        }
+        (v1, v2) => float(farith(to_float(&v1), to_float(&v2))),
+    }
+}
+
+// Divides after checking for a zero divisor, which is also how the
+// compiled code divides numbers
+fn snake_div(v1: SnakeVal, v2: SnakeVal, h: &Heap) -> Interp<f64> {
+    let (v1, v2) = operands(v1, v2, "arithmetic", h)?;
+    if to_float(&v2) == 0.0 {
+        return Err(InterpErr::DivisionByZero);
+    }
+    let q = to_float(&v1) / to_float(&v2);
+    if q.is_finite() {
+        Ok(q)
     } else {
-        Ok(SnakeVal::Num(n3))
+        Err(InterpErr::Overflow)
     }
 }
 
-fn snake_log<F>(v1: SnakeVal, v2: SnakeVal, log: F, op: &str) -> Interp<SnakeVal>
+// Rounds the quotient down like the compiled code: exactly for two
+// numbers, and for floats when the result fits into a number
+fn snake_floor_div(v1: SnakeVal, v2: SnakeVal, h: &Heap) -> Interp<SnakeVal> {
+    match operands(v1, v2, "arithmetic", h)? {
+        (SnakeVal::Num(_), SnakeVal::Num(0)) => Err(InterpErr::DivisionByZero),
+        (SnakeVal::Num(n1), SnakeVal::Num(n2)) => {
+            let q = n1.checked_div(n2).ok_or(InterpErr::Overflow)?;
+            let q = if n1 % n2 != 0 && (n1 < 0) != (n2 < 0) { q - 1 } else { q };
+            if out_of_bounds(q) {
+                Err(InterpErr::Overflow)
+            } else {
+                Ok(SnakeVal::Num(q))
+            }
+        }
+        (v1, v2) => {
+            let q = snake_div(v1, v2, h)?.floor();
+            if q < MIN_INT as f64 || q >= -(MIN_INT as f64) {
+                Err(InterpErr::Overflow)
+            } else {
+                Ok(SnakeVal::Num(q as i64))
+            }
+        }
+    }
+}
+
+fn snake_log<F>(v1: SnakeVal, v2: SnakeVal, log: F, h: &Heap) -> Interp<SnakeVal>
 where
     F: Fn(bool, bool) -> bool,
 {
-    Ok(SnakeVal::Bool(log(
-        bool(v1, "logic", op)?,
-        bool(v2, "logic", op)?,
-    )))
+    let b2 = bool(v2, "logic", h)?;
+    let b1 = bool(v1, "logic", h)?;
+    Ok(SnakeVal::Bool(log(b1, b2)))
 }
 
-fn snake_cmp<F>(v1: SnakeVal, v2: SnakeVal, cmp: F, op: &str) -> Interp<SnakeVal>
+fn snake_cmp<F, G>(v1: SnakeVal, v2: SnakeVal, cmp: F, fcmp: G, h: &Heap) -> Interp<SnakeVal>
 where
     F: Fn(i64, i64) -> bool,
+    G: Fn(f64, f64) -> bool,
 {
-    Ok(SnakeVal::Bool(cmp(
-        num(v1, "comparison", op)?,
-        num(v2, "comparison", op)?,
-    )))
+    Ok(SnakeVal::Bool(match operands(v1, v2, "comparison", h)? {
+        (SnakeVal::Num(n1), SnakeVal::Num(n2)) => cmp(n1, n2),
+        (v1, v2) => fcmp(to_float(&v1), to_float(&v2)),
+    }))
 }
 
 fn interpret_prim2(p: &Prim, v1: SnakeVal, v2: SnakeVal, heap: &Heap) -> Interp<SnakeVal>
 where
 {
     match p {
-        Prim::Add => snake_arith(v1, v2, |n1, n2| n1.overflowing_add(n2), "+"),
-        Prim::Sub => snake_arith(v1, v2, |n1, n2| n1.overflowing_sub(n2), "-"),
-        Prim::Mul => snake_arith(v1, v2, |n1, n2| n1.overflowing_mul(n2), "*"),
+        Prim::Add => snake_arith(v1, v2, |n1, n2| n1.overflowing_add(n2), |f1, f2| f1 + f2, heap),
+        Prim::Sub => snake_arith(v1, v2, |n1, n2| n1.overflowing_sub(n2), |f1, f2| f1 - f2, heap),
+        Prim::Mul => snake_arith(v1, v2, |n1, n2| n1.overflowing_mul(n2), |f1, f2| f1 * f2, heap),
+        Prim::Div => Ok(SnakeVal::Float(snake_div(v1, v2, heap)?)),
+        Prim::FloorDiv => snake_floor_div(v1, v2, heap),
 
-        Prim::And => snake_log(v1, v2, |b1, b2| b1 && b2, "&&"),
-        Prim::Or => snake_log(v1, v2, |b1, b2| b1 || b2, "||"),
+        Prim::And => snake_log(v1, v2, |b1, b2| b1 && b2, heap),
+        Prim::Or => snake_log(v1, v2, |b1, b2| b1 || b2, heap),
 
-        Prim::Lt => snake_cmp(v1, v2, |n1, n2| n1 < n2, "<"),
-        Prim::Le => snake_cmp(v1, v2, |n1, n2| n1 <= n2, "<="),
-        Prim::Gt => snake_cmp(v1, v2, |n1, n2| n1 > n2, ">"),
-        Prim::Ge => snake_cmp(v1, v2, |n1, n2| n1 >= n2, ">="),
+        Prim::Lt => snake_cmp(v1, v2, |n1, n2| n1 < n2, |f1, f2| f1 < f2, heap),
+        Prim::Le => snake_cmp(v1, v2, |n1, n2| n1 <= n2, |f1, f2| f1 <= f2, heap),
+        Prim::Gt => snake_cmp(v1, v2, |n1, n2| n1 > n2, |f1, f2| f1 > f2, heap),
+        Prim::Ge => snake_cmp(v1, v2, |n1, n2| n1 >= n2, |f1, f2| f1 >= f2, heap),
 
         Prim::Eq => Ok(SnakeVal::Bool(equal_snake_val(&v1, &v2, heap))),
         Prim::Neq => Ok(SnakeVal::Bool(!equal_snake_val(&v1, &v2, heap))),
+
+        Prim::GetIndex => match v1 {
+            SnakeVal::Array(addr) => {
+                let i = array_index(addr, v2, heap)?;
+                Ok(heap[addr][i].clone())
+            }
+            _ => {
+                let addr = tuple(v1, "indexing", heap)?;
+                let i = num(v2, "index", heap)?;
+                let elems = &heap[addr];
+                if i < 0 || i as usize >= elems.len() {
+                    Err(InterpErr::IndexOutOfBounds { index: i })
+                } else {
+                    Ok(elems[i as usize].clone())
+                }
+            }
+        },
+        // unlike arithmetic, the first operand is checked first
+        Prim::Concat => {
+            let s1 = string(v1, "string operation", heap)?;
+            let s2 = string(v2, "string operation", heap)?;
+            Ok(SnakeVal::Str([&s1[..], &s2[..]].concat().into()))
+        }
         _ => unreachable!(),
     }
 }
 
+// The index i of the array at addr, if it's within its bounds
+fn array_index(addr: usize, i: SnakeVal, heap: &Heap) -> Interp<usize> {
+    let i = num(i, "index", heap)?;
+    let length = heap[addr].len();
+    if i < 0 || i as usize >= length {
+        Err(InterpErr::ArrayIndexOutOfBounds { index: i, length })
+    } else {
+        Ok(i as usize)
+    }
+}
+
+fn make_array(n: SnakeVal, init: SnakeVal, heap: &mut Heap) -> Interp<SnakeVal> {
+    match n {
+        // the compiled code runs out of memory where the interpreter
+        // would run out of addresses
+        SnakeVal::Num(n) if n >= 0 => {
+            let mut elems = Vec::new();
+            if elems.try_reserve_exact(n as usize).is_err() {
+                return Err(InterpErr::OutOfMemory { words: n as u64 + 2 });
+            }
+            elems.resize(n as usize, init);
+            heap.push(elems);
+            Ok(SnakeVal::Array(heap.len() - 1))
+        }
+        _ => Err(InterpErr::ArraySize {
+            got: sprint_snake_val(&n, heap),
+        }),
+    }
+}
+
+// a[i] := v is v
+fn set_index(a: SnakeVal, i: SnakeVal, v: SnakeVal, heap: &mut Heap) -> Interp<SnakeVal> {
+    let addr = array(a, "array operation", heap)?;
+    let i = array_index(addr, i, heap)?;
+    heap[addr][i] = v.clone();
+    Ok(v)
+}
+
+// substr(s, start, end) is the bytes of s from start up to but not
+// including end, reporting start if it's out of bounds and end otherwise.
+// An index inside a character is out of bounds too.
+fn interpret_substr(s: SnakeVal, start: SnakeVal, end: SnakeVal, heap: &Heap) -> Interp<SnakeVal> {
+    let s = string(s, "string operation", heap)?;
+    let start = num(start, "index", heap)?;
+    if start < 0 || start as usize > s.len() {
+        return Err(InterpErr::IndexOutOfBounds { index: start });
+    }
+    let end = num(end, "index", heap)?;
+    if end < start || end as usize > s.len() {
+        return Err(InterpErr::IndexOutOfBounds { index: end });
+    }
+    // the same order as the compiled program, which checks both bounds first
+    let inside_char = |i: &&i64| s.get(**i as usize).is_some_and(|b| b & 0xC0 == 0x80);
+    if let Some(index) = [start, end].iter().find(inside_char) {
+        return Err(InterpErr::IndexOutOfBounds { index: *index });
+    }
+    Ok(SnakeVal::Str(Rc::from(&s[start as usize..end as usize])))
+}
+
 impl<A> std::iter::FromIterator<A> for List<A> {
     fn from_iter<T>(iter: T) -> Self
     where
@@ -303,6 +637,26 @@ enum Stack<'exp, Ann> {
         env: Env,
         stk: Box<Stack<'exp, Ann>>,
     },
+    Semicolon {
+        e2: &'exp Exp<Ann>,
+        env: Env,
+        stk: Box<Stack<'exp, Ann>>,
+    },
+    WhileCond {
+        e: &'exp Exp<Ann>,
+        env: Env,
+        stk: Box<Stack<'exp, Ann>>,
+    },
+    // e is the whole loop, to go back to its condition
+    WhileBody {
+        e: &'exp Exp<Ann>,
+        env: Env,
+        stk: Box<Stack<'exp, Ann>>,
+    },
+    Assign {
+        cell: Rc<RefCell<SnakeVal>>,
+        stk: Box<Stack<'exp, Ann>>,
+    },
     Let {
         var: &'exp str,
         env: Env,
@@ -310,8 +664,27 @@ enum Stack<'exp, Ann> {
         body: &'exp Exp<Ann>,
         stk: Box<Stack<'exp, Ann>>,
     },
+    // also evaluates the fields of variants and the arguments of substr
+    // and of a[i] := v
+    MakeTuple {
+        into: Evaled,
+        evaled: Vec<SnakeVal>,
+        env: Env,
+        remaining: Vec<&'exp Exp<Ann>>,
+        stk: Box<Stack<'exp, Ann>>,
+    },
+    Match {
+        arms: &'exp [(Pattern<Ann>, Exp<Ann>)],
+        env: Env,
+        stk: Box<Stack<'exp, Ann>>,
+    },
+    CallFun {
+        env: Env,
+        args: &'exp [Exp<Ann>],
+        stk: Box<Stack<'exp, Ann>>,
+    },
     CallArgs {
-        fun: usize, // the closure
+        fun: SnakeVal, // should be a closure
         evaled_args: Vec<SnakeVal>,
         env: Env,
         remaining_args: Vec<&'exp Exp<Ann>>,
@@ -319,23 +692,87 @@ enum Stack<'exp, Ann> {
     },
 }
 
-// No heap for now!
-type Heap = ();
-type Funs<'e, Ann> = Vec<(Env, &'e SurfFunDecl<Ann>)>;
+// What the values evaluated by Stack::MakeTuple are for
+enum Evaled {
+    Tuple,
+    // the name and number of the constructor
+    Variant(Rc<str>, usize),
+    Substr,
+    SetIndex,
+}
+
+// Each tuple is an immutable vector of values, and so is each array
+// but for a[i] := v
+type Heap = Vec<Vec<SnakeVal>>;
+// A closure is the captured environment with the parameters and body
+type Funs<'e, Ann> = Vec<(Env, &'e [String], &'e Exp<Ann>)>;
 struct State<'e, Ann> {
     funs: Funs<'e, Ann>,
     heap: Heap,
+    // a constructor is numbered by its declaration, like the compiler
+    // does, however often the declaration is evaluated
+    constructors: Vec<&'e Constructor<Ann>>,
 }
 
 impl<'e, Ann> State<'e, Ann> {
     fn new() -> Self {
         State {
             funs: vec![],
-            heap: (),
+            heap: vec![],
+            constructors: vec![],
+        }
+    }
+
+    fn constructor_number(&mut self, c: &'e Constructor<Ann>) -> usize {
+        match self.constructors.iter().position(|d| std::ptr::eq(*d, c)) {
+            Some(n) => n,
+            None => {
+                self.constructors.push(c);
+                self.constructors.len() - 1
+            }
         }
     }
 }
 
+fn constructor(env: &Env, name: &str) -> usize {
+    match get(env, name).map(|v| v.borrow().clone()) {
+        Some(SnakeVal::Num(n)) => n as usize,
+        _ => panic!("Unbound constructor in interpreter! You should catch this in the check function!"),
+    }
+}
+
+// Whether v matches p, pushing the values of the variables of p
+fn matches<Ann>(p: &Pattern<Ann>, v: &SnakeVal, env: &Env, h: &Heap, bound: &mut Vec<(String, SnakeVal)>) -> bool {
+    match (p, v) {
+        (Pattern::Wildcard(_), _) => true,
+        (Pattern::Var(x, _), _) => {
+            bound.push((x.clone(), v.clone()));
+            true
+        }
+        (Pattern::Constructor(name, ps, _), SnakeVal::Variant(_, c, addr)) => {
+            *c == constructor(env, name) && ps.iter().zip(h[*addr].iter()).all(|(p, v)| matches(p, v, env, h, bound))
+        }
+        (Pattern::Constructor(..), _) => false,
+    }
+}
+
+// Allocate closures for a mutually recursive sequence of function
+// declarations, returning an environment where the functions'
+// names are associated to the address of their closure.
+fn alloc_funs<'e, Ann>(decls: &'e [SurfFunDecl<Ann>], mut env: Env, funs: &mut Funs<'e, Ann>) -> Env {
+    // Each of the closures captures the same environment: the
+    // current environment extended with all of their names
+    // i.e., the env we return.
+    let i = funs.len();
+    for (j, d) in decls.iter().enumerate() {
+        env = push_local(&env, d.name.clone(), SnakeVal::Closure(i + j));
+    }
+    for d in decls.iter() {
+        funs.push((env.clone(), &d.parameters, &d.body));
+    }
+    env
+}
+
 /*
  *  Abstract machine-style interpreter.
  *
@@ -343,74 +780,142 @@ impl<'e, Ann> State<'e, Ann> {
  *  so that we don't blow the Rust stack/rely on Rust TCE.
  *
 */
-fn machine<'exp, Ann, W>(e: &'exp Exp<Ann>, buf: &mut W, store: &mut State<'exp, Ann>) -> Interp<()>
+fn machine<'exp, Ann, W>(
+    e: &'exp Exp<Ann>,
+    env: Env,
+    buf: &mut W,
+    store: &mut State<'exp, Ann>,
+) -> Interp<SnakeVal>
 where
     W: std::io::Write,
     Ann: Clone,
 {
     fn call<'exp, Ann>(
-        fun: usize,
+        fun: SnakeVal,
         args: Vec<SnakeVal>,
         stk: Stack<'exp, Ann>,
         funs: &Funs<'exp, Ann>,
+        heap: &Heap,
     ) -> Interp<Machine<'exp, Ann>>
     where
         Ann: Clone,
     {
-        let (orig_env, d) = &funs[fun];
+        let (orig_env, parameters, body) = match fun {
+            SnakeVal::Closure(ix) => &funs[ix],
+            _ => {
+                return Err(InterpErr::ExpectedFun {
+                    who: String::from("call"),
+                    got: sprint_snake_val(&fun, heap),
+                })
+            }
+        };
 	let mut env = orig_env.clone();
 
-        if args.len() != d.parameters.len() {
+        if args.len() != parameters.len() {
             return Err(InterpErr::ArityErr {
-                expected_arity: d.parameters.len(),
+                expected_arity: parameters.len(),
                 num_provided: args.len(),
             });
         }
 	// environment for the body should consist of the captured env
 	// extended with the new parameters
-	for (v, x) in args.iter().zip(d.parameters.iter()) {
+	for (v, x) in args.iter().zip(parameters.iter()) {
 	    env = push_local(&env, x.to_string(), v.clone())
 	}
 	Ok(Machine::Descending {
-            e: &d.body,
+            e: body,
             env,
             stk,
         })
     }
 
-    fn mangle_fun_name(s: &str) -> String {
-	format!("{}#fun", s)
-    }
-    
-    // Allocate closures for a mutually recursive sequence of function
-    // declarations, returning an environment where the functions'
-    // names (mangled to avoid clashing with value variables) are
-    // associated to the address of their closure.
-    fn alloc_funs<'e, Ann>(decls: &'e [SurfFunDecl<Ann>], mut env: Env, funs: &mut Funs<'e, Ann>) -> Env {
-	// Each of the closures captures the same environment: the
-	// current environment extended with all of their names
-	// i.e., the env we return.
-	let i = funs.len();
-	for (j, d) in decls.iter().enumerate() {
-	    env = push_local(&env, mangle_fun_name(&d.name), SnakeVal::Closure(i + j));
-	}
-	for d in decls.iter() {
-	    funs.push((env.clone(), &d));
-	}
-	env
+    // Starts evaluating the arguments of a call to fun
+    fn call_args<'exp, Ann>(
+        fun: SnakeVal,
+        args: &'exp [Exp<Ann>],
+        env: Env,
+        stk: Stack<'exp, Ann>,
+        funs: &Funs<'exp, Ann>,
+        heap: &Heap,
+    ) -> Interp<Machine<'exp, Ann>>
+    where
+        Ann: Clone,
+    {
+        let mut remaining_args: Vec<&Exp<_>> = args.iter().collect();
+        remaining_args.reverse();
+        match remaining_args.pop() {
+            None => call(fun, Vec::new(), stk, funs, heap),
+            Some(e) => Ok(Machine::Descending {
+                e,
+                env: env.clone(),
+                stk: Stack::CallArgs {
+                    fun,
+                    evaled_args: Vec::new(),
+                    env,
+                    remaining_args,
+                    stk: Box::new(stk),
+                },
+            }),
+        }
     }
 
     let mut machine = Machine::Descending {
         e,
         stk: Stack::Done,
-        env: Rc::new(List::Empty),
+        env,
     };
     loop {
         match machine {
             Machine::Descending { e, stk, env } => match e {
-                Exp::InternalTailCall(..) | Exp::ExternalCall { .. } => {
+                Exp::InternalTailCall(..) | Exp::ExternalCall { .. } | Exp::MakeClosure { .. } => {
                     panic!("Should never happen: interpreter called with internal compiler forms")
                 }
+                Exp::TypeDecl { constructors, body, .. } => {
+                    let mut env = env;
+                    for c in constructors {
+                        let n = store.constructor_number(c);
+                        env = push_local(&env, c.name.clone(), SnakeVal::Num(n as i64));
+                    }
+                    machine = Machine::Descending { e: body, env, stk }
+                }
+                Exp::Construct { name, args, .. } => {
+                    let (name, c): (Rc<str>, _) = (Rc::from(name.as_str()), constructor(&env, name));
+                    let mut remaining: Vec<&Exp<_>> = args.iter().collect();
+                    remaining.reverse();
+                    match remaining.pop() {
+                        None => {
+                            store.heap.push(vec![]);
+                            machine = Machine::Returning {
+                                v: SnakeVal::Variant(name, c, store.heap.len() - 1),
+                                stk,
+                            };
+                        }
+                        Some(e) => {
+                            machine = Machine::Descending {
+                                e,
+                                env: env.clone(),
+                                stk: Stack::MakeTuple {
+                                    into: Evaled::Variant(name, c),
+                                    evaled: Vec::new(),
+                                    env,
+                                    remaining,
+                                    stk: Box::new(stk),
+                                },
+                            }
+                        }
+                    }
+                }
+                Exp::Match { scrutinee, arms, .. } => {
+                    machine = Machine::Descending {
+                        e: scrutinee,
+                        stk: Stack::Match {
+                            arms,
+                            env: env.clone(),
+                            stk: Box::new(stk),
+                        },
+                        env,
+                    }
+                }
                 Exp::Num(n, _) => {
                     machine = Machine::Returning {
                         v: SnakeVal::Num(*n),
@@ -429,16 +934,23 @@ where
                         stk,
                     }
                 }
+                Exp::Str(s, _) => {
+                    machine = Machine::Returning {
+                        v: SnakeVal::Str(Rc::from(s.as_bytes())),
+                        stk,
+                    }
+                }
                 Exp::Var(x, _) => {
                     let v = get(&*env, x).expect("Unbound variable in interpreter! You should catch this in the check function!");
-                    machine = Machine::Returning { v: v.clone(), stk }
+                    machine = Machine::Returning { v: v.borrow().clone(), stk }
                 }
 
                 Exp::Prim(op, es, _) => {
                     match op {
                         Prim::Add1 | Prim::Sub1 | Prim::Not |
-                        Prim::Print | Prim::IsBool |
-                        Prim::IsNum | Prim::IsFloat | Prim::Sqrt | Prim::Cos => {
+                        Prim::Print | Prim::IsBool | Prim::IsTuple |
+                        Prim::IsNum | Prim::IsFloat | Prim::Sqrt | Prim::Cos | Prim::Unbox |
+                        Prim::Len | Prim::ToString | Prim::Length => {
                             let e = &es[0];
                             machine = Machine::Descending {
                                 e,
@@ -449,7 +961,9 @@ where
                         Prim::Add | Prim::Sub | Prim::Mul |
                         Prim::And | Prim::Or | Prim::Lt |
                         Prim::Gt | Prim::Le | Prim::Ge |
-                        Prim::Eq | Prim::Neq | Prim::Div | Prim::FloorDiv => {
+                        Prim::Eq | Prim::Neq | Prim::Div | Prim::FloorDiv |
+                        Prim::GetIndex | Prim::ClosureEnv | Prim::SetBox | Prim::Concat |
+                        Prim::MakeArray => {
                             let e1 = &es[0];
                             let e2 = &es[1];
                             machine = Machine::Descending {
@@ -465,6 +979,39 @@ where
                                 env,
                             };
                         }
+                        Prim::MakeTuple | Prim::Substr | Prim::SetIndex => {
+                            let mut remaining: Vec<&Exp<_>> = es.iter().map(|e| &**e).collect();
+                            remaining.reverse();
+                            match remaining.pop() {
+                                None => {
+                                    store.heap.push(vec![]);
+                                    machine = Machine::Returning {
+                                        v: SnakeVal::Tuple(store.heap.len() - 1),
+                                        stk,
+                                    };
+                                }
+                                Some(e) => {
+                                    machine = Machine::Descending {
+                                        e,
+                                        env: env.clone(),
+                                        stk: Stack::MakeTuple {
+                                            into: match op {
+                                                Prim::Substr => Evaled::Substr,
+                                                Prim::SetIndex => Evaled::SetIndex,
+                                                _ => Evaled::Tuple,
+                                            },
+                                            evaled: Vec::new(),
+                                            env,
+                                            remaining,
+                                            stk: Box::new(stk),
+                                        },
+                                    }
+                                }
+                            }
+                        }
+                        Prim::MatchFail | Prim::IsVariant | Prim::VariantField | Prim::MakeVariant => {
+                            panic!("Should never happen: interpreter called with internal compiler forms")
+                        }
                     }
                 }
                 Exp::Let { bindings, body, .. } => {
@@ -500,31 +1047,62 @@ where
                         env,
                     }
                 }
+                Exp::Semicolon { e1, e2, .. } => {
+                    machine = Machine::Descending {
+                        e: e1,
+                        stk: Stack::Semicolon {
+                            e2,
+                            env: env.clone(),
+                            stk: Box::new(stk),
+                        },
+                        env,
+                    }
+                }
+                Exp::While { cond, .. } => {
+                    machine = Machine::Descending {
+                        e: cond,
+                        stk: Stack::WhileCond {
+                            e,
+                            env: env.clone(),
+                            stk: Box::new(stk),
+                        },
+                        env,
+                    }
+                }
+                Exp::Assign { var, value, .. } => {
+                    let cell = get(&*env, var).expect("Unbound variable in interpreter! You should catch this in the check function!");
+                    machine = Machine::Descending {
+                        e: value,
+                        stk: Stack::Assign {
+                            cell: cell.clone(),
+                            stk: Box::new(stk),
+                        },
+                        env,
+                    }
+                }
                 Exp::Call(fun, args, _) => {
-		    let ix = match get(&*env, &mangle_fun_name(fun)) {
-			Some(SnakeVal::Closure(ix)) => *ix,
-			_ => panic!("bug in interpreter?"),
-		    };
-                    let mut remaining_args: Vec<&Exp<_>> = args.iter().collect();
-                    remaining_args.reverse();
-                    match remaining_args.pop() {
-                        None => {
-                            machine =
-                                call(ix, Vec::new(), stk, &store.funs)?;
-                        }
-                        Some(e) => {
-                            machine = Machine::Descending {
-                                e: &e,
-                                env: env.clone(),
-                                stk: Stack::CallArgs {
-                                    fun: ix,
-                                    evaled_args: Vec::new(),
-                                    env,
-                                    remaining_args,
-                                    stk: Box::new(stk),
-                                },
-                            }
-                        }
+		    let fun = get(&*env, fun).expect("Unbound function in interpreter! You should catch this in the check function!");
+                    let fun = fun.borrow().clone();
+                    machine = call_args(fun, args, env, stk, &store.funs, &store.heap)?;
+                }
+                Exp::ClosureCall { fun, args, .. } => {
+                    machine = Machine::Descending {
+                        e: fun,
+                        env: env.clone(),
+                        stk: Stack::CallFun {
+                            env,
+                            args,
+                            stk: Box::new(stk),
+                        },
+                    }
+                }
+                Exp::Lambda {
+                    parameters, body, ..
+                } => {
+                    store.funs.push((env, parameters, body));
+                    machine = Machine::Returning {
+                        v: SnakeVal::Closure(store.funs.len() - 1),
+                        stk,
                     }
                 }
 		Exp::FunDefs { decls, body, .. } => {
@@ -538,10 +1116,7 @@ where
 		}
             },
             Machine::Returning { v, stk } => match stk {
-                Stack::Done => {
-                    print_snake_val(buf, v, &store.heap)?;
-                    return Ok(());
-                }
+                Stack::Done => return Ok(v),
                 Stack::Prim1(op, stk) => {
                     let v = interpret_prim1(&op, buf, v, &store.heap)?;
                     machine = Machine::Returning { v, stk: *stk }
@@ -553,6 +1128,10 @@ where
                         stk: Stack::Prim2R(op, v, stk),
                     };
                 }
+                Stack::Prim2R(Prim::MakeArray, vl, stk) => {
+                    let v = make_array(vl, v, &mut store.heap)?;
+                    machine = Machine::Returning { v, stk: *stk };
+                }
                 Stack::Prim2R(op, vl, stk) => {
                     let v = interpret_prim2(&op, vl, v, &store.heap)?;
                     machine = Machine::Returning { v, stk: *stk };
@@ -586,9 +1165,105 @@ where
                 }
 
                 Stack::If { thn, els, env, stk } => {
-                    let e = if bool(v, "if", "if")? { thn } else { els };
+                    let e = if bool(v, "if", &store.heap)? { thn } else { els };
                     machine = Machine::Descending { e, env, stk: *stk }
                 }
+                Stack::Semicolon { e2, env, stk } => {
+                    machine = Machine::Descending { e: e2, env, stk: *stk }
+                }
+                Stack::WhileCond { e, env, stk } => {
+                    machine = match e {
+                        Exp::While { body, .. } if bool(v, "while", &store.heap)? => Machine::Descending {
+                            e: body,
+                            env: env.clone(),
+                            stk: Stack::WhileBody { e, env, stk },
+                        },
+                        _ => Machine::Returning {
+                            v: SnakeVal::Bool(false),
+                            stk: *stk,
+                        },
+                    }
+                }
+                Stack::WhileBody { e, env, stk } => {
+                    machine = Machine::Descending {
+                        e,
+                        env,
+                        stk: *stk,
+                    }
+                }
+                Stack::Assign { cell, stk } => {
+                    *cell.borrow_mut() = v.clone();
+                    machine = Machine::Returning { v, stk: *stk }
+                }
+                Stack::Match { arms, env, stk } => {
+                    let mut bound = vec![];
+                    let arm = arms.iter().find(|(p, _)| {
+                        bound.clear();
+                        matches(p, &v, &env, &store.heap, &mut bound)
+                    });
+                    match arm {
+                        Some((_, body)) => {
+                            let mut env = env;
+                            for (x, v) in bound {
+                                env = push_local(&env, x, v);
+                            }
+                            machine = Machine::Descending { e: body, env, stk: *stk }
+                        }
+                        None => {
+                            return Err(InterpErr::NoMatch {
+                                got: sprint_snake_val(&v, &store.heap),
+                            })
+                        }
+                    }
+                }
+                Stack::MakeTuple {
+                    into,
+                    mut evaled,
+                    env,
+                    mut remaining,
+                    stk,
+                } => {
+                    evaled.push(v);
+                    match remaining.pop() {
+                        None if matches!(into, Evaled::Substr) => {
+                            let (end, start) = (evaled.pop().unwrap(), evaled.pop().unwrap());
+                            let v = interpret_substr(evaled.pop().unwrap(), start, end, &store.heap)?;
+                            machine = Machine::Returning { v, stk: *stk };
+                        }
+                        None if matches!(into, Evaled::SetIndex) => {
+                            let (v, i) = (evaled.pop().unwrap(), evaled.pop().unwrap());
+                            let v = set_index(evaled.pop().unwrap(), i, v, &mut store.heap)?;
+                            machine = Machine::Returning { v, stk: *stk };
+                        }
+                        None => {
+                            store.heap.push(evaled);
+                            let addr = store.heap.len() - 1;
+                            machine = Machine::Returning {
+                                v: match into {
+                                    Evaled::Variant(name, c) => SnakeVal::Variant(name, c, addr),
+                                    _ => SnakeVal::Tuple(addr),
+                                },
+                                stk: *stk,
+                            };
+                        }
+                        Some(e) => {
+                            machine = Machine::Descending {
+                                e,
+                                env: env.clone(),
+                                stk: Stack::MakeTuple {
+                                    into,
+                                    evaled,
+                                    env,
+                                    remaining,
+                                    stk,
+                                },
+                            }
+                        }
+                    }
+                }
+                Stack::CallFun { env, args, stk } => {
+                    machine = call_args(v, args, env, *stk, &store.funs, &store.heap)?;
+                }
                 Stack::CallArgs {
                     fun: fun_v,
                     mut evaled_args,
@@ -604,6 +1279,7 @@ where
                                 evaled_args,
                                 *stk,
                                 &store.funs,
+                                &store.heap,
                             )?;
                         }
                         Some(e) => {
@@ -632,7 +1308,10 @@ where
     Ann: Clone,
     W: std::io::Write,
 {
-    machine(e, w, &mut State::new())
+    let mut store = State::new();
+    let v = machine(e, Rc::new(List::Empty), w, &mut store)?;
+    print_snake_val(w, v, &store.heap)?;
+    Ok(())
 }
 
 pub fn prog<Ann, W>(p: &SurfProg<Ann>, w: &mut W) -> Interp<()>
@@ -640,5 +1319,63 @@ where
     W: std::io::Write,
     Ann: Clone,
 {
-    machine(&p, w, &mut State::new())
+    exp(p, w)
+}
+
+/* The state of the interpreter between the inputs of the REPL: the
+ * values bound by earlier inputs and the heap they point into. A
+ * failing input leaves the session as it was before it.
+ */
+pub struct Session<'e, Ann> {
+    env: Env,
+    store: State<'e, Ann>,
+}
+
+impl<'e, Ann> Session<'e, Ann>
+where
+    Ann: Clone,
+{
+    pub fn new() -> Self {
+        Session {
+            env: Rc::new(List::Empty),
+            store: State::new(),
+        }
+    }
+
+    // Evaluates the bindings of a let in order and keeps them bound
+    pub fn define_let<W>(&mut self, bindings: &'e [(String, Exp<Ann>)], w: &mut W) -> Interp<()>
+    where
+        W: std::io::Write,
+    {
+        let mut env = self.env.clone();
+        for (x, e) in bindings {
+            let v = machine(e, env.clone(), w, &mut self.store)?;
+            env = push_local(&env, x.clone(), v);
+        }
+        self.env = env;
+        Ok(())
+    }
+
+    pub fn define_funs(&mut self, decls: &'e [SurfFunDecl<Ann>]) {
+        self.env = alloc_funs(decls, self.env.clone(), &mut self.store.funs);
+    }
+
+    // Evaluates e with the bindings so far and prints its value
+    pub fn exp<W>(&mut self, e: &'e Exp<Ann>, w: &mut W) -> Interp<()>
+    where
+        W: std::io::Write,
+    {
+        let v = machine(e, self.env.clone(), w, &mut self.store)?;
+        print_snake_val(w, v, &self.store.heap)?;
+        Ok(())
+    }
+}
+
+impl<'e, Ann> Default for Session<'e, Ann>
+where
+    Ann: Clone,
+{
+    fn default() -> Self {
+        Session::new()
+    }
 }
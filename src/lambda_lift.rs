@@ -1,102 +1,104 @@
-use crate::syntax::{Exp, FunDecl};
+use crate::syntax::{Exp, FunDecl, Prim, Signature};
 use core::panic;
 use std::collections::{HashMap, HashSet};
 
-pub fn lambda_lift_helper<Ann>(
+// What lifting a program builds up as it goes: the lifted functions,
+// and the bodies, captured variables and arities of the functions
+// defined so far
+pub struct LiftState<'a, Ann> {
+    pub lifted: Vec<FunDecl<Exp<Ann>, Ann>>,
+    pub should_lift: &'a HashSet<String>,
+    pub fun_to_decl: HashMap<String, FunDecl<Exp<Ann>, Ann>>,
+    pub fun_to_env: HashMap<String, Vec<String>>,
+    pub fun_to_arity: HashMap<String, usize>,
+}
+
+pub fn lambda_lift_helper<Ann: Clone>(
     e: &Exp<Ann>,
     env: &mut Vec<String>,
-    lifted: &mut Vec<FunDecl<Exp<()>, ()>>,
-    should_lift: &HashSet<String>,
-    fun_to_decl: &mut HashMap<String, FunDecl<Exp<()>, ()>>,
     is_tail: bool,
-    fun_to_env: &mut HashMap<String, Vec<String>>,
-) -> Exp<()> {
+    state: &mut LiftState<'_, Ann>,
+) -> Exp<Ann> {
     match e {
-        Exp::Num(n, _) => Exp::Num(*n, ()),
-        Exp::Bool(b, _) => Exp::Bool(*b, ()),
-        Exp::Float(f, _) => Exp::Float(f.clone(), ()),
-        Exp::Var(s, _) => Exp::Var(s.clone(), ()),
-        Exp::Prim(p, exps, _) => {
+        Exp::Num(n, ann) => Exp::Num(*n, ann.clone()),
+        Exp::Bool(b, ann) => Exp::Bool(*b, ann.clone()),
+        Exp::Float(f, ann) => Exp::Float(f.clone(), ann.clone()),
+        Exp::Str(s, ann) => Exp::Str(s.clone(), ann.clone()),
+        Exp::Var(s, ann) => match state.fun_to_arity.get(s) {
+            // a function used as a value becomes lambda (x1, ..., xn): s(x1, ..., xn) end
+            Some(arity) => {
+                let parameters: Vec<String> =
+                    (0..*arity).map(|i| format!("{}_arg_{}", s, i)).collect();
+                let eta = Exp::Lambda {
+                    parameters: parameters.clone(),
+                    body: Box::new(Exp::Call(
+                        s.clone(),
+                        parameters.iter().map(|x| Exp::Var(x.clone(), ann.clone())).collect(),
+                        ann.clone(),
+                    )),
+                    ann: ann.clone(),
+                };
+                lambda_lift_helper(&eta, env, is_tail, state)
+            }
+            None => Exp::Var(s.clone(), ann.clone()),
+        },
+        Exp::Prim(p, exps, ann) => {
             let mut new_exps = vec![];
             for exp in exps {
-                new_exps.push(Box::new(lambda_lift_helper(
-                    exp,
-                    &mut env.clone(),
-                    lifted,
-                    should_lift,
-                    fun_to_decl,
-                    false,
-                    fun_to_env,
-                )));
+                new_exps.push(Box::new(lambda_lift_helper(exp, &mut env.clone(), false, state)));
             }
-            Exp::Prim(p.clone(), new_exps, ())
+            Exp::Prim(p.clone(), new_exps, ann.clone())
         }
-        Exp::Let { bindings, body, .. } => {
+        Exp::Let { bindings, body, ann } => {
             let mut new_bindings = vec![];
             for (a, b) in bindings {
                 new_bindings.push((
                     a.clone(),
-                    lambda_lift_helper(
-                        b,
-                        &mut env.clone(),
-                        lifted,
-                        should_lift,
-                        fun_to_decl,
-                        false,
-                        fun_to_env,
-                    ),
+                    lambda_lift_helper(b, &mut env.clone(), false, state),
                 ));
                 env.push(a.clone());
             }
             Exp::Let {
                 bindings: new_bindings,
-                body: Box::new(lambda_lift_helper(
-                    body,
-                    &mut env.clone(),
-                    lifted,
-                    should_lift,
-                    fun_to_decl,
-                    is_tail,
-                    fun_to_env,
-                )),
-                ann: (),
+                body: Box::new(lambda_lift_helper(body, &mut env.clone(), is_tail, state)),
+                ann: ann.clone(),
             }
         }
-        Exp::If { cond, thn, els, .. } => Exp::If {
-            cond: Box::new(lambda_lift_helper(
-                cond,
-                &mut env.clone(),
-                lifted,
-                should_lift,
-                fun_to_decl,
-                false,
-                fun_to_env,
-            )),
-            thn: Box::new(lambda_lift_helper(
-                thn,
-                &mut env.clone(),
-                lifted,
-                should_lift,
-                fun_to_decl,
-                is_tail,
-                fun_to_env,
-            )),
-            els: Box::new(lambda_lift_helper(
-                els,
-                &mut env.clone(),
-                lifted,
-                should_lift,
-                fun_to_decl,
-                is_tail,
-                fun_to_env,
-            )),
-            ann: (),
+        Exp::If {
+            cond,
+            thn,
+            els,
+            ann,
+        } => Exp::If {
+            cond: Box::new(lambda_lift_helper(cond, &mut env.clone(), false, state)),
+            thn: Box::new(lambda_lift_helper(thn, &mut env.clone(), is_tail, state)),
+            els: Box::new(lambda_lift_helper(els, &mut env.clone(), is_tail, state)),
+            ann: ann.clone(),
         },
-        Exp::FunDefs { decls, body, .. } => {
+        Exp::Semicolon { e1, e2, ann } => Exp::Semicolon {
+            e1: Box::new(lambda_lift_helper(e1, &mut env.clone(), false, state)),
+            e2: Box::new(lambda_lift_helper(e2, &mut env.clone(), is_tail, state)),
+            ann: ann.clone(),
+        },
+        Exp::While { cond, body, ann } => Exp::While {
+            cond: Box::new(lambda_lift_helper(cond, &mut env.clone(), false, state)),
+            body: Box::new(lambda_lift_helper(body, &mut env.clone(), false, state)),
+            ann: ann.clone(),
+        },
+        Exp::Assign { var, value, ann } => Exp::Assign {
+            var: var.clone(),
+            value: Box::new(lambda_lift_helper(value, &mut env.clone(), false, state)),
+            ann: ann.clone(),
+        },
+        Exp::FunDefs { decls, body, ann } => {
             let mut new_bodies = vec![];
             let mut new_envs = vec![];
             for decl in decls {
-                fun_to_env.insert(decl.name.clone(), env.clone());
+                state.fun_to_env.insert(decl.name.clone(), env.clone());
+                state.fun_to_arity.insert(decl.name.clone(), decl.parameters.len());
+            }
+            for decl in decls {
+                state.fun_to_env.insert(decl.name.clone(), env.clone());
                 let mut new_env = vec![];
                 for key in env.iter() {
                     if new_env.contains(key) {
@@ -105,155 +107,236 @@ pub fn lambda_lift_helper<Ann>(
                     new_env.push(key.clone());
                 }
                 new_env.extend(decl.parameters.clone());
-                let new_body = lambda_lift_helper(
-                    &decl.body,
-                    &mut new_env.clone(),
-                    lifted,
-                    should_lift,
-                    fun_to_decl,
-                    true,
-                    fun_to_env,
-                );
+                let new_body = lambda_lift_helper(&decl.body, &mut new_env.clone(), true, state);
                 let new_decl = FunDecl {
                     name: decl.name.clone(),
                     parameters: decl.parameters.clone(),
+                    signature: decl.signature.clone(),
                     body: new_body.clone(),
-                    ann: (),
+                    ann: decl.ann.clone(),
                 };
-                fun_to_decl.insert(decl.name.clone(), new_decl.clone());
+                state.fun_to_decl.insert(decl.name.clone(), new_decl.clone());
                 new_bodies.push(new_body.clone());
                 new_envs.push(new_env.clone());
             }
 
             let mut new_decls = vec![];
             for i in 0..decls.len() {
-                if should_lift.contains(&decls[i].name) {
-                    let new_body =
-                        copy_def(&new_bodies[i], fun_to_decl, fun_to_env, &mut env.clone());
-                    lifted.push(FunDecl {
+                if state.should_lift.contains(&decls[i].name) {
+                    let suffix = format!(".copy.{}", decls[i].name);
+                    let new_body = copy_def(
+                        &new_bodies[i],
+                        &mut state.fun_to_decl,
+                        &mut state.fun_to_env,
+                        &mut env.clone(),
+                        &suffix,
+                    );
+                    state.lifted.push(FunDecl {
                         name: decls[i].name.clone(),
                         parameters: new_envs[i].clone(),
+                        signature: Signature::default(),
                         body: new_body.clone(),
-                        ann: (),
+                        ann: decls[i].ann.clone(),
                     });
                 } else {
                     new_decls.push(FunDecl {
                         name: decls[i].name.clone(),
                         parameters: decls[i].parameters.clone(),
+                        signature: decls[i].signature.clone(),
                         body: new_bodies[i].clone(),
-                        ann: (),
+                        ann: decls[i].ann.clone(),
                     });
                 }
             }
-            let new_body = lambda_lift_helper(
-                body,
-                &mut env.clone(),
-                lifted,
-                should_lift,
-                fun_to_decl,
-                true,
-                fun_to_env,
-            );
+            let new_body = lambda_lift_helper(body, &mut env.clone(), is_tail, state);
             if new_decls.is_empty() {
                 return new_body;
             }
             Exp::FunDefs {
                 decls: new_decls,
                 body: Box::new(new_body),
-                ann: (),
+                ann: ann.clone(),
             }
         }
-        Exp::Call(fun_name, args, _) => {
+        Exp::Call(fun_name, args, ann) => {
             let mut new_args = vec![];
             for arg in args {
-                new_args.push(lambda_lift_helper(
-                    arg,
-                    env,
-                    lifted,
-                    should_lift,
-                    fun_to_decl,
-                    false,
-                    fun_to_env,
-                ))
+                new_args.push(lambda_lift_helper(arg, &mut env.clone(), false, state))
             }
-            if is_tail && !should_lift.contains(fun_name) {
-                return Exp::InternalTailCall(fun_name.clone(), new_args, ());
+            if !state.fun_to_arity.contains_key(fun_name) {
+                return Exp::ClosureCall {
+                    fun: Box::new(Exp::Var(fun_name.clone(), ann.clone())),
+                    args: new_args,
+                    is_tail,
+                    ann: ann.clone(),
+                };
+            }
+            if is_tail && !state.should_lift.contains(fun_name) {
+                return Exp::InternalTailCall(fun_name.clone(), new_args, ann.clone());
             }
             Exp::ExternalCall {
                 fun_name: fun_name.clone(),
                 args: new_args,
                 is_tail,
-                ann: (),
+                ann: ann.clone(),
+            }
+        }
+        Exp::Lambda {
+            parameters,
+            body,
+            ann,
+        } => {
+            let mut new_env = env.clone();
+            new_env.extend(parameters.clone());
+            let new_body = lambda_lift_helper(body, &mut new_env, true, state);
+            // the lifted function takes the closure itself as its first
+            // argument and unpacks the captured variables from it
+            let self_name = String::from("#self");
+            let bindings: Vec<(String, Exp<Ann>)> = env
+                .iter()
+                .enumerate()
+                .map(|(i, x)| {
+                    (
+                        x.clone(),
+                        Exp::Prim(
+                            Prim::ClosureEnv,
+                            vec![
+                                Box::new(Exp::Var(self_name.clone(), ann.clone())),
+                                Box::new(Exp::Num(i as i64, ann.clone())),
+                            ],
+                            ann.clone(),
+                        ),
+                    )
+                })
+                .collect();
+            let name = format!("lambda_{}", state.lifted.len());
+            let mut new_parameters = vec![self_name];
+            new_parameters.extend(parameters.clone());
+            state.lifted.push(FunDecl {
+                name: name.clone(),
+                parameters: new_parameters,
+                signature: Signature::default(),
+                body: if bindings.is_empty() {
+                    new_body
+                } else {
+                    Exp::Let {
+                        bindings,
+                        body: Box::new(new_body),
+                        ann: ann.clone(),
+                    }
+                },
+                ann: ann.clone(),
+            });
+            Exp::MakeClosure {
+                fun_name: name,
+                arity: parameters.len(),
+                env: env.clone(),
+                ann: ann.clone(),
+            }
+        }
+        Exp::ClosureCall { fun, args, ann, .. } => {
+            let new_fun = lambda_lift_helper(fun, &mut env.clone(), false, state);
+            let mut new_args = vec![];
+            for arg in args {
+                new_args.push(lambda_lift_helper(arg, &mut env.clone(), false, state))
+            }
+            Exp::ClosureCall {
+                fun: Box::new(new_fun),
+                args: new_args,
+                is_tail,
+                ann: ann.clone(),
             }
         }
         _ => panic!("lambda_lift get wrong Exp"),
     }
 }
 
-fn copy_def(
-    e: &Exp<()>,
-    fun_to_decl: &mut HashMap<String, FunDecl<Exp<()>, ()>>,
+fn copy_def<Ann: Clone>(
+    e: &Exp<Ann>,
+    fun_to_decl: &mut HashMap<String, FunDecl<Exp<Ann>, Ann>>,
     fun_to_env: &mut HashMap<String, Vec<String>>,
     env: &mut Vec<String>,
-) -> Exp<()> {
+    suffix: &str,
+) -> Exp<Ann> {
     match e {
-        Exp::Let { bindings, body, .. } => {
+        Exp::Let { bindings, body, ann } => {
             for (a, _) in bindings {
                 env.push(a.clone());
             }
             Exp::Let {
                 bindings: bindings.clone(),
-                body: Box::new(copy_def(body, fun_to_decl, fun_to_env, &mut env.clone())),
-                ann: (),
+                body: Box::new(copy_def(body, fun_to_decl, fun_to_env, &mut env.clone(), suffix)),
+                ann: ann.clone(),
             }
         }
-        Exp::If { cond, thn, els, .. } => Exp::If {
+        Exp::If {
+            cond,
+            thn,
+            els,
+            ann,
+        } => Exp::If {
             cond: cond.clone(),
-            thn: Box::new(copy_def(thn, fun_to_decl, fun_to_env, &mut env.clone())),
-            els: Box::new(copy_def(els, fun_to_decl, fun_to_env, &mut env.clone())),
-            ann: (),
+            thn: Box::new(copy_def(thn, fun_to_decl, fun_to_env, &mut env.clone(), suffix)),
+            els: Box::new(copy_def(els, fun_to_decl, fun_to_env, &mut env.clone(), suffix)),
+            ann: ann.clone(),
         },
-        Exp::FunDefs { decls, body, .. } => {
+        Exp::Semicolon { e1, e2, ann } => Exp::Semicolon {
+            e1: e1.clone(),
+            e2: Box::new(copy_def(e2, fun_to_decl, fun_to_env, &mut env.clone(), suffix)),
+            ann: ann.clone(),
+        },
+        Exp::FunDefs { decls, body, ann } => {
+            // functions defined in the copied body come along with it
+            for decl in decls {
+                env.push(decl.name.clone());
+            }
             let mut new_decls = vec![];
             for decl in decls {
-                let new_body = copy_def(&decl.body, fun_to_decl, fun_to_env, &mut env.clone());
-                let new_decl: FunDecl<Exp<()>, ()> = FunDecl {
+                let new_body = copy_def(&decl.body, fun_to_decl, fun_to_env, &mut env.clone(), suffix);
+                let new_decl: FunDecl<Exp<Ann>, Ann> = FunDecl {
                     name: decl.name.clone(),
                     parameters: decl.parameters.clone(),
+                    signature: decl.signature.clone(),
                     body: new_body,
-                    ann: (),
+                    ann: decl.ann.clone(),
                 };
                 new_decls.push(new_decl);
             }
             Exp::FunDefs {
                 decls: new_decls,
-                body: Box::new(copy_def(body, fun_to_decl, fun_to_env, &mut env.clone())),
-                ann: (),
+                body: Box::new(copy_def(body, fun_to_decl, fun_to_env, &mut env.clone(), suffix)),
+                ann: ann.clone(),
             }
         }
-        Exp::InternalTailCall(fun_name, args, _) => match fun_to_decl.get(fun_name) {
+        Exp::InternalTailCall(fun_name, _, _) if env.contains(fun_name) => e.clone(),
+        // the copies made for one lifted function share the suffix
+        Exp::InternalTailCall(fun_name, args, ann) => match fun_to_decl.get(fun_name).cloned() {
             Some(value) => {
-                if !fun_to_env.contains_key(&format!("{}_copy", value.name.clone())) {
-                    let new_name = format!("{}_copy", value.name.clone());
-                    let new_decl = FunDecl {
-                        name: new_name.clone(),
-                        parameters: value.parameters.clone(),
-                        body: value.body.clone(),
-                        ann: (),
-                    };
+                let new_name = format!("{}{}", value.name, suffix);
+                if !fun_to_env.contains_key(&new_name) {
                     match fun_to_env.get(fun_name) {
                         Some(value) => {
                             fun_to_env.insert(new_name.clone(), value.clone());
                         }
                         None => panic!("copy cannot find in fun_to_env"),
                     }
+                    let mut funs = vec![];
+                    local_funs(&value.body, &mut funs);
+                    let body = rename_local_funs(&value.body, &funs, fun_to_env, suffix);
+                    let new_decl = FunDecl {
+                        name: new_name.clone(),
+                        parameters: value.parameters.clone(),
+                        signature: value.signature.clone(),
+                        body: copy_def(&body, fun_to_decl, fun_to_env, &mut vec![], suffix),
+                        ann: value.ann.clone(),
+                    };
                     Exp::FunDefs {
                         decls: vec![new_decl],
-                        body: Box::new(Exp::InternalTailCall(new_name.clone(), args.clone(), ())),
-                        ann: (),
+                        body: Box::new(Exp::InternalTailCall(new_name, args.clone(), ann.clone())),
+                        ann: ann.clone(),
                     }
                 } else {
-                    Exp::InternalTailCall(format!("{}_copy", value.name.clone()), args.clone(), ())
+                    Exp::InternalTailCall(new_name, args.clone(), ann.clone())
                 }
             }
             None => panic!("copy_def didn't find any fun name"),
@@ -262,3 +345,142 @@ fn copy_def(
         _ => e.clone(),
     }
 }
+
+// The local functions defined in e. Functions that are never called
+// aren't lifted, so they can be defined anywhere.
+fn local_funs<Ann>(e: &Exp<Ann>, funs: &mut Vec<String>) {
+    match e {
+        Exp::Prim(_, es, _) => {
+            for e in es {
+                local_funs(e, funs);
+            }
+        }
+        Exp::Let { bindings, body, .. } => {
+            for (_, e) in bindings {
+                local_funs(e, funs);
+            }
+            local_funs(body, funs);
+        }
+        Exp::If { cond, thn, els, .. } => {
+            local_funs(cond, funs);
+            local_funs(thn, funs);
+            local_funs(els, funs);
+        }
+        Exp::Semicolon { e1, e2, .. } => {
+            local_funs(e1, funs);
+            local_funs(e2, funs);
+        }
+        Exp::While { cond, body, .. } => {
+            local_funs(cond, funs);
+            local_funs(body, funs);
+        }
+        Exp::Assign { value, .. } => local_funs(value, funs),
+        Exp::FunDefs { decls, body, .. } => {
+            for decl in decls {
+                funs.push(decl.name.clone());
+                local_funs(&decl.body, funs);
+            }
+            local_funs(body, funs);
+        }
+        Exp::InternalTailCall(_, args, _) | Exp::ExternalCall { args, .. } => {
+            for e in args {
+                local_funs(e, funs);
+            }
+        }
+        Exp::ClosureCall { fun, args, .. } => {
+            local_funs(fun, funs);
+            for e in args {
+                local_funs(e, funs);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Appends suffix to the names of the local functions in funs, so a copy
+// of a function body doesn't define the same labels as the original
+fn rename_local_funs<Ann: Clone>(
+    e: &Exp<Ann>,
+    funs: &[String],
+    fun_to_env: &mut HashMap<String, Vec<String>>,
+    suffix: &str,
+) -> Exp<Ann> {
+    let rename = |e: &Exp<Ann>, fun_to_env: &mut HashMap<String, Vec<String>>| {
+        rename_local_funs(e, funs, fun_to_env, suffix)
+    };
+    match e {
+        Exp::Prim(p, es, ann) => Exp::Prim(*p, es.iter().map(|e| Box::new(rename(e, fun_to_env))).collect(), ann.clone()),
+        Exp::Let { bindings, body, ann } => Exp::Let {
+            bindings: bindings.iter().map(|(x, e)| (x.clone(), rename(e, fun_to_env))).collect(),
+            body: Box::new(rename(body, fun_to_env)),
+            ann: ann.clone(),
+        },
+        Exp::If {
+            cond,
+            thn,
+            els,
+            ann,
+        } => Exp::If {
+            cond: Box::new(rename(cond, fun_to_env)),
+            thn: Box::new(rename(thn, fun_to_env)),
+            els: Box::new(rename(els, fun_to_env)),
+            ann: ann.clone(),
+        },
+        Exp::Semicolon { e1, e2, ann } => Exp::Semicolon {
+            e1: Box::new(rename(e1, fun_to_env)),
+            e2: Box::new(rename(e2, fun_to_env)),
+            ann: ann.clone(),
+        },
+        Exp::While { cond, body, ann } => Exp::While {
+            cond: Box::new(rename(cond, fun_to_env)),
+            body: Box::new(rename(body, fun_to_env)),
+            ann: ann.clone(),
+        },
+        Exp::Assign { var, value, ann } => Exp::Assign {
+            var: var.clone(),
+            value: Box::new(rename(value, fun_to_env)),
+            ann: ann.clone(),
+        },
+        Exp::FunDefs { decls, body, ann } => {
+            let mut new_decls = vec![];
+            for decl in decls {
+                let new_name = format!("{}{}", decl.name, suffix);
+                let env = fun_to_env[&decl.name].clone();
+                fun_to_env.insert(new_name.clone(), env);
+                new_decls.push(FunDecl {
+                    name: new_name,
+                    parameters: decl.parameters.clone(),
+                    signature: decl.signature.clone(),
+                    body: rename(&decl.body, fun_to_env),
+                    ann: decl.ann.clone(),
+                });
+            }
+            Exp::FunDefs {
+                decls: new_decls,
+                body: Box::new(rename(body, fun_to_env)),
+                ann: ann.clone(),
+            }
+        }
+        Exp::InternalTailCall(fun_name, args, ann) => {
+            let args = args.iter().map(|e| rename(e, fun_to_env)).collect();
+            if funs.contains(fun_name) {
+                Exp::InternalTailCall(format!("{}{}", fun_name, suffix), args, ann.clone())
+            } else {
+                Exp::InternalTailCall(fun_name.clone(), args, ann.clone())
+            }
+        }
+        Exp::ExternalCall { fun_name, args, is_tail, ann } => Exp::ExternalCall {
+            fun_name: fun_name.clone(),
+            args: args.iter().map(|e| rename(e, fun_to_env)).collect(),
+            is_tail: *is_tail,
+            ann: ann.clone(),
+        },
+        Exp::ClosureCall { fun, args, is_tail, ann } => Exp::ClosureCall {
+            fun: Box::new(rename(fun, fun_to_env)),
+            args: args.iter().map(|e| rename(e, fun_to_env)).collect(),
+            is_tail: *is_tail,
+            ann: ann.clone(),
+        },
+        _ => e.clone(),
+    }
+}
@@ -0,0 +1,251 @@
+/* The tokens of the surface syntax, for the parser in parser.lalrpop.
+ *
+ * Whitespace and comments are skipped: `#` comments go to the end of
+ * the line and `(* ... *)` comments may be nested, so code that has a
+ * comment in it can be commented out as a whole. The offsets of the
+ * tokens are those of the whole text, comments included, so spans
+ * point at the source as it was written.
+ *
+ * Otherwise a token is the longest text that matches one, and a
+ * keyword or symbol wins over a name or number of the same length,
+ * e.g. `-1` is a number, `iff` a name and `if` the keyword. Names that
+ * start with a capital letter are the names of constructors.
+ *
+ * A string literal is the text between two `"`, in which `\n`, `\t`,
+ * `\\` and `\"` stand for a newline, a tab, `\` and `"`. Its token
+ * is the text as written, unescape gives the string it stands for.
+ *
+ * Integers have to fit into 64 bits, so that the parser can read them.
+ */
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tok<'input> {
+    Id(&'input str),
+    // a name that starts with a capital letter
+    Con(&'input str),
+    Num(&'input str),
+    Float(&'input str),
+    // the text between the quotes of a string literal
+    Str(&'input str),
+    // keywords and symbols, as they are written
+    Sym(&'static str),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LexError {
+    // a character no token starts with, at its offset
+    InvalidToken(usize),
+    // a `(*` without its `*)`, at the offset of the `(*`
+    UnterminatedComment(usize),
+    // a `"` without its closing one, at the offset of the first
+    UnterminatedString(usize),
+    // a `\` not followed by one of `n`, `t`, `\` or `"`, at its offset
+    InvalidEscape(usize),
+    // an integer that doesn't fit into 64 bits, at its start and end
+    NumTooBig(usize, usize),
+}
+
+pub type Spanned<'input> = Result<(usize, Tok<'input>, usize), LexError>;
+
+static SYMBOLS: [&str; 63] = [
+    "and", "in", "def", "import", "export", "Int", "Float", "Bool", "Tuple", "String", "Array", "if",
+    "else", "let", "lambda", "end", "while", "type", "match", "add1", "sub1", "print", "isnum",
+    "isbool", "isfloat", "istuple", "sqrt", "cos", "len", "substr", "tostring", "array", "length",
+    "true", "false", "(", ")", "[", "]", ",", ":", ":=", "->", "=", "=>", "+", "++", "-", "*", "/",
+    "//", "&&", "||", "|", "<", "<=", ">", ">=", "==", "!=", "!", ";", "_",
+];
+
+pub struct Lexer<'input> {
+    text: &'input str,
+    pos: usize,
+}
+
+impl<'input> Lexer<'input> {
+    pub fn new(text: &'input str) -> Self {
+        Lexer { text, pos: 0 }
+    }
+
+    // Moves past the whitespace and comments at pos
+    fn skip(&mut self) -> Result<(), LexError> {
+        loop {
+            let rest = &self.text[self.pos..];
+            let trimmed = rest.trim_start();
+            self.pos += rest.len() - trimmed.len();
+            if trimmed.starts_with('#') {
+                self.pos += trimmed.find('\n').unwrap_or(trimmed.len());
+            } else if trimmed.starts_with("(*") {
+                self.pos += block_comment(trimmed).ok_or(LexError::UnterminatedComment(self.pos))?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<'input> Iterator for Lexer<'input> {
+    type Item = Spanned<'input>;
+
+    fn next(&mut self) -> Option<Spanned<'input>> {
+        if let Err(e) = self.skip() {
+            // nothing after an error
+            self.pos = self.text.len();
+            return Some(Err(e));
+        }
+        let rest = &self.text[self.pos..];
+        if rest.is_empty() {
+            return None;
+        }
+        if rest.starts_with('"') {
+            let start = self.pos;
+            return Some(match string_len(rest, start) {
+                Ok(len) => {
+                    self.pos += len;
+                    Ok((start, Tok::Str(&rest[1..len - 1]), self.pos))
+                }
+                Err(e) => {
+                    self.pos = self.text.len();
+                    Err(e)
+                }
+            });
+        }
+        let sym = SYMBOLS.iter().copied().filter(|s| rest.starts_with(s)).max_by_key(|s| s.len()).unwrap_or("");
+        let (len, tok) = [
+            (id_len(rest), name as fn(&'input str) -> Tok<'input>),
+            (num_len(rest), Tok::Num),
+            (float_len(rest), Tok::Float),
+        ]
+        .iter()
+        .copied()
+        .filter(|(len, _)| *len > sym.len())
+        .max_by_key(|(len, _)| *len)
+        .map_or((sym.len(), Tok::Sym(sym)), |(len, tok)| (len, tok(&rest[..len])));
+        if len == 0 {
+            let start = self.pos;
+            self.pos = self.text.len();
+            return Some(Err(LexError::InvalidToken(start)));
+        }
+        let start = self.pos;
+        if let Tok::Num(n) = tok {
+            if n.parse::<i64>().is_err() {
+                self.pos = self.text.len();
+                return Some(Err(LexError::NumTooBig(start, start + len)));
+            }
+        }
+        self.pos += len;
+        Some(Ok((start, tok, self.pos)))
+    }
+}
+
+fn name(s: &str) -> Tok<'_> {
+    if s.starts_with(|c: char| c.is_ascii_uppercase()) {
+        Tok::Con(s)
+    } else {
+        Tok::Id(s)
+    }
+}
+
+// The length of the string literal s starts with, quotes included; s
+// is at offset start of the text
+fn string_len(s: &str, start: usize) -> Result<usize, LexError> {
+    let mut chars = s.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok(i + 1),
+            '\\' => match chars.next() {
+                Some((_, 'n' | 't' | '\\' | '"')) => {}
+                _ => return Err(LexError::InvalidEscape(start + i)),
+            },
+            _ => {}
+        }
+    }
+    Err(LexError::UnterminatedString(start))
+}
+
+// The string the text of a string literal stands for
+pub fn unescape(s: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        unescaped.push(match c {
+            '\\' => match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some(c) => c,
+                None => unreachable!("the lexer checks escapes"),
+            },
+            c => c,
+        });
+    }
+    unescaped
+}
+
+// The length of the comment s starts with, up to its matching `*)`
+fn block_comment(s: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut i = 0;
+    while i < s.len() {
+        if s[i..].starts_with("(*") {
+            depth += 1;
+            i += 2;
+        } else if s[i..].starts_with("*)") {
+            depth -= 1;
+            i += 2;
+            if depth == 0 {
+                return Some(i);
+            }
+        } else {
+            i += s[i..].chars().next().unwrap().len_utf8();
+        }
+    }
+    None
+}
+
+fn digits(s: &str) -> usize {
+    s.bytes().take_while(|b| b.is_ascii_digit()).count()
+}
+
+fn sign(s: &str) -> usize {
+    if s.starts_with('+') || s.starts_with('-') {
+        1
+    } else {
+        0
+    }
+}
+
+// [a-zA-Z][a-zA-Z0-9_]*
+fn id_len(s: &str) -> usize {
+    match s.bytes().next() {
+        Some(b) if b.is_ascii_alphabetic() => {
+            1 + s[1..].bytes().take_while(|b| b.is_ascii_alphanumeric() || *b == b'_').count()
+        }
+        _ => 0,
+    }
+}
+
+// [+-]?[0-9]+
+fn num_len(s: &str) -> usize {
+    let sign = sign(s);
+    match digits(&s[sign..]) {
+        0 => 0,
+        n => sign + n,
+    }
+}
+
+// [+-]?([0-9]+)?\.([0-9]+)?([eE][+-]?[0-9]+)?
+fn float_len(s: &str) -> usize {
+    let mut len = sign(s);
+    len += digits(&s[len..]);
+    if !s[len..].starts_with('.') {
+        return 0;
+    }
+    len += 1;
+    len += digits(&s[len..]);
+    if s[len..].starts_with('e') || s[len..].starts_with('E') {
+        let exp = 1 + sign(&s[len + 1..]);
+        let n = digits(&s[len + exp..]);
+        if n > 0 {
+            len += exp + n;
+        }
+    }
+    len
+}
@@ -1,6 +1,7 @@
 pub mod asm;
 pub mod compile;
 pub mod interp;
+pub mod lexer;
 pub mod parser;
 pub mod runner;
 pub mod span;
@@ -8,6 +9,13 @@ pub mod syntax;
 pub mod check;
 pub mod tag;
 pub mod should_lift;
+pub mod matching;
+pub mod boxing;
 pub mod lambda_lift;
 pub mod seq_exp;
-pub mod compile_with_env;
\ No newline at end of file
+pub mod compile_with_env;
+pub mod infer;
+pub mod regalloc;
+pub mod fuzz;
+pub mod repl;
+pub mod diagnostic;
@@ -1,5 +1,5 @@
 use snake::runner::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 static USAGE_MSG: &str = "\
 To compile a program and emit assembly code to stdout use
@@ -10,27 +10,105 @@ To compile a program, link it and run the produced binary use
 
     snake --run INPUT_FILE
 
+The heap of the produced binary holds 1048576 words, or WORDS when
+given
+
+    --heap=WORDS
+
+Errors are printed for people to read, or as one JSON object per
+line for editors and other tools when given
+
+    --error-format=json
+
+To compile a program to an object and an interface next to it, and
+the files it imports that have changed, use
+
+    snake --object INPUT_FILE
+
+To link the objects of a program with the runtime into an executable
+named after the first one, whose body is run, use
+
+    snake --link OBJECT_FILE...
+
 To run the reference interpreter use
 
     snake --interp INPUT_FILE
 
+To run a program with both the interpreter and the compiler and
+report where their outputs differ use
+
+    snake --diff INPUT_FILE
+
+To run COUNT random programs through both the interpreter and the
+compiler, and save a minimal version of the first one they disagree on
+under examples, use
+
+    snake --fuzz COUNT
+
+The random programs start from the one for SEED when given
+
+    --seed=SEED
+
+To start an interactive interpreter, where definitions stay in scope
+for the expressions after them, use
+
+    snake --repl
+
 To see this usage message run
 
     snake --help
 ";
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() == 2 {
+    let mut heap = None;
+    let mut seed = None;
+    let mut format = ErrorFormat::Human;
+    let mut args = vec![];
+    for arg in std::env::args() {
+        if let Some(words) = arg.strip_prefix("--heap=") {
+            match words.parse() {
+                Ok(words) => heap = Some(words),
+                Err(_) => return usage(Some("the heap size must be a number of words")),
+            }
+        } else if let Some(f) = arg.strip_prefix("--error-format=") {
+            match f {
+                "human" => format = ErrorFormat::Human,
+                "json" => format = ErrorFormat::Json,
+                _ => return usage(Some("the error format must be human or json")),
+            }
+        } else if let Some(n) = arg.strip_prefix("--seed=") {
+            match n.parse() {
+                Ok(n) => seed = Some(n),
+                Err(_) => return usage(Some("the seed must be a number")),
+            }
+        } else {
+            args.push(arg)
+        }
+    }
+    if args.len() > 2 && args[1] == "--link" {
+        let objects: Vec<PathBuf> = args[2..].iter().map(PathBuf::from).collect();
+        link(&objects, format)
+    } else if args.len() == 2 {
         match args[1].as_str() {
-            "--interp" | "--run" => usage(Some("interp and run comands require an input file")),
+            "--interp" | "--run" | "--diff" | "--object" => {
+                usage(Some("interp, run, diff and object comands require an input file"))
+            }
+            "--link" => usage(Some("link requires the objects to link")),
+            "--fuzz" => usage(Some("fuzz requires a number of programs")),
+            "--repl" => repl(),
             "--help" => usage(None),
-            path => emit_assembly(Path::new(&path)),
+            path => emit_assembly(Path::new(&path), format),
         }
     } else if args.len() == 3 {
         match args[1].as_str() {
             "--interp" => interp(Path::new(&args[2]), &mut std::io::stdout()),
-            "--run" => run(Path::new(&args[2])),
+            "--run" => run(Path::new(&args[2]), heap, format),
+            "--object" => emit_object(Path::new(&args[2]), format),
+            "--diff" => diff(Path::new(&args[2])),
+            "--fuzz" => match args[2].parse() {
+                Ok(count) => fuzz(count, seed),
+                Err(_) => usage(Some("the number of programs must be a number")),
+            },
             _ => usage(Some("Failed to parse input")),
         }
     } else if args.len() <= 1 {
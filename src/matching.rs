@@ -0,0 +1,349 @@
+use crate::syntax::{Exp, FunDecl, Pattern, Prim, Signature};
+use std::collections::HashMap;
+
+/* Turns the type declarations, constructors and matches of e into the
+ * forms the rest of the compiler knows. A value made by a constructor
+ * is a heap object with the number of the constructor and its fields;
+ * the numbers start at first and go up in the order of the names
+ * returned, which the runtime prints the value with.
+ *
+ * A match becomes a decision tree of ifs, each of which tests one part
+ * of the value against one constructor, so no part is tested twice on
+ * the way to an arm. An arm the tree reaches in more than one place
+ * becomes a local function of the variables of its pattern, which the
+ * tree calls, so its body is still compiled once.
+ *
+ * The names of e must be unique, as tag_exp leaves them.
+ */
+pub fn lower_matches<Ann: Clone>(e: &Exp<Ann>, first: usize) -> (Exp<Ann>, Vec<String>) {
+    let mut lower = Lower {
+        names: vec![],
+        first,
+        next: 0,
+    };
+    let e = lower.exp(e, &HashMap::new());
+    (e, lower.names)
+}
+
+struct Lower {
+    // the constructors, by their number less first
+    names: Vec<String>,
+    first: usize,
+    // for the names of the variables a match binds
+    next: usize,
+}
+
+// What is left to match of an arm: the parts of the value its pattern
+// still has constructors for, and the variables matched so far
+struct Row<'p, Ann> {
+    tests: Vec<(String, &'p Pattern<Ann>)>,
+    bindings: Vec<(String, String)>,
+    arm: usize,
+}
+
+enum Tree {
+    Fail,
+    // the arm, with the parts of the value its variables are bound to
+    Arm(usize, Vec<(String, String)>),
+    // if the part is made by the constructor, its fields are bound
+    Test {
+        part: String,
+        constructor: usize,
+        fields: Vec<String>,
+        yes: Box<Tree>,
+        no: Box<Tree>,
+    },
+}
+
+impl Lower {
+    fn fresh(&mut self) -> String {
+        self.next += 1;
+        format!("match#{}", self.next)
+    }
+
+    fn exp<Ann: Clone>(&mut self, e: &Exp<Ann>, scope: &HashMap<String, usize>) -> Exp<Ann> {
+        match e {
+            Exp::TypeDecl { constructors, body, .. } => {
+                let mut scope = scope.clone();
+                for c in constructors {
+                    scope.insert(c.name.clone(), self.first + self.names.len());
+                    self.names.push(c.name.clone());
+                }
+                self.exp(body, &scope)
+            }
+            Exp::Construct { name, args, ann } => {
+                let mut es = vec![Box::new(Exp::Num(scope[name] as i64, ann.clone()))];
+                es.extend(args.iter().map(|e| Box::new(self.exp(e, scope))));
+                Exp::Prim(Prim::MakeVariant, es, ann.clone())
+            }
+            Exp::Match { scrutinee, arms, ann } => self.lower_match(scrutinee, arms, ann, scope),
+            Exp::Num(..) | Exp::Bool(..) | Exp::Float(..) | Exp::Str(..) | Exp::Var(..) | Exp::MakeClosure { .. } => e.clone(),
+            Exp::Prim(p, es, ann) => Exp::Prim(*p, es.iter().map(|e| Box::new(self.exp(e, scope))).collect(), ann.clone()),
+            Exp::Let { bindings, body, ann } => Exp::Let {
+                bindings: bindings.iter().map(|(x, e)| (x.clone(), self.exp(e, scope))).collect(),
+                body: Box::new(self.exp(body, scope)),
+                ann: ann.clone(),
+            },
+            Exp::If { cond, thn, els, ann } => Exp::If {
+                cond: Box::new(self.exp(cond, scope)),
+                thn: Box::new(self.exp(thn, scope)),
+                els: Box::new(self.exp(els, scope)),
+                ann: ann.clone(),
+            },
+            Exp::FunDefs { decls, body, ann } => Exp::FunDefs {
+                decls: decls
+                    .iter()
+                    .map(|decl| FunDecl {
+                        name: decl.name.clone(),
+                        parameters: decl.parameters.clone(),
+                        signature: decl.signature.clone(),
+                        body: self.exp(&decl.body, scope),
+                        ann: decl.ann.clone(),
+                    })
+                    .collect(),
+                body: Box::new(self.exp(body, scope)),
+                ann: ann.clone(),
+            },
+            Exp::Semicolon { e1, e2, ann } => Exp::Semicolon {
+                e1: Box::new(self.exp(e1, scope)),
+                e2: Box::new(self.exp(e2, scope)),
+                ann: ann.clone(),
+            },
+            Exp::While { cond, body, ann } => Exp::While {
+                cond: Box::new(self.exp(cond, scope)),
+                body: Box::new(self.exp(body, scope)),
+                ann: ann.clone(),
+            },
+            Exp::Assign { var, value, ann } => Exp::Assign {
+                var: var.clone(),
+                value: Box::new(self.exp(value, scope)),
+                ann: ann.clone(),
+            },
+            Exp::Lambda { parameters, body, ann } => Exp::Lambda {
+                parameters: parameters.clone(),
+                body: Box::new(self.exp(body, scope)),
+                ann: ann.clone(),
+            },
+            Exp::Call(f, args, ann) => Exp::Call(f.clone(), args.iter().map(|e| self.exp(e, scope)).collect(), ann.clone()),
+            Exp::ClosureCall { fun, args, is_tail, ann } => Exp::ClosureCall {
+                fun: Box::new(self.exp(fun, scope)),
+                args: args.iter().map(|e| self.exp(e, scope)).collect(),
+                is_tail: *is_tail,
+                ann: ann.clone(),
+            },
+            Exp::InternalTailCall(f, args, ann) => {
+                Exp::InternalTailCall(f.clone(), args.iter().map(|e| self.exp(e, scope)).collect(), ann.clone())
+            }
+            Exp::ExternalCall { fun_name, args, is_tail, ann } => Exp::ExternalCall {
+                fun_name: fun_name.clone(),
+                args: args.iter().map(|e| self.exp(e, scope)).collect(),
+                is_tail: *is_tail,
+                ann: ann.clone(),
+            },
+        }
+    }
+
+    fn lower_match<Ann: Clone>(
+        &mut self,
+        scrutinee: &Exp<Ann>,
+        arms: &[(Pattern<Ann>, Exp<Ann>)],
+        ann: &Ann,
+        scope: &HashMap<String, usize>,
+    ) -> Exp<Ann> {
+        let value = self.fresh();
+        let rows = arms
+            .iter()
+            .enumerate()
+            .map(|(arm, (pattern, _))| Row {
+                tests: vec![(value.clone(), pattern)],
+                bindings: vec![],
+                arm,
+            })
+            .collect();
+        let tree = self.tree(rows, scope);
+
+        let mut uses = vec![0; arms.len()];
+        count_arms(&tree, &mut uses);
+        let mut shared = HashMap::new();
+        let mut decls = vec![];
+        for (arm, (pattern, body)) in arms.iter().enumerate() {
+            if uses[arm] > 1 {
+                let name = format!("fun_match_{}", self.fresh().trim_start_matches("match#"));
+                decls.push(FunDecl {
+                    name: name.clone(),
+                    parameters: pattern.vars().into_iter().cloned().collect(),
+                    signature: Signature::default(),
+                    body: self.exp(body, scope),
+                    ann: ann.clone(),
+                });
+                shared.insert(arm, name);
+            }
+        }
+        let mut bodies: Vec<Option<Exp<Ann>>> = arms
+            .iter()
+            .enumerate()
+            .map(|(arm, (_, body))| match uses[arm] {
+                1 => Some(self.exp(body, scope)),
+                _ => None,
+            })
+            .collect();
+        let mut body = emit(&tree, &value, arms, &shared, &mut bodies, ann);
+        if !decls.is_empty() {
+            body = Exp::FunDefs {
+                decls,
+                body: Box::new(body),
+                ann: ann.clone(),
+            };
+        }
+        Exp::Let {
+            bindings: vec![(value, self.exp(scrutinee, scope))],
+            body: Box::new(body),
+            ann: ann.clone(),
+        }
+    }
+
+    /* The decision tree of rows, of which the first one that matches
+     * wins. Once the first row tests nothing more it matches; otherwise
+     * its first test is made, and the rows that agree with either
+     * outcome go on to the branch of that outcome.
+     */
+    fn tree<Ann>(&mut self, rows: Vec<Row<Ann>>, scope: &HashMap<String, usize>) -> Tree {
+        // wildcards match anything and variables are bound to the part
+        let mut rows: Vec<Row<Ann>> = rows
+            .into_iter()
+            .map(|mut row| {
+                let mut tests = vec![];
+                for (part, p) in row.tests {
+                    match p {
+                        Pattern::Wildcard(_) => {}
+                        Pattern::Var(x, _) => row.bindings.push((x.clone(), part)),
+                        Pattern::Constructor(..) => tests.push((part, p)),
+                    }
+                }
+                row.tests = tests;
+                row
+            })
+            .collect();
+        if rows.is_empty() {
+            return Tree::Fail;
+        }
+        if rows[0].tests.is_empty() {
+            let row = rows.swap_remove(0);
+            return Tree::Arm(row.arm, row.bindings);
+        }
+        let (part, c, arity) = match &rows[0].tests[0] {
+            (part, Pattern::Constructor(c, ps, _)) => (part.clone(), c.clone(), ps.len()),
+            _ => unreachable!(),
+        };
+        let fields: Vec<String> = (0..arity).map(|_| self.fresh()).collect();
+        let mut yes = vec![];
+        let mut no = vec![];
+        for row in rows {
+            let test = row.tests.iter().position(|(p, _)| *p == part);
+            match test.map(|i| (i, row.tests[i].1)) {
+                Some((i, Pattern::Constructor(d, ps, _))) if *d == c => {
+                    let mut tests = row.tests[..i].to_vec();
+                    tests.extend(fields.iter().cloned().zip(ps.iter()));
+                    tests.extend(row.tests[i + 1..].iter().cloned());
+                    yes.push(Row {
+                        tests,
+                        bindings: row.bindings,
+                        arm: row.arm,
+                    });
+                }
+                Some(_) => no.push(row),
+                None => {
+                    yes.push(Row {
+                        tests: row.tests.clone(),
+                        bindings: row.bindings.clone(),
+                        arm: row.arm,
+                    });
+                    no.push(row);
+                }
+            }
+        }
+        Tree::Test {
+            part,
+            constructor: scope[&c],
+            fields,
+            yes: Box::new(self.tree(yes, scope)),
+            no: Box::new(self.tree(no, scope)),
+        }
+    }
+}
+
+fn count_arms(tree: &Tree, uses: &mut Vec<usize>) {
+    match tree {
+        Tree::Fail => {}
+        Tree::Arm(arm, _) => uses[*arm] += 1,
+        Tree::Test { yes, no, .. } => {
+            count_arms(yes, uses);
+            count_arms(no, uses);
+        }
+    }
+}
+
+fn emit<Ann: Clone>(
+    tree: &Tree,
+    value: &str,
+    arms: &[(Pattern<Ann>, Exp<Ann>)],
+    shared: &HashMap<usize, String>,
+    bodies: &mut Vec<Option<Exp<Ann>>>,
+    ann: &Ann,
+) -> Exp<Ann> {
+    let var = |x: &str| Exp::Var(x.to_string(), ann.clone());
+    match tree {
+        Tree::Fail => Exp::Prim(Prim::MatchFail, vec![Box::new(var(value))], ann.clone()),
+        Tree::Arm(arm, bindings) => match shared.get(arm) {
+            Some(f) => {
+                let part = |x: &String| bindings.iter().find(|(y, _)| y == x).map(|(_, part)| var(part)).unwrap();
+                Exp::Call(f.clone(), arms[*arm].0.vars().into_iter().map(part).collect(), ann.clone())
+            }
+            None => {
+                let body = bodies[*arm].take().unwrap();
+                if bindings.is_empty() {
+                    return body;
+                }
+                Exp::Let {
+                    bindings: bindings.iter().map(|(x, part)| (x.clone(), var(part))).collect(),
+                    body: Box::new(body),
+                    ann: ann.clone(),
+                }
+            }
+        },
+        Tree::Test {
+            part,
+            constructor,
+            fields,
+            yes,
+            no,
+        } => {
+            let mut thn = emit(yes, value, arms, shared, bodies, ann);
+            if !fields.is_empty() {
+                thn = Exp::Let {
+                    bindings: fields
+                        .iter()
+                        .enumerate()
+                        .map(|(i, x)| {
+                            let i = Exp::Num(i as i64, ann.clone());
+                            (x.clone(), Exp::Prim(Prim::VariantField, vec![Box::new(var(part)), Box::new(i)], ann.clone()))
+                        })
+                        .collect(),
+                    body: Box::new(thn),
+                    ann: ann.clone(),
+                };
+            }
+            let cond = Exp::Prim(
+                Prim::IsVariant,
+                vec![Box::new(var(part)), Box::new(Exp::Num(*constructor as i64, ann.clone()))],
+                ann.clone(),
+            );
+            Exp::If {
+                cond: Box::new(cond),
+                thn: Box::new(thn),
+                els: Box::new(emit(no, value, arms, shared, bodies, ann)),
+                ann: ann.clone(),
+            }
+        }
+    }
+}
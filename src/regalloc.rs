@@ -0,0 +1,366 @@
+use crate::asm::Reg;
+use crate::syntax::{ImmExp, Prim, SeqExp};
+use std::collections::{HashMap, HashSet};
+
+// Registers that let-bound variables can be kept in. rax, r8, r9, rdi,
+// rsi and rdx are scratch registers of the code generator and r15 is
+// the heap pointer.
+pub static VAR_REGS: [Reg; 7] = [
+    Reg::Rbx,
+    Reg::R12,
+    Reg::R13,
+    Reg::R14,
+    Reg::Rcx,
+    Reg::R10,
+    Reg::R11,
+];
+
+// the registers the runtime functions may overwrite
+pub static CALLER_SAVED: [Reg; 3] = [Reg::Rcx, Reg::R10, Reg::R11];
+
+/* Where the variables of one body of code live. The bodies of local
+ * functions are entered with a jump that overwrites the frame, so each
+ * of them gets an allocation of its own.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Allocation {
+    // variables that got a register, all others live in stack slots
+    pub regs: HashMap<String, Reg>,
+    // registers holding live variables at a call or allocation, by its tag
+    pub saved: HashMap<u32, Vec<Reg>>,
+    // allocations of the local functions defined in this body
+    pub locals: HashMap<String, Allocation>,
+    // stack slots needed by this body and its local functions
+    pub slots: i32,
+}
+
+impl Allocation {
+    pub fn saved_at(&self, ann: &u32) -> &[Reg] {
+        match self.saved.get(ann) {
+            Some(regs) => regs,
+            None => &[],
+        }
+    }
+
+    pub fn local(&self, fun_name: &str) -> &Allocation {
+        &self.locals[fun_name]
+    }
+}
+
+// The last slots of a frame link the frame of a callee to it, see
+// link_frame in compile_with_env
+pub static LINK_SLOTS: i32 = 2;
+
+// The index of the frame slot a register is saved in around calls,
+// counted from the end of a frame of the given size, before the links.
+pub fn save_slot(reg: Reg, space: i32) -> i32 {
+    let i = VAR_REGS.iter().position(|r| *r == reg).unwrap();
+    space - 1 - LINK_SLOTS - i as i32
+}
+
+struct Liveness {
+    // variables that are live at the same time as each variable
+    graph: HashMap<String, HashSet<String>>,
+    // variables live across each call or allocation
+    sites: HashMap<u32, HashSet<String>>,
+}
+
+impl Liveness {
+    fn interfere(&mut self, x: &str, live: &HashSet<String>) {
+        self.graph.entry(x.to_string()).or_default();
+        for y in live.iter().filter(|y| *y != x) {
+            self.graph.get_mut(x).unwrap().insert(y.clone());
+            self.graph.entry(y.clone()).or_default().insert(x.to_string());
+        }
+    }
+}
+
+fn add_imm(live: &mut HashSet<String>, e: &ImmExp) {
+    if let ImmExp::Var(x) = e {
+        live.insert(x.clone());
+    }
+}
+
+// Computes the variables live before e, given the ones live after it.
+fn live_in(
+    e: &SeqExp<u32>,
+    live_out: HashSet<String>,
+    fun_to_env: &HashMap<String, Vec<String>>,
+    st: &mut Liveness,
+) -> HashSet<String> {
+    match e {
+        SeqExp::Imm(imm, _) => {
+            let mut live = live_out;
+            add_imm(&mut live, imm);
+            live
+        }
+        SeqExp::Prim(op, args, ann) => {
+            let mut live = live_out.clone();
+            for arg in args {
+                add_imm(&mut live, arg);
+            }
+            match op {
+                // the elements are only read after the allocation, and
+                // so are the operands of arithmetic that boxes a float
+                // and of the operations that make strings
+                Prim::MakeTuple
+                | Prim::MakeVariant
+                | Prim::Concat
+                | Prim::Substr
+                | Prim::ToString
+                | Prim::MakeArray
+                | Prim::Add1
+                | Prim::Sub1
+                | Prim::Add
+                | Prim::Sub
+                | Prim::Mul
+                | Prim::Div
+                | Prim::Sqrt
+                | Prim::Cos => {
+                    st.sites.insert(*ann, live.clone());
+                }
+                Prim::Print | Prim::Eq | Prim::Neq => {
+                    st.sites.insert(*ann, live_out);
+                }
+                _ => {}
+            }
+            live
+        }
+        SeqExp::Let {
+            var,
+            bound_exp,
+            body,
+            ..
+        } => {
+            let mut live = live_in(body, live_out, fun_to_env, st);
+            live.remove(var);
+            st.interfere(var, &live);
+            live_in(bound_exp, live, fun_to_env, st)
+        }
+        SeqExp::If { cond, thn, els, .. } => {
+            let mut live = live_in(thn, live_out.clone(), fun_to_env, st);
+            live.extend(live_in(els, live_out, fun_to_env, st));
+            add_imm(&mut live, cond);
+            live
+        }
+        // what is live before the loop is live after its body too, so
+        // the sets grow until they stop changing
+        SeqExp::While { cond, body, .. } => {
+            let mut live = HashSet::new();
+            loop {
+                let mut after_cond = live_out.clone();
+                after_cond.extend(live_in(body, live.clone(), fun_to_env, st));
+                let new_live = live_in(cond, after_cond, fun_to_env, st);
+                if new_live == live {
+                    return live;
+                }
+                live = new_live;
+            }
+        }
+        SeqExp::Assign { var, value, .. } => {
+            let mut live = live_out;
+            live.remove(var);
+            st.interfere(var, &live);
+            add_imm(&mut live, value);
+            live
+        }
+        // the local functions are allocated on their own
+        SeqExp::FunDefs { body, .. } => live_in(body, live_out, fun_to_env, st),
+        SeqExp::InternalTailCall(fun_name, args, _) => {
+            let mut live = live_out;
+            live.extend(fun_to_env[fun_name].iter().cloned());
+            for arg in args {
+                add_imm(&mut live, arg);
+            }
+            live
+        }
+        SeqExp::ExternalCall {
+            fun_name,
+            args,
+            is_tail,
+            ann,
+        } => {
+            if !is_tail {
+                st.sites.insert(*ann, live_out.clone());
+            }
+            let mut live = live_out;
+            live.extend(fun_to_env[fun_name].iter().cloned());
+            for arg in args {
+                add_imm(&mut live, arg);
+            }
+            live
+        }
+        SeqExp::ClosureCall {
+            fun,
+            args,
+            is_tail,
+            ann,
+        } => {
+            if !is_tail {
+                st.sites.insert(*ann, live_out.clone());
+            }
+            let mut live = live_out;
+            add_imm(&mut live, fun);
+            for arg in args {
+                add_imm(&mut live, arg);
+            }
+            live
+        }
+        SeqExp::MakeClosure { env, ann, .. } => {
+            let mut live = live_out;
+            for x in env {
+                add_imm(&mut live, x);
+            }
+            st.sites.insert(*ann, live.clone());
+            live
+        }
+    }
+}
+
+/* Colors the interference graph with the registers in VAR_REGS.
+ * Variables with fewer neighbors than there are registers can always
+ * be colored, so they are set aside first; when none is left the one
+ * with the most neighbors is spilled to the stack.
+ */
+fn color(graph: &HashMap<String, HashSet<String>>) -> HashMap<String, Reg> {
+    let k = VAR_REGS.len();
+    let mut remaining: HashMap<&String, usize> = graph.iter().map(|(x, ns)| (x, ns.len())).collect();
+    let mut stack = vec![];
+    while !remaining.is_empty() {
+        // sort the candidates so that the output doesn't depend on hashing
+        let mut candidates: Vec<(&String, usize)> = remaining.iter().map(|(x, d)| (*x, *d)).collect();
+        candidates.sort();
+        let (x, spill) = match candidates.iter().find(|(_, d)| *d < k) {
+            Some((x, _)) => (*x, false),
+            None => (candidates.iter().max_by_key(|(_, d)| *d).unwrap().0, true),
+        };
+        remaining.remove(x);
+        for n in graph[x].iter() {
+            if let Some(d) = remaining.get_mut(n) {
+                *d -= 1;
+            }
+        }
+        if !spill {
+            stack.push(x);
+        }
+    }
+
+    let mut regs = HashMap::new();
+    while let Some(x) = stack.pop() {
+        let taken: Vec<Reg> = graph[x].iter().filter_map(|n| regs.get(n).copied()).collect();
+        if let Some(r) = VAR_REGS.iter().find(|r| !taken.contains(r)) {
+            regs.insert(x.clone(), *r);
+        }
+    }
+    regs
+}
+
+// The number of stack slots taken by the variables that are not in
+// registers, mirroring how compile_with_env extends its environment.
+fn stack_slots(e: &SeqExp<u32>, env: &mut Vec<String>, regs: &HashMap<String, Reg>) -> i32 {
+    match e {
+        SeqExp::Let {
+            var,
+            bound_exp,
+            body,
+            ..
+        } => {
+            let bound = stack_slots(bound_exp, &mut env.clone(), regs);
+            let mut env = env.clone();
+            if !regs.contains_key(var) && !env.contains(var) {
+                env.push(var.clone());
+            }
+            std::cmp::max(bound, stack_slots(body, &mut env, regs))
+        }
+        SeqExp::If { thn, els, .. } => std::cmp::max(
+            stack_slots(thn, &mut env.clone(), regs),
+            stack_slots(els, &mut env.clone(), regs),
+        ),
+        SeqExp::While { cond, body, .. } => std::cmp::max(
+            stack_slots(cond, &mut env.clone(), regs),
+            stack_slots(body, &mut env.clone(), regs),
+        ),
+        SeqExp::FunDefs { body, .. } => stack_slots(body, env, regs),
+        _ => env.len() as i32,
+    }
+}
+
+// Allocates the variables of a body whose frame starts out with the
+// given variables, e.g. the parameters of a function.
+pub fn allocate(
+    e: &SeqExp<u32>,
+    pinned: &[String],
+    fun_to_env: &HashMap<String, Vec<String>>,
+) -> Allocation {
+    let mut st = Liveness {
+        graph: HashMap::new(),
+        sites: HashMap::new(),
+    };
+    live_in(e, HashSet::new(), fun_to_env, &mut st);
+    for x in pinned {
+        st.graph.remove(x);
+    }
+    for ns in st.graph.values_mut() {
+        for x in pinned {
+            ns.remove(x);
+        }
+    }
+    let regs = color(&st.graph);
+
+    let mut saved = HashMap::new();
+    for (ann, live) in st.sites {
+        let mut used: Vec<Reg> = live.iter().filter_map(|x| regs.get(x).copied()).collect();
+        used.sort_by_key(|r| VAR_REGS.iter().position(|v| v == r));
+        used.dedup();
+        saved.insert(ann, used);
+    }
+
+    let mut slots = stack_slots(e, &mut pinned.to_vec(), &regs);
+    let mut locals = HashMap::new();
+    allocate_locals(e, fun_to_env, &mut locals);
+    for local in locals.values() {
+        slots = std::cmp::max(slots, local.slots);
+    }
+    Allocation {
+        regs,
+        saved,
+        locals,
+        slots,
+    }
+}
+
+fn allocate_locals(
+    e: &SeqExp<u32>,
+    fun_to_env: &HashMap<String, Vec<String>>,
+    locals: &mut HashMap<String, Allocation>,
+) {
+    match e {
+        SeqExp::Let {
+            bound_exp, body, ..
+        } => {
+            allocate_locals(bound_exp, fun_to_env, locals);
+            allocate_locals(body, fun_to_env, locals);
+        }
+        SeqExp::If { thn, els, .. } => {
+            allocate_locals(thn, fun_to_env, locals);
+            allocate_locals(els, fun_to_env, locals);
+        }
+        SeqExp::While { cond, body, .. } => {
+            allocate_locals(cond, fun_to_env, locals);
+            allocate_locals(body, fun_to_env, locals);
+        }
+        SeqExp::FunDefs { decls, body, .. } => {
+            for decl in decls {
+                let mut pinned = fun_to_env[&decl.name].clone();
+                for x in &decl.parameters {
+                    if !pinned.contains(x) {
+                        pinned.push(x.clone());
+                    }
+                }
+                locals.insert(decl.name.clone(), allocate(&decl.body, &pinned, fun_to_env));
+            }
+            allocate_locals(body, fun_to_env, locals);
+        }
+        _ => {}
+    }
+}
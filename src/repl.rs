@@ -0,0 +1,303 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+use lalrpop_util::ParseError;
+
+use crate::check::check_exp;
+use crate::compile::{compile_to_string, CompileErr, NO_SPAN};
+use crate::infer::TypeSession;
+use crate::interp;
+use crate::lexer::LexError;
+use crate::diagnostic::Source;
+use crate::runner::{locate, located, parse, parse_recovering, read_file, RunnerErr};
+use crate::span::{Span1, Span2};
+use crate::syntax::{Exp, FunDecl, SurfFunDecl};
+
+/* The outer lets and function definitions of an input, e.g. the x and
+ * f of `let x = 1 in def f(y): x + y in f(2)`, stay in scope for the
+ * inputs after it.
+ *
+ * The interpreter's closures borrow the expressions they were created
+ * from for the rest of the session, so inputs are leaked.
+ */
+#[derive(Clone, Copy)]
+enum Def {
+    Let(&'static [(String, Exp<Span1>)]),
+    Funs(&'static [SurfFunDecl<Span1>]),
+}
+
+// The definitions of an input, outermost first, and the expression
+// whose value it prints
+fn split(mut e: &'static Exp<Span1>) -> (Vec<Def>, &'static Exp<Span1>) {
+    let mut defs = vec![];
+    loop {
+        match e {
+            Exp::Let { bindings, body, .. } => {
+                defs.push(Def::Let(bindings));
+                e = body;
+            }
+            Exp::FunDefs { decls, body, .. } => {
+                defs.push(Def::Funs(decls));
+                e = body;
+            }
+            _ => return (defs, e),
+        }
+    }
+}
+
+pub struct Repl {
+    // what check_exp knows about the names in scope
+    vars: HashSet<String>,
+    funs: HashMap<String, usize>,
+    types: TypeSession,
+    interp: interp::Session<'static, Span1>,
+    // every definition so far, to compile an input with :asm
+    defs: Vec<Def>,
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Repl::new()
+    }
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        Repl {
+            vars: HashSet::new(),
+            funs: HashMap::new(),
+            types: TypeSession::new(),
+            interp: interp::Session::new(),
+            defs: vec![],
+        }
+    }
+
+    /* Reads inputs until the end of input, printing their values and
+     * errors to out. An input that isn't a whole expression yet goes
+     * on in the next line, until an empty line.
+     */
+    pub fn run<R, W>(&mut self, input: R, out: &mut W, prompt: bool) -> std::io::Result<()>
+    where
+        R: BufRead,
+        W: Write,
+    {
+        let mut lines = input.lines();
+        loop {
+            if prompt {
+                write!(out, "> ")?;
+                out.flush()?;
+            }
+            let mut src = match lines.next() {
+                None => return Ok(()),
+                Some(line) => line?,
+            };
+            if src.trim().is_empty() {
+                continue;
+            }
+            if let Some(cmd) = src.trim().strip_prefix(':') {
+                self.command(cmd, out)?;
+                continue;
+            }
+            while incomplete(&src) {
+                if prompt {
+                    write!(out, ". ")?;
+                    out.flush()?;
+                }
+                match lines.next() {
+                    Some(line) => {
+                        let line = line?;
+                        if line.trim().is_empty() {
+                            break;
+                        }
+                        src.push('\n');
+                        src.push_str(&line);
+                    }
+                    None => break,
+                }
+            }
+            if let Err(e) = self.input(&repl_source(src), out) {
+                writeln!(out, "{}", e)?;
+            }
+        }
+    }
+
+    fn command<W>(&mut self, cmd: &str, out: &mut W) -> std::io::Result<()>
+    where
+        W: Write,
+    {
+        let (name, arg) = match cmd.split_once(char::is_whitespace) {
+            Some((name, arg)) => (name, arg.trim()),
+            None => (cmd, ""),
+        };
+        let r = match name {
+            "type" => self.type_of(&repl_source(arg.to_string())).map(|t| t.to_string()),
+            "asm" => self.asm(&repl_source(arg.to_string())),
+            "load" => match read_file(Path::new(arg)) {
+                Ok(text) => {
+                    let source = Source {
+                        name: arg.to_string(),
+                        text,
+                    };
+                    if let Err(e) = self.input(&source, out) {
+                        writeln!(out, "{}", e)?;
+                    }
+                    return Ok(());
+                }
+                Err(e) => Err(e),
+            },
+            _ => {
+                writeln!(out, "Unknown command :{}, try :type EXP, :asm EXP or :load FILE", name)?;
+                return Ok(());
+            }
+        };
+        match r {
+            Ok(s) => writeln!(out, "{}", s),
+            Err(e) => writeln!(out, "{}", e),
+        }
+    }
+
+    /* Checks a whole input before running any of it, so that an input
+     * with an error defines nothing. Definitions before a runtime
+     * error stay.
+     */
+    fn input<W>(&mut self, src: &Source, out: &mut W) -> Result<(), RunnerErr<Span2>>
+    where
+        W: Write,
+    {
+        let e: &'static Exp<Span1> = Box::leak(Box::new(parse_input(src)?));
+        self.check(e).map_err(|errs| located(src, errs))?;
+        let (defs, value) = split(e);
+        let mut types = self.types.clone();
+        for def in defs.iter() {
+            define_type(&mut types, def).map_err(|e| located(src, vec![e]))?;
+        }
+        types.type_of(value).map_err(|e| located(src, vec![e]))?;
+
+        for def in defs {
+            match def {
+                Def::Let(bindings) => {
+                    self.interp.define_let(bindings, out).map_err(RunnerErr::Interp)?;
+                    for (x, _) in bindings {
+                        self.vars.insert(x.clone());
+                        self.funs.remove(x);
+                    }
+                }
+                Def::Funs(decls) => {
+                    self.interp.define_funs(decls);
+                    for decl in decls {
+                        self.funs.insert(decl.name.clone(), decl.parameters.len());
+                        self.vars.remove(&decl.name);
+                    }
+                }
+            }
+            define_type(&mut self.types, &def).map_err(|e| located(src, vec![e]))?;
+            self.defs.push(def);
+        }
+        self.interp.exp(value, out).map_err(RunnerErr::Interp)
+    }
+
+    fn check(&self, e: &Exp<Span1>) -> Result<(), Vec<CompileErr<Span1>>> {
+        let mut errs = vec![];
+        check_exp(e, &mut self.vars.clone(), &mut self.funs.clone(), &mut HashMap::new(), &mut errs);
+        // warnings don't stop an input
+        errs.retain(|e| !e.is_warning());
+        if errs.is_empty() {
+            Ok(())
+        } else {
+            Err(errs)
+        }
+    }
+
+    fn type_of(&self, src: &Source) -> Result<crate::syntax::Type, RunnerErr<Span2>> {
+        let e = parse_input(src)?;
+        self.check(&e).map_err(|errs| located(src, errs))?;
+        self.types.type_of(&e).map_err(|e| located(src, vec![e]))
+    }
+
+    /* The assembly of the input as the body of all the definitions so
+     * far. The definitions come from other inputs, so the runtime errors
+     * in them have no location.
+     */
+    fn asm(&self, src: &Source) -> Result<String, RunnerErr<Span2>> {
+        self.type_of(src)?;
+        let mut prog = parse_input(src)?.map_ann(&|span| Some(*span));
+        for def in self.defs.iter().rev() {
+            let ann = prog.ann();
+            prog = match def {
+                Def::Let(bindings) => Exp::Let {
+                    bindings: bindings.iter().map(|(x, e)| (x.clone(), e.map_ann(&|_| None))).collect(),
+                    body: Box::new(prog),
+                    ann,
+                },
+                Def::Funs(decls) => Exp::FunDefs {
+                    decls: decls
+                        .iter()
+                        .map(|d| FunDecl {
+                            name: d.name.clone(),
+                            parameters: d.parameters.clone(),
+                            signature: d.signature.clone(),
+                            body: d.body.map_ann(&|_| None),
+                            ann: None,
+                        })
+                        .collect(),
+                    body: Box::new(prog),
+                    ann,
+                },
+            };
+        }
+        let input = prog.ann();
+        let locate = locate(src);
+        compile_to_string(&prog, |span: &Option<Span1>| {
+            span.as_ref().map_or((String::new(), NO_SPAN), &locate)
+        }).map_err(|errs| {
+            // the definitions were checked when they were made, so the
+            // errors are in the input
+            let errs = errs.into_iter().map(|e| e.map_span(|span| span.or(input).unwrap())).collect();
+            located(src, errs)
+        })
+    }
+}
+
+fn define_type(types: &mut TypeSession, def: &Def) -> Result<(), CompileErr<Span1>> {
+    match def {
+        Def::Let(bindings) => types.define_let(bindings),
+        Def::Funs(decls) => types.define_funs(decls),
+    }
+}
+
+// The program of an input, which can't import files since only the
+// files of a program can
+fn parse_input(src: &Source) -> Result<Exp<Span1>, RunnerErr<Span2>> {
+    let file = parse(src)?;
+    match file.imports.first() {
+        None => Ok(file.prog),
+        Some((path, location)) => Err(located(
+            src,
+            vec![CompileErr::ImportFailed {
+                path: path.clone(),
+                reason: "the REPL can't import files".to_string(),
+                location: *location,
+            }],
+        )),
+    }
+}
+
+fn repl_source(text: String) -> Source {
+    Source {
+        name: "<repl>".to_string(),
+        text,
+    }
+}
+
+// An input that only fails to parse because it ends too early, or in
+// a comment or a string
+fn incomplete(src: &str) -> bool {
+    matches!(
+        parse_recovering(src).1.first(),
+        Some(ParseError::UnrecognizedEOF { .. })
+            | Some(ParseError::User {
+                error: LexError::UnterminatedComment(_) | LexError::UnterminatedString(_)
+            })
+    )
+}
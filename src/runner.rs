@@ -1,17 +1,22 @@
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::{BufReader, Read, Write};
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 
 use std::fmt::{Display, Formatter};
 
 use crate::compile;
 use crate::compile::{compile_to_string, CompileErr};
+use crate::diagnostic::{json, json_compile, render, render_parse, Source};
+use crate::infer;
 use crate::interp;
 use crate::interp::InterpErr;
+use crate::lexer::{LexError, Lexer, Tok};
 use crate::parser::ProgParser;
-use crate::syntax::SurfProg;
+use lalrpop_util::ParseError;
+use crate::syntax::{Constructor, Exp, FunDecl, Signature, SurfFile, SurfProg, Type};
 
 mod span {
     use crate::span::{Span1, Span2};
@@ -63,19 +68,26 @@ mod span {
     impl<Span> CompileErr<Span> {
         pub fn map_span<F, SpanPrime>(self, f: F) -> CompileErr<SpanPrime>
         where
-            F: FnOnce(&Span) -> SpanPrime,
+            F: Fn(&Span) -> SpanPrime,
         {
             match self {
-                CompileErr::UnboundVariable { unbound, location } => CompileErr::UnboundVariable {
+                CompileErr::UnboundVariable {
+                    unbound,
+                    location,
+                    suggestion,
+                } => CompileErr::UnboundVariable {
                     unbound,
                     location: f(&location),
+                    suggestion,
                 },
                 CompileErr::DuplicateBinding {
                     duplicated_name,
                     location,
+                    first,
                 } => CompileErr::DuplicateBinding {
                     duplicated_name,
                     location: f(&location),
+                    first: f(&first),
                 },
                 CompileErr::NumOverflow { num, location } => CompileErr::NumOverflow {
                     num,
@@ -89,9 +101,11 @@ mod span {
                 CompileErr::UndefinedFunction {
                     undefined,
                     location,
+                    suggestion,
                 } => CompileErr::UndefinedFunction {
                     undefined: undefined.clone(),
                     location: f(&location),
+                    suggestion,
                 },
                 CompileErr::DuplicateArgName {
                     duplicated_name,
@@ -104,37 +118,123 @@ mod span {
                 CompileErr::DuplicateFunName {
                     duplicated_name,
                     location,
+                    first,
                 } => CompileErr::DuplicateFunName {
                     duplicated_name: duplicated_name.clone(),
                     location: f(&location),
+                    first: first.as_ref().map(&f),
                 },
 
-                CompileErr::FunctionUsedAsValue {
+                CompileErr::FunctionCalledWrongArity {
                     function_name,
+                    correct_arity,
+                    arity_used,
                     location,
-                } => CompileErr::FunctionUsedAsValue {
+                } => CompileErr::FunctionCalledWrongArity {
                     function_name: function_name.clone(),
+                    correct_arity: correct_arity.clone(),
+                    arity_used: arity_used.clone(),
                     location: f(&location),
                 },
 
-                CompileErr::ValueUsedAsFunction {
-                    variable_name,
+                CompileErr::TypeMismatch {
+                    who,
+                    expected,
+                    got,
                     location,
-                } => CompileErr::ValueUsedAsFunction {
-                    variable_name: variable_name.clone(),
+                } => CompileErr::TypeMismatch {
+                    who,
+                    expected,
+                    got,
                     location: f(&location),
                 },
 
-                CompileErr::FunctionCalledWrongArity {
+                CompileErr::ArityMismatch {
+                    expected,
+                    got,
+                    location,
+                } => CompileErr::ArityMismatch {
+                    expected,
+                    got,
+                    location: f(&location),
+                },
+
+                CompileErr::AssignToFunction { function_name, location } => CompileErr::AssignToFunction {
                     function_name,
+                    location: f(&location),
+                },
+
+                CompileErr::UnknownConstructor {
+                    unknown,
+                    location,
+                    suggestion,
+                } => CompileErr::UnknownConstructor {
+                    unknown,
+                    location: f(&location),
+                    suggestion,
+                },
+
+                CompileErr::ConstructorWrongArity {
+                    constructor,
                     correct_arity,
                     arity_used,
                     location,
-                } => CompileErr::FunctionCalledWrongArity {
-                    function_name: function_name.clone(),
-                    correct_arity: correct_arity.clone(),
-                    arity_used: arity_used.clone(),
+                } => CompileErr::ConstructorWrongArity {
+                    constructor,
+                    correct_arity,
+                    arity_used,
+                    location: f(&location),
+                },
+
+                CompileErr::DuplicateConstructor {
+                    duplicated_name,
+                    location,
+                    first,
+                } => CompileErr::DuplicateConstructor {
+                    duplicated_name,
+                    location: f(&location),
+                    first: first.as_ref().map(&f),
+                },
+
+                CompileErr::DuplicatePatternVar {
+                    duplicated_name,
+                    location,
+                    first,
+                } => CompileErr::DuplicatePatternVar {
+                    duplicated_name,
+                    location: f(&location),
+                    first: f(&first),
+                },
+
+                CompileErr::NonExhaustiveMatch { missing, location } => CompileErr::NonExhaustiveMatch {
+                    missing,
+                    location: f(&location),
+                },
+
+                CompileErr::ImportFailed { path, reason, location } => CompileErr::ImportFailed {
+                    path,
+                    reason,
+                    location: f(&location),
+                },
+
+                CompileErr::ImportCycle { cycle, location } => CompileErr::ImportCycle {
+                    cycle,
+                    location: f(&location),
+                },
+
+                CompileErr::ExportNotTopLevel { function_name, location } => CompileErr::ExportNotTopLevel {
+                    function_name,
+                    location: f(&location),
+                },
+
+                CompileErr::ConflictingImport {
+                    function_name,
+                    location,
+                    first,
+                } => CompileErr::ConflictingImport {
+                    function_name,
                     location: f(&location),
+                    first: f(&first),
                 },
             }
         }
@@ -157,144 +257,149 @@ use span::{file_info, span1_to_span2, FileInfo};
 pub enum RunnerErr<Span> {
     FileOpen(String),
     Lex(String),
-    Parse(String),
-    CodeGen(CompileErr<Span>),
+    // every syntax error the parser found, and the program
+    Parse(Vec<ParseErr<Span>>, Source),
+    // every error of the program, in source order, and the program
+    CodeGen(Vec<CompileErr<Span>>, Source),
     Link(String),
     Interp(InterpErr),
     Run(String),
+    // the compiled program exited with an error, the runtime exits
+    // with 1 + the code of the error and reports its message and the
+    // file and span it happened at last, which is taken out of stderr
+    RunFailed {
+        status: std::process::ExitStatus,
+        stderr: String,
+        message: String,
+        location: Option<(String, Span2)>,
+    },
+}
+
+// A syntax error, at the token the parser didn't expect if it has one
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseErr<Span> {
+    pub message: String,
+    // what the token should have been, shown under it
+    pub label: String,
+    pub location: Option<Span>,
 }
 
 impl<Span> Display for CompileErr<Span>
 where
     Span: Display,
 {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} at {}", self.message(), self.location())
+    }
+}
+
+impl Display for Type {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            CompileErr::UnboundVariable { unbound, location } => {
-                write!(f, "Unbound variable {} at {}", unbound, location)
+            Type::Int => write!(f, "Int"),
+            Type::Float => write!(f, "Float"),
+            Type::Bool => write!(f, "Bool"),
+            Type::Tuple => write!(f, "Tuple"),
+            Type::String => write!(f, "String"),
+            Type::Array => write!(f, "Array"),
+            Type::Fun(params, ret) => {
+                let params: Vec<String> = params.iter().map(|p| p.to_string()).collect();
+                write!(f, "({}) -> {}", params.join(", "), ret)
             }
-            CompileErr::UndefinedFunction {
-                undefined,
-                location,
-            } => {
-                write!(f, "Undefined function {} called at {}", undefined, location)
-            }
-            CompileErr::DuplicateBinding {
-                duplicated_name,
-                location,
-            } => write!(
-                f,
-                "Variable {} defined twice in let-expression at {}",
-                duplicated_name, location
-            ),
-
-            CompileErr::NumOverflow { num, location } => write!(
-                f,
-                "Number literal {} doesn't fit into 63-bit integer at {}, overflow",
-                num, location
-            ),
-
-            CompileErr::FloatOverflow { num, location } => write!(
-                f,
-                "Float literal {} doesn't fit into 32-bit float at {}, overflow",
-                num.0, location
-            ),
-
-            CompileErr::DuplicateArgName {
-                duplicated_name,
-                location,
-            } => write!(
-                f,
-                "multiple arguments named \"{}\" at {}",
-                duplicated_name, location,
-            ),
-
-            CompileErr::DuplicateFunName {
-                duplicated_name,
-                location,
-            } => write!(
-                f,
-                "multiple defined functions named \"{}\" at {}",
-                duplicated_name, location
-            ),
-
-            CompileErr::FunctionUsedAsValue {
-                function_name,
-                location,
-            } => write!(
-                f,
-                "function {} used in non-function call position at {}",
-                function_name, location
-            ),
-
-            CompileErr::ValueUsedAsFunction {
-                variable_name,
-                location,
-            } => write!(
-                f,
-                "value variable {} used in function call position at {}",
-                variable_name, location
-            ),
-
-            CompileErr::FunctionCalledWrongArity {
-                function_name,
-                correct_arity,
-                arity_used,
-                location,
-            } => write!(
-                f,
-                "function {} of arity {} called with {} arguments at {}",
-                function_name, correct_arity, arity_used, location
-            ),
+            Type::Var(v) => write!(f, "'t{}", v),
+            Type::Dyn => write!(f, "Dyn"),
+            Type::Data(name) => write!(f, "{}", name),
         }
     }
 }
 
-impl<Span> Display for RunnerErr<Span>
-where
-    Span: Display,
-{
+impl Display for RunnerErr<Span2> {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
         match self {
             RunnerErr::FileOpen(s) => write!(f, "Error reading file: {}", s),
             RunnerErr::Lex(s) => write!(f, "Error lexing input: {}", s),
-            RunnerErr::Parse(s) => write!(f, "Error parsing input: {}", s),
-            RunnerErr::CodeGen(ce) => write!(f, "Error generating assembly: {}", ce),
+            RunnerErr::Parse(errs, source) => {
+                for pe in errs {
+                    writeln!(f, "{}", render_parse(pe, source))?;
+                }
+                let plural = if errs.len() == 1 { "" } else { "s" };
+                write!(f, "parsing failed with {} error{}", errs.len(), plural)
+            }
+            RunnerErr::CodeGen(errs, source) => {
+                for ce in errs {
+                    writeln!(f, "{}", render(ce, source))?;
+                }
+                let plural = if errs.len() == 1 { "" } else { "s" };
+                write!(f, "compilation failed with {} error{}", errs.len(), plural)
+            }
             RunnerErr::Link(s) => write!(f, "Error linking generated assembly with runtime: {}", s),
             RunnerErr::Interp(s) => write!(f, "Error in interpreter: {}", s),
             RunnerErr::Run(s) => write!(f, "Error running your compiled output: {}", s),
+            RunnerErr::RunFailed { status, stderr, .. } => write!(
+                f,
+                "Error running your compiled output: Error code {} when running compiled code Stderr:\n{}",
+                status, stderr
+            ),
         }
     }
 }
 
-fn fail<Span>(e: RunnerErr<Span>)
-where
-    Span: Display,
-{
+// How errors are printed to stderr
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorFormat {
+    Human,
+    // one JSON object per line for each error
+    Json,
+}
+
+fn fail(e: RunnerErr<Span2>) {
     eprintln!("{}", e);
     std::process::exit(1);
 }
 
-fn handle_errs<Span>(r: Result<String, RunnerErr<Span>>)
-where
-    Span: Display,
-{
-    match r {
-        Ok(s) => println!("{}", s),
-        Err(e) => {
-            eprintln!("{}", e);
+fn fail_as(e: RunnerErr<Span2>, p: &Path, format: ErrorFormat) {
+    match format {
+        ErrorFormat::Human => fail(e),
+        ErrorFormat::Json => {
+            for line in json(&e, &p.display().to_string()) {
+                eprintln!("{}", line);
+            }
             std::process::exit(1);
         }
     }
 }
 
-pub fn emit_assembly(p: &Path) {
-    handle_errs(compile_file(p))
+// Prints the warnings of the program in p, which don't stop it from
+// being compiled or run. Its errors are left to what compiles or runs it.
+fn warn(p: &Path, format: ErrorFormat) {
+    if let Ok((files, prog)) = resolve(p) {
+        if let Ok(warnings) = compile::check_prog(&prog) {
+            for located in files.by_file(warnings) {
+                if let RunnerErr::CodeGen(warnings, source) = located {
+                    for w in warnings {
+                        match format {
+                            ErrorFormat::Human => eprintln!("{}", render(&w, &source)),
+                            ErrorFormat::Json => eprintln!("{}", json_compile(&w, &source.name)),
+                        }
+                    }
+                }
+            }
+        }
+    }
 }
 
-pub fn run(p: &Path) {
-    if let Err(e) = compile_and_run_file(p, Path::new("runtime"), &mut std::io::stdout()) {
-        fail(e)
+pub fn emit_assembly(p: &Path, format: ErrorFormat) {
+    warn(p, format);
+    match compile_file(p) {
+        Ok(s) => println!("{}", s),
+        Err(e) => fail_as(e, p, format),
+    }
+}
+
+pub fn run(p: &Path, heap: Option<usize>, format: ErrorFormat) {
+    warn(p, format);
+    if let Err(e) = compile_and_run_with_heap(p, Path::new("runtime"), heap, &mut std::io::stdout()) {
+        fail_as(e, p, format)
     }
 }
 
@@ -302,6 +407,7 @@ pub fn interp<W>(p: &Path, w: &mut W)
 where
     W: std::io::Write,
 {
+    warn(p, ErrorFormat::Human);
     if let Err(e) = interpret_file(p, w) {
         fail(e)
     }
@@ -311,29 +417,252 @@ pub fn interpret_file<W>(p: &Path, w: &mut W) -> Result<(), RunnerErr<Span2>>
 where
     W: std::io::Write,
 {
-    let (info, prog) = parse_file(p)?;
-    let () = compile::check_prog(&prog)
-        .map_err(|e| RunnerErr::CodeGen(e.map_span(|s| span1_to_span2(&info, *s))))?;
+    let (files, prog) = resolve(p)?;
+    compile::check_prog(&prog).map_err(|errs| files.located(errs))?;
+    let () = infer::infer_prog(&prog).map_err(|e| files.located(vec![e]))?;
 
     interp::prog(&prog, w).map_err(|e| RunnerErr::Interp(e))?;
     Ok(())
 }
 
+pub fn diff(p: &Path) {
+    match diff_file(p, Path::new("runtime")) {
+        Ok(None) => println!("the interpreter and the compiled program agree"),
+        Ok(Some(report)) => {
+            print!("{}", report);
+            std::process::exit(1);
+        }
+        Err(e) => fail(e),
+    }
+}
+
+pub fn fuzz(count: u64, seed: Option<u64>) {
+    let seed = seed.unwrap_or_else(|| {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs())
+    });
+    println!("running {} random programs from seed {}", count, seed);
+    match crate::fuzz::fuzz(seed, count, Path::new("runtime"), Path::new("examples")) {
+        Ok(None) => println!("the interpreter and the compiled programs agree"),
+        Ok(Some((path, report))) => {
+            println!("saved a minimal program they disagree on as {}", path.display());
+            print!("{}", report);
+            std::process::exit(1);
+        }
+        Err(e) => fail(e),
+    }
+}
+
+pub fn repl() {
+    let stdin = std::io::stdin();
+    if let Err(e) = crate::repl::Repl::new().run(stdin.lock(), &mut std::io::stdout(), true) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+// What a program printed and the error it stopped with, if any
+#[derive(Debug, PartialEq, Eq)]
+struct Outcome {
+    stdout: String,
+    error: Option<String>,
+}
+
+/* The error the runtime reported as JSON on the last line of stderr,
+ * see snake_error in stub.rs: its message and the file and span it
+ * happened at, if it knows them.
+ */
+fn runtime_error(stderr: &str) -> Option<(String, Option<(String, Span2)>)> {
+    let mut json = Json(stderr.lines().last()?.strip_prefix("error: ")?);
+    json.expect("{\"message\": ")?;
+    let message = json.string()?;
+    json.expect(", \"file\": ")?;
+    let location = if json.expect("null, \"span\": null").is_some() {
+        None
+    } else {
+        let file = json.string()?;
+        json.expect(", \"span\": [")?;
+        let start_line = json.number()?;
+        json.expect(", ")?;
+        let start_col = json.number()?;
+        json.expect(", ")?;
+        let end_line = json.number()?;
+        json.expect(", ")?;
+        let end_col = json.number()?;
+        json.expect("]")?;
+        Some((
+            file,
+            Span2 {
+                start_line,
+                start_col,
+                end_line,
+                end_col,
+            },
+        ))
+    };
+    json.expect("}")?;
+    if json.0.is_empty() {
+        Some((message, location))
+    } else {
+        None
+    }
+}
+
+// The JSON the runtime writes that is left to read
+struct Json<'a>(&'a str);
+
+impl Json<'_> {
+    fn expect(&mut self, text: &str) -> Option<()> {
+        self.0 = self.0.strip_prefix(text)?;
+        Some(())
+    }
+
+    fn number(&mut self) -> Option<usize> {
+        let len = self.0.find(|c: char| !c.is_ascii_digit()).unwrap_or(self.0.len());
+        let n = self.0[..len].parse().ok()?;
+        self.0 = &self.0[len..];
+        Some(n)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.expect("\"")?;
+        let mut out = String::new();
+        let mut chars = self.0.char_indices();
+        loop {
+            match chars.next()? {
+                (i, '"') => {
+                    self.0 = &self.0[i + 1..];
+                    return Some(out);
+                }
+                (_, '\\') => match chars.next()?.1 {
+                    'n' => out.push('\n'),
+                    'r' => out.push('\r'),
+                    't' => out.push('\t'),
+                    'u' => {
+                        let hex: String = (0..4).map(|_| chars.next().map(|(_, c)| c)).collect::<Option<_>>()?;
+                        out.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                    }
+                    c => out.push(c),
+                },
+                (_, c) => out.push(c),
+            }
+        }
+    }
+}
+
+// Errors of the compiled program are the message the runtime
+// reported, which the interpreter matches. The location and the
+// backtrace the runtime prints with it are left out.
+// Errors before the program runs come from the same checks on both
+// sides.
+fn outcome(stdout: Vec<u8>, r: Result<(), RunnerErr<Span2>>) -> Result<Outcome, RunnerErr<Span2>> {
+    let error = match r {
+        Ok(()) => None,
+        Err(RunnerErr::Interp(e)) => Some(e.to_string()),
+        Err(RunnerErr::RunFailed { message, .. }) => Some(message),
+        Err(e @ (RunnerErr::Parse(..) | RunnerErr::CodeGen(..))) => Some(e.to_string()),
+        Err(e) => return Err(e),
+    };
+    Ok(Outcome {
+        stdout: String::from_utf8_lossy(&stdout).to_string(),
+        error,
+    })
+}
+
+/* Runs the program in p through both the reference interpreter and the
+ * compiler, building in dir. Returns a report of how their outputs
+ * differ, or None when they agree. Running out of memory only happens
+ * to the compiled program, which then stops early, so only the lines
+ * it printed before are compared.
+ */
+pub fn diff_file(p: &Path, dir: &Path) -> Result<Option<String>, RunnerErr<Span2>> {
+    let mut w = Vec::new();
+    let r = interpret_file(p, &mut w);
+    let interpreted = outcome(w, r)?;
+    let mut w = Vec::new();
+    let r = compile_and_run_file(p, dir, &mut w);
+    let compiled = outcome(w, r)?;
+
+    if interpreted == compiled {
+        return Ok(None);
+    }
+    let out_of_memory = compiled.error.as_ref().is_some_and(|e| e.starts_with("out of memory"));
+    let (i_lines, c_lines): (Vec<&str>, Vec<&str>) =
+        (interpreted.stdout.lines().collect(), compiled.stdout.lines().collect());
+    let lines = if out_of_memory {
+        c_lines.len()
+    } else {
+        std::cmp::max(i_lines.len(), c_lines.len())
+    };
+    let differing: Vec<usize> = (0..lines).filter(|n| i_lines.get(*n) != c_lines.get(*n)).collect();
+    let errors_differ = !out_of_memory && interpreted.error != compiled.error;
+    if differing.is_empty() && !errors_differ {
+        return Ok(None);
+    }
+
+    let mut report = format!("{}: the interpreter and the compiled program differ\n", p.display());
+    for n in differing {
+        let (i, c) = (i_lines.get(n), c_lines.get(n));
+        report.push_str(&format!("stdout line {}:\n", n + 1));
+        report.push_str(&format!("  interpreter: {}\n", i.unwrap_or(&"<nothing>")));
+        report.push_str(&format!("  compiled:    {}\n", c.unwrap_or(&"<nothing>")));
+    }
+    if errors_differ {
+        let none = String::from("<no error>");
+        report.push_str("error:\n");
+        report.push_str(&format!("  interpreter: {}\n", interpreted.error.as_ref().unwrap_or(&none)));
+        report.push_str(&format!("  compiled:    {}\n", compiled.error.as_ref().unwrap_or(&none)));
+    }
+    Ok(Some(report))
+}
+
 pub fn compile_and_run_file<W>(p: &Path, dir: &Path, out: &mut W) -> Result<(), RunnerErr<Span2>>
+where
+    W: std::io::Write,
+{
+    compile_and_run_with_heap(p, dir, None, out)
+}
+
+// The compiled program gets a heap of the given number of words instead
+// of the default size of the runtime
+pub fn compile_and_run_with_heap<W>(
+    p: &Path,
+    dir: &Path,
+    heap: Option<usize>,
+    out: &mut W,
+) -> Result<(), RunnerErr<Span2>>
 where
     W: std::io::Write,
 {
     let asm = compile_file(p)?;
-    link_and_run(&asm, dir, out)
+    link_and_run(&asm, dir, heap, out)
 }
 
 fn compile_file(p: &Path) -> Result<String, RunnerErr<Span2>> {
-    let (info, prog) = parse_file(p)?;
-    compile_to_string(&prog)
-        .map_err(|e| RunnerErr::CodeGen(e.map_span(|s| span1_to_span2(&info, *s))))
+    let (files, prog) = resolve(p)?;
+    compile_to_string(&prog, |s| files.locate(s)).map_err(|errs| files.located(errs))
+}
+
+// The file a span of source is in and where in it
+pub(crate) fn locate(source: &Source) -> impl Fn(&Span1) -> (String, Span2) + '_ {
+    let info = file_info(&source.text);
+    move |s| (source.name.clone(), span1_to_span2(&info, *s))
+}
+
+// The errors found in a file, sorted by where they start in it
+pub(crate) fn located(source: &Source, mut errs: Vec<CompileErr<Span1>>) -> RunnerErr<Span2> {
+    let info = file_info(&source.text);
+    errs.sort_by_key(|e| e.location().start_ix);
+    RunnerErr::CodeGen(
+        errs.into_iter()
+            .map(|e| e.map_span(|s| span1_to_span2(&info, *s)))
+            .collect(),
+        source.clone(),
+    )
 }
 
-fn read_file<Span>(p: &Path) -> Result<String, RunnerErr<Span>> {
+pub(crate) fn read_file<Span>(p: &Path) -> Result<String, RunnerErr<Span>> {
     let mut f = File::open(p).map_err(|e| RunnerErr::FileOpen(e.to_string()))?;
     let mut buf = String::new();
     f.read_to_string(&mut buf)
@@ -341,19 +670,1013 @@ fn read_file<Span>(p: &Path) -> Result<String, RunnerErr<Span>> {
     Ok(buf)
 }
 
-fn parse_file(p: &Path) -> Result<(FileInfo, SurfProg<Span1>), RunnerErr<Span2>> {
-    let s = read_file(p)?;
-    let e = ProgParser::new()
-        .parse(&s)
-        .map_err(|e| RunnerErr::Parse(e.to_string()))?;
-    Ok((file_info(&s), e))
+/* The files of a program: the one that was run, then the ones it
+ * imports in the order they were loaded. The spans of the resolved
+ * program are offsets into their texts one after the other, each file
+ * starting at its base, so that an error can be shown in the file it
+ * is in.
+ */
+struct Files {
+    files: Vec<(usize, Source, FileInfo)>,
+}
+
+impl Files {
+    fn new() -> Self {
+        Files { files: vec![] }
+    }
+
+    // Adds a file after the others, and returns its base
+    fn push(&mut self, source: Source) -> usize {
+        let base = self.files.last().map_or(0, |(base, source, _)| base + source.text.len() + 1);
+        let info = file_info(&source.text);
+        self.files.push((base, source, info));
+        base
+    }
+
+    // The file that the span points into
+    fn file_of(&self, s: &Span1) -> &(usize, Source, FileInfo) {
+        self.files.iter().rev().find(|(base, _, _)| *base <= s.start_ix).unwrap()
+    }
+
+    fn span2(&self, s: &Span1) -> Span2 {
+        let (base, _, info) = self.file_of(s);
+        span1_to_span2(
+            info,
+            Span1 {
+                start_ix: s.start_ix - base,
+                end_ix: s.end_ix - base,
+            },
+        )
+    }
+
+    // The name of the file a span is in and where in it
+    fn locate(&self, s: &Span1) -> (String, Span2) {
+        (self.file_of(s).1.name.clone(), self.span2(s))
+    }
+
+    // The errors of each file that has some, in the order the files
+    // were loaded, sorted by where they start in it
+    fn by_file(&self, mut errs: Vec<CompileErr<Span1>>) -> Vec<RunnerErr<Span2>> {
+        errs.sort_by_key(|e| e.location().start_ix);
+        let mut located: Vec<RunnerErr<Span2>> = vec![];
+        for e in errs {
+            let source = &self.file_of(e.location()).1;
+            let e = e.map_span(|s| self.span2(s));
+            match located.last_mut() {
+                Some(RunnerErr::CodeGen(errs, last)) if last == source => errs.push(e),
+                _ => located.push(RunnerErr::CodeGen(vec![e], source.clone())),
+            }
+        }
+        located
+    }
+
+    // The errors of the first file that has some
+    fn located(&self, errs: Vec<CompileErr<Span1>>) -> RunnerErr<Span2> {
+        self.by_file(errs).swap_remove(0)
+    }
+}
+
+// A file of the program, with its spans moved to its base
+struct Module {
+    file: SurfFile<Span1>,
+    // what the names of its functions start with in the program
+    stem: String,
+    // the modules it imports, with the import of each
+    imports: Vec<(usize, Span1)>,
+}
+
+/* Loads a file and the ones it imports, and puts their functions in
+ * one program. The functions of a file that is imported are named
+ * `stem.name`, e.g. `util.double`, so that they can't clash with the
+ * functions of other files, including the ones they don't export.
+ */
+struct Resolver {
+    files: Files,
+    modules: Vec<Option<Module>>,
+    // the modules by their canonical path
+    paths: HashMap<PathBuf, usize>,
+    // the modules whose imports are being loaded, to find cycles
+    stack: Vec<usize>,
+    // the modules, each after the ones it imports
+    order: Vec<usize>,
+}
+
+// The file in p and the ones it imports as one program, and its files
+fn resolve(p: &Path) -> Result<(Files, SurfProg<Span1>), RunnerErr<Span2>> {
+    let mut resolver = Resolver {
+        files: Files::new(),
+        modules: vec![],
+        paths: HashMap::new(),
+        stack: vec![],
+        order: vec![],
+    };
+    let text = read_file(p)?;
+    let canonical = p.canonicalize().map_err(|e| RunnerErr::FileOpen(e.to_string()))?;
+    resolver.load(p, canonical, text)?;
+    let prog = resolver.link()?;
+    Ok((resolver.files, prog))
+}
+
+impl Resolver {
+    fn load(&mut self, p: &Path, canonical: PathBuf, text: String) -> Result<usize, RunnerErr<Span2>> {
+        let source = Source {
+            name: p.display().to_string(),
+            text,
+        };
+        let file = parse(&source)?;
+        let base = self.files.push(source);
+        let shift = |s: &Span1| Span1 {
+            start_ix: s.start_ix + base,
+            end_ix: s.end_ix + base,
+        };
+        let file = SurfFile {
+            imports: file.imports.iter().map(|(path, s)| (path.clone(), shift(s))).collect(),
+            exports: file.exports.iter().map(|(f, s)| (f.clone(), shift(s))).collect(),
+            prog: file.prog.map_ann(&shift),
+        };
+        let i = self.modules.len();
+        self.modules.push(None);
+        self.paths.insert(canonical, i);
+        self.stack.push(i);
+
+        // imports are relative to the file
+        let dir = p.parent().unwrap_or_else(|| Path::new(""));
+        let mut imports = vec![];
+        for (path, location) in &file.imports {
+            let failed = |reason: String| CompileErr::ImportFailed {
+                path: path.clone(),
+                reason,
+                location: *location,
+            };
+            let imported = dir.join(path);
+            let canonical = imported
+                .canonicalize()
+                .map_err(|e| self.files.located(vec![failed(e.to_string())]))?;
+            let j = match self.paths.get(&canonical) {
+                Some(&j) => match self.stack.iter().position(|k| *k == j) {
+                    Some(start) => {
+                        let mut cycle: Vec<String> =
+                            self.stack[start..].iter().map(|k| self.files.files[*k].1.name.clone()).collect();
+                        cycle.push(self.files.files[j].1.name.clone());
+                        return Err(self.files.located(vec![CompileErr::ImportCycle {
+                            cycle,
+                            location: *location,
+                        }]));
+                    }
+                    None => j,
+                },
+                None => match read_file(&imported) {
+                    Ok(text) => self.load(&imported, canonical, text)?,
+                    Err(RunnerErr::FileOpen(reason)) => return Err(self.files.located(vec![failed(reason)])),
+                    Err(e) => return Err(e),
+                },
+            };
+            imports.push((j, *location));
+        }
+
+        self.stack.pop();
+        let stem = self.stem(p);
+        self.modules[i] = Some(Module { file, stem, imports });
+        self.order.push(i);
+        Ok(i)
+    }
+
+    // The name of the module of p, made into one that no other module
+    // has
+    fn stem(&self, p: &Path) -> String {
+        let stem = module_name(p);
+        let taken = |stem: &String| self.modules.iter().flatten().any(|m| &m.stem == stem);
+        let mut unique = stem.clone();
+        let mut n = 2;
+        while taken(&unique) {
+            unique = format!("{}{}", stem, n);
+            n += 1;
+        }
+        unique
+    }
+
+    fn module(&self, i: usize) -> &Module {
+        self.modules[i].as_ref().unwrap()
+    }
+
+    // The name a function of module i has in the program, the functions
+    // of the file that was run keep theirs
+    fn qualified(&self, i: usize, f: &str) -> String {
+        if i == 0 {
+            f.to_string()
+        } else {
+            format!("{}.{}", self.module(i).stem, f)
+        }
+    }
+
+    /* The top-level functions and types of each module, the ones it
+     * imports first, around the body of the file that was run. The
+     * bodies of the files it imports are left out. The names a file
+     * imports and its own top-level functions, types and constructors
+     * are renamed to their names in the program.
+     */
+    fn link(&self) -> Result<SurfProg<Span1>, RunnerErr<Span2>> {
+        let mut errs = vec![];
+        let mut groups = vec![];
+        let mut body = None;
+        for &i in &self.order {
+            let module = self.module(i);
+            let mut scope: HashMap<String, String> = HashMap::new();
+            let mut imported_by: HashMap<&String, &Span1> = HashMap::new();
+            for (j, location) in &module.imports {
+                for (f, _) in &self.module(*j).file.exports {
+                    let name = self.qualified(*j, f);
+                    match imported_by.get(f) {
+                        Some(first) if scope[f] != name => errs.push(CompileErr::ConflictingImport {
+                            function_name: f.clone(),
+                            location: *location,
+                            first: **first,
+                        }),
+                        _ => {
+                            imported_by.insert(f, location);
+                            scope.insert(f.clone(), name);
+                        }
+                    }
+                }
+            }
+
+            let mut e = &module.file.prog;
+            let mut top_level = vec![];
+            loop {
+                // each group is in scope in the ones after it
+                match e {
+                    Exp::FunDefs { decls, body, ann } => {
+                        for decl in decls {
+                            scope.insert(decl.name.clone(), self.qualified(i, &decl.name));
+                            top_level.push(decl.ann);
+                        }
+                        let decls = decls
+                            .iter()
+                            .map(|decl| FunDecl {
+                                name: scope[&decl.name].clone(),
+                                ..decl.clone()
+                            })
+                            .collect();
+                        let group = Exp::FunDefs {
+                            decls,
+                            body: Box::new(Exp::Num(0, *ann)),
+                            ann: *ann,
+                        };
+                        groups.push(group.rename(&scope));
+                        e = body;
+                    }
+                    Exp::TypeDecl { name, constructors, body, ann } => {
+                        let constructors = constructors
+                            .iter()
+                            .map(|c| {
+                                scope.insert(c.name.clone(), self.qualified(i, &c.name));
+                                Constructor {
+                                    name: scope[&c.name].clone(),
+                                    ..c.clone()
+                                }
+                            })
+                            .collect();
+                        groups.push(Exp::TypeDecl {
+                            name: self.qualified(i, name),
+                            constructors,
+                            body: Box::new(Exp::Num(0, *ann)),
+                            ann: *ann,
+                        });
+                        e = body;
+                    }
+                    _ => break,
+                }
+            }
+            if i == 0 {
+                body = Some(e.rename(&scope));
+            }
+            for (f, location) in &module.file.exports {
+                if !top_level.contains(location) {
+                    errs.push(CompileErr::ExportNotTopLevel {
+                        function_name: f.clone(),
+                        location: *location,
+                    });
+                }
+            }
+        }
+        if !errs.is_empty() {
+            return Err(self.files.located(errs));
+        }
+        Ok(groups.into_iter().rev().fold(body.unwrap(), |body, group| match group {
+            Exp::FunDefs { decls, ann, .. } => Exp::FunDefs {
+                decls,
+                body: Box::new(body),
+                ann,
+            },
+            Exp::TypeDecl { name, constructors, ann, .. } => Exp::TypeDecl {
+                name,
+                constructors,
+                body: Box::new(body),
+                ann,
+            },
+            _ => unreachable!("only functions and types are hoisted"),
+        }))
+    }
+}
+
+/* Separate compilation. A file compiled on its own becomes an object,
+ * e.g. `math_util.o`, whose exported functions are global symbols, and
+ * an interface next to it, `math_util.iface`, which says what the
+ * object has and what it needs from the others:
+ *
+ *     module math_util
+ *     constructors 3211264
+ *     export square 1
+ *     use string_util show_square 1
+ *
+ * A file is compiled against the interfaces of the files it imports,
+ * which are compiled first unless their objects are newer than them
+ * and than the interfaces they were compiled against. The link step
+ * puts the objects of a program together with the runtime.
+ *
+ * The types of the functions of other objects aren't known, so
+ * passing one of them a value it can't use is an error when the
+ * program runs rather than when it's compiled.
+ */
+#[derive(Debug, PartialEq, Eq)]
+struct Interface {
+    module: String,
+    // the number of its first constructor
+    constructors: usize,
+    // its exported functions and their arities
+    exports: Vec<(String, usize)>,
+    // the functions of other modules it calls or refers to, by their
+    // module
+    uses: Vec<(String, String, usize)>,
+}
+
+impl Display for Interface {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        writeln!(f, "module {}", self.module)?;
+        writeln!(f, "constructors {}", self.constructors)?;
+        for (name, arity) in &self.exports {
+            writeln!(f, "export {} {}", name, arity)?;
+        }
+        for (module, name, arity) in &self.uses {
+            writeln!(f, "use {} {} {}", module, name, arity)?;
+        }
+        Ok(())
+    }
+}
+
+fn read_interface(p: &Path) -> Result<Interface, RunnerErr<Span2>> {
+    let text = read_file(p)?;
+    let bad = |line: &str| RunnerErr::Link(format!("{}: not an interface line: {}", p.display(), line));
+    let mut iface = Interface {
+        module: String::new(),
+        constructors: 0,
+        exports: vec![],
+        uses: vec![],
+    };
+    for line in text.lines() {
+        match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["module", m] => iface.module = m.to_string(),
+            ["constructors", n] => iface.constructors = n.parse().map_err(|_| bad(line))?,
+            ["export", f, n] => iface.exports.push((f.to_string(), n.parse().map_err(|_| bad(line))?)),
+            ["use", m, f, n] => iface
+                .uses
+                .push((m.to_string(), f.to_string(), n.parse().map_err(|_| bad(line))?)),
+            _ => return Err(bad(line)),
+        }
+    }
+    Ok(iface)
+}
+
+// The number of the first constructor of a module, from its name so
+// that it doesn't depend on the other modules. Modules whose names get
+// the same one can't be linked together.
+fn first_constructor(module: &str) -> usize {
+    // FNV-1a, which unlike the hasher of std is the same in every build
+    let mut h: u32 = 0x811c_9dc5;
+    for b in module.bytes() {
+        h ^= b as u32;
+        h = h.wrapping_mul(0x0100_0193);
+    }
+    ((h % 0x3fff) as usize + 1) << 16
+}
+
+// Whether every output was modified after every input
+fn up_to_date(outputs: &[PathBuf], inputs: &[PathBuf]) -> bool {
+    let modified = |p: &PathBuf| std::fs::metadata(p).and_then(|m| m.modified()).ok();
+    outputs.iter().all(|o| match modified(o) {
+        Some(t) => inputs.iter().all(|i| modified(i).is_some_and(|s| s < t)),
+        None => false,
+    })
+}
+
+pub fn emit_object(p: &Path, format: ErrorFormat) {
+    warn(p, format);
+    if let Err(e) = compile_object(p) {
+        fail_as(e, p, format)
+    }
+}
+
+// Errors are reported against the first object, the one whose body is run
+pub fn link(objects: &[PathBuf], format: ErrorFormat) {
+    if let Err(e) = link_objects(objects) {
+        fail_as(e, &objects[0], format)
+    }
+}
+
+// Compiles the file in p to an object next to it, and the files it
+// imports first, unless they are up to date. Returns the object.
+pub fn compile_object(p: &Path) -> Result<PathBuf, RunnerErr<Span2>> {
+    let canonical = p.canonicalize().map_err(|e| RunnerErr::FileOpen(e.to_string()))?;
+    build_object(p, &mut vec![(canonical, p.display().to_string())])?;
+    Ok(p.with_extension("o"))
+}
+
+// stack holds the files whose imports are being compiled, to find
+// cycles, by their canonical path and their name
+fn build_object(p: &Path, stack: &mut Vec<(PathBuf, String)>) -> Result<Interface, RunnerErr<Span2>> {
+    let source = Source {
+        name: p.display().to_string(),
+        text: read_file(p)?,
+    };
+    let file = parse(&source)?;
+    let mut files = Files::new();
+    files.push(source);
+    let name = module_name(p);
+
+    // the interfaces of the files it imports, each once, by their
+    // canonical path, and the first import of each
+    let mut imported: Vec<(PathBuf, Interface, Span1)> = vec![];
+    let mut imports = vec![];
+    let dir = p.parent().unwrap_or_else(|| Path::new(""));
+    for (path, location) in &file.imports {
+        let failed = |reason: String| CompileErr::ImportFailed {
+            path: path.clone(),
+            reason,
+            location: *location,
+        };
+        let q = dir.join(path);
+        let canonical = q
+            .canonicalize()
+            .map_err(|e| files.located(vec![failed(e.to_string())]))?;
+        if let Some(start) = stack.iter().position(|(c, _)| *c == canonical) {
+            let mut cycle: Vec<String> = stack[start..].iter().map(|(_, name)| name.clone()).collect();
+            cycle.push(stack[start].1.clone());
+            return Err(files.located(vec![CompileErr::ImportCycle {
+                cycle,
+                location: *location,
+            }]));
+        }
+        let j = match imported.iter().position(|(c, _, _)| *c == canonical) {
+            Some(j) => j,
+            None => {
+                stack.push((canonical.clone(), q.display().to_string()));
+                let iface = build_object(&q, stack)?;
+                stack.pop();
+                // their names are the prefixes of their symbols
+                if iface.module == name || imported.iter().any(|(_, other, _)| other.module == iface.module) {
+                    let reason = format!("another module of the program is also named {}", iface.module);
+                    return Err(files.located(vec![failed(reason)]));
+                }
+                imported.push((canonical, iface, *location));
+                imported.len() - 1
+            }
+        };
+        imports.push((j + 1, *location));
+    }
+
+    let object = p.with_extension("o");
+    let interface = p.with_extension("iface");
+    let mut inputs = vec![p.to_path_buf()];
+    inputs.extend(imported.iter().map(|(c, _, _)| c.with_extension("iface")));
+    if up_to_date(&[object.clone(), interface.clone()], &inputs) {
+        return read_interface(&interface);
+    }
+
+    /* The functions of each interface are declared with the names they
+     * have in whole programs, `module.name`, which are their symbols,
+     * with bodies that are never compiled.
+     */
+    let mut resolver = Resolver {
+        files,
+        modules: vec![None],
+        paths: HashMap::new(),
+        stack: vec![],
+        order: vec![],
+    };
+    let mut externs = vec![];
+    let mut uses = vec![];
+    for (c, iface, location) in &imported {
+        let decls: Vec<FunDecl<Exp<Span1>, Span1>> = iface
+            .exports
+            .iter()
+            .map(|(f, arity)| FunDecl {
+                name: f.clone(),
+                parameters: (0..*arity).map(|k| format!("x{}", k)).collect(),
+                signature: Signature {
+                    parameters: vec![Some(Type::Dyn); *arity],
+                    ret: Some(Type::Dyn),
+                },
+                body: Exp::Num(0, *location),
+                ann: *location,
+            })
+            .collect();
+        for (f, arity) in &iface.exports {
+            externs.push(format!("{}.{}", iface.module, f));
+            uses.push((iface.module.clone(), f.clone(), *arity));
+        }
+        let prog = if decls.is_empty() {
+            Exp::Num(0, *location)
+        } else {
+            Exp::FunDefs {
+                decls,
+                body: Box::new(Exp::Num(0, *location)),
+                ann: *location,
+            }
+        };
+        resolver.files.push(Source {
+            name: c.with_extension("iface").display().to_string(),
+            text: iface.to_string(),
+        });
+        resolver.order.push(resolver.modules.len());
+        resolver.modules.push(Some(Module {
+            file: SurfFile {
+                imports: vec![],
+                exports: iface.exports.iter().map(|(f, _)| (f.clone(), *location)).collect(),
+                prog,
+            },
+            stem: iface.module.clone(),
+            imports: vec![],
+        }));
+    }
+
+    // the arities of the functions it exports
+    let mut exports = vec![];
+    let mut e = &file.prog;
+    loop {
+        match e {
+            Exp::FunDefs { decls, body, .. } => {
+                for decl in decls {
+                    if file.exports.iter().any(|(f, _)| *f == decl.name) {
+                        exports.push((decl.name.clone(), decl.parameters.len()));
+                    }
+                }
+                e = body;
+            }
+            Exp::TypeDecl { body, .. } => e = body,
+            _ => break,
+        }
+    }
+    resolver.modules[0] = Some(Module {
+        file,
+        stem: name.clone(),
+        imports,
+    });
+    resolver.order.push(0);
+    let prog = resolver.link()?;
+    // only the functions it refers to are used, so that the modules it
+    // imports can drop the exports it doesn't call
+    let mut referenced = HashSet::new();
+    mentions(&prog, &mut referenced);
+    let unused: HashSet<String> = externs.iter().filter(|f| !referenced.contains(f.as_str())).cloned().collect();
+    let prog = without_decls(prog, &unused);
+    externs.retain(|f| !unused.contains(f));
+    uses.retain(|(m, f, _)| !unused.contains(&format!("{}.{}", m, f)));
+
+    let linkage = compile::Linkage {
+        module: name.clone(),
+        exports: exports.iter().map(|(f, _)| f.clone()).collect(),
+        externs,
+        first_constructor: first_constructor(&name),
+    };
+    let files = &resolver.files;
+    let asm = compile::compile_module(&prog, |s| files.locate(s), &linkage).map_err(|errs| files.located(errs))?;
+    assemble(&asm, &p.with_extension("s"), &object)?;
+    let iface = Interface {
+        module: name,
+        constructors: linkage.first_constructor,
+        exports,
+        uses,
+    };
+    std::fs::write(&interface, iface.to_string()).map_err(|e| RunnerErr::Link(e.to_string()))?;
+    Ok(iface)
+}
+
+// The program without the functions of names that it declares with
+// the others it starts with
+fn without_decls(e: Exp<Span1>, names: &HashSet<String>) -> Exp<Span1> {
+    match e {
+        Exp::FunDefs { mut decls, body, ann } => {
+            decls.retain(|d| !names.contains(&d.name));
+            let body = Box::new(without_decls(*body, names));
+            if decls.is_empty() {
+                *body
+            } else {
+                Exp::FunDefs { decls, body, ann }
+            }
+        }
+        Exp::TypeDecl { name, constructors, body, ann } => Exp::TypeDecl {
+            name,
+            constructors,
+            body: Box::new(without_decls(*body, names)),
+            ann,
+        },
+        e => e,
+    }
+}
+
+// The functions and variables e calls or refers to. The functions of
+// other modules have names no variable can have, so none of them is
+// hidden by a variable of the same name.
+fn mentions<'e, Ann>(e: &'e Exp<Ann>, names: &mut HashSet<&'e str>) {
+    match e {
+        Exp::Var(x, _) => {
+            names.insert(x);
+        }
+        Exp::Call(f, args, _) => {
+            names.insert(f);
+            args.iter().for_each(|e| mentions(e, names));
+        }
+        Exp::Prim(_, es, _) => es.iter().for_each(|e| mentions(e, names)),
+        Exp::Let { bindings, body, .. } => {
+            bindings.iter().for_each(|(_, e)| mentions(e, names));
+            mentions(body, names);
+        }
+        Exp::If { cond, thn, els, .. } => {
+            mentions(cond, names);
+            mentions(thn, names);
+            mentions(els, names);
+        }
+        Exp::FunDefs { decls, body, .. } => {
+            decls.iter().for_each(|d| mentions(&d.body, names));
+            mentions(body, names);
+        }
+        Exp::Semicolon { e1: cond, e2: body, .. } | Exp::While { cond, body, .. } => {
+            mentions(cond, names);
+            mentions(body, names);
+        }
+        Exp::Assign { value: body, .. } | Exp::Lambda { body, .. } | Exp::TypeDecl { body, .. } => {
+            mentions(body, names)
+        }
+        Exp::Construct { args, .. } => args.iter().for_each(|e| mentions(e, names)),
+        Exp::Match { scrutinee, arms, .. } => {
+            mentions(scrutinee, names);
+            arms.iter().for_each(|(_, e)| mentions(e, names));
+        }
+        _ => {}
+    }
+}
+
+/* Links the objects of the modules of a program with the runtime into
+ * an executable named after the first, whose body is the one that is
+ * run, and returns it. Each object needs the ones whose functions it
+ * uses, with the arities it was compiled against.
+ */
+pub fn link_objects(objects: &[PathBuf]) -> Result<PathBuf, RunnerErr<Span2>> {
+    if objects.is_empty() {
+        return Err(RunnerErr::Link("no objects to link".to_string()));
+    }
+    let mut ifaces: Vec<Interface> = vec![];
+    for o in objects {
+        let iface = read_interface(&o.with_extension("iface"))?;
+        if let Some(other) = ifaces.iter().find(|other| other.module == iface.module) {
+            return Err(RunnerErr::Link(format!("module {} is linked twice", other.module)));
+        }
+        if let Some(other) = ifaces.iter().find(|other| other.constructors == iface.constructors) {
+            return Err(RunnerErr::Link(format!(
+                "modules {} and {} number their constructors the same, rename one of them",
+                other.module, iface.module
+            )));
+        }
+        ifaces.push(iface);
+    }
+    for iface in &ifaces {
+        for (m, f, arity) in &iface.uses {
+            let exported = match ifaces.iter().find(|other| other.module == *m) {
+                Some(other) => other.exports.iter().find(|(g, _)| g == f),
+                None => {
+                    return Err(RunnerErr::Link(format!(
+                        "module {} uses {}.{}, but no object of module {} is linked",
+                        iface.module, m, f, m
+                    )))
+                }
+            };
+            match exported {
+                Some((_, n)) if n == arity => {}
+                Some((_, n)) => {
+                    return Err(RunnerErr::Link(format!(
+                        "module {} calls {}.{} with {} arguments, but it takes {}, compile {} again",
+                        iface.module, m, f, arity, n, iface.module
+                    )))
+                }
+                None => {
+                    return Err(RunnerErr::Link(format!(
+                        "module {} uses {}.{}, which module {} doesn't export",
+                        iface.module, m, f, m
+                    )))
+                }
+            }
+        }
+    }
+
+    let dir = tempfile::TempDir::new().map_err(|e| RunnerErr::Link(e.to_string()))?;
+    let modules: Vec<String> = ifaces.into_iter().map(|iface| iface.module).collect();
+    let glue = dir.path().join("link.o");
+    assemble(&compile::link_glue(&modules), &dir.path().join("link.s"), &glue)?;
+    let mut all = vec![glue];
+    all.extend(objects.iter().cloned());
+    let exe = objects[0].with_extension("exe");
+    link_with_runtime(&all, dir.path(), &exe)?;
+    Ok(exe)
+}
+
+// The name of the file without its extension, made into a name
+fn module_name(p: &Path) -> String {
+    let name = p.file_stem().map_or(String::new(), |s| s.to_string_lossy().to_string());
+    let mut stem: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if !stem.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        stem.insert(0, 'm');
+    }
+    stem
+}
+
+// The file in source, or every syntax error in it
+pub(crate) fn parse(source: &Source) -> Result<SurfFile<Span1>, RunnerErr<Span2>> {
+    let (prog, errs) = parse_recovering(&source.text);
+    match prog {
+        Some(prog) if errs.is_empty() => Ok(prog),
+        _ => Err(RunnerErr::Parse(
+            without_cascades(&source.text, errs).into_iter().map(|e| parse_err(e, source)).collect(),
+            source.clone(),
+        )),
+    }
+}
+
+// An error of the parser, at the byte offsets of the text
+pub(crate) type SyntaxErr<'input> = ParseError<usize, Tok<'input>, LexError>;
+
+/* The parser goes on after the errors it can recover from, see BaseExp
+ * in parser.lalrpop, and stops at the others. The errors are returned
+ * in the order they were found, and the program only if the parser
+ * didn't stop.
+ */
+pub(crate) fn parse_recovering(text: &str) -> (Option<SurfFile<Span1>>, Vec<SyntaxErr<'_>>) {
+    let mut recovered = vec![];
+    let r = ProgParser::new().parse(&mut recovered, &mut vec![], Lexer::new(text));
+    let mut errs: Vec<_> = recovered.into_iter().map(|e| e.error).collect();
+    match r {
+        Ok(prog) => (Some(prog), errs),
+        Err(e) => {
+            errs.push(e);
+            (None, errs)
+        }
+    }
+}
+
+/* After an error the parser often fails again on the rest of the
+ * construct it gave up on, e.g. on the `:` and the `in` of
+ * `def len(x): x in`, where `len` can't be the name of a function. The
+ * errors up to the next token that ends a construct are left out, so
+ * only the first of them is reported.
+ */
+fn without_cascades<'input>(text: &str, errs: Vec<SyntaxErr<'input>>) -> Vec<SyntaxErr<'input>> {
+    let ends: Vec<(usize, usize)> = Lexer::new(text)
+        .map_while(Result::ok)
+        .filter(|(_, tok, _)| matches!(tok, Tok::Sym("in" | "and" | "end" | "else" | ";")))
+        .map(|(l, _, r)| (l, r))
+        .collect();
+    let mut quiet_until = 0;
+    let mut kept = vec![];
+    for e in errs {
+        let (start, _) = error_span(&e, text);
+        if start < quiet_until {
+            continue;
+        }
+        quiet_until = ends.iter().find(|(l, _)| *l >= start).map_or(usize::MAX, |(_, r)| *r);
+        kept.push(e);
+    }
+    kept
+}
+
+// The byte offsets of the token a parse error stopped at
+fn error_span(e: &SyntaxErr, text: &str) -> (usize, usize) {
+    let len = text.len();
+    match e {
+        ParseError::InvalidToken { location } => (*location, location + 1),
+        ParseError::UnrecognizedEOF { location, .. } => {
+            // the end of the last token, which may be the end of the file
+            let start = std::cmp::min(*location, len.saturating_sub(1));
+            (start, start + 1)
+        }
+        ParseError::UnrecognizedToken { token: (l, _, r), .. }
+        | ParseError::ExtraToken { token: (l, _, r) } => (*l, *r),
+        ParseError::User {
+            error: LexError::InvalidToken(location),
+        } => (*location, location + text[*location..].chars().next().map_or(1, char::len_utf8)),
+        ParseError::User {
+            error: LexError::UnterminatedComment(location),
+        }
+        | ParseError::User {
+            error: LexError::InvalidEscape(location),
+        } => (*location, location + 2),
+        ParseError::User {
+            error: LexError::UnterminatedString(location),
+        } => (*location, location + 1),
+        ParseError::User {
+            error: LexError::NumTooBig(start, end),
+        } => (*start, *end),
+    }
+}
+
+// A parse error located at the token it stopped at
+fn parse_err(e: SyntaxErr, source: &Source) -> ParseErr<Span2> {
+    let len = source.text.len();
+    let location = Some(error_span(&e, &source.text))
+        .filter(|(start, end)| start < end && *end <= len)
+        .map(|(start_ix, end_ix)| span1_to_span2(&file_info(&source.text), Span1 { start_ix, end_ix }));
+    let (message, label) = match &e {
+        ParseError::InvalidToken { .. }
+        | ParseError::User {
+            error: LexError::InvalidToken(_),
+        } => ("invalid token".to_string(), "not part of the language".to_string()),
+        ParseError::User {
+            error: LexError::UnterminatedComment(_),
+        } => ("unterminated comment".to_string(), "`(*` without a matching `*)`".to_string()),
+        ParseError::User {
+            error: LexError::UnterminatedString(_),
+        } => ("unterminated string".to_string(), "`\"` without a matching `\"`".to_string()),
+        ParseError::User {
+            error: LexError::InvalidEscape(_),
+        } => ("invalid escape".to_string(), "not one of `\\n`, `\\t`, `\\\\` or `\\\"`".to_string()),
+        ParseError::User {
+            error: LexError::NumTooBig(..),
+        } => ("number too big".to_string(), "doesn't fit into 64 bits".to_string()),
+        ParseError::UnrecognizedEOF { location, expected } => {
+            let (what, context) = describe_expected(&viable(&source.text[..*location], expected));
+            (format!("expected {}{}, found the end of the input", what, context), format!("expected {}", what))
+        }
+        ParseError::UnrecognizedToken {
+            token: (l, _, r),
+            expected,
+        } => {
+            let (what, context) = describe_expected(&viable(&source.text[..*l], expected));
+            (
+                format!("expected {}{}, found `{}`", what, context, &source.text[*l..*r]),
+                format!("expected {}", what),
+            )
+        }
+        ParseError::ExtraToken { token: (l, _, r) } => (
+            format!("unexpected `{}` after the end of the program", &source.text[*l..*r]),
+            "expected the end of the input".to_string(),
+        ),
+    };
+    ParseErr {
+        message,
+        label,
+        location,
+    }
+}
+
+/* The terminals that can really come after before, the text up to an
+ * error. The states of the parser are shared between similar places of
+ * the grammar, so the tokens it says it expected include the ones that
+ * only fit one of the others; each is tried by parsing the text with it
+ * put in place of the error. "" stands for the end of the input.
+ */
+fn viable(before: &str, expected: &[String]) -> Vec<String> {
+    let fits = |probe: &str| {
+        parse_recovering(probe).1.iter().all(|e| match e {
+            ParseError::UnrecognizedEOF { .. } => true,
+            ParseError::UnrecognizedToken { token: (l, _, _), .. } => *l < before.len(),
+            _ => false,
+        })
+    };
+    let mut tokens: Vec<String> = expected
+        .iter()
+        .filter(|t| fits(&format!("{} {}", before, sample(t))))
+        .cloned()
+        .collect();
+    if parse_recovering(before).1.iter().all(|e| !matches!(e, ParseError::UnrecognizedEOF { .. })) {
+        tokens.push(String::new());
+    }
+    tokens
+}
+
+// Some text that a terminal of the grammar matches
+fn sample(t: &str) -> &str {
+    match token_name(t).as_str() {
+        "a name" => "x",
+        "a constructor" => "C",
+        "a number" => "1",
+        "a float" => "1.5",
+        "a string" => "\"s\"",
+        _ => t.trim_matches('"'),
+    }
 }
 
-fn link_and_run<W>(assembly: &str, dir: &Path, out: &mut W) -> Result<(), RunnerErr<Span2>>
+/* What the parser expected, in the words of the README rather than the
+ * terminals of the grammar, and after what if that is clear from them,
+ * e.g. ("`else:`", " after the then-branch"). The terminals that close
+ * a construct say the most, so they are looked for first; the operators
+ * and `;` that could extend the expression before them are left out.
+ */
+fn describe_expected(expected: &[String]) -> (String, &'static str) {
+    let has = |t: &str| expected.iter().any(|e| e.trim_matches('"') == t);
+    let names: Vec<String> = expected.iter().map(|t| token_name(t)).collect();
+    let extends = |e: &String| matches!(e.trim_matches('"'), "(" | "[" | ";") || operator(e);
+    let (what, context) = if has("else") {
+        ("`else:`", " after the then-branch")
+    } else if has("end") && has("|") {
+        ("`|` or `end`", " after an arm of a `match`")
+    } else if has("end") {
+        ("`end`", " after the body of a lambda")
+    } else if has("=>") {
+        ("`=>`", " after a pattern")
+    } else if has("in") && has("and") {
+        ("`in` or `and`", " after a function definition")
+    } else if has("in") {
+        ("`in` or `,`", " after the bindings of a `let`")
+    } else if has("=") {
+        ("`=`", " after the name of a binding")
+    } else if has("->") {
+        ("`:` or `->`", " after the parameters of a function")
+    } else if has(":") && has("+") {
+        ("`:`", " after the condition of an `if` or `while`")
+    } else if has("Int") {
+        ("a type", "")
+    } else if has("]") {
+        ("`]`", " after the index")
+    } else if has(")") && has(",") {
+        ("`,` or `)`", "")
+    } else if has(")") && has("+") {
+        ("`)`", "")
+    } else if names.iter().any(|n| n == "a number") {
+        ("an expression", "")
+    } else if !expected.is_empty() && expected.iter().all(|e| extends(e) || e.is_empty()) {
+        if has("") {
+            ("an operator or the end of the input", "")
+        } else {
+            ("an operator", "")
+        }
+    } else {
+        return (one_of(&names), "");
+    };
+    (what.to_string(), context)
+}
+
+fn operator(t: &str) -> bool {
+    matches!(
+        t.trim_matches('"'),
+        "+" | "++" | "-" | "*" | "/" | "//" | "&&" | "||" | "<" | "<=" | ">" | ">=" | "==" | "!="
+    )
+}
+
+// How a terminal of the grammar is called in messages
+fn token_name(t: &str) -> String {
+    if t.is_empty() {
+        "the end of the input".to_string()
+    } else if t == "ID" {
+        "a name".to_string()
+    } else if t == "CON" {
+        "a constructor".to_string()
+    } else if t == "FLOAT" {
+        "a float".to_string()
+    } else if t == "NUM" {
+        "a number".to_string()
+    } else if t == "STR" {
+        "a string".to_string()
+    } else {
+        format!("`{}`", t.trim_matches('"'))
+    }
+}
+
+fn one_of(names: &[String]) -> String {
+    match names {
+        [] => "nothing more".to_string(),
+        [name] => name.clone(),
+        [init @ .., last] => format!("one of {} or {}", init.join(", "), last),
+    }
+}
+
+fn link_and_run<W>(assembly: &str, dir: &Path, heap: Option<usize>, out: &mut W) -> Result<(), RunnerErr<Span2>>
 where
     W: std::io::Write,
 {
-    let (nasm_format, lib_name) = if cfg!(target_os = "linux") {
+    let asm_fname = dir.join("compiled_code.s");
+    let obj_fname = dir.join("compiled_code.o");
+    let exe_fname = dir.join("stub.exe");
+
+    assemble(assembly, &asm_fname, &obj_fname)?;
+    link_with_runtime(&[obj_fname], dir, &exe_fname)?;
+    run_exe(&exe_fname, heap, out)
+}
+
+// The format nasm writes objects in, and the name of the library of
+// the compiled code the runtime links with
+fn object_format() -> (&'static str, &'static str) {
+    if cfg!(target_os = "linux") {
         ("elf64", "libcompiled_code.a")
     } else if cfg!(target_os = "macos") {
         ("macho64", "libcompiled_code.a")
@@ -361,15 +1684,15 @@ where
         ("win64", "compiled_code.lib")
     } else {
         panic!("Runner script only works on linux, macos and windows")
-    };
+    }
+}
 
-    let asm_fname = dir.join("compiled_code.s");
-    let obj_fname = dir.join("compiled_code.o");
-    let lib_fname = dir.join(lib_name);
-    let exe_fname = dir.join("stub.exe");
+// Writes the assembly to asm_fname and assembles it to obj_fname
+fn assemble(assembly: &str, asm_fname: &Path, obj_fname: &Path) -> Result<(), RunnerErr<Span2>> {
+    let (nasm_format, _) = object_format();
 
-    // first put the assembly in a new file compiled_code.s
-    let mut asm_file = File::create(&asm_fname).map_err(|e| RunnerErr::Link(e.to_string()))?;
+    // first put the assembly in a new file
+    let mut asm_file = File::create(asm_fname).map_err(|e| RunnerErr::Link(e.to_string()))?;
     asm_file
         .write(assembly.as_bytes())
         .map_err(|e| RunnerErr::Link(e.to_string()))?;
@@ -382,8 +1705,8 @@ where
         .arg("-f")
         .arg(nasm_format)
         .arg("-o")
-        .arg(&obj_fname)
-        .arg(&asm_fname)
+        .arg(obj_fname)
+        .arg(asm_fname)
         .output()
         .map_err(|e| RunnerErr::Link(format!("nasm err: {}", e)))?;
     if !nasm_out.status.success() {
@@ -393,12 +1716,20 @@ where
             std::str::from_utf8(&nasm_out.stderr).expect("nasm produced invalid UTF-8")
         )));
     }
+    Ok(())
+}
+
+// Puts the objects in the library of the compiled code in dir, and
+// builds the runtime with it into exe_fname
+fn link_with_runtime(objects: &[PathBuf], dir: &Path, exe_fname: &Path) -> Result<(), RunnerErr<Span2>> {
+    let (_, lib_name) = object_format();
+    let lib_fname = dir.join(lib_name);
 
     // ar r libcompiled_code.a compiled_code.o
     let ar_out = Command::new("ar")
         .arg("rus")
         .arg(lib_fname)
-        .arg(&obj_fname)
+        .args(objects)
         .output()
         .map_err(|e| RunnerErr::Link(format!("ar err: {}", e)))?;
     if !ar_out.status.success() {
@@ -418,7 +1749,7 @@ where
             .arg("-L")
             .arg(dir)
             .arg("-o")
-            .arg(&exe_fname)
+            .arg(exe_fname)
             .output()
             .map_err(|e| RunnerErr::Link(format!("rustc err: {}", e)))?
     } else {
@@ -427,7 +1758,7 @@ where
             .arg("-L")
             .arg(dir)
             .arg("-o")
-            .arg(&exe_fname)
+            .arg(exe_fname)
             .output()
             .map_err(|e| RunnerErr::Link(format!("rustc err: {}", e)))?
     };
@@ -438,8 +1769,16 @@ where
             std::str::from_utf8(&rustc_out.stderr).expect("rustc produced invalid UTF-8")
         )));
     }
+    Ok(())
+}
 
-    let mut child = Command::new(&exe_fname)
+// Runs the program in exe_fname, writing what it prints to out
+fn run_exe<W>(exe_fname: &Path, heap: Option<usize>, out: &mut W) -> Result<(), RunnerErr<Span2>>
+where
+    W: std::io::Write,
+{
+    let mut child = Command::new(exe_fname)
+        .args(heap.map(|words| words.to_string()))
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()
@@ -470,10 +1809,21 @@ where
         for line in compiled_err.lines() {
             stderr.push_str(&format!("{}\n", line.unwrap()));
         }
-        return Err(RunnerErr::Run(format!(
-            "Error code {} when running compiled code Stderr:\n{}",
-            status, stderr
-        )));
+        // the runtime didn't get to report the error if it crashed
+        let (message, location) = match runtime_error(&stderr) {
+            Some(error) => {
+                let end = stderr.trim_end().rfind('\n').map_or(0, |n| n + 1);
+                stderr.truncate(end);
+                error
+            }
+            None => (stderr.trim_end().to_string(), None),
+        };
+        return Err(RunnerErr::RunFailed {
+            status,
+            stderr,
+            message,
+            location,
+        });
     }
     Ok(())
 }
@@ -5,13 +5,20 @@ use crate::{
     tag::tag_exp,
 };
 
-pub fn seq_exp(e: &Exp<u32>) -> SeqExp<()> {
+// The sequential form of e, whose nodes keep the annotation of the
+// node they come from. The numbers of e name the temporaries.
+pub fn seq_exp<Ann>(e: &Exp<(u32, Ann)>) -> SeqExp<Ann>
+where
+    Ann: Clone,
+{
+    let (tag, ann) = e.ann();
     match e {
-        Exp::Var(name, _) => SeqExp::Imm(ImmExp::Var(name.clone()), ()),
-        Exp::Num(num, _) => SeqExp::Imm(ImmExp::Num(*num), ()),
-        Exp::Bool(b, _) => SeqExp::Imm(ImmExp::Bool(*b), ()),
-        Exp::Float(s, _) => SeqExp::Imm(ImmExp::Float(s.clone()), ()),
-        Exp::Prim(op, exps, tag) => match op {
+        Exp::Var(name, _) => SeqExp::Imm(ImmExp::Var(name.clone()), ann.clone()),
+        Exp::Num(num, _) => SeqExp::Imm(ImmExp::Num(*num), ann.clone()),
+        Exp::Bool(b, _) => SeqExp::Imm(ImmExp::Bool(*b), ann.clone()),
+        Exp::Float(s, _) => SeqExp::Imm(ImmExp::Float(s.clone()), ann.clone()),
+        Exp::Str(s, _) => SeqExp::Imm(ImmExp::Str(s.clone()), ann.clone()),
+        Exp::Prim(op, exps, _) => match op {
             Prim::Add
             | Prim::Sub
             | Prim::Mul
@@ -24,6 +31,13 @@ pub fn seq_exp(e: &Exp<u32>) -> SeqExp<()> {
             | Prim::Eq
             | Prim::Div
             | Prim::FloorDiv
+            | Prim::GetIndex
+            | Prim::ClosureEnv
+            | Prim::SetBox
+            | Prim::IsVariant
+            | Prim::VariantField
+            | Prim::Concat
+            | Prim::MakeArray
             | Prim::Neq => {
                 let (s1, s2) = (seq_exp(&exps[0]), seq_exp(&exps[1]));
                 let (n1, n2) = (format!("#prim2_1_{}", tag), format!("#prim2_2_{}", tag));
@@ -36,31 +50,56 @@ pub fn seq_exp(e: &Exp<u32>) -> SeqExp<()> {
                         body: Box::new(SeqExp::Prim(
                             *op,
                             vec![ImmExp::Var(n1), ImmExp::Var(n2)],
-                            (),
+                            ann.clone(),
                         )),
-                        ann: (),
+                        ann: ann.clone(),
                     }),
-                    ann: (),
+                    ann: ann.clone(),
                 }
             }
             Prim::Add1
             | Prim::Sub1
             | Prim::IsBool
             | Prim::IsFloat
+            | Prim::IsTuple
             | Prim::IsNum
             | Prim::Sqrt
             | Prim::Cos
             | Prim::Not
+            | Prim::Unbox
+            | Prim::MatchFail
+            | Prim::Len
+            | Prim::ToString
+            | Prim::Length
             | Prim::Print => {
                 let n1 = format!("#prim1_1_{}", tag);
                 let s1 = seq_exp(&exps[0]);
                 SeqExp::Let {
                     var: n1.clone(),
                     bound_exp: Box::new(s1),
-                    body: Box::new(SeqExp::Prim(*op, vec![ImmExp::Var(n1)], ())),
-                    ann: (),
+                    body: Box::new(SeqExp::Prim(*op, vec![ImmExp::Var(n1)], ann.clone())),
+                    ann: ann.clone(),
                 }
             }
+            Prim::MakeTuple | Prim::MakeVariant | Prim::Substr | Prim::SetIndex => {
+                let new_names: Vec<String> = (0..exps.len())
+                    .map(|i| format!("#tuple_{}_{}", tag, i))
+                    .collect();
+                let mut seq = Box::new(SeqExp::Prim(
+                    *op,
+                    new_names.iter().map(|n| ImmExp::Var(n.clone())).collect(),
+                    ann.clone(),
+                ));
+                for i in (0..exps.len()).rev() {
+                    seq = Box::new(SeqExp::Let {
+                        var: new_names[i].clone(),
+                        bound_exp: Box::new(seq_exp(&exps[i])),
+                        body: seq,
+                        ann: ann.clone(),
+                    });
+                }
+                *seq
+            }
         },
         Exp::Let { bindings, body, .. } => {
             let mut seq = Box::new(seq_exp(body));
@@ -69,31 +108,50 @@ pub fn seq_exp(e: &Exp<u32>) -> SeqExp<()> {
                     var: x.clone(),
                     bound_exp: Box::new(seq_exp(bound_exp)),
                     body: seq,
-                    ann: (),
+                    ann: ann.clone(),
                 });
             }
             *seq
         }
-        Exp::If {
-            cond,
-            thn,
-            els,
-            ann,
-        } => {
-            let name = format!("{}{:?}", "if_", ann);
+        Exp::If { cond, thn, els, .. } => {
+            let name = format!("{}{:?}", "if_", tag);
             let bound_exp = Box::new(seq_exp(cond));
             let body = Box::new(SeqExp::If {
                 cond: ImmExp::Var(name.clone()),
                 thn: Box::new(seq_exp(thn)),
                 els: Box::new(seq_exp(els)),
-                ann: (),
+                ann: ann.clone(),
             });
 
             SeqExp::Let {
                 var: name,
                 bound_exp,
                 body,
-                ann: (),
+                ann: ann.clone(),
+            }
+        }
+        Exp::Semicolon { e1, e2, .. } => SeqExp::Let {
+            var: format!("#seq_{}", tag),
+            bound_exp: Box::new(seq_exp(e1)),
+            body: Box::new(seq_exp(e2)),
+            ann: ann.clone(),
+        },
+        Exp::While { cond, body, .. } => SeqExp::While {
+            cond: Box::new(seq_exp(cond)),
+            body: Box::new(seq_exp(body)),
+            ann: ann.clone(),
+        },
+        Exp::Assign { var, value, .. } => {
+            let name = format!("#assign_{}", tag);
+            SeqExp::Let {
+                var: name.clone(),
+                bound_exp: Box::new(seq_exp(value)),
+                body: Box::new(SeqExp::Assign {
+                    var: var.clone(),
+                    value: ImmExp::Var(name),
+                    ann: ann.clone(),
+                }),
+                ann: ann.clone(),
             }
         }
         Exp::FunDefs { decls, body, .. } => {
@@ -102,35 +160,36 @@ pub fn seq_exp(e: &Exp<u32>) -> SeqExp<()> {
                 new_decls.push(FunDecl {
                     name: decl.name.clone(),
                     parameters: decl.parameters.clone(),
+                    signature: decl.signature.clone(),
                     body: seq_exp(&decl.body),
-                    ann: (),
+                    ann: decl.ann.1.clone(),
                 });
             }
             SeqExp::FunDefs {
                 decls: new_decls,
                 body: Box::new(seq_exp(body)),
-                ann: (),
+                ann: ann.clone(),
             }
         }
-        Exp::InternalTailCall(fun_name, args, ann) => {
+        Exp::InternalTailCall(fun_name, args, _) => {
             if args.is_empty() {
-                return SeqExp::InternalTailCall(fun_name.clone(), Vec::new(), ());
+                return SeqExp::InternalTailCall(fun_name.clone(), Vec::new(), ann.clone());
             }
             let mut new_names = vec![];
             for i in 0..args.len() {
-                new_names.push(format!("#arg_{}_{}", ann, i));
+                new_names.push(format!("#arg_{}_{}", tag, i));
             }
             let mut new_args = vec![];
             for n in &new_names {
                 new_args.push(ImmExp::Var(n.clone()));
             }
-            let mut seq = Box::new(SeqExp::InternalTailCall(fun_name.clone(), new_args, ()));
+            let mut seq = Box::new(SeqExp::InternalTailCall(fun_name.clone(), new_args, ann.clone()));
             for i in (0..args.len()).rev() {
                 seq = Box::new(SeqExp::Let {
                     var: new_names[i].clone(),
                     bound_exp: Box::new(seq_exp(&args[i])),
                     body: seq,
-                    ann: (),
+                    ann: ann.clone(),
                 });
             }
             *seq
@@ -139,19 +198,19 @@ pub fn seq_exp(e: &Exp<u32>) -> SeqExp<()> {
             fun_name,
             args,
             is_tail,
-            ann,
+            ..
         } => {
             if args.is_empty() {
                 return SeqExp::ExternalCall {
                     fun_name: fun_name.clone(),
                     args: Vec::new(),
                     is_tail: *is_tail,
-                    ann: (),
+                    ann: ann.clone(),
                 };
             }
             let mut new_names = vec![];
             for i in 0..args.len() {
-                new_names.push(format!("#arg_{}_{}", ann, i));
+                new_names.push(format!("#arg_{}_{}", tag, i));
             }
             let mut new_args = vec![];
             for n in &new_names {
@@ -161,30 +220,78 @@ pub fn seq_exp(e: &Exp<u32>) -> SeqExp<()> {
                 fun_name: fun_name.clone(),
                 args: new_args,
                 is_tail: *is_tail,
-                ann: (),
+                ann: ann.clone(),
             });
             for i in (0..args.len()).rev() {
                 seq = Box::new(SeqExp::Let {
                     var: new_names[i].clone(),
                     bound_exp: Box::new(seq_exp(&args[i])),
                     body: seq,
-                    ann: (),
+                    ann: ann.clone(),
                 });
             }
             *seq
         }
+        Exp::ClosureCall {
+            fun, args, is_tail, ..
+        } => {
+            let fun_name = format!("#fun_{}", tag);
+            let mut new_names = vec![];
+            for i in 0..args.len() {
+                new_names.push(format!("#arg_{}_{}", tag, i));
+            }
+            let mut new_args = vec![];
+            for n in &new_names {
+                new_args.push(ImmExp::Var(n.clone()));
+            }
+            let mut seq = Box::new(SeqExp::ClosureCall {
+                fun: ImmExp::Var(fun_name.clone()),
+                args: new_args,
+                is_tail: *is_tail,
+                ann: ann.clone(),
+            });
+            for i in (0..args.len()).rev() {
+                seq = Box::new(SeqExp::Let {
+                    var: new_names[i].clone(),
+                    bound_exp: Box::new(seq_exp(&args[i])),
+                    body: seq,
+                    ann: ann.clone(),
+                });
+            }
+            SeqExp::Let {
+                var: fun_name,
+                bound_exp: Box::new(seq_exp(fun)),
+                body: seq,
+                ann: ann.clone(),
+            }
+        }
+        Exp::MakeClosure {
+            fun_name,
+            arity,
+            env,
+            ..
+        } => SeqExp::MakeClosure {
+            fun_name: fun_name.clone(),
+            arity: *arity,
+            env: env.iter().map(|x| ImmExp::Var(x.clone())).collect(),
+            ann: ann.clone(),
+        },
         _ => panic!("seq_exp() cannot"),
     }
 }
 
-pub fn seq_decl(decls: &[FunDecl<Exp<()>, ()>]) -> Vec<FunDecl<SeqExp<()>, ()>> {
+pub fn seq_decl<Ann>(decls: &[FunDecl<Exp<Ann>, Ann>]) -> Vec<FunDecl<SeqExp<Ann>, Ann>>
+where
+    Ann: Clone,
+{
     let mut new_decls = vec![];
     for decl in decls {
         new_decls.push(FunDecl {
             name: decl.name.clone(),
             parameters: decl.parameters.clone(),
+            signature: decl.signature.clone(),
             body: seq_exp(&tag_exp(&decl.body, &mut 0, &mut HashMap::new(), false)),
-            ann: (),
+            ann: decl.ann.clone(),
         });
     }
     new_decls
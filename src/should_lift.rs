@@ -1,39 +1,78 @@
 use crate::syntax::Exp;
 use std::collections::HashSet;
 
-pub fn should_lift_helper<Ann>(p: &Exp<Ann>, hashset: &mut HashSet<String>, is_tail: bool) {
+// own holds the functions of the innermost group p is in the body of a
+// function of, outer the functions of the groups around that one
+pub fn should_lift_helper<Ann>(
+    p: &Exp<Ann>,
+    hashset: &mut HashSet<String>,
+    is_tail: bool,
+    outer: &[String],
+    own: &[String],
+) {
     match p {
         Exp::Prim(_, exps, _) => {
             for exp in exps {
-                should_lift_helper(exp, hashset, false);
+                should_lift_helper(exp, hashset, false, outer, own);
             }
         }
         Exp::Let { bindings, body, .. } => {
             for (_, b) in bindings {
-                should_lift_helper(b, hashset, false);
+                should_lift_helper(b, hashset, false, outer, own);
             }
-            should_lift_helper(body, hashset, is_tail);
+            should_lift_helper(body, hashset, is_tail, outer, own);
         }
         Exp::If { cond, thn, els, .. } => {
-            should_lift_helper(cond, hashset, false);
-            should_lift_helper(thn, hashset, is_tail);
-            should_lift_helper(els, hashset, is_tail);
+            should_lift_helper(cond, hashset, false, outer, own);
+            should_lift_helper(thn, hashset, is_tail, outer, own);
+            should_lift_helper(els, hashset, is_tail, outer, own);
         }
+        // only e2 is in tail position
+        Exp::Semicolon { e1, e2, .. } => {
+            should_lift_helper(e1, hashset, false, outer, own);
+            should_lift_helper(e2, hashset, is_tail, outer, own);
+        }
+        Exp::While { cond, body, .. } => {
+            should_lift_helper(cond, hashset, false, outer, own);
+            should_lift_helper(body, hashset, false, outer, own);
+        }
+        Exp::Assign { value, .. } => should_lift_helper(value, hashset, false, outer, own),
         Exp::FunDefs { decls, body, .. } => {
+            let names: Vec<String> = decls.iter().map(|decl| decl.name.clone()).collect();
+            let mut enclosing = outer.to_vec();
+            enclosing.extend(own.iter().cloned());
             for decl in decls {
-                should_lift_helper(&decl.body, hashset, is_tail);
+                should_lift_helper(&decl.body, hashset, is_tail, &enclosing, &names);
             }
-            should_lift_helper(body, hashset, true);
+            should_lift_helper(body, hashset, is_tail, outer, own);
         }
         Exp::Call(fun_name, args, _) => {
-            if !is_tail {
+            // a lifted function gets copies of the local functions it calls,
+            // but not of the ones it is nested in, which contain it
+            // themselves, so those are lifted too
+            if !is_tail || outer.contains(fun_name) {
                 hashset.insert(fun_name.clone());
             }
             for arg in args {
-                should_lift_helper(arg, hashset, false);
+                should_lift_helper(arg, hashset, false, outer, own);
             }
         }
-        Exp::Num(_, _) | Exp::Bool(_, _) | Exp::Var(_, _) | Exp::Float(_, _) => (),
+        Exp::Lambda { body, .. } => {
+            // the body becomes its own top-level function, so everything
+            // it calls has to be reachable from there
+            should_lift_helper(body, hashset, false, outer, own);
+        }
+        Exp::ClosureCall { fun, args, .. } => {
+            should_lift_helper(fun, hashset, false, outer, own);
+            for arg in args {
+                should_lift_helper(arg, hashset, false, outer, own);
+            }
+        }
+        // functions used as values are called from a closure
+        Exp::Var(x, _) => {
+            hashset.insert(x.clone());
+        }
+        Exp::Num(_, _) | Exp::Bool(_, _) | Exp::Float(_, _) | Exp::Str(_, _) => (),
         _ => panic!("should_lift wrong"),
     }
 }
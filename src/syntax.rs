@@ -1,20 +1,122 @@
+use std::collections::HashMap;
+
 pub type SurfProg<Ann> = Exp<Ann>;
 pub type SurfFunDecl<Ann> = FunDecl<Exp<Ann>, Ann>;
 
+// A source file: the paths of the files it imports, as written, the
+// functions it exports and its program
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SurfFile<Ann> {
+    pub imports: Vec<(String, Ann)>,
+    pub exports: Vec<(String, Ann)>,
+    pub prog: SurfProg<Ann>,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct FunDecl<E, Ann> {
     pub name: String,
     pub parameters: Vec<String>,
+    pub signature: Signature,
     pub body: E,
     pub ann: Ann,
 }
 
+/* Types */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Type {
+    Int,
+    Float,
+    Bool,
+    Tuple,
+    String,
+    Array,
+    Fun(Vec<Type>, Box<Type>),
+    // unification variable, only produced by type inference
+    Var(u32),
+    // not known statically, e.g. the elements of a tuple
+    Dyn,
+    // a type declared with `type`, by its name
+    Data(String),
+}
+
+// The optional annotations of a declaration `def f(x: Int, y) -> Float: ...`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Signature {
+    pub parameters: Vec<Option<Type>>,
+    pub ret: Option<Type>,
+}
+
+// One of the constructors of `type shape = Circle(r) | Rect(w, h)`,
+// the names of its fields only say how many there are
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Constructor<Ann> {
+    pub name: String,
+    pub fields: Vec<String>,
+    pub ann: Ann,
+}
+
+// The name of a constructor in its file, without the module that a
+// program importing the file qualifies it with, see Resolver::link
+pub fn unqualified(constructor: &str) -> &str {
+    constructor.rsplit('.').next().unwrap()
+}
+
+// The left of an arm `| Rect(w, _) => ...` of a match
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Pattern<Ann> {
+    Wildcard(Ann),
+    Var(String, Ann),
+    Constructor(String, Vec<Pattern<Ann>>, Ann),
+}
+
+impl<Ann: Clone> Pattern<Ann> {
+    pub fn ann(&self) -> Ann {
+        match self {
+            Pattern::Wildcard(ann) | Pattern::Var(_, ann) | Pattern::Constructor(_, _, ann) => ann.clone(),
+        }
+    }
+
+    // The variables the pattern binds, from left to right
+    pub fn vars(&self) -> Vec<&String> {
+        match self {
+            Pattern::Wildcard(_) => vec![],
+            Pattern::Var(x, _) => vec![x],
+            Pattern::Constructor(_, ps, _) => ps.iter().flat_map(|p| p.vars()).collect(),
+        }
+    }
+
+    // The same pattern with the constructors in renames renamed
+    pub fn rename(&self, renames: &HashMap<String, String>) -> Pattern<Ann> {
+        match self {
+            Pattern::Wildcard(_) | Pattern::Var(..) => self.clone(),
+            Pattern::Constructor(c, ps, ann) => Pattern::Constructor(
+                renames.get(c).unwrap_or(c).clone(),
+                ps.iter().map(|p| p.rename(renames)).collect(),
+                ann.clone(),
+            ),
+        }
+    }
+
+    pub fn map_ann<B, F>(&self, f: &F) -> Pattern<B>
+    where
+        F: Fn(&Ann) -> B,
+    {
+        match self {
+            Pattern::Wildcard(ann) => Pattern::Wildcard(f(ann)),
+            Pattern::Var(x, ann) => Pattern::Var(x.clone(), f(ann)),
+            Pattern::Constructor(c, ps, ann) => {
+                Pattern::Constructor(c.clone(), ps.iter().map(|p| p.map_ann(f)).collect(), f(ann))
+            }
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct FloatWrapper(pub f64);
-// Implement Eq for FloatWrapper with epsilon comparison
+// Implement Eq for FloatWrapper by comparing the bits of the doubles
 impl PartialEq for FloatWrapper {
     fn eq(&self, other: &Self) -> bool {
-        (self.0 - other.0).abs() < f32::EPSILON as f64
+        self.0.to_bits() == other.0.to_bits()
     }
 }
 impl Eq for FloatWrapper {}
@@ -26,6 +128,8 @@ pub enum Exp<Ann> {
     Bool(bool, Ann),
     Var(String, Ann),
     Float(FloatWrapper, Ann),
+    // the text of a string literal, with its escapes replaced
+    Str(String, Ann),
     Prim(Prim, Vec<Box<Exp<Ann>>>, Ann),
     Let {
         bindings: Vec<(String, Exp<Ann>)>,
@@ -43,11 +147,65 @@ pub enum Exp<Ann> {
         body: Box<Exp<Ann>>,
         ann: Ann,
     },
+    // e1; e2 runs e1 for its effects and has the value of e2
+    Semicolon {
+        e1: Box<Exp<Ann>>,
+        e2: Box<Exp<Ann>>,
+        ann: Ann,
+    },
+    // runs body as long as cond is true, has the value false
+    While {
+        cond: Box<Exp<Ann>>,
+        body: Box<Exp<Ann>>,
+        ann: Ann,
+    },
+    // x := e, has the value of e
+    Assign {
+        var: String,
+        value: Box<Exp<Ann>>,
+        ann: Ann,
+    },
+
+    Lambda {
+        parameters: Vec<String>,
+        body: Box<Exp<Ann>>,
+        ann: Ann,
+    },
+
+    // type name = C1(x, y) | C2 | ... in body
+    TypeDecl {
+        name: String,
+        constructors: Vec<Constructor<Ann>>,
+        body: Box<Exp<Ann>>,
+        ann: Ann,
+    },
+    // C(e1, ..., en), or just C without fields
+    Construct {
+        name: String,
+        args: Vec<Exp<Ann>>,
+        ann: Ann,
+    },
+    // The body of the first arm whose pattern matches the value of
+    // scrutinee. Turned into ifs by matching.rs
+    Match {
+        scrutinee: Box<Exp<Ann>>,
+        arms: Vec<(Pattern<Ann>, Exp<Ann>)>,
+        ann: Ann,
+    },
 
     // Source program calls will be parsed as a call.
     // In your lambda_lift function you should
     Call(String, Vec<Exp<Ann>>, Ann),
 
+    // A call to a function value. Calls of the form f(...) where f is
+    // a value variable are turned into these by lambda_lift
+    ClosureCall {
+        fun: Box<Exp<Ann>>,
+        args: Vec<Exp<Ann>>,
+        is_tail: bool,
+        ann: Ann,
+    },
+
     // An internal tail call to a locally defined function.
     InternalTailCall(String, Vec<Exp<Ann>>, Ann),
     // A call to one of the top-level function definitions
@@ -59,6 +217,260 @@ pub enum Exp<Ann> {
         is_tail: bool,
         ann: Ann,
     },
+    // Allocates a closure for the lifted function fun_name,
+    // capturing the values of the variables in env
+    MakeClosure {
+        fun_name: String,
+        arity: usize,
+        env: Vec<String>,
+        ann: Ann,
+    },
+}
+
+impl<Ann: Clone> Exp<Ann> {
+    pub fn ann(&self) -> Ann {
+        match self {
+            Exp::Num(_, ann)
+            | Exp::Bool(_, ann)
+            | Exp::Var(_, ann)
+            | Exp::Float(_, ann)
+            | Exp::Str(_, ann)
+            | Exp::Prim(_, _, ann)
+            | Exp::Call(_, _, ann)
+            | Exp::InternalTailCall(_, _, ann)
+            | Exp::Let { ann, .. }
+            | Exp::If { ann, .. }
+            | Exp::FunDefs { ann, .. }
+            | Exp::Semicolon { ann, .. }
+            | Exp::While { ann, .. }
+            | Exp::Assign { ann, .. }
+            | Exp::Lambda { ann, .. }
+            | Exp::TypeDecl { ann, .. }
+            | Exp::Construct { ann, .. }
+            | Exp::Match { ann, .. }
+            | Exp::ClosureCall { ann, .. }
+            | Exp::ExternalCall { ann, .. }
+            | Exp::MakeClosure { ann, .. } => ann.clone(),
+        }
+    }
+
+    // The same expression with f applied to every annotation
+    pub fn map_ann<B, F>(&self, f: &F) -> Exp<B>
+    where
+        F: Fn(&Ann) -> B,
+    {
+        let map_decl = |d: &FunDecl<Exp<Ann>, Ann>| FunDecl {
+            name: d.name.clone(),
+            parameters: d.parameters.clone(),
+            signature: d.signature.clone(),
+            body: d.body.map_ann(f),
+            ann: f(&d.ann),
+        };
+        match self {
+            Exp::Num(n, ann) => Exp::Num(*n, f(ann)),
+            Exp::Bool(b, ann) => Exp::Bool(*b, f(ann)),
+            Exp::Var(x, ann) => Exp::Var(x.clone(), f(ann)),
+            Exp::Float(x, ann) => Exp::Float(x.clone(), f(ann)),
+            Exp::Str(x, ann) => Exp::Str(x.clone(), f(ann)),
+            Exp::Prim(p, es, ann) => Exp::Prim(*p, es.iter().map(|e| Box::new(e.map_ann(f))).collect(), f(ann)),
+            Exp::Let { bindings, body, ann } => Exp::Let {
+                bindings: bindings.iter().map(|(x, e)| (x.clone(), e.map_ann(f))).collect(),
+                body: Box::new(body.map_ann(f)),
+                ann: f(ann),
+            },
+            Exp::If { cond, thn, els, ann } => Exp::If {
+                cond: Box::new(cond.map_ann(f)),
+                thn: Box::new(thn.map_ann(f)),
+                els: Box::new(els.map_ann(f)),
+                ann: f(ann),
+            },
+            Exp::FunDefs { decls, body, ann } => Exp::FunDefs {
+                decls: decls.iter().map(map_decl).collect(),
+                body: Box::new(body.map_ann(f)),
+                ann: f(ann),
+            },
+            Exp::Semicolon { e1, e2, ann } => Exp::Semicolon {
+                e1: Box::new(e1.map_ann(f)),
+                e2: Box::new(e2.map_ann(f)),
+                ann: f(ann),
+            },
+            Exp::While { cond, body, ann } => Exp::While {
+                cond: Box::new(cond.map_ann(f)),
+                body: Box::new(body.map_ann(f)),
+                ann: f(ann),
+            },
+            Exp::Assign { var, value, ann } => Exp::Assign {
+                var: var.clone(),
+                value: Box::new(value.map_ann(f)),
+                ann: f(ann),
+            },
+            Exp::Lambda { parameters, body, ann } => Exp::Lambda {
+                parameters: parameters.clone(),
+                body: Box::new(body.map_ann(f)),
+                ann: f(ann),
+            },
+            Exp::TypeDecl { name, constructors, body, ann } => Exp::TypeDecl {
+                name: name.clone(),
+                constructors: constructors
+                    .iter()
+                    .map(|c| Constructor {
+                        name: c.name.clone(),
+                        fields: c.fields.clone(),
+                        ann: f(&c.ann),
+                    })
+                    .collect(),
+                body: Box::new(body.map_ann(f)),
+                ann: f(ann),
+            },
+            Exp::Construct { name, args, ann } => Exp::Construct {
+                name: name.clone(),
+                args: args.iter().map(|e| e.map_ann(f)).collect(),
+                ann: f(ann),
+            },
+            Exp::Match { scrutinee, arms, ann } => Exp::Match {
+                scrutinee: Box::new(scrutinee.map_ann(f)),
+                arms: arms.iter().map(|(p, e)| (p.map_ann(f), e.map_ann(f))).collect(),
+                ann: f(ann),
+            },
+            Exp::Call(x, args, ann) => Exp::Call(x.clone(), args.iter().map(|e| e.map_ann(f)).collect(), f(ann)),
+            Exp::ClosureCall { fun, args, is_tail, ann } => Exp::ClosureCall {
+                fun: Box::new(fun.map_ann(f)),
+                args: args.iter().map(|e| e.map_ann(f)).collect(),
+                is_tail: *is_tail,
+                ann: f(ann),
+            },
+            Exp::InternalTailCall(x, args, ann) => {
+                Exp::InternalTailCall(x.clone(), args.iter().map(|e| e.map_ann(f)).collect(), f(ann))
+            }
+            Exp::ExternalCall { fun_name, args, is_tail, ann } => Exp::ExternalCall {
+                fun_name: fun_name.clone(),
+                args: args.iter().map(|e| e.map_ann(f)).collect(),
+                is_tail: *is_tail,
+                ann: f(ann),
+            },
+            Exp::MakeClosure { fun_name, arity, env, ann } => Exp::MakeClosure {
+                fun_name: fun_name.clone(),
+                arity: *arity,
+                env: env.clone(),
+                ann: f(ann),
+            },
+        }
+    }
+
+    /* The same expression with the free variables, functions and
+     * constructors in renames renamed, e.g. the functions a file imports
+     * to the names they have in the program. The names bound in the
+     * expression hide the ones of the same name in renames.
+     */
+    pub fn rename(&self, renames: &HashMap<String, String>) -> Exp<Ann> {
+        let name = |x: &String| renames.get(x).unwrap_or(x).clone();
+        match self {
+            Exp::Num(..) | Exp::Bool(..) | Exp::Float(..) | Exp::Str(..) | Exp::MakeClosure { .. } => self.clone(),
+            Exp::Var(x, ann) => Exp::Var(name(x), ann.clone()),
+            Exp::Prim(p, es, ann) => Exp::Prim(*p, es.iter().map(|e| Box::new(e.rename(renames))).collect(), ann.clone()),
+            Exp::Let { bindings, body, ann } => {
+                // each binding is in scope in the ones after it
+                let mut renames = renames.clone();
+                let bindings = bindings
+                    .iter()
+                    .map(|(x, e)| {
+                        let e = e.rename(&renames);
+                        renames.remove(x);
+                        (x.clone(), e)
+                    })
+                    .collect();
+                Exp::Let {
+                    bindings,
+                    body: Box::new(body.rename(&renames)),
+                    ann: ann.clone(),
+                }
+            }
+            Exp::If { cond, thn, els, ann } => Exp::If {
+                cond: Box::new(cond.rename(renames)),
+                thn: Box::new(thn.rename(renames)),
+                els: Box::new(els.rename(renames)),
+                ann: ann.clone(),
+            },
+            Exp::FunDefs { decls, body, ann } => {
+                let renames = without(renames, decls.iter().map(|d| &d.name));
+                Exp::FunDefs {
+                    decls: decls
+                        .iter()
+                        .map(|d| FunDecl {
+                            body: d.body.rename(&without(&renames, &d.parameters)),
+                            ..d.clone()
+                        })
+                        .collect(),
+                    body: Box::new(body.rename(&renames)),
+                    ann: ann.clone(),
+                }
+            }
+            Exp::Semicolon { e1, e2, ann } => Exp::Semicolon {
+                e1: Box::new(e1.rename(renames)),
+                e2: Box::new(e2.rename(renames)),
+                ann: ann.clone(),
+            },
+            Exp::While { cond, body, ann } => Exp::While {
+                cond: Box::new(cond.rename(renames)),
+                body: Box::new(body.rename(renames)),
+                ann: ann.clone(),
+            },
+            Exp::Assign { var, value, ann } => Exp::Assign {
+                var: name(var),
+                value: Box::new(value.rename(renames)),
+                ann: ann.clone(),
+            },
+            Exp::Lambda { parameters, body, ann } => Exp::Lambda {
+                parameters: parameters.clone(),
+                body: Box::new(body.rename(&without(renames, parameters))),
+                ann: ann.clone(),
+            },
+            Exp::TypeDecl { name, constructors, body, ann } => Exp::TypeDecl {
+                name: name.clone(),
+                constructors: constructors.clone(),
+                body: Box::new(body.rename(&without(renames, constructors.iter().map(|c| &c.name)))),
+                ann: ann.clone(),
+            },
+            Exp::Construct { name: c, args, ann } => Exp::Construct {
+                name: name(c),
+                args: args.iter().map(|e| e.rename(renames)).collect(),
+                ann: ann.clone(),
+            },
+            Exp::Match { scrutinee, arms, ann } => Exp::Match {
+                scrutinee: Box::new(scrutinee.rename(renames)),
+                arms: arms
+                    .iter()
+                    .map(|(p, e)| (p.rename(renames), e.rename(&without(renames, p.vars()))))
+                    .collect(),
+                ann: ann.clone(),
+            },
+            Exp::Call(f, args, ann) => Exp::Call(name(f), args.iter().map(|e| e.rename(renames)).collect(), ann.clone()),
+            Exp::ClosureCall { fun, args, is_tail, ann } => Exp::ClosureCall {
+                fun: Box::new(fun.rename(renames)),
+                args: args.iter().map(|e| e.rename(renames)).collect(),
+                is_tail: *is_tail,
+                ann: ann.clone(),
+            },
+            Exp::InternalTailCall(f, args, ann) => {
+                Exp::InternalTailCall(name(f), args.iter().map(|e| e.rename(renames)).collect(), ann.clone())
+            }
+            Exp::ExternalCall { fun_name, args, is_tail, ann } => Exp::ExternalCall {
+                fun_name: name(fun_name),
+                args: args.iter().map(|e| e.rename(renames)).collect(),
+                is_tail: *is_tail,
+                ann: ann.clone(),
+            },
+        }
+    }
+}
+
+// renames but for the names xs, which are bound again
+fn without<'a>(renames: &HashMap<String, String>, xs: impl IntoIterator<Item = &'a String>) -> HashMap<String, String> {
+    let mut renames = renames.clone();
+    for x in xs {
+        renames.remove(x);
+    }
+    renames
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -71,8 +483,16 @@ pub enum Prim {
     IsBool,
     IsNum,
     IsFloat,
+    IsTuple,
     Sqrt,
     Cos,
+    Len,
+    ToString,
+    Length,
+    // internal: the value in a box, see boxing.rs
+    Unbox,
+    // internal: the error of a match none of whose patterns matches
+    MatchFail,
 
     // binary
     Add,
@@ -88,6 +508,29 @@ pub enum Prim {
     Ge,
     Eq,
     Neq,
+    GetIndex,
+    Concat,
+    // the length and the value of every element
+    MakeArray,
+    // internal: the i-th captured variable of a closure
+    ClosureEnv,
+    // internal: puts a value in a box
+    SetBox,
+    // internal: whether a value was made by the constructor with the
+    // given number, see matching.rs
+    IsVariant,
+    // internal: the i-th field of a value made by a constructor
+    VariantField,
+
+    // ternary: the string, the start and the end
+    Substr,
+    // ternary: the array, the index and the new value
+    SetIndex,
+
+    // variadic
+    MakeTuple,
+    // internal: the number of a constructor followed by the fields
+    MakeVariant,
 }
 
 /* Sequential Expressions */
@@ -106,6 +549,7 @@ pub enum ImmExp {
     Bool(bool),
     Var(String),
     Float(FloatWrapper),
+    Str(String),
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -131,6 +575,18 @@ pub enum SeqExp<Ann> {
         els: Box<SeqExp<Ann>>,
         ann: Ann,
     },
+    // cond is evaluated again before every run of body
+    While {
+        cond: Box<SeqExp<Ann>>,
+        body: Box<SeqExp<Ann>>,
+        ann: Ann,
+    },
+    // Stores value where var lives
+    Assign {
+        var: String,
+        value: ImmExp,
+        ann: Ann,
+    },
     // An internal tail call to a locally defined function.
     // Implemented by setting arguments and then jmp in Assembly
     InternalTailCall(String, Vec<ImmExp>, Ann),
@@ -143,4 +599,35 @@ pub enum SeqExp<Ann> {
         is_tail: bool,
         ann: Ann,
     },
+    // A call to a closure, arity is checked at runtime
+    ClosureCall {
+        fun: ImmExp,
+        args: Vec<ImmExp>,
+        is_tail: bool,
+        ann: Ann,
+    },
+    MakeClosure {
+        fun_name: String,
+        arity: usize,
+        env: Vec<ImmExp>,
+        ann: Ann,
+    },
+}
+
+impl<Ann: Clone> SeqExp<Ann> {
+    pub fn ann(&self) -> Ann {
+        match self {
+            SeqExp::Imm(_, ann)
+            | SeqExp::Prim(_, _, ann)
+            | SeqExp::InternalTailCall(_, _, ann)
+            | SeqExp::Let { ann, .. }
+            | SeqExp::FunDefs { ann, .. }
+            | SeqExp::If { ann, .. }
+            | SeqExp::While { ann, .. }
+            | SeqExp::Assign { ann, .. }
+            | SeqExp::ExternalCall { ann, .. }
+            | SeqExp::ClosureCall { ann, .. }
+            | SeqExp::MakeClosure { ann, .. } => ann.clone(),
+        }
+    }
 }
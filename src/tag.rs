@@ -1,26 +1,35 @@
-use crate::syntax::{Exp, FunDecl, ImmExp, SeqExp, SeqFunDecl, SurfProg};
+use crate::syntax::{Constructor, Exp, FunDecl, ImmExp, Pattern, SeqExp, SeqFunDecl, SurfProg};
 use std::collections::HashMap;
 
-pub fn tag_exp<Span>(
-    e: &SurfProg<Span>,
+/* Annotates every node of e with a number unique in e, paired with
+ * its annotation, and renames the variables and functions of e apart
+ * when is_uniquify is set.
+ */
+pub fn tag_exp<Ann>(
+    e: &SurfProg<Ann>,
     counter: &mut u32,
     fun_map: &mut HashMap<String, String>,
     is_uniquify: bool,
-) -> Exp<u32> {
+) -> Exp<(u32, Ann)>
+where
+    Ann: Clone,
+{
+    let ann = e.ann();
     let result = match e {
-        Exp::Num(n, _) => Exp::Num(*n, *counter),
-        Exp::Bool(b, _) => Exp::Bool(*b, *counter),
+        Exp::Num(n, _) => Exp::Num(*n, (*counter, ann.clone())),
+        Exp::Bool(b, _) => Exp::Bool(*b, (*counter, ann.clone())),
         Exp::Float(f, _) => {
-            Exp::Float(f.clone(), *counter)
+            Exp::Float(f.clone(), (*counter, ann.clone()))
         }
+        Exp::Str(s, _) => Exp::Str(s.clone(), (*counter, ann.clone())),
         Exp::Var(x, _) => {
             if is_uniquify {
                 match fun_map.get(x) {
-                    Some(value) => Exp::Var(value.clone(), *counter),
-                    None => Exp::Var(x.clone(), *counter),
+                    Some(value) => Exp::Var(value.clone(), (*counter, ann.clone())),
+                    None => Exp::Var(x.clone(), (*counter, ann.clone())),
                 }
             } else {
-                Exp::Var(x.clone(), *counter)
+                Exp::Var(x.clone(), (*counter, ann.clone()))
             }
         }
         Exp::Prim(p, exps, _) => {
@@ -28,29 +37,51 @@ pub fn tag_exp<Span>(
                 .iter()
                 .map(|e| Box::new(tag_exp(e, counter, fun_map, is_uniquify)))
                 .collect();
-            Exp::Prim(*p, new_exps, *counter)
+            Exp::Prim(*p, new_exps, (*counter, ann.clone()))
         }
         Exp::Let { bindings, body, .. } => {
+            // the bindings are only in scope in the let
+            let fun_map = &mut fun_map.clone();
             let mut new_bindings = vec![];
             for (x, e) in bindings {
                 let new_exp = tag_exp(e, counter, fun_map, is_uniquify);
                 let new_x = format!("{}_{}", x, *counter);
+                *counter += 1;
                 fun_map.insert(x.clone(), new_x.clone());
                 new_bindings.push((if is_uniquify { new_x } else { x.clone() }, new_exp));
             }
             Exp::Let {
                 bindings: new_bindings,
                 body: Box::new(tag_exp(body, counter, fun_map, is_uniquify)),
-                ann: *counter,
+                ann: (*counter, ann.clone()),
             }
         }
         Exp::If { cond, thn, els, .. } => Exp::If {
             cond: Box::new(tag_exp(cond, counter, fun_map, is_uniquify)),
             thn: Box::new(tag_exp(thn, counter, fun_map, is_uniquify)),
             els: Box::new(tag_exp(els, counter, fun_map, is_uniquify)),
-            ann: *counter,
+            ann: (*counter, ann.clone()),
+        },
+        Exp::Semicolon { e1, e2, .. } => Exp::Semicolon {
+            e1: Box::new(tag_exp(e1, counter, fun_map, is_uniquify)),
+            e2: Box::new(tag_exp(e2, counter, fun_map, is_uniquify)),
+            ann: (*counter, ann.clone()),
+        },
+        Exp::While { cond, body, .. } => Exp::While {
+            cond: Box::new(tag_exp(cond, counter, fun_map, is_uniquify)),
+            body: Box::new(tag_exp(body, counter, fun_map, is_uniquify)),
+            ann: (*counter, ann.clone()),
+        },
+        Exp::Assign { var, value, .. } => Exp::Assign {
+            var: match fun_map.get(var) {
+                Some(value) if is_uniquify => value.clone(),
+                _ => var.clone(),
+            },
+            value: Box::new(tag_exp(value, counter, fun_map, is_uniquify)),
+            ann: (*counter, ann.clone()),
         },
         Exp::FunDefs { decls, body, .. } => {
+            let fun_map = &mut fun_map.clone();
             let mut new_decls = vec![];
             let mut new_names = vec![];
             for decl in decls {
@@ -80,8 +111,9 @@ pub fn tag_exp<Span>(
                     } else {
                         decls[i].parameters.clone()
                     },
+                    signature: decls[i].signature.clone(),
                     body: tag_exp(&decls[i].body, counter, &mut new_fun_map, is_uniquify),
-                    ann: *counter,
+                    ann: (*counter, decls[i].ann.clone()),
                 };
                 new_decls.push(new_decl);
                 *counter += 1
@@ -89,7 +121,100 @@ pub fn tag_exp<Span>(
             Exp::FunDefs {
                 decls: new_decls,
                 body: Box::new(tag_exp(body, counter, fun_map, is_uniquify)),
-                ann: *counter,
+                ann: (*counter, ann.clone()),
+            }
+        }
+        Exp::Lambda {
+            parameters, body, ..
+        } => {
+            let mut new_fun_map = fun_map.clone();
+            let mut new_parameters = vec![];
+            for para in parameters {
+                let new_para = format!("{}_{}", para.clone(), *counter);
+                *counter += 1;
+                new_fun_map.insert(para.clone(), new_para.clone());
+                new_parameters.push(new_para);
+            }
+            Exp::Lambda {
+                parameters: if is_uniquify {
+                    new_parameters
+                } else {
+                    parameters.clone()
+                },
+                body: Box::new(tag_exp(body, counter, &mut new_fun_map, is_uniquify)),
+                ann: (*counter, ann.clone()),
+            }
+        }
+        Exp::TypeDecl {
+            name,
+            constructors,
+            body,
+            ..
+        } => {
+            let mut new_constructors = vec![];
+            for c in constructors {
+                new_constructors.push(Constructor {
+                    name: c.name.clone(),
+                    fields: c.fields.clone(),
+                    ann: (*counter, c.ann.clone()),
+                });
+                *counter += 1;
+            }
+            Exp::TypeDecl {
+                name: name.clone(),
+                constructors: new_constructors,
+                body: Box::new(tag_exp(body, counter, fun_map, is_uniquify)),
+                ann: (*counter, ann.clone()),
+            }
+        }
+        Exp::Construct { name, args, .. } => Exp::Construct {
+            name: name.clone(),
+            args: args
+                .iter()
+                .map(|e| tag_exp(e, counter, fun_map, is_uniquify))
+                .collect(),
+            ann: (*counter, ann.clone()),
+        },
+        Exp::Match { scrutinee, arms, .. } => {
+            let scrutinee = Box::new(tag_exp(scrutinee, counter, fun_map, is_uniquify));
+            let mut new_arms = vec![];
+            for (pattern, body) in arms {
+                // the variables of a pattern are only in scope in its arm
+                let mut arm_fun_map = fun_map.clone();
+                let pattern = tag_pattern(pattern, counter, &mut arm_fun_map, is_uniquify);
+                new_arms.push((pattern, tag_exp(body, counter, &mut arm_fun_map, is_uniquify)));
+            }
+            Exp::Match {
+                scrutinee,
+                arms: new_arms,
+                ann: (*counter, ann.clone()),
+            }
+        }
+        Exp::ClosureCall {
+            fun, args, is_tail, ..
+        } => Exp::ClosureCall {
+            fun: Box::new(tag_exp(fun, counter, fun_map, is_uniquify)),
+            args: args
+                .iter()
+                .map(|e| tag_exp(e, counter, fun_map, is_uniquify))
+                .collect(),
+            is_tail: *is_tail,
+            ann: (*counter, ann.clone()),
+        },
+        Exp::MakeClosure {
+            fun_name,
+            arity,
+            env,
+            ..
+        } => {
+            if is_uniquify {
+                panic!("uniquify cannot meet MakeClosure")
+            }
+            Exp::MakeClosure {
+                fun_name: fun_name.clone(),
+                arity: *arity,
+                env: env.clone(),
+                ann: (*counter, ann.clone()),
             }
         }
         Exp::Call(fun_name, args, _) => {
@@ -99,11 +224,11 @@ pub fn tag_exp<Span>(
                 .collect();
             if is_uniquify {
                 match fun_map.get(fun_name) {
-                    Some(value) => Exp::Call(value.clone(), new_args, *counter),
+                    Some(value) => Exp::Call(value.clone(), new_args, (*counter, ann.clone())),
                     None => panic!("wrong in call is_uniquify"),
                 }
             } else {
-                Exp::Call(fun_name.clone(), new_args, *counter)
+                Exp::Call(fun_name.clone(), new_args, (*counter, ann.clone()))
             }
         }
         Exp::InternalTailCall(fun_name, args, _) => {
@@ -118,7 +243,7 @@ pub fn tag_exp<Span>(
                 // }
                 panic!("uniquify cannot meet Internal")
             } else {
-                Exp::InternalTailCall(fun_name.clone(), new_args, *counter)
+                Exp::InternalTailCall(fun_name.clone(), new_args, (*counter, ann.clone()))
             }
         }
         Exp::ExternalCall {
@@ -152,7 +277,7 @@ pub fn tag_exp<Span>(
                     fun_name: fun_name.clone(),
                     args: new_args,
                     is_tail: *is_tail,
-                    ann: *counter,
+                    ann: (*counter, ann.clone()),
                 }
             }
         }
@@ -161,19 +286,53 @@ pub fn tag_exp<Span>(
     result
 }
 
-pub fn tag_seq<Span>(e: &SeqExp<Span>, counter: &mut u32) -> SeqExp<u32> {
+fn tag_pattern<Ann>(
+    p: &Pattern<Ann>,
+    counter: &mut u32,
+    fun_map: &mut HashMap<String, String>,
+    is_uniquify: bool,
+) -> Pattern<(u32, Ann)>
+where
+    Ann: Clone,
+{
+    let result = match p {
+        Pattern::Wildcard(ann) => Pattern::Wildcard((*counter, ann.clone())),
+        Pattern::Var(x, ann) => {
+            let new_x = format!("{}_{}", x, *counter);
+            fun_map.insert(x.clone(), new_x.clone());
+            Pattern::Var(if is_uniquify { new_x } else { x.clone() }, (*counter, ann.clone()))
+        }
+        Pattern::Constructor(c, ps, ann) => Pattern::Constructor(
+            c.clone(),
+            ps.iter()
+                .map(|p| tag_pattern(p, counter, fun_map, is_uniquify))
+                .collect(),
+            (*counter, ann.clone()),
+        ),
+    };
+    *counter += 1;
+    result
+}
+
+// Numbers the nodes of e, remembering the annotation each number
+// replaces in anns
+pub fn tag_seq<Ann>(e: &SeqExp<Ann>, counter: &mut u32, anns: &mut HashMap<u32, Ann>) -> SeqExp<u32>
+where
+    Ann: Clone,
+{
     let result = match e {
         SeqExp::Imm(ie, _) => match ie {
             ImmExp::Num(n) => SeqExp::Imm(ImmExp::Num(*n), *counter),
             ImmExp::Var(x) => SeqExp::Imm(ImmExp::Var(x.clone()), *counter),
             ImmExp::Bool(b) => SeqExp::Imm(ImmExp::Bool(*b), *counter),
             ImmExp::Float(f) => SeqExp::Imm(ImmExp::Float(f.clone()), *counter),
+            ImmExp::Str(s) => SeqExp::Imm(ImmExp::Str(s.clone()), *counter),
         },
         SeqExp::Prim(p, ies, _) => SeqExp::Prim(*p, ies.clone(), *counter),
         SeqExp::If { cond, thn, els, .. } => SeqExp::If {
             cond: cond.clone(),
-            thn: Box::new(tag_seq(thn, counter)),
-            els: Box::new(tag_seq(els, counter)),
+            thn: Box::new(tag_seq(thn, counter, anns)),
+            els: Box::new(tag_seq(els, counter, anns)),
             ann: *counter,
         },
         SeqExp::Let {
@@ -183,8 +342,18 @@ pub fn tag_seq<Span>(e: &SeqExp<Span>, counter: &mut u32) -> SeqExp<u32> {
             ..
         } => SeqExp::Let {
             var: var.clone(),
-            bound_exp: Box::new(tag_seq(bound_exp, counter)),
-            body: Box::new(tag_seq(body, counter)),
+            bound_exp: Box::new(tag_seq(bound_exp, counter, anns)),
+            body: Box::new(tag_seq(body, counter, anns)),
+            ann: *counter,
+        },
+        SeqExp::While { cond, body, .. } => SeqExp::While {
+            cond: Box::new(tag_seq(cond, counter, anns)),
+            body: Box::new(tag_seq(body, counter, anns)),
+            ann: *counter,
+        },
+        SeqExp::Assign { var, value, .. } => SeqExp::Assign {
+            var: var.clone(),
+            value: value.clone(),
             ann: *counter,
         },
         SeqExp::FunDefs {
@@ -197,14 +366,16 @@ pub fn tag_seq<Span>(e: &SeqExp<Span>, counter: &mut u32) -> SeqExp<u32> {
                 new_decls.push(FunDecl {
                     name: decl.name.clone(),
                     parameters: decl.parameters.clone(),
-                    body: tag_seq(&decl.body, counter),
+                    signature: decl.signature.clone(),
+                    body: tag_seq(&decl.body, counter, anns),
                     ann: *counter,
                 });
+                anns.insert(*counter, decl.ann.clone());
                 *counter += 1;
             }
             SeqExp::FunDefs {
                 decls: new_decls,
-                body: Box::new(tag_seq(body, counter)),
+                body: Box::new(tag_seq(body, counter, anns)),
                 ann: *counter,
             }
         }
@@ -222,20 +393,45 @@ pub fn tag_seq<Span>(e: &SeqExp<Span>, counter: &mut u32) -> SeqExp<u32> {
             is_tail: *is_tail,
             ann: *counter,
         },
+        SeqExp::ClosureCall {
+            fun, args, is_tail, ..
+        } => SeqExp::ClosureCall {
+            fun: fun.clone(),
+            args: args.clone(),
+            is_tail: *is_tail,
+            ann: *counter,
+        },
+        SeqExp::MakeClosure {
+            fun_name,
+            arity,
+            env,
+            ..
+        } => SeqExp::MakeClosure {
+            fun_name: fun_name.clone(),
+            arity: *arity,
+            env: env.clone(),
+            ann: *counter,
+        },
     };
+    anns.insert(*counter, e.ann());
     *counter += 1;
     result
 }
 
-pub fn tag_funs(funs: &Vec<SeqFunDecl<()>>, counter: &mut u32) -> Vec<SeqFunDecl<u32>> {
+pub fn tag_funs<Ann>(funs: &Vec<SeqFunDecl<Ann>>, counter: &mut u32, anns: &mut HashMap<u32, Ann>) -> Vec<SeqFunDecl<u32>>
+where
+    Ann: Clone,
+{
     let mut new_funs = vec![];
     for fun in funs {
         new_funs.push(FunDecl {
             name: fun.name.clone(),
             parameters: fun.parameters.clone(),
-            body: tag_seq(&fun.body, counter),
+            signature: fun.signature.clone(),
+            body: tag_seq(&fun.body, counter, anns),
             ann: *counter,
         });
+        anns.insert(*counter, fun.ann.clone());
         *counter += 1;
     }
     new_funs
@@ -1,4 +1,6 @@
-use snake::runner;
+use snake::lexer::Lexer;
+use snake::parser::ProgParser;
+use snake::{compile, diagnostic, fuzz, infer, repl, runner};
 
 macro_rules! mk_test {
     ($test_name:ident, $file_name:expr, $expected_output:expr) => {
@@ -102,7 +104,7 @@ fn test_example_fail(f: &str, includes: &str) -> std::io::Result<()> {
 mk_fail_test!(
     addbool,
     "../examples/addbool.cobra",
-    "arithmetic expected a number"
+    "arithmetic expected a number or float, but got true"
 );
 mk_test!(basic_print, "../examples/basic_print.cobra", "1\n1");
 mk_fail_test!(
@@ -113,7 +115,7 @@ mk_fail_test!(
 mk_test!(foo, "../examples/foo.diamondback", "6");
 mk_test!(mul, "../examples/mul.cobra", "20\n20");
 mk_fail_test!(overflow, "../examples/overflow.cobra", "overflow");
-mk_fail_test!(wrong_if, "../examples/wrong_if.boa", "if expected a bool");
+mk_fail_test!(wrong_if, "../examples/wrong_if.boa", "if expected a boolean, but got 5");
 mk_test!(simple_let0, "../examples/simple_let0.adder", "false");
 mk_test!(simple_let1, "../examples/simple_let1.adder", "1");
 mk_test!(simple_let2, "../examples/simple_let2.adder", "13");
@@ -132,11 +134,52 @@ mk_fail_test!(float_overflow2, "../examples/float_overflow2.garter", "overflow")
 mk_test!(float_underflow1, "../examples/float_underflow1.garter", "0.0");
 mk_test!(float_logic1, "../examples/float_logic1.garter", "true");
 mk_test!(simple_float_minus, "../examples/simple_float_minus.garter", "-0.6");
-mk_test!(cos_and_sqrt, "../examples/cos_and_sqrt.garter", "11.937541");
+mk_test!(cos_and_sqrt, "../examples/cos_and_sqrt.garter", "11.937540358852686");
 mk_fail_test!(wrong_sqrt, "../examples/wrong_sqrt.garter", "sqrt expected a non-negative value");
+mk_fail_test!(float_neg_sqrt, "../examples/float_neg_sqrt.garter", "sqrt expected a non-negative value");
+mk_fail_test!(float_neg_overflow, "../examples/float_neg_overflow.garter", "overflow");
+mk_test!(float_floor_neg, "../examples/float_floor_neg.garter", "(3, -4, 3, -4, -3)");
+mk_test!(float_cos_live, "../examples/float_cos_live.garter", "51");
+mk_test!(
+    float_double,
+    "../examples/float_double.garter",
+    "(0.30000000000000004, 0.3333333333333333, 680564700000000000000000000000000000000, true)"
+);
+mk_test!(float_gc, "../examples/float_gc.garter", "(2500, (1.5, -2.75))");
+mk_test!(
+    float_interp,
+    "../examples/float_interp.garter",
+    "((3, -4, 3, 3, 0.25, 4), (3.5, -1.5, 3, 2.5, -0.5), (false, true, true, true, true), (4, 1, true, false, false))"
+);
+mk_fail_test!(mul_overflow63, "../examples/mul_overflow63.garter", "overflow");
 mk_fail_test!(division_by_zero, "../examples/division_by_zero.garter", "division by zero");
+mk_fail_test!(
+    runtime_location,
+    "../examples/runtime_location.garter",
+    "division by zero at line 2, column 3"
+);
+mk_fail_test!(
+    runtime_location_lambda,
+    "../examples/runtime_location_lambda.garter",
+    "index out of bounds: 2 at line 1, column 26"
+);
+mk_fail_test!(
+    runtime_backtrace,
+    "../examples/runtime_backtrace.garter",
+    "index out of bounds: 2 at line 2, column 3 of examples/../examples/runtime_backtrace.garter\nbacktrace:\n  0: inner\n  1: outer\n  2: lambda\n  3: main"
+);
+mk_fail_test!(
+    runtime_error_text,
+    "../examples/runtime_error_text.garter",
+    "but got a\"b\n\tc at line 9 at line 3, column 1 of examples/../examples/runtime_error_text.garter\nbacktrace:"
+);
+mk_fail_test!(
+    backtrace_names,
+    "../examples/backtrace_names.garter",
+    "backtrace:\n  0: my_copy_fn\n  1: lambda\n  2: deep_copy_list\n  3: main"
+);
 mk_test!(add, "../examples/add.garter", "2.5");
-mk_fail_test!(addbool2, "../examples/addbool.garter", "arithmetic expected a number or float");
+mk_fail_test!(addbool2, "../examples/addbool.garter", "arithmetic expected a number or float, but got true");
 mk_test!(basic_print2, "../examples/basic_print.garter", "10.5\n10.5");
 mk_test!(basic, "../examples/basic.garter", "true");
 mk_fail_test!(divide0, "../examples/divide0.garter", "division by zero");
@@ -157,10 +200,824 @@ mk_test!(isfloat1, "../examples/test_isfloat1.garter", "true");
 mk_test!(isfloat2, "../examples/test_isfloat2.garter", "false");
 mk_test!(isboolnum, "../examples/test_isboolnum.garter", "false");
 mk_test!(floor_division, "../examples/floor_division.garter", "3");
+mk_test!(equal_mixed, "../examples/equal_mixed.garter", "(true, true, true, true, false)");
+mk_test!(floor_div_exact, "../examples/floor_div_exact.garter", "(9007199254740993, -4, -4, -4)");
+mk_fail_test!(floor_div_overflow, "../examples/floor_div_overflow.garter", "overflow");
+mk_fail_test!(floor_div_float_overflow, "../examples/floor_div_float_overflow.garter", "overflow");
 mk_test!(test_float_eq, "../examples/test_float_eq.garter", "true");
 mk_test!(test_float_neq, "../examples/test_float_neq.garter", "false");
-mk_fail_test!(arith_error, "../examples/arith_error.garter", "arithmetic expected a number or float");
-mk_fail_test!(wrong_if1, "../examples/logic_error.garter", "if expected a bool");
-mk_fail_test!(comp_error, "../examples/comp_error.garter", "comparison expected a number or float");
+mk_fail_test!(arith_error, "../examples/arith_error.garter", "arithmetic expected a number or float, but got true");
+mk_fail_test!(wrong_if1, "../examples/logic_error.garter", "if expected a boolean, but got 5.1");
+mk_fail_test!(comp_error, "../examples/comp_error.garter", "comparison expected a number or float, but got true");
 mk_test!(func, "../examples/func.garter", "430");
-mk_test!(func2, "../examples/func2.garter", "14.4742565"); 
\ No newline at end of file
+mk_test!(func2, "../examples/func2.garter", "14.474267134822583"); 
+/* Tuple tests */
+mk_test!(tuple, "../examples/tuple.garter", "(true, 4)");
+mk_test!(tuple_print, "../examples/tuple_print.garter", "(1, (2,), ())\n(1, (2,), ())");
+mk_test!(tuple_get, "../examples/tuple_get.garter", "42");
+mk_test!(tuple_equal, "../examples/tuple_equal.garter", "true");
+mk_fail_test!(tuple_bounds, "../examples/tuple_bounds.garter", "index out of bounds: 3");
+mk_fail_test!(tuple_not_tuple, "../examples/tuple_not_tuple.garter", "expected a tuple");
+mk_fail_test!(tuple_arith, "../examples/tuple_arith.garter", "arithmetic expected a number or float");
+mk_test!(bool_equal, "../examples/bool_equal.garter", "(true, true)");
+
+/* Closure tests */
+mk_test!(lambda, "../examples/lambda.garter", "7");
+mk_test!(lambda_capture, "../examples/lambda_capture.garter", "6\n17");
+mk_test!(lambda_higher_order, "../examples/lambda_higher_order.garter", "(2, 4, 6)\n(101, 102, 103)");
+mk_test!(lambda_tail, "../examples/lambda_tail.garter", "100000");
+mk_test!(lambda_print, "../examples/lambda_print.garter", "<closure>");
+mk_fail_test!(lambda_arity, "../examples/lambda_arity.garter", "Function expecting 2 arguments called with 1 arguments");
+mk_fail_test!(lambda_not_fun, "../examples/lambda_not_fun.garter", "expected a function");
+
+/* Scoping and lambda lifting tests */
+mk_test!(let_scope, "../examples/let_scope.garter", "1");
+mk_test!(lambda_shadow_let, "../examples/lambda_shadow_let.garter", "112");
+mk_test!(fundefs_not_tail, "../examples/fundefs_not_tail.garter", "5");
+mk_test!(fundefs_copy_nested, "../examples/fundefs_copy_nested.garter", "200");
+mk_test!(fundefs_copy_shared, "../examples/fundefs_copy_shared.garter", "500");
+mk_test!(lambda_call_args, "../examples/lambda_call_args.garter", "3");
+mk_test!(fundefs_call_enclosing, "../examples/fundefs_call_enclosing.garter", "24");
+
+/* Garbage collection tests */
+mk_test!(gc_garbage, "../examples/gc_garbage.garter", "1000000");
+mk_test!(gc_live, "../examples/gc_live.garter", "500507");
+mk_fail_test!(gc_out_of_memory, "../examples/gc_out_of_memory.garter", "out of memory");
+
+fn run_with_heap(f: &str, words: usize) -> Result<String, String> {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let mut w = Vec::new();
+    runner::compile_and_run_with_heap(std::path::Path::new(f), tmp_dir.path(), Some(words), &mut w)
+        .map(|()| String::from_utf8(w).unwrap())
+        .map_err(|e| e.to_string())
+}
+
+#[test]
+fn gc_heap_size() {
+    // the 1000 live tuples take 3000 words
+    assert_eq!(run_with_heap("examples/gc_live.garter", 4096), Ok("500507\n".to_string()));
+    let err = run_with_heap("examples/gc_live.garter", 1024).unwrap_err();
+    assert!(err.contains("out of memory"), "{}", err);
+}
+
+/* Type inference tests */
+mk_test!(typed, "../examples/typed.garter", "(1.5, 20, 1, true)");
+mk_test!(type_poly, "../examples/type_poly.garter", "7");
+mk_fail_test!(type_err_arg, "../examples/type_err_arg.garter", "argument 1 of f expected Int, but got Bool");
+mk_fail_test!(type_err_ret, "../examples/type_err_ret.garter", "the body of f expected Bool");
+mk_fail_test!(type_err_branch, "../examples/type_err_branch.garter", "the else branch expected Int, but got Bool");
+mk_fail_test!(type_dyn_runtime, "../examples/type_dyn_runtime.garter", "arithmetic expected a number or float, but got true");
+mk_test!(type_fallback, "../examples/type_fallback.garter", "1");
+mk_test!(type_dispatch, "../examples/type_dispatch.garter", "(4, false)");
+
+/* Register allocation tests */
+mk_test!(regalloc_spill, "../examples/regalloc_spill.garter", "15\n61");
+mk_test!(regalloc_gc, "../examples/regalloc_gc.garter", "(5000050000, (1, 1))");
+mk_test!(regalloc_captured, "../examples/regalloc_captured.garter", "120");
+
+#[test]
+fn regalloc_gc_small_heap() {
+    // the tuples held in registers have to survive many collections
+    assert_eq!(
+        run_with_heap("examples/regalloc_gc.garter", 64),
+        Ok("(5000050000, (1, 1))\n".to_string())
+    );
+}
+
+#[test]
+fn float_gc_small_heap() {
+    // every float result is boxed, so this collects many times
+    assert_eq!(
+        run_with_heap("examples/float_gc.garter", 64),
+        Ok("(2500, (1.5, -2.75))\n".to_string())
+    );
+}
+
+/* Sequencing tests */
+mk_test!(sequence, "../examples/sequence.garter", "(1, 2)\n3\n4\n100000");
+mk_fail_test!(sequence_error, "../examples/sequence_error.garter", "arithmetic expected a number or float, but got true");
+
+/* While and assignment tests */
+mk_test!(while_loop, "../examples/while.garter", "(55, 11, 3, false)");
+mk_test!(assign_captured, "../examples/assign_captured.garter", "12\n0\n(4, 12)");
+mk_fail_test!(while_error, "../examples/while_error.garter", "while expected a boolean, but got 1");
+mk_fail_test!(assign_function, "../examples/assign_function.garter", "cannot assign to function f");
+
+/* Algebraic data type tests */
+mk_test!(adt, "../examples/adt.garter", "(Circle(2), Rect(3, 4), Dot)\ntrue\nfalse\n(12, 12, 0)");
+mk_test!(match_nested, "../examples/match_nested.garter", "Cons((1, 2), Cons((3, 3), Nil))\n6\n6");
+mk_test!(match_gc, "../examples/match_gc.garter", "(2, 2, 0, 1)\n505000");
+mk_fail_test!(match_error, "../examples/match_error.garter", "no pattern matches Blue");
+mk_fail_test!(constructor_unknown, "../examples/constructor_unknown.garter", "Unknown constructor Square");
+mk_fail_test!(constructor_arity, "../examples/constructor_arity.garter", "constructor Pair of arity 2 used with 1 fields");
+
+#[test]
+fn match_gc_small_heap() {
+    // every list takes 400 words, the variants have to be moved
+    assert_eq!(
+        run_with_heap("examples/match_gc.garter", 600),
+        Ok("(2, 2, 0, 1)\n505000\n".to_string())
+    );
+}
+
+/* String tests */
+mk_test!(
+    strings,
+    "../examples/strings.garter",
+    "hello, world\na \"quote\"\tand a backslash \\\n12\nworld\ntrue\ntrue\ntrue\n(1, 2.5, true)!\n3\n(in, a tuple)\nababab"
+);
+mk_test!(string_gc, "../examples/string_gc.garter", "(890123456789, 12)");
+mk_fail_test!(string_error, "../examples/string_error.garter", "string operation expected a string, but got 2");
+mk_fail_test!(string_utf8, "../examples/string_utf8.garter", "index out of bounds: 2 at line 4, column 1");
+mk_fail_test!(substr_bounds, "../examples/substr_bounds.garter", "index out of bounds: 6");
+
+#[test]
+fn string_gc_small_heap() {
+    // the string that is kept is copied by every collection
+    assert_eq!(
+        run_with_heap("examples/string_gc.garter", 32),
+        Ok("(890123456789, 12)\n".to_string())
+    );
+}
+
+#[test]
+fn string_lex_errors() {
+    let err = interp("examples/invalid_escape.garter").unwrap_err();
+    assert!(
+        err.starts_with("error: invalid escape\n --> examples/invalid_escape.garter:3:4\n"),
+        "{}",
+        err
+    );
+    assert!(err.contains("  |    ^^ not one of `\\n`, `\\t`, `\\\\` or `\\\"`"), "{}", err);
+    let err = interp("examples/unterminated_string.garter").unwrap_err();
+    assert!(
+        err.starts_with("error: unterminated string\n --> examples/unterminated_string.garter:2:6\n"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn num_lex_errors() {
+    let err = interp("examples/num_too_big.garter").unwrap_err();
+    assert!(err.starts_with("error: number too big\n --> examples/num_too_big.garter:2:9\n"), "{}", err);
+    assert!(err.contains("  |         ^^^^^^^^^^^^^^^^^^^^ doesn't fit into 64 bits"), "{}", err);
+    // the session goes on after it
+    let out = repl_session("99999999999999999999\n1 + 1\n");
+    assert!(out.contains("error: number too big") && out.ends_with("\n2\n"), "{}", out);
+}
+
+/* Array tests */
+mk_test!(
+    arrays,
+    "../examples/arrays.garter",
+    "[0, 1, 4, 9, 16]\n9\n17\n[]\ntrue\nfalse\n10\n[[...], 10, 4, 9, 16]\n8"
+);
+mk_test!(array_gc, "../examples/array_gc.garter", "[(1200,), (1225,), (1250,), (1275,)]");
+mk_fail_test!(
+    array_bounds,
+    "../examples/array_bounds.garter",
+    "index 5 out of bounds for an array of length 3"
+);
+mk_fail_test!(array_error, "../examples/array_error.garter", "array operation expected an array, but got 5");
+mk_fail_test!(
+    array_size,
+    "../examples/array_size.garter",
+    "array size expected a non-negative number, but got -1"
+);
+mk_fail_test!(
+    array_type,
+    "../examples/array_type.garter",
+    "arithmetic expected a number or float, but got [1]"
+);
+mk_fail_test!(array_too_big, "../examples/array_too_big.garter", "out of memory");
+
+#[test]
+fn array_gc_small_heap() {
+    // the kept array and its tuples are copied by every collection
+    assert_eq!(
+        run_with_heap("examples/array_gc.garter", 48),
+        Ok("[(1200,), (1225,), (1250,), (1275,)]\n".to_string())
+    );
+}
+
+/* Module tests */
+mk_test!(imports, "../examples/imports.garter", "3 squared is 9\n81\n110\n121");
+mk_test!(math_util, "../examples/math_util.garter", "16\n10");
+mk_test!(string_util, "../examples/string_util.garter", "7 squared is 49");
+mk_fail_test!(import_broken, "../examples/import_broken.garter", "Unbound variable base");
+mk_fail_test!(
+    import_missing,
+    "../examples/import_missing.garter",
+    "error[E0016]: cannot import no_such_util.garter"
+);
+mk_fail_test!(import_cycle, "../examples/import_cycle_a.garter", "import_cycle_b.garter -> ");
+mk_fail_test!(
+    import_conflict,
+    "../examples/import_conflict.garter",
+    "function square imported from two files"
+);
+mk_fail_test!(
+    export_nested,
+    "../examples/export_nested.garter",
+    "cannot export scale, which isn't a top-level function"
+);
+mk_fail_test!(import_runtime_error, "../examples/import_runtime_error.garter", "0: math_util.square");
+mk_fail_test!(
+    import_runtime_error_file,
+    "../examples/import_runtime_error.garter",
+    "but got true at line 3, column 23 of examples/../examples/math_util.garter"
+);
+mk_test!(import_types, "../examples/import_types.garter", "Circle(2)\nCircle(1, 3)\n(2, 0)\n(12, 9)");
+
+#[test]
+fn import_errors_in_their_file() {
+    // the error is shown in the file that has it, not the one run
+    let err = interp("examples/import_broken.garter").unwrap_err();
+    assert!(err.contains(" --> examples/broken_util.garter:1:27\n"), "{}", err);
+    let err = interp("examples/import_cycle_a.garter").unwrap_err();
+    assert!(err.contains(" --> examples/import_cycle_b.garter:1:1\n"), "{}", err);
+}
+
+// Copies the files of examples into a new directory, where compiling
+// them to objects leaves those next to them
+fn copy_examples(files: &[&str]) -> tempfile::TempDir {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    for f in files {
+        std::fs::copy(format!("examples/{}", f), tmp_dir.path().join(f)).unwrap();
+    }
+    tmp_dir
+}
+
+fn link_and_run(objects: &[std::path::PathBuf]) -> Result<String, String> {
+    let exe = runner::link_objects(objects).map_err(|e| e.to_string())?;
+    let out = std::process::Command::new(exe).output().unwrap();
+    Ok(String::from_utf8(out.stdout).unwrap())
+}
+
+#[test]
+fn separate_compilation() {
+    let tmp_dir = copy_examples(&["math_util.garter", "string_util.garter", "imports.garter"]);
+    let dir = tmp_dir.path();
+    let main = runner::compile_object(&dir.join("imports.garter")).unwrap();
+    let objects = vec![main, dir.join("math_util.o"), dir.join("string_util.o")];
+    assert_eq!(link_and_run(&objects), Ok("3 squared is 9\n81\n110\n121\n".to_string()));
+    let iface = std::fs::read_to_string(dir.join("math_util.iface")).unwrap();
+    assert!(iface.contains("export square 1\n"), "{}", iface);
+    assert!(!iface.contains("twice"), "{}", iface);
+
+    // the library is only compiled again once it changes
+    let modified = |f: &str| std::fs::metadata(dir.join(f)).unwrap().modified().unwrap();
+    let before = modified("math_util.o");
+    runner::compile_object(&dir.join("imports.garter")).unwrap();
+    assert_eq!(modified("math_util.o"), before);
+    let text = std::fs::read_to_string(dir.join("math_util.garter")).unwrap();
+    std::fs::write(dir.join("math_util.garter"), text.replace("x * x", "x * x + 1")).unwrap();
+    runner::compile_object(&dir.join("imports.garter")).unwrap();
+    assert_ne!(modified("math_util.o"), before);
+    assert_eq!(link_and_run(&objects), Ok("3 squared is 10\n101\n110\n122\n".to_string()));
+}
+
+#[test]
+fn link_errors() {
+    let tmp_dir = copy_examples(&["math_util.garter", "string_util.garter", "imports.garter"]);
+    let dir = tmp_dir.path();
+    let main = runner::compile_object(&dir.join("imports.garter")).unwrap();
+    let err = link_and_run(&[main.clone(), dir.join("string_util.o")]).unwrap_err();
+    assert!(err.contains("uses math_util.square, but no object of module math_util is linked"), "{}", err);
+    let err = link_and_run(&[main.clone(), main.clone()]).unwrap_err();
+    assert!(err.contains("module imports is linked twice"), "{}", err);
+    let out = std::process::Command::new(env!("CARGO_BIN_EXE_snake"))
+        .args(["--link", "--error-format=json"])
+        .args([&main, &main])
+        .output()
+        .unwrap();
+    let err = String::from_utf8(out.stderr).unwrap();
+    assert!(err.starts_with("{\"kind\": \"link\", \"code\": null"), "{}", err);
+    assert!(err.contains(&format!("\"file\": \"{}\"", main.display())), "{}", err);
+
+    // a library compiled again after the files that use it
+    let util = "export def square(x, y): x * y\nand export def fourth(x): x\nand export def sum_to(n): n\nin\n0";
+    std::fs::write(dir.join("math_util.garter"), util).unwrap();
+    let util = runner::compile_object(&dir.join("math_util.garter")).unwrap();
+    let err = link_and_run(&[main, util, dir.join("string_util.o")]).unwrap_err();
+    assert!(err.contains("calls math_util.square with 1 arguments, but it takes 2"), "{}", err);
+}
+
+#[test]
+fn separate_compilation_frames() {
+    // the library's frames are much bigger than the program's, and both
+    // are collected while it runs
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let dir = tmp_dir.path();
+    let vars: Vec<String> = (1..=40).map(|i| format!("a{}", i)).collect();
+    let bindings: Vec<String> = vars.iter().enumerate().map(|(i, a)| format!("{} = (n, {})", a, i + 1)).collect();
+    let sum: Vec<String> = vars.iter().map(|a| format!("{}[1]", a)).collect();
+    let lib = format!("export def big(n):\n  let {} in\n  {}\nin\n0\n", bindings.join(", "), sum.join(" + "));
+    std::fs::write(dir.join("big.garter"), lib).unwrap();
+    let app = "import \"big.garter\"\n\
+               def build(n, acc): if n == 0: acc else: build(n - 1, (n, acc))\n\
+               and def sum(l, acc): if l == (): acc else: sum(l[1], acc + l[0])\n\
+               and def churn(n, l, total): if n == 0: total + sum(l, 0) else: churn(n - 1, l, total + big(n))\n\
+               in\n\
+               churn(20000, build(100, ()), 0)\n";
+    std::fs::write(dir.join("app.garter"), app).unwrap();
+    let main = runner::compile_object(&dir.join("app.garter")).unwrap();
+    assert_eq!(link_and_run(&[main, dir.join("big.o")]), Ok("16405050\n".to_string()));
+}
+
+#[test]
+fn separate_compilation_uses() {
+    // only the functions the program refers to are its uses, so the
+    // library can drop the others without it being compiled again
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let dir = tmp_dir.path();
+    std::fs::write(dir.join("lib.garter"), "export def inc(x): x + 1\nand export def dec(x): x - 1\nin\n0\n").unwrap();
+    std::fs::write(dir.join("app.garter"), "import \"lib.garter\"\nlet f = inc in\nf(1) + inc(2)\n").unwrap();
+    let main = runner::compile_object(&dir.join("app.garter")).unwrap();
+    let iface = std::fs::read_to_string(dir.join("app.iface")).unwrap();
+    assert!(iface.contains("use lib inc 1\n") && !iface.contains("dec"), "{}", iface);
+    std::fs::write(dir.join("lib.garter"), "export def inc(x): x + 1\nin\n0\n").unwrap();
+    runner::compile_object(&dir.join("lib.garter")).unwrap();
+    assert_eq!(link_and_run(&[main, dir.join("lib.o")]), Ok("5\n".to_string()));
+}
+
+#[test]
+fn separate_compilation_types() {
+    let tmp_dir = copy_examples(&["shape_util.garter", "import_types.garter"]);
+    let dir = tmp_dir.path();
+    let main = runner::compile_object(&dir.join("import_types.garter")).unwrap();
+    assert_eq!(
+        link_and_run(&[main, dir.join("shape_util.o")]),
+        Ok("Circle(2)\nCircle(1, 3)\n(2, 0)\n(12, 9)\n".to_string())
+    );
+}
+
+#[test]
+fn separate_compilation_names() {
+    // names the compiler gives the copies of local functions are plain
+    // names in the source
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let dir = tmp_dir.path();
+    std::fs::write(dir.join("lib.garter"), "export def deep_copy_list(x): (x, x)\nin\n0\n").unwrap();
+    std::fs::write(dir.join("app.garter"), "import \"lib.garter\"\ndeep_copy_list(1)\n").unwrap();
+    let main = runner::compile_object(&dir.join("app.garter")).unwrap();
+    assert_eq!(link_and_run(&[main, dir.join("lib.o")]), Ok("(1, 1)\n".to_string()));
+}
+
+fn match_warnings(source: &str) -> Vec<String> {
+    let p = ProgParser::new().parse(&mut vec![], &mut vec![], Lexer::new(source)).unwrap().prog;
+    compile::check_prog(&p)
+        .unwrap()
+        .into_iter()
+        .map(|w| match w {
+            compile::CompileErr::NonExhaustiveMatch { missing, .. } => missing,
+            w => panic!("not a warning: {:?}", w),
+        })
+        .collect()
+}
+
+#[test]
+fn non_exhaustive_match() {
+    let list = "type list = Nil | Cons(head, tail) in ";
+    assert_eq!(
+        match_warnings(&format!("{}match Nil: | Nil => 0 | Cons(x, Nil) => 1 end", list)),
+        ["Cons(_, Cons(_, _))"]
+    );
+    assert_eq!(match_warnings(&format!("{}match Nil: | Cons(_, _) => 0 end", list)), ["Nil"]);
+    assert!(match_warnings(&format!("{}match Nil: | Cons(_, Nil) => 0 | Cons(_, _) => 1 | Nil => 2 end", list)).is_empty());
+    assert!(match_warnings(&format!("{}match Nil: | l => 0 end", list)).is_empty());
+}
+
+/* Interpreter tests */
+fn interp(f: &str) -> Result<String, String> {
+    let mut w = Vec::new();
+    runner::interpret_file(std::path::Path::new(f), &mut w)
+        .map(|()| String::from_utf8(w).unwrap())
+        .map_err(|e| e.to_string())
+}
+
+#[test]
+fn interp_floats() {
+    assert_eq!(
+        interp("examples/float_interp.garter"),
+        Ok("((3, -4, 3, 3, 0.25, 4), (3.5, -1.5, 3, 2.5, -0.5), (false, true, true, true, true), (4, 1, true, false, false))\n".to_string())
+    );
+    assert_eq!(interp("examples/cos_and_sqrt.garter"), Ok("11.937540358852686\n".to_string()));
+    assert_eq!(interp("examples/equal_mixed.garter"), Ok("(true, true, true, true, false)\n".to_string()));
+    assert_eq!(
+        interp("examples/floor_div_exact.garter"),
+        Ok("(9007199254740993, -4, -4, -4)\n".to_string())
+    );
+}
+
+#[test]
+fn deep_values() {
+    let nested: String = (1..=20000).map(|n| format!("({}, ", n)).collect();
+    let expected = format!("168895\ntrue\n{}0{}\n", nested, ")".repeat(20000));
+    assert_eq!(interp("examples/deep_tuple.garter"), Ok(expected.clone()));
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let mut w = Vec::new();
+    runner::compile_and_run_file(std::path::Path::new("examples/deep_tuple.garter"), tmp_dir.path(), &mut w).unwrap();
+    assert_eq!(String::from_utf8(w).unwrap(), expected);
+}
+
+#[test]
+fn interp_float_errors() {
+    // the same messages as the runtime of the compiled code
+    for (f, msg) in [
+        ("examples/float_neg_sqrt.garter", "sqrt expected a non-negative value"),
+        ("examples/float_neg_overflow.garter", "overflow"),
+        ("examples/divide000.garter", "division by zero"),
+        ("examples/divide0000.garter", "division by zero"),
+        ("examples/mul_overflow63.garter", "overflow"),
+        ("examples/floor_div_overflow.garter", "overflow"),
+        ("examples/floor_div_float_overflow.garter", "overflow"),
+        ("examples/type_dyn_runtime.garter", "arithmetic expected a number or float, but got true"),
+    ] {
+        let err = interp(f).unwrap_err();
+        assert!(err.ends_with(msg), "{}: {}", f, err);
+    }
+}
+
+/* Differential tests */
+#[test]
+fn diff_multiline_runtime_error() {
+    // the errors are compared whole, not by their first lines
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let report = runner::diff_file(std::path::Path::new("examples/runtime_error_text.garter"), tmp_dir.path());
+    assert_eq!(report.unwrap(), None);
+}
+
+#[test]
+fn diff_examples() {
+    let mut paths: Vec<_> = std::fs::read_dir("examples")
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    paths.sort();
+
+    // every example gets linked and run, so spread them over threads
+    let threads = std::thread::available_parallelism().map_or(4, |n| n.get());
+    let chunks: Vec<_> = paths.chunks(paths.len().div_ceil(threads)).collect();
+    let reports: Vec<String> = std::thread::scope(|s| {
+        let handles: Vec<_> = chunks
+            .iter()
+            .map(|chunk| {
+                s.spawn(move || {
+                    let tmp_dir = tempfile::TempDir::new().unwrap();
+                    let mut reports = vec![];
+                    for path in chunk.iter() {
+                        match runner::diff_file(path, tmp_dir.path()) {
+                            Ok(None) => {}
+                            Ok(Some(report)) => reports.push(report),
+                            Err(e) => reports.push(format!("{}: {}\n", path.display(), e)),
+                        }
+                    }
+                    reports
+                })
+            })
+            .collect();
+        handles.into_iter().flat_map(|h| h.join().unwrap()).collect()
+    });
+    assert!(reports.is_empty(), "\n{}", reports.concat());
+}
+
+/* Fuzzing tests */
+#[test]
+fn fuzz_programs_are_well_formed() {
+    for seed in 0..300 {
+        let p = fuzz::gen_prog(seed);
+        compile::check_prog(&p).unwrap();
+        infer::infer_prog(&p).unwrap();
+        // the printed program parses back to itself
+        let source = fuzz::pretty(&p);
+        let parsed = ProgParser::new().parse(&mut vec![], &mut vec![], Lexer::new(&source)).unwrap().prog;
+        assert_eq!(fuzz::pretty(&parsed), source);
+    }
+}
+
+#[test]
+fn fuzz_shrinks_to_a_minimal_program() {
+    let p = (0..)
+        .map(fuzz::gen_prog)
+        .find(|p| fuzz::pretty(p).contains("//") && fuzz::pretty(p).len() > 40)
+        .unwrap();
+    let shrunk = fuzz::shrink(&p, |q| fuzz::pretty(q).contains("//"));
+    let source = fuzz::pretty(&shrunk);
+    // nothing is left but the floor division and the variable it uses
+    assert_eq!(source, "(let fuel_while6 = 0 in\n    (fuel_while6 // 0))");
+    let parsed = ProgParser::new().parse(&mut vec![], &mut vec![], Lexer::new(&source)).unwrap().prog;
+    assert_eq!(fuzz::pretty(&parsed), source);
+}
+
+#[test]
+fn fuzz_programs_agree() {
+    let seeds: Vec<u64> = (0..32).collect();
+    let reports: Vec<String> = std::thread::scope(|s| {
+        let handles: Vec<_> = seeds
+            .chunks(8)
+            .map(|chunk| {
+                s.spawn(move || {
+                    let tmp_dir = tempfile::TempDir::new().unwrap();
+                    let file = tmp_dir.path().join("fuzz.garter");
+                    let mut reports = vec![];
+                    for &seed in chunk {
+                        match fuzz::differ(&fuzz::gen_prog(seed), &file, tmp_dir.path()) {
+                            Ok(None) => {}
+                            Ok(Some(report)) => reports.push(format!("seed {}: {}", seed, report)),
+                            Err(e) => reports.push(format!("seed {}: {}\n", seed, e)),
+                        }
+                    }
+                    reports
+                })
+            })
+            .collect();
+        handles.into_iter().flat_map(|h| h.join().unwrap()).collect()
+    });
+    assert!(reports.is_empty(), "\n{}", reports.concat());
+}
+
+/* REPL tests */
+fn repl_session(input: &str) -> String {
+    let mut w = Vec::new();
+    repl::Repl::new().run(input.as_bytes(), &mut w, false).unwrap();
+    String::from_utf8(w).unwrap()
+}
+
+#[test]
+fn repl_keeps_definitions() {
+    let out = repl_session(
+        "\
+def f(x): x + 1 in f(2)
+let y = f(10) in y
+y * 2
+(* a comment goes on
+   in the next lines *) y + 1 # up to here
+def g(n):
+  if n < 1: 0
+  else: n + g(n - 1)
+in g(3)
+g(y)
+",
+    );
+    assert_eq!(out, "3\n11\n22\n12\n6\n66\n");
+}
+
+#[test]
+fn repl_errors_keep_going() {
+    let out = repl_session(
+        "\
+let x = 1 in x
+x + true
+let y = 1 // 0 in y
+y
+(1 2)
+def f(a): a in 0
+def f(b): b in 0
+x
+",
+    );
+    let mut rest = out.as_str();
+    for expected in [
+        "1\n",
+        "arithmetic expected a number or float",
+        "division by zero",
+        "Unbound variable y",
+        "error: expected `,` or `)`, found `2`",
+        "0\n",
+        "multiple defined functions named \"f\"",
+    ] {
+        match rest.find(expected) {
+            Some(i) => rest = &rest[i + expected.len()..],
+            None => panic!("expected {} in order in\n{}", expected, out),
+        }
+    }
+    assert!(out.ends_with("\n1\n"), "{}", out);
+}
+
+#[test]
+fn repl_commands() {
+    let out = repl_session(
+        "\
+def id(x): x in 0
+:type id
+:type (id(true), 1.5 + 1)
+:load examples/add.garter
+:asm id(5)
+",
+    );
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines[..4], ["0", "('t1) -> 't1", "Tuple", "2.5"]);
+    assert!(out.contains("start_here:"), "{}", out);
+}
+
+#[test]
+fn repl_rejects_imports() {
+    let out = repl_session("import \"examples/math_util.garter\" square(2)\n1\n");
+    assert!(out.contains("the REPL can't import files"), "{}", out);
+    assert!(out.ends_with("\n1\n"), "{}", out);
+}
+
+/* Error reporting tests */
+mk_fail_test!(many_errors, "../examples/many_errors.garter", "compilation failed with 10 errors");
+
+#[test]
+fn errors_in_source_order() {
+    let err = interp("examples/many_errors.garter").unwrap_err();
+    let headers: Vec<&str> = err.lines().filter(|l| l.starts_with("error")).collect();
+    let locations: Vec<&str> = err.lines().filter(|l| l.starts_with(" --> ")).collect();
+    // recovering from an unbound variable or a wrong arity call still
+    // finds the errors after it
+    let expected = [
+        ("error[E0001]: Unbound variable z", "2:9"),
+        ("error[E0007]: multiple arguments named \"u\"", "3:1"),
+        ("error[E0008]: function f of arity 2 called with 1 arguments", "3:14"),
+        ("error[E0006]: multiple defined functions named \"g\"", "4:1"),
+        ("error[E0008]: function f of arity 2 called with 1 arguments", "5:9"),
+        ("error[E0003]: Variable b defined twice in let-expression", "5:19"),
+        ("error[E0001]: Unbound variable q", "5:19"),
+        ("error[E0002]: Undefined function h called", "6:1"),
+        ("error[E0001]: Unbound variable w", "6:6"),
+        ("error[E0008]: function f of arity 2 called with 3 arguments", "6:11"),
+    ];
+    assert_eq!(headers.len(), expected.len(), "{}", err);
+    for ((header, location), (expected_header, line_col)) in headers.iter().zip(locations).zip(expected) {
+        assert_eq!(*header, expected_header);
+        assert_eq!(location, format!(" --> examples/many_errors.garter:{}", line_col));
+    }
+    assert!(err.ends_with("\ncompilation failed with 10 errors"), "{}", err);
+}
+
+#[test]
+fn error_snippets() {
+    let err = interp("examples/diagnostics.garter").unwrap_err();
+    assert_eq!(
+        err,
+        "\
+error[E0002]: Undefined function fac called
+ --> examples/diagnostics.garter:2:36
+  |
+2 | def fact(n): if n < 1: 1 else: n * fac(n - 1) in
+  |                                    ^^^^^^^^^^ not a function in this scope
+  |
+  = help: did you mean `fact`?
+
+error[E0003]: Variable x defined twice in let-expression
+ --> examples/diagnostics.garter:3:16
+  |
+3 | let x = 1, x = 2 in
+  |         - first binding of `x`
+  |                ^ `x` bound again here
+
+error[E0001]: Unbound variable cont
+ --> examples/diagnostics.garter:4:1
+  |
+4 | cont + fact(2, 3) + (1 + true)
+  | ^^^^ not found in this scope
+  |
+  = help: did you mean `count`?
+
+error[E0008]: function fact of arity 1 called with 2 arguments
+ --> examples/diagnostics.garter:4:8
+  |
+4 | cont + fact(2, 3) + (1 + true)
+  |        ^^^^^^^^^^ expected 1 arguments
+
+compilation failed with 4 errors"
+    );
+}
+
+#[test]
+fn parse_error_recovery() {
+    let err = interp("examples/parse_errors.garter").unwrap_err();
+    assert_eq!(
+        err,
+        "\
+error: expected an expression, found `)`
+ --> examples/parse_errors.garter:1:14
+  |
+1 | let x = (1 + ) in
+  |              ^ expected an expression
+
+error: expected `in` or `,` after the bindings of a `let`, found `4`
+ --> examples/parse_errors.garter:2:11
+  |
+2 | let y = 3 4 in
+  |           ^ expected `in` or `,`
+
+error: expected `else:` after the then-branch, found `1`
+ --> examples/parse_errors.garter:3:9
+  |
+3 | if x: y 1 else: y
+  |         ^ expected `else:`
+
+parsing failed with 3 errors"
+    );
+}
+
+#[test]
+fn parse_error_cascade() {
+    // the `:` and the `in` of the definition the parser gave up on
+    // aren't reported as errors of their own
+    let err = interp("examples/parse_keyword_name.garter").unwrap_err();
+    assert_eq!(
+        err,
+        "\
+error: expected a name, found `len`
+ --> examples/parse_keyword_name.garter:1:5
+  |
+1 | def len(x):
+  |     ^^^ expected a name
+
+parsing failed with 1 error"
+    );
+}
+
+#[test]
+fn snippet_unicode() {
+    // the carets count characters, not bytes
+    let err = interp("examples/snippet_unicode.garter").unwrap_err();
+    assert!(
+        err.contains("2 | let s = \"héllo wörld\" in s ++ nmae\n  |                               ^^^^ not found"),
+        "{}",
+        err
+    );
+    let err = interp("examples/snippet_unicode_span.garter").unwrap_err();
+    assert!(err.contains("2 | (1 \"wörld\")\n  |    ^^^^^^^ expected"), "{}", err);
+}
+
+mk_test!(comments, "../examples/comments.garter", "(42, -1)");
+mk_fail_test!(unterminated_comment, "../examples/unterminated_comment.garter", "unterminated comment");
+
+#[test]
+fn comment_offsets() {
+    // the comments before an error count towards its line and column
+    let err = interp("examples/comment_error.garter").unwrap_err();
+    assert_eq!(
+        err,
+        "\
+error[E0001]: Unbound variable y
+ --> examples/comment_error.garter:4:17
+  |
+4 | x + (* not x *) y
+  |                 ^ not found in this scope
+
+compilation failed with 1 error"
+    );
+    let err = interp("examples/unterminated_comment.garter").unwrap_err();
+    assert!(
+        err.starts_with("error: unterminated comment\n --> examples/unterminated_comment.garter:2:5\n"),
+        "{}",
+        err
+    );
+    assert!(err.contains("  |     ^^ `(*` without a matching `*)`"), "{}", err);
+}
+
+fn json_errors(f: &str) -> Vec<String> {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let err = runner::compile_and_run_file(std::path::Path::new(f), tmp_dir.path(), &mut Vec::new()).unwrap_err();
+    diagnostic::json(&err, f)
+}
+
+#[test]
+fn json_compile_errors() {
+    let errs = json_errors("examples/diagnostics.garter");
+    assert_eq!(errs.len(), 4);
+    assert_eq!(
+        errs[1],
+        "{\"kind\": \"compile\", \"code\": \"E0003\", \"message\": \"Variable x defined twice in let-expression\", \
+         \"file\": \"examples/diagnostics.garter\", \"spans\": [\
+         {\"start_line\": 3, \"start_col\": 15, \"end_line\": 3, \"end_col\": 16, \"primary\": true, \"label\": \"`x` bound again here\"}, \
+         {\"start_line\": 3, \"start_col\": 8, \"end_line\": 3, \"end_col\": 9, \"primary\": false, \"label\": \"first binding of `x`\"}], \
+         \"help\": null}"
+    );
+    assert!(errs[2].contains("\"code\": \"E0001\"") && errs[2].contains("\"help\": \"did you mean `count`?\""));
+}
+
+#[test]
+fn json_parse_and_runtime_errors() {
+    let errs = json_errors("examples/parse_error.garter");
+    assert_eq!(errs.len(), 1);
+    assert_eq!(
+        errs[0],
+        "{\"kind\": \"parse\", \"code\": null, \"message\": \"expected an expression, found the end of the input\", \
+         \"file\": \"examples/parse_error.garter\", \"spans\": [{\"start_line\": 2, \"start_col\": 3, \"end_line\": 2, \"end_col\": 4, \"primary\": true, \"label\": \"expected an expression\"}], \"help\": null}"
+    );
+    assert_eq!(json_errors("examples/parse_errors.garter").len(), 3);
+    // the runtime exits with 1 + the code passed to snake_error
+    assert_eq!(
+        json_errors("examples/type_dyn_runtime.garter"),
+        ["{\"kind\": \"runtime\", \"code\": \"R0001\", \"message\": \"arithmetic expected a number or float, but got true\", \
+          \"file\": \"examples/type_dyn_runtime.garter\", \"spans\": [{\"start_line\": 2, \"start_col\": 0, \"end_line\": 2, \"end_col\": 8, \"primary\": true, \"label\": \"the error happened here\"}], \"help\": null}"]
+    );
+    assert!(json_errors("examples/division_by_zero.garter")[0].contains("\"code\": \"R0006\""));
+    // the message is all of the value, whatever text it has
+    assert_eq!(
+        json_errors("examples/runtime_error_text.garter"),
+        ["{\"kind\": \"runtime\", \"code\": \"R0001\", \"message\": \"arithmetic expected a number or float, but got a\\\"b\\n\\tc at line 9\", \
+          \"file\": \"examples/runtime_error_text.garter\", \"spans\": [{\"start_line\": 3, \"start_col\": 0, \"end_line\": 3, \"end_col\": 5, \"primary\": true, \"label\": \"the error happened here\"}], \"help\": null}"]
+    );
+    // an error in an imported file is in that file
+    assert!(json_errors("examples/import_runtime_error.garter")[0].contains("\"file\": \"examples/math_util.garter\""));
+}